@@ -2,6 +2,8 @@ use std::path::Path;
 
 use clap::Parser;
 use hyperblow::parser::magnet_uri_parser::MagnetURIMeta;
+
+use crate::core::tracker::TierMode;
 use thiserror::Error;
 
 #[derive(Debug, Parser, Default)]
@@ -14,6 +16,16 @@ pub struct Arguments {
     /// URI of the torrent file you wish to download
     #[arg(short('m'), long("magnet"), value_name = "URI")]
     pub magnet_uri: Option<String>,
+
+    /// Announce to every tracker tier at once, rather than to the next tier only when the trackers
+    /// of the one before it fail
+    #[arg(long("all-tiers"))]
+    pub all_tiers: bool,
+
+    /// Serve the files of the torrents over HTTP on 127.0.0.1 at this port, so a media player can
+    /// play them while they download. A port of 0 picks a free one
+    #[arg(long("stream-port"), value_name = "PORT")]
+    pub stream_port: Option<u16>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -57,6 +69,14 @@ impl Arguments {
         !(self.is_file_argument_provided() || self.is_magnet_uri_provided())
     }
 
+    pub fn tier_mode(&self) -> TierMode {
+        if self.all_tiers {
+            TierMode::Parallel
+        } else {
+            TierMode::Failover
+        }
+    }
+
     pub fn source(&self) -> Result<Option<TorrentInput>, ArgumentError> {
         if self.is_both_argument_provided() {
             return Err(ArgumentError::MultipleSources);
@@ -83,6 +103,8 @@ impl Arguments {
 #[cfg(test)]
 mod tests {
     use super::{ArgumentError, Arguments, TorrentInput};
+    use crate::core::tracker::TierMode;
+    use clap::Parser;
 
     #[test]
     fn no_source_starts_idle() {
@@ -96,6 +118,7 @@ mod tests {
         let args = Arguments {
             torrent_file: Some("test.torrent".to_string()),
             magnet_uri: Some("magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10".to_string()),
+            ..Default::default()
         };
 
         assert!(matches!(args.source(), Err(ArgumentError::MultipleSources)));
@@ -107,8 +130,23 @@ mod tests {
         let args = Arguments {
             torrent_file: None,
             magnet_uri: Some(uri.clone()),
+            ..Default::default()
         };
 
         assert_eq!(args.source().expect("magnet should be valid"), Some(TorrentInput::MagnetUri(uri)));
     }
+
+    #[test]
+    fn all_tiers_flag_announces_to_tiers_in_parallel() {
+        assert_eq!(Arguments::default().tier_mode(), TierMode::Failover);
+        let args = Arguments::parse_from(["hyperblow", "--all-tiers"]);
+        assert_eq!(args.tier_mode(), TierMode::Parallel);
+    }
+
+    #[test]
+    fn stream_port_is_optional() {
+        assert_eq!(Arguments::default().stream_port, None);
+        let args = Arguments::parse_from(["hyperblow", "--stream-port", "8888"]);
+        assert_eq!(args.stream_port, Some(8888));
+    }
 }
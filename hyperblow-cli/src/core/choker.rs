@@ -0,0 +1,191 @@
+use super::{peer::PeerCommand, state::State};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::debug;
+
+/// How often the peers to unchoke are chosen again
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke moves on to another peer every third rechoke, i.e every 30 seconds
+const OPTIMISTIC_ROTATION_ROUNDS: usize = 3;
+
+/// No of peers unchoked for their rate, the optimistic unchoke comes on top of these
+pub const UNCHOKE_SLOTS: usize = 4;
+
+/// Tit-for-tat choker of a torrent
+///
+/// Every 10 seconds, it unchokes the interested peers that give us the best download rate, or the
+/// ones we upload the fastest to once we're seeding, and chokes everyone else. One more peer is
+/// unchoked optimistically regardless of its rate, so new peers get a chance to prove themselves,
+/// that slot rotates every 30 seconds.
+#[derive(Debug)]
+pub struct Choker {
+    state: Arc<State>,
+    round: usize,
+    optimistic: Option<SocketAddr>,
+}
+
+impl Choker {
+    pub fn new(state: Arc<State>) -> Self {
+        Self {
+            state,
+            round: 0,
+            optimistic: None,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut ticker = interval(RECHOKE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.rechoke().await;
+        }
+    }
+
+    /// Decides which peers are unchoked for the next 10 seconds and tells their sessions
+    pub async fn rechoke(&mut self) {
+        let seeding = self.state.piece_picker.lock().await.all_wanted_completed();
+        let peers = self.state.peers.lock().await.clone();
+        let mut candidates = Vec::with_capacity(peers.len());
+        for peer in &peers {
+            candidates.push(ChokeCandidate {
+                socket_adr: peer.socket_adr,
+                interested: peer.is_interested_in_us().await,
+                rate: if seeding {
+                    peer.upload_rate.bytes_per_second()
+                } else {
+                    peer.download_rate.bytes_per_second()
+                },
+            });
+        }
+
+        let regular = ChokeRound::regular_unchokes(&candidates);
+        let optimistic_lost = self.optimistic.is_none_or(|optimistic| {
+            !candidates
+                .iter()
+                .any(|candidate| candidate.socket_adr == optimistic && candidate.interested)
+        });
+        if self.round.is_multiple_of(OPTIMISTIC_ROTATION_ROUNDS) || optimistic_lost {
+            self.optimistic = ChokeRound::optimistic_unchoke(&candidates, &regular);
+        }
+        self.state
+            .set_optimistic_unchoke(self.optimistic.filter(|optimistic| !regular.contains(optimistic)));
+        self.round = self.round.wrapping_add(1);
+
+        let mut unchoked = regular;
+        unchoked.extend(self.optimistic.filter(|optimistic| !unchoked.contains(optimistic)));
+        debug!(seeding, unchoked = unchoked.len(), peers = peers.len(), "rechoked peers");
+
+        for peer in &peers {
+            let command = if unchoked.contains(&peer.socket_adr) {
+                PeerCommand::Unchoke
+            } else {
+                PeerCommand::Choke
+            };
+            peer.send_command(command);
+        }
+    }
+
+    /// Whether a newly interested peer can be unchoked right away, without waiting for the next
+    /// rechoke
+    ///
+    /// Only the peers with a running session take up a slot, and the optimistic unchoke doesn't
+    /// count as it comes on top of the regular slots
+    pub async fn has_free_slot(state: &State) -> bool {
+        let peers = state.peers.lock().await.clone();
+        let optimistic = state.optimistic_unchoke();
+        let mut unchoked = 0;
+        for peer in peers.iter().filter(|peer| Some(peer.socket_adr) != optimistic) {
+            if peer.is_unchoked_by_us().await {
+                unchoked += 1;
+            }
+        }
+        unchoked < UNCHOKE_SLOTS
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChokeCandidate {
+    socket_adr: SocketAddr,
+    interested: bool,
+    rate: usize,
+}
+
+/// The pure part of a rechoke, kept apart from the peers so it can be tested
+struct ChokeRound;
+
+impl ChokeRound {
+    /// The interested peers with the best rate, they keep their slot for as long as they stay on top
+    fn regular_unchokes(candidates: &[ChokeCandidate]) -> Vec<SocketAddr> {
+        let mut interested = candidates.iter().filter(|candidate| candidate.interested).collect::<Vec<_>>();
+        interested.sort_by(|a, b| b.rate.cmp(&a.rate).then_with(|| a.socket_adr.cmp(&b.socket_adr)));
+        interested
+            .into_iter()
+            .take(UNCHOKE_SLOTS)
+            .map(|candidate| candidate.socket_adr)
+            .collect()
+    }
+
+    /// A random interested peer that didn't get a regular slot
+    fn optimistic_unchoke(candidates: &[ChokeCandidate], regular: &[SocketAddr]) -> Option<SocketAddr> {
+        let choked = candidates
+            .iter()
+            .filter(|candidate| candidate.interested && !regular.contains(&candidate.socket_adr))
+            .collect::<Vec<_>>();
+        if choked.is_empty() {
+            return None;
+        }
+        Some(choked[rand::random_range(0..choked.len())].socket_adr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChokeCandidate, ChokeRound, UNCHOKE_SLOTS};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn unchokes_fastest_interested_peers() {
+        let candidates = (0..6)
+            .map(|index| ChokeCandidate {
+                socket_adr: address(index),
+                interested: index != 5,
+                rate: index as usize * 100,
+            })
+            .collect::<Vec<_>>();
+
+        let unchoked = ChokeRound::regular_unchokes(&candidates);
+
+        assert_eq!(unchoked.len(), UNCHOKE_SLOTS);
+        assert_eq!(unchoked, vec![address(4), address(3), address(2), address(1)]);
+    }
+
+    #[test]
+    fn optimistic_unchoke_skips_regular_and_uninterested_peers() {
+        let candidates = vec![
+            ChokeCandidate {
+                socket_adr: address(1),
+                interested: true,
+                rate: 500,
+            },
+            ChokeCandidate {
+                socket_adr: address(2),
+                interested: false,
+                rate: 0,
+            },
+            ChokeCandidate {
+                socket_adr: address(3),
+                interested: true,
+                rate: 0,
+            },
+        ];
+
+        assert_eq!(ChokeRound::optimistic_unchoke(&candidates, &[address(1)]), Some(address(3)));
+        assert_eq!(ChokeRound::optimistic_unchoke(&candidates, &[address(1), address(3)]), None);
+    }
+
+    fn address(index: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 6000 + index))
+    }
+}
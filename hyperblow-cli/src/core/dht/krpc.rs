@@ -0,0 +1,250 @@
+use super::{routing_table::NodeEntry, NodeId};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Length of a node in the compact "nodes" format, 20 bytes of id followed by 6 bytes of address
+pub const COMPACT_NODE_LENGTH: usize = 26;
+
+/// Length of a peer in the compact "values" format, 4 bytes of IPv4 address and 2 bytes of port
+pub const COMPACT_PEER_LENGTH: usize = 6;
+
+/// A KRPC message, every DHT query, response and error is a single bencoded dictionary sent in
+/// a single UDP packet
+///
+/// "y" tells them apart, it's "q" for a query with its method in "q" and arguments in "a", "r"
+/// for a response with its values in "r" and "e" for an error as a list of code and message.
+/// Fields are declared in the order of their keys, as bencode dictionaries must be sorted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<KrpcArguments>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<KrpcValues>,
+
+    /// Transaction id, the response echoes back the one of its query
+    pub t: ByteBuf,
+
+    pub y: String,
+}
+
+/// Arguments of a query, "id" is always there and the rest depend on the method
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KrpcArguments {
+    pub id: ByteBuf,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+/// Values of a response, "id" is always there and the rest depend on the method of the query
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KrpcValues {
+    pub id: ByteBuf,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+}
+
+/// Methods of the queries a node answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KrpcMethod {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+}
+
+impl KrpcMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ping => "ping",
+            Self::FindNode => "find_node",
+            Self::GetPeers => "get_peers",
+            Self::AnnouncePeer => "announce_peer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ping" => Some(Self::Ping),
+            "find_node" => Some(Self::FindNode),
+            "get_peers" => Some(Self::GetPeers),
+            "announce_peer" => Some(Self::AnnouncePeer),
+            _ => None,
+        }
+    }
+}
+
+impl KrpcMessage {
+    pub fn query(transaction_id: &[u8], method: KrpcMethod, arguments: KrpcArguments) -> Self {
+        Self {
+            a: Some(arguments),
+            e: None,
+            q: Some(method.name().to_string()),
+            r: None,
+            t: ByteBuf::from(transaction_id.to_vec()),
+            y: "q".to_string(),
+        }
+    }
+
+    pub fn response(transaction_id: &[u8], values: KrpcValues) -> Self {
+        Self {
+            a: None,
+            e: None,
+            q: None,
+            r: Some(values),
+            t: ByteBuf::from(transaction_id.to_vec()),
+            y: "r".to_string(),
+        }
+    }
+
+    pub fn error(transaction_id: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            a: None,
+            e: Some((code, message.to_string())),
+            q: None,
+            r: None,
+            t: ByteBuf::from(transaction_id.to_vec()),
+            y: "e".to_string(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        serde_bencode::to_bytes(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(bytes)
+    }
+
+    /// Whether a UDP packet looks like KRPC rather than a UDP tracker response, tracker responses
+    /// start with a big endian action that's never bigger than 3, KRPC always starts with "d"
+    pub fn is_krpc(packet: &[u8]) -> bool {
+        packet.first() == Some(&b'd')
+    }
+}
+
+/// Encoding of the compact node and peer formats of BEP 5, only IPv4 is carried in them
+pub struct CompactInfo;
+
+impl CompactInfo {
+    pub fn encode_nodes(nodes: &[NodeEntry]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LENGTH);
+        for node in nodes {
+            if let SocketAddr::V4(address) = node.address {
+                bytes.extend_from_slice(&node.id);
+                bytes.extend_from_slice(&address.ip().octets());
+                bytes.extend_from_slice(&address.port().to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Decodes the "nodes" of a response, a trailing partial entry is ignored
+    pub fn decode_nodes(bytes: &[u8]) -> Vec<NodeEntry> {
+        bytes
+            .chunks_exact(COMPACT_NODE_LENGTH)
+            .filter_map(|node| {
+                let mut id: NodeId = [0; 20];
+                id.copy_from_slice(&node[..20]);
+                let address = Self::decode_peer(&node[20..])?;
+                Some(NodeEntry::new(id, address))
+            })
+            .collect()
+    }
+
+    pub fn encode_peer(address: SocketAddr) -> Option<Vec<u8>> {
+        match address {
+            SocketAddr::V4(address) => {
+                let mut bytes = address.ip().octets().to_vec();
+                bytes.extend_from_slice(&address.port().to_be_bytes());
+                Some(bytes)
+            }
+            SocketAddr::V6(_) => None,
+        }
+    }
+
+    pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+        if bytes.len() != COMPACT_PEER_LENGTH {
+            return None;
+        }
+        let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+        let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+        (port != 0).then_some(SocketAddr::new(IpAddr::V4(ip), port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompactInfo, KrpcArguments, KrpcMessage, KrpcMethod};
+    use crate::core::dht::routing_table::NodeEntry;
+    use serde_bytes::ByteBuf;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn encodes_ping_query_like_bep_5_example() {
+        let arguments = KrpcArguments {
+            id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+            ..KrpcArguments::default()
+        };
+        let query = KrpcMessage::query(b"aa", KrpcMethod::Ping, arguments);
+
+        let bytes = query.to_bytes().expect("query should encode");
+
+        assert_eq!(bytes, b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+        assert_eq!(KrpcMessage::from_bytes(&bytes).expect("query should decode"), query);
+        assert!(KrpcMessage::is_krpc(&bytes));
+        assert!(!KrpcMessage::is_krpc(&[0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn decodes_error_and_ignores_unknown_keys() {
+        let error = KrpcMessage::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:v4:LT011:y1:ee").expect("error should decode");
+
+        assert_eq!(error.e, Some((201, "A Generic Error Ocurred".to_string())));
+        assert_eq!(error.t.as_ref(), b"aa");
+    }
+
+    #[test]
+    fn round_trips_compact_nodes() {
+        let nodes = vec![
+            NodeEntry::new([1; 20], SocketAddr::from(([127, 0, 0, 1], 6881))),
+            NodeEntry::new([2; 20], SocketAddr::from(([10, 0, 0, 2], 51413))),
+        ];
+
+        let bytes = CompactInfo::encode_nodes(&nodes);
+        let decoded = CompactInfo::decode_nodes(&bytes);
+
+        assert_eq!(bytes.len(), 52);
+        assert_eq!(
+            decoded.iter().map(|node| (node.id, node.address)).collect::<Vec<_>>(),
+            nodes.iter().map(|node| (node.id, node.address)).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,819 @@
+// Mainline DHT, see BEP 5 : https://www.bittorrent.org/beps/bep_0005.html
+
+mod krpc;
+mod routing_table;
+
+use self::{
+    krpc::{CompactInfo, KrpcArguments, KrpcMessage, KrpcMethod, KrpcValues},
+    routing_table::{Distance, NodeEntry, RoutingTable, K},
+};
+use super::resume::STATE_DIRECTORY;
+use crossbeam::atomic::AtomicCell;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    fs,
+    net::{lookup_host, UdpSocket},
+    sync::{oneshot, Mutex},
+    task::JoinSet,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+/// Ids of DHT nodes and info hashes share the same 160 bit space
+pub type NodeId = [u8; 20];
+
+/// Well known routers used to join the DHT when we don't know any node yet
+const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// File inside of the state directory that keeps our node id and the nodes we knew
+const NODE_TABLE_FILE: &str = "dht.dat";
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// No of nodes queried at once during a lookup
+const ALPHA: usize = 3;
+
+/// A lookup gives up after this many rounds even if it keeps getting closer
+const MAX_LOOKUP_ROUNDS: usize = 16;
+
+/// The secret that tokens are made from changes this often, tokens of the previous secret are
+/// still accepted, so a token stays valid for 5 to 10 minutes
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Peers announced to us are forgotten after this long unless they announce again
+const ANNOUNCED_PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+const MAX_ANNOUNCED_PEERS: usize = 100;
+
+/// Most nodes that queried us out of the blue being pinged at once, the queries of more new nodes
+/// are answered but they aren't pinged
+const MAX_VERIFYING_NODES: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error("DHT socket error")]
+    Io(#[from] io::Error),
+
+    #[error("KRPC bencode could not be encoded or decoded")]
+    Bencode(#[from] serde_bencode::Error),
+
+    #[error("DHT query to {0} timed out")]
+    Timeout(SocketAddr),
+
+    #[error("DHT node {address} answered with error {code}: {message}")]
+    Remote { address: SocketAddr, code: i64, message: String },
+
+    #[error("DHT node {0} sent a malformed response")]
+    MalformedResponse(SocketAddr),
+}
+
+/// What a node answered to get_peers
+#[derive(Debug, Default)]
+pub struct GetPeersReply {
+    /// Has to be given back when announcing to the node
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<NodeEntry>,
+}
+
+/// Result of an iterative lookup
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    /// The nodes that answered, with the token they gave, closest to the target first
+    responded: Vec<(NodeEntry, Option<Vec<u8>>)>,
+}
+
+type PendingQueries = HashMap<(SocketAddr, Vec<u8>), oneshot::Sender<Result<KrpcValues, DhtError>>>;
+
+/// A node of the mainline DHT
+///
+/// It doesn't own a receive loop, the UDP socket is shared with the UDP trackers and whoever
+/// reads the socket hands the KRPC packets over to [DhtNode::handle_packet]
+#[derive(Debug)]
+pub struct DhtNode {
+    socket: Arc<UdpSocket>,
+    routing_table: Mutex<RoutingTable>,
+    /// Queries we sent that are waiting for their response, by address and transaction id
+    pending: Mutex<PendingQueries>,
+    next_transaction_id: AtomicCell<u16>,
+    tokens: Mutex<TokenSecrets>,
+    /// Peers that announced themselves to us, by info hash
+    announced_peers: Mutex<HashMap<NodeId, Vec<(SocketAddr, Instant)>>>,
+    /// Nodes that queried us and aren't in the routing table yet, they're pinged first and go
+    /// in once they answer (BEP 5), anybody can put any id in a query of theirs
+    verifying: Mutex<HashSet<SocketAddr>>,
+    /// Where the node table is persisted, None keeps the node in memory only
+    node_table_path: Option<PathBuf>,
+    /// Nodes of the previous session, they're tried along with the routers on bootstrap
+    saved_nodes: Vec<SocketAddr>,
+}
+
+impl DhtNode {
+    /// Creates a node on the given socket, with the id and the nodes saved in the node table of
+    /// the download directory, or a random id when there's no node table yet
+    pub async fn new(socket: Arc<UdpSocket>, download_directory: &Path) -> Self {
+        let node_table_path = download_directory.join(STATE_DIRECTORY).join(NODE_TABLE_FILE);
+        let node_table = DhtNodeTable::load(&node_table_path).await;
+        let id = node_table.as_ref().map_or_else(rand::random, |node_table| node_table.id);
+        let saved_nodes = node_table.map(|node_table| node_table.nodes).unwrap_or_default();
+        Self {
+            node_table_path: Some(node_table_path),
+            saved_nodes,
+            ..Self::with_id(socket, id)
+        }
+    }
+
+    pub fn with_id(socket: Arc<UdpSocket>, id: NodeId) -> Self {
+        Self {
+            socket,
+            routing_table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::default(),
+            next_transaction_id: AtomicCell::new(rand::random()),
+            tokens: Mutex::new(TokenSecrets::new()),
+            announced_peers: Mutex::default(),
+            verifying: Mutex::default(),
+            node_table_path: None,
+            saved_nodes: Vec::new(),
+        }
+    }
+
+    pub async fn id(&self) -> NodeId {
+        self.routing_table.lock().await.own_id()
+    }
+
+    pub async fn node_count(&self) -> usize {
+        self.routing_table.lock().await.len()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// A node on its own localhost socket, with the receive loop a torrent would run
+    #[cfg(test)]
+    pub(crate) async fn spawn_local(id: NodeId) -> Arc<Self> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("socket should bind"));
+        let node = Arc::new(Self::with_id(socket.clone(), id));
+        let receiver = node.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if Self::is_krpc(&buf[..len]) {
+                    receiver.handle_packet(&buf[..len], from).await;
+                }
+            }
+        });
+        node
+    }
+
+    /// Whether a packet received on the shared socket is meant for the DHT
+    pub fn is_krpc(packet: &[u8]) -> bool {
+        KrpcMessage::is_krpc(packet)
+    }
+
+    /// Handles a KRPC packet received on the socket, queries are answered and responses are
+    /// handed over to the query waiting for them
+    pub async fn handle_packet(self: &Arc<Self>, packet: &[u8], from: SocketAddr) {
+        let message = match KrpcMessage::from_bytes(packet) {
+            Ok(message) => message,
+            Err(error) => {
+                debug!(node = %from, error = %error, "dropping malformed KRPC packet");
+                return;
+            }
+        };
+
+        match message.y.as_str() {
+            "q" => self.answer_query(message, from).await,
+            "r" => {
+                let values = message.r.ok_or(DhtError::MalformedResponse(from));
+                self.complete_query(from, message.t.into_vec(), values).await;
+            }
+            "e" => {
+                let (code, message_text) = message.e.unwrap_or_default();
+                let error = DhtError::Remote {
+                    address: from,
+                    code,
+                    message: message_text,
+                };
+                self.complete_query(from, message.t.into_vec(), Err(error)).await;
+            }
+            kind => debug!(node = %from, kind, "dropping KRPC message of unknown kind"),
+        }
+    }
+
+    /// Joins the DHT through the nodes of the previous session and the well known routers
+    pub async fn bootstrap(self: &Arc<Self>) {
+        let mut addresses = self.saved_nodes.clone();
+        for router in BOOTSTRAP_NODES {
+            match lookup_host(router).await {
+                Ok(resolved) => addresses.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(error) => debug!(router, error = %error, "failed to resolve DHT router"),
+            }
+        }
+        self.bootstrap_from(addresses).await;
+    }
+
+    /// Asks the given nodes for the nodes closest to us, and then looks ourselves up to fill the
+    /// routing table with our neighbourhood
+    pub async fn bootstrap_from(self: &Arc<Self>, addresses: Vec<SocketAddr>) {
+        let own_id = self.id().await;
+        let mut queries = JoinSet::new();
+        for address in addresses {
+            let dht = self.clone();
+            queries.spawn(async move { dht.find_node(address, own_id).await });
+        }
+        while queries.join_next().await.is_some() {}
+
+        self.lookup(own_id, false).await;
+        let node_count = self.node_count().await;
+        info!(node_count, "DHT bootstrapped");
+    }
+
+    /// Looks up the peers of a torrent, and announces that we're one of them on the nodes
+    /// closest to the info hash when a port is given
+    pub async fn find_peers(self: &Arc<Self>, info_hash: NodeId, announce_port: Option<u16>) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        if let Some(port) = announce_port {
+            let mut announces = JoinSet::new();
+            for (node, token) in lookup.responded.into_iter().take(K) {
+                let Some(token) = token else {
+                    continue;
+                };
+                let dht = self.clone();
+                announces.spawn(async move { (node.address, dht.announce_peer(node.address, info_hash, port, token).await) });
+            }
+            while let Some(announce) = announces.join_next().await {
+                if let Ok((address, Err(error))) = announce {
+                    debug!(node = %address, error = %error, "DHT announce failed");
+                }
+            }
+        }
+        lookup.peers
+    }
+
+    pub async fn ping(&self, address: SocketAddr) -> Result<NodeId, DhtError> {
+        let values = self.query(address, KrpcMethod::Ping, KrpcArguments::default()).await?;
+        NodeIdBytes::parse(&values.id).ok_or(DhtError::MalformedResponse(address))
+    }
+
+    pub async fn find_node(&self, address: SocketAddr, target: NodeId) -> Result<Vec<NodeEntry>, DhtError> {
+        let arguments = KrpcArguments {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..KrpcArguments::default()
+        };
+        let values = self.query(address, KrpcMethod::FindNode, arguments).await?;
+        Ok(values.nodes.map(|nodes| CompactInfo::decode_nodes(&nodes)).unwrap_or_default())
+    }
+
+    pub async fn get_peers(&self, address: SocketAddr, info_hash: NodeId) -> Result<GetPeersReply, DhtError> {
+        let arguments = KrpcArguments {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            ..KrpcArguments::default()
+        };
+        let values = self.query(address, KrpcMethod::GetPeers, arguments).await?;
+        Ok(GetPeersReply {
+            token: values.token.map(ByteBuf::into_vec),
+            peers: values
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|peer| CompactInfo::decode_peer(peer))
+                .collect(),
+            nodes: values.nodes.map(|nodes| CompactInfo::decode_nodes(&nodes)).unwrap_or_default(),
+        })
+    }
+
+    pub async fn announce_peer(&self, address: SocketAddr, info_hash: NodeId, port: u16, token: Vec<u8>) -> Result<(), DhtError> {
+        let arguments = KrpcArguments {
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(i64::from(port)),
+            token: Some(ByteBuf::from(token)),
+            implied_port: Some(0),
+            ..KrpcArguments::default()
+        };
+        self.query(address, KrpcMethod::AnnouncePeer, arguments).await.map(|_| ())
+    }
+
+    /// Writes our id and the nodes we know into the node table, so the next session can join the
+    /// DHT without the routers
+    pub async fn save_node_table(&self) {
+        let Some(ref path) = self.node_table_path else {
+            return;
+        };
+        let (id, nodes) = {
+            let routing_table = self.routing_table.lock().await;
+            (routing_table.own_id(), routing_table.nodes())
+        };
+        if nodes.is_empty() {
+            return;
+        }
+        let node_table = DhtNodeTable {
+            id,
+            nodes: nodes.iter().map(|node| node.address).collect(),
+        };
+        if let Err(error) = node_table.save(path).await {
+            warn!(path = %path.display(), error = %error, "failed to save DHT node table");
+        }
+    }
+
+    /// Iterative Kademlia lookup, it keeps querying the closest nodes it heard of until the [K]
+    /// closest of them have all been asked
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> Lookup {
+        let own_id = self.id().await;
+        let mut candidates = self.routing_table.lock().await.closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup::default();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            let mut seen = HashSet::new();
+            candidates.retain(|node| node.id != own_id && seen.insert(node.address));
+            candidates.sort_by_key(|node| Distance::between(&node.id, &target));
+            let batch = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.address))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for node in batch {
+                queried.insert(node.address);
+                let dht = self.clone();
+                queries.spawn(async move {
+                    let reply = if get_peers {
+                        dht.get_peers(node.address, target).await
+                    } else {
+                        dht.find_node(node.address, target).await.map(|nodes| GetPeersReply {
+                            nodes,
+                            ..GetPeersReply::default()
+                        })
+                    };
+                    (node, reply)
+                });
+            }
+
+            while let Some(result) = queries.join_next().await {
+                let Ok((node, reply)) = result else {
+                    continue;
+                };
+                match reply {
+                    Ok(reply) => {
+                        for peer in reply.peers {
+                            if !lookup.peers.contains(&peer) {
+                                lookup.peers.push(peer);
+                            }
+                        }
+                        candidates.extend(reply.nodes);
+                        lookup.responded.push((node, reply.token));
+                    }
+                    Err(error) => {
+                        debug!(node = %node.address, error = %error, "DHT lookup query failed");
+                        candidates.retain(|candidate| candidate.address != node.address);
+                    }
+                }
+            }
+        }
+
+        lookup.responded.sort_by_key(|(node, _)| Distance::between(&node.id, &target));
+        debug!(
+            peers = lookup.peers.len(),
+            responded = lookup.responded.len(),
+            "DHT lookup finished"
+        );
+        lookup
+    }
+
+    /// Sends a query and waits for its response, the node answering goes into the routing table
+    /// and the one that doesn't is marked as failed
+    async fn query(&self, address: SocketAddr, method: KrpcMethod, mut arguments: KrpcArguments) -> Result<KrpcValues, DhtError> {
+        arguments.id = ByteBuf::from(self.id().await.to_vec());
+        let transaction_id = self.next_transaction_id.fetch_add(1).to_be_bytes().to_vec();
+        let key = (address, transaction_id.clone());
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().await.insert(key.clone(), sender);
+
+        let result = self.send_query(address, &transaction_id, method, arguments, receiver).await;
+        self.pending.lock().await.remove(&key);
+
+        match result {
+            Ok(ref values) => match NodeIdBytes::parse(&values.id) {
+                Some(id) => {
+                    self.routing_table.lock().await.insert(NodeEntry::new(id, address));
+                }
+                None => return Err(DhtError::MalformedResponse(address)),
+            },
+            Err(DhtError::Timeout(_)) => self.routing_table.lock().await.mark_failed(address),
+            Err(_) => {}
+        }
+        result
+    }
+
+    async fn send_query(
+        &self,
+        address: SocketAddr,
+        transaction_id: &[u8],
+        method: KrpcMethod,
+        arguments: KrpcArguments,
+        receiver: oneshot::Receiver<Result<KrpcValues, DhtError>>,
+    ) -> Result<KrpcValues, DhtError> {
+        let packet = KrpcMessage::query(transaction_id, method, arguments).to_bytes()?;
+        self.socket.send_to(&packet, address).await?;
+        match timeout(QUERY_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => Err(DhtError::Timeout(address)),
+        }
+    }
+
+    async fn complete_query(&self, from: SocketAddr, transaction_id: Vec<u8>, result: Result<KrpcValues, DhtError>) {
+        match self.pending.lock().await.remove(&(from, transaction_id)) {
+            Some(sender) => {
+                let _ = sender.send(result);
+            }
+            None => debug!(node = %from, "dropping KRPC response without a pending query"),
+        }
+    }
+
+    async fn answer_query(self: &Arc<Self>, message: KrpcMessage, from: SocketAddr) {
+        let transaction_id = message.t.into_vec();
+        let Some(arguments) = message.a else {
+            self.send(from, KrpcMessage::error(&transaction_id, 203, "missing arguments")).await;
+            return;
+        };
+        let Some(querier_id) = NodeIdBytes::parse(&arguments.id) else {
+            self.send(from, KrpcMessage::error(&transaction_id, 203, "invalid id")).await;
+            return;
+        };
+
+        let reply = match message.q.as_deref().and_then(KrpcMethod::from_name) {
+            Some(method) => self.answer(method, &arguments, from).await,
+            None => Err((204, "method unknown")),
+        };
+        let reply = match reply {
+            Ok(values) => KrpcMessage::response(&transaction_id, values),
+            Err((code, message)) => KrpcMessage::error(&transaction_id, code, message),
+        };
+        self.send(from, reply).await;
+        if !self.routing_table.lock().await.refresh(&querier_id, from) {
+            self.verify(from).await;
+        }
+    }
+
+    /// Pings a node that queried us but isn't in the routing table, the ping's [DhtNode::query]
+    /// puts it in once it answers
+    async fn verify(self: &Arc<Self>, address: SocketAddr) {
+        {
+            let mut verifying = self.verifying.lock().await;
+            if verifying.len() >= MAX_VERIFYING_NODES || !verifying.insert(address) {
+                return;
+            }
+        }
+        let dht = self.clone();
+        tokio::spawn(async move {
+            if let Err(error) = dht.ping(address).await {
+                debug!(node = %address, error = %error, "DHT node that queried us didn't answer ping");
+            }
+            dht.verifying.lock().await.remove(&address);
+        });
+    }
+
+    async fn answer(&self, method: KrpcMethod, arguments: &KrpcArguments, from: SocketAddr) -> Result<KrpcValues, (i64, &'static str)> {
+        let (own_id, values) = {
+            let routing_table = self.routing_table.lock().await;
+            let own_id = routing_table.own_id();
+            let closest_to = |target: &NodeId| ByteBuf::from(CompactInfo::encode_nodes(&routing_table.closest(target, K)));
+            let values = match method {
+                KrpcMethod::Ping | KrpcMethod::AnnouncePeer => KrpcValues::default(),
+                KrpcMethod::FindNode => {
+                    let target = arguments.target.as_ref().and_then(|target| NodeIdBytes::parse(target));
+                    KrpcValues {
+                        nodes: Some(closest_to(&target.ok_or((203, "missing target"))?)),
+                        ..KrpcValues::default()
+                    }
+                }
+                KrpcMethod::GetPeers => {
+                    let info_hash = arguments.info_hash.as_ref().and_then(|info_hash| NodeIdBytes::parse(info_hash));
+                    KrpcValues {
+                        nodes: Some(closest_to(&info_hash.ok_or((203, "missing info_hash"))?)),
+                        ..KrpcValues::default()
+                    }
+                }
+            };
+            (own_id, values)
+        };
+        let mut values = KrpcValues {
+            id: ByteBuf::from(own_id.to_vec()),
+            ..values
+        };
+
+        match method {
+            KrpcMethod::GetPeers => {
+                let info_hash = arguments.info_hash.as_ref().and_then(|info_hash| NodeIdBytes::parse(info_hash));
+                let peers = self.announced_peers(&info_hash.ok_or((203, "missing info_hash"))?).await;
+                if !peers.is_empty() {
+                    values.values = Some(peers.into_iter().filter_map(CompactInfo::encode_peer).map(ByteBuf::from).collect());
+                    values.nodes = None;
+                }
+                values.token = Some(ByteBuf::from(self.tokens.lock().await.issue(from.ip())));
+            }
+            KrpcMethod::AnnouncePeer => {
+                let info_hash = arguments.info_hash.as_ref().and_then(|info_hash| NodeIdBytes::parse(info_hash));
+                let info_hash = info_hash.ok_or((203, "missing info_hash"))?;
+                let token = arguments.token.as_ref().ok_or((203, "missing token"))?;
+                if !self.tokens.lock().await.verify(from.ip(), token) {
+                    return Err((203, "bad token"));
+                }
+                let port = if arguments.implied_port.unwrap_or_default() != 0 {
+                    from.port()
+                } else {
+                    arguments
+                        .port
+                        .and_then(|port| u16::try_from(port).ok())
+                        .ok_or((203, "missing port"))?
+                };
+                self.store_announced_peer(info_hash, SocketAddr::new(from.ip(), port)).await;
+            }
+            KrpcMethod::Ping | KrpcMethod::FindNode => {}
+        }
+        Ok(values)
+    }
+
+    async fn announced_peers(&self, info_hash: &NodeId) -> Vec<SocketAddr> {
+        let mut announced_peers = self.announced_peers.lock().await;
+        let Some(peers) = announced_peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        peers.retain(|(_, announced_at)| now.saturating_duration_since(*announced_at) < ANNOUNCED_PEER_TTL);
+        peers.iter().map(|(peer, _)| *peer).collect()
+    }
+
+    async fn store_announced_peer(&self, info_hash: NodeId, peer: SocketAddr) {
+        let mut announced_peers = self.announced_peers.lock().await;
+        let peers = announced_peers.entry(info_hash).or_default();
+        peers.retain(|(known, _)| *known != peer);
+        if peers.len() >= MAX_ANNOUNCED_PEERS {
+            peers.remove(0);
+        }
+        peers.push((peer, Instant::now()));
+        debug!(peer = %peer, "peer announced itself on the DHT");
+    }
+
+    async fn send(&self, address: SocketAddr, message: KrpcMessage) {
+        let packet = match message.to_bytes() {
+            Ok(packet) => packet,
+            Err(error) => {
+                warn!(error = %error, "failed to encode KRPC message");
+                return;
+            }
+        };
+        if let Err(error) = self.socket.send_to(&packet, address).await {
+            debug!(node = %address, error = %error, "failed to send KRPC message");
+        }
+    }
+}
+
+struct NodeIdBytes;
+
+impl NodeIdBytes {
+    fn parse(bytes: &[u8]) -> Option<NodeId> {
+        NodeId::try_from(bytes).ok()
+    }
+}
+
+/// Tokens handed out in get_peers responses, an announce_peer must bring back a token given to
+/// the same IP address, so nobody can announce peers on behalf of someone else
+#[derive(Debug)]
+struct TokenSecrets {
+    current: [u8; 8],
+    previous: [u8; 8],
+    rotated_at: Instant,
+}
+
+impl TokenSecrets {
+    fn new() -> Self {
+        let current = rand::random();
+        Self {
+            current,
+            previous: current,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn issue(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        Self::token(&self.current, ip)
+    }
+
+    fn verify(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+        token == Self::token(&self.current, ip).as_slice() || token == Self::token(&self.previous, ip).as_slice()
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated_at = Instant::now();
+        }
+    }
+
+    fn token(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize()[..8].to_vec()
+    }
+}
+
+/// The persisted node table, our node id and the addresses of the nodes we knew
+#[derive(Debug, PartialEq)]
+struct DhtNodeTable {
+    id: NodeId,
+    nodes: Vec<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DhtNodeTableFile {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+impl DhtNodeTable {
+    async fn load(path: &Path) -> Option<Self> {
+        let bytes = fs::read(path).await.ok()?;
+        let file = serde_bencode::from_bytes::<DhtNodeTableFile>(&bytes)
+            .inspect_err(|error| warn!(path = %path.display(), error = %error, "ignoring unreadable DHT node table"))
+            .ok()?;
+        Some(Self {
+            id: NodeIdBytes::parse(&file.id)?,
+            nodes: file
+                .nodes
+                .chunks_exact(krpc::COMPACT_PEER_LENGTH)
+                .filter_map(CompactInfo::decode_peer)
+                .collect(),
+        })
+    }
+
+    async fn save(&self, path: &Path) -> Result<(), DhtError> {
+        let file = DhtNodeTableFile {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: ByteBuf::from(
+                self.nodes
+                    .iter()
+                    .filter_map(|node| CompactInfo::encode_peer(*node))
+                    .flatten()
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let temporary_path = path.with_extension("dat.tmp");
+        fs::write(&temporary_path, serde_bencode::to_bytes(&file)?).await?;
+        fs::rename(&temporary_path, path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        krpc::{KrpcArguments, KrpcMessage, KrpcMethod},
+        DhtError, DhtNode, DhtNodeTable,
+    };
+    use crate::core::test_support::temp_dir;
+    use serde_bytes::ByteBuf;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{
+        net::UdpSocket,
+        time::{sleep, timeout},
+    };
+
+    #[tokio::test]
+    async fn finds_peers_announced_on_other_nodes() {
+        let router = DhtNode::spawn_local([0x10; 20]).await;
+        let seeder = DhtNode::spawn_local([0x20; 20]).await;
+        let leecher = DhtNode::spawn_local([0x30; 20]).await;
+        let info_hash = [0x22; 20];
+
+        seeder.bootstrap_from(vec![TestNode::address(&router)]).await;
+        assert!(seeder.find_peers(info_hash, Some(51413)).await.is_empty());
+        // The router only knows the seeder once it answered its ping
+        TestNode::wait_for_nodes(&router, 1).await;
+
+        leecher.bootstrap_from(vec![TestNode::address(&router)]).await;
+        let peers = leecher.find_peers(info_hash, None).await;
+
+        assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 51413))]);
+        // The seeder was learnt through the router
+        assert_eq!(leecher.node_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn answers_ping_and_rejects_announce_without_token() {
+        let node = DhtNode::spawn_local([0x40; 20]).await;
+        let other = DhtNode::spawn_local([0x50; 20]).await;
+
+        assert_eq!(
+            other.ping(TestNode::address(&node)).await.expect("ping should be answered"),
+            [0x40; 20]
+        );
+
+        let announce = other
+            .announce_peer(TestNode::address(&node), [0x22; 20], 6881, b"forged".to_vec())
+            .await;
+        assert!(matches!(announce, Err(DhtError::Remote { code: 203, .. })));
+    }
+
+    #[tokio::test]
+    async fn pings_a_querier_before_it_goes_into_the_routing_table() {
+        let node = DhtNode::spawn_local([0x40; 20]).await;
+        // A querier that never answers, eg. one with a spoofed address
+        let silent = UdpSocket::bind("127.0.0.1:0").await.expect("socket should bind");
+        let query = KrpcMessage::query(
+            b"aa",
+            KrpcMethod::Ping,
+            KrpcArguments {
+                id: ByteBuf::from(vec![0x70; 20]),
+                ..KrpcArguments::default()
+            },
+        );
+        silent
+            .send_to(&query.to_bytes().expect("query should encode"), TestNode::address(&node))
+            .await
+            .expect("query should send");
+
+        let mut buf = [0; 1024];
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (len, _) = timeout(Duration::from_secs(2), silent.recv_from(&mut buf))
+                .await
+                .expect("node should answer and ping")
+                .expect("socket should receive");
+            received.push(KrpcMessage::from_bytes(&buf[..len]).expect("KRPC message should decode"));
+        }
+
+        // The query is answered and the querier is pinged, it's only taken in once it answers
+        assert!(received.iter().any(|message| message.y == "r"));
+        assert!(received
+            .iter()
+            .any(|message| message.y == "q" && message.q.as_deref() == Some("ping")));
+        assert_eq!(node.node_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn persists_node_table() {
+        let path = temp_dir("dht").join("dht.dat");
+        let node_table = DhtNodeTable {
+            id: [0x60; 20],
+            nodes: vec![SocketAddr::from(([127, 0, 0, 1], 6881)), SocketAddr::from(([10, 0, 0, 1], 51413))],
+        };
+
+        node_table.save(&path).await.expect("node table should save");
+
+        assert_eq!(DhtNodeTable::load(&path).await, Some(node_table));
+
+        std::fs::remove_dir_all(path.parent().expect("node table should have a directory")).expect("temp dir should be removed");
+    }
+
+    struct TestNode;
+
+    impl TestNode {
+        fn address(node: &DhtNode) -> SocketAddr {
+            node.local_addr().expect("socket should have an address")
+        }
+
+        async fn wait_for_nodes(node: &DhtNode, count: usize) {
+            timeout(Duration::from_secs(2), async {
+                while node.node_count().await < count {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("nodes should get into the routing table");
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use super::NodeId;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// No of nodes a bucket holds, it's also the no of closest nodes a lookup goes after
+pub const K: usize = 8;
+
+/// A node that hasn't been heard from for this long is questionable and can be replaced
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// A node that failed to answer this many queries in a row is dropped
+const MAX_FAILED_QUERIES: u8 = 2;
+
+/// A DHT node we know about
+#[derive(Debug, Clone)]
+pub struct NodeEntry {
+    pub id: NodeId,
+    pub address: SocketAddr,
+    last_seen: Instant,
+    failed_queries: u8,
+}
+
+impl NodeEntry {
+    pub fn new(id: NodeId, address: SocketAddr) -> Self {
+        Self {
+            id,
+            address,
+            last_seen: Instant::now(),
+            failed_queries: 0,
+        }
+    }
+
+    fn is_questionable(&self, now: Instant) -> bool {
+        self.failed_queries > 0 || now.saturating_duration_since(self.last_seen) > QUESTIONABLE_AFTER
+    }
+}
+
+/// XOR metric of Kademlia, the distance between two ids is their XOR read as a big endian number
+pub struct Distance;
+
+impl Distance {
+    pub fn between(a: &NodeId, b: &NodeId) -> NodeId {
+        let mut distance = [0; 20];
+        for (index, byte) in distance.iter_mut().enumerate() {
+            *byte = a[index] ^ b[index];
+        }
+        distance
+    }
+
+    /// No of leading bits two ids share, 160 when they're equal
+    fn common_prefix_length(a: &NodeId, b: &NodeId) -> usize {
+        let distance = Self::between(a, b);
+        distance
+            .iter()
+            .position(|&byte| byte != 0)
+            .map_or(160, |index| index * 8 + distance[index].leading_zeros() as usize)
+    }
+}
+
+/// Kademlia routing table, the nodes are kept in 160 buckets of [K] nodes by the length of the
+/// prefix their id shares with ours, so we know many nodes close to us and few far away
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<NodeEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    /// Records a node that just answered us or queried us
+    ///
+    /// A known node is refreshed, a new one is added when its bucket has room or holds a
+    /// questionable node to replace, otherwise the table stays as it is and false is given
+    pub fn insert(&mut self, node: NodeEntry) -> bool {
+        let Some(bucket) = self.bucket_mut(&node.id) else {
+            return false;
+        };
+
+        if let Some(known) = bucket.iter_mut().find(|known| known.id == node.id) {
+            known.address = node.address;
+            known.last_seen = Instant::now();
+            known.failed_queries = 0;
+            return true;
+        }
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+
+        let now = Instant::now();
+        let replaceable = bucket
+            .iter()
+            .enumerate()
+            .filter(|(_, known)| known.is_questionable(now))
+            .min_by_key(|(_, known)| known.last_seen)
+            .map(|(index, _)| index);
+        match replaceable {
+            Some(index) => {
+                bucket[index] = node;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the node as just seen when it's known at that address, and gives whether it is
+    pub fn refresh(&mut self, id: &NodeId, address: SocketAddr) -> bool {
+        let Some(bucket) = self.bucket_mut(id) else {
+            return false;
+        };
+        match bucket.iter_mut().find(|known| known.id == *id && known.address == address) {
+            Some(known) => {
+                known.last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a query the node at the address didn't answer, it's dropped after a few of them
+    pub fn mark_failed(&mut self, address: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(index) = bucket.iter().position(|node| node.address == address) {
+                bucket[index].failed_queries = bucket[index].failed_queries.saturating_add(1);
+                if bucket[index].failed_queries >= MAX_FAILED_QUERIES {
+                    bucket.remove(index);
+                }
+                return;
+            }
+        }
+    }
+
+    /// The known nodes closest to the target, closest first
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeEntry> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| Distance::between(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeEntry> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn bucket_mut(&mut self, id: &NodeId) -> Option<&mut Vec<NodeEntry>> {
+        let prefix_length = Distance::common_prefix_length(&self.own_id, id);
+        // Our own id has no bucket
+        self.buckets.get_mut(prefix_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NodeEntry, RoutingTable, K};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn keeps_at_most_k_good_nodes_per_bucket() {
+        let mut table = RoutingTable::new([0; 20]);
+        // Every id with the first bit set lands in the farthest bucket
+        for index in 0..=K as u8 {
+            let mut id = [0; 20];
+            id[0] = 0x80;
+            id[19] = index;
+            table.insert(NodeEntry::new(id, address(index)));
+        }
+
+        assert_eq!(table.len(), K);
+        assert!(!table.insert(NodeEntry::new([0; 20], address(99))));
+
+        table.mark_failed(address(0));
+        let mut id = [0; 20];
+        id[0] = 0x80;
+        id[19] = 42;
+        assert!(table.insert(NodeEntry::new(id, address(42))));
+        assert!(!table.nodes().iter().any(|node| node.address == address(0)));
+    }
+
+    #[test]
+    fn gives_closest_nodes_by_xor_distance() {
+        let mut table = RoutingTable::new([0; 20]);
+        for first_byte in [0x01, 0x02, 0x40, 0x80] {
+            let mut id = [0; 20];
+            id[0] = first_byte;
+            table.insert(NodeEntry::new(id, address(first_byte)));
+        }
+
+        let mut target = [0; 20];
+        target[0] = 0x03;
+        let closest = table.closest(&target, 2);
+
+        assert_eq!(closest.iter().map(|node| node.id[0]).collect::<Vec<_>>(), vec![0x02, 0x01]);
+    }
+
+    fn address(index: u8) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 7000 + index as u16))
+    }
+}
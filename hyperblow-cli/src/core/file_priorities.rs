@@ -0,0 +1,202 @@
+use super::{piece_storage::PieceStorage, state::State};
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// How soon the first piece of a file selected in the Files tab is wanted, a player reads it first
+const FILE_HEAD_DEADLINE: Duration = Duration::from_secs(1);
+
+/// How soon the last piece of a selected file is wanted, players of most containers look for the
+/// index of the media at the end of the file
+const FILE_TAIL_DEADLINE: Duration = Duration::from_secs(3);
+
+/// How eagerly a file of the torrent is downloaded, the pieces of higher priority files are picked
+/// before the ones of lower priority files, whatever their rarity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    /// The file isn't downloaded and never gets allocated on the disk
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    /// The priority the Files tab switches a file to next, it goes round from High back to Skip
+    pub fn next(self) -> Self {
+        match self {
+            Self::Skip => Self::Low,
+            Self::Low => Self::Normal,
+            Self::Normal => Self::High,
+            Self::High => Self::Skip,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Skip => "Skip",
+            Self::Low => "Low",
+            Self::Normal => "Normal",
+            Self::High => "High",
+        }
+    }
+}
+
+/// Turns the priorities of the files of a torrent into the priorities of its pieces
+pub struct FilePriorities;
+
+impl FilePriorities {
+    /// Gives the files at the indexes the priority and hands the piece priorities that result from
+    /// it to the piece picker
+    ///
+    /// A file that was skipped and never allocated is missing the parts of the pieces it shares
+    /// with its neighbours, those pieces are downloaded again once the file is no longer skipped
+    pub async fn set(state: &State, files: &[usize], priority: FilePriority) {
+        let (file_priorities, unskipped) = {
+            let mut file_priorities = state.file_priorities.lock().await;
+            let mut unskipped = Vec::new();
+            for &file_index in files {
+                let Some(file_priority) = file_priorities.get_mut(file_index) else {
+                    continue;
+                };
+                if *file_priority == FilePriority::Skip && priority != FilePriority::Skip && !PieceStorage::file_exists(state, file_index) {
+                    unskipped.push(file_index);
+                }
+                *file_priority = priority;
+            }
+            (file_priorities.clone(), unskipped)
+        };
+        debug!(files = files.len(), priority = priority.label(), "setting file priorities");
+
+        let file_pieces = PieceStorage::file_pieces(state);
+        let piece_priorities = Self::piece_priorities(&file_pieces, &file_priorities, state.pieces_hash.len());
+        let mut piece_picker = state.piece_picker.lock().await;
+        piece_picker.set_priorities(&piece_priorities);
+        for piece_index in unskipped
+            .into_iter()
+            .filter_map(|file_index| file_pieces.get(file_index))
+            .flat_map(Clone::clone)
+        {
+            if piece_picker.is_completed(piece_index) {
+                piece_picker.mark_missing(piece_index);
+                let piece_length = state.piece_length_at(piece_index).unwrap_or_default();
+                state.set_bytes_complete(state.bytes_complete().saturating_sub(piece_length));
+                state.set_pieces_downloaded(state.pieces_downloaded().saturating_sub(1));
+            }
+        }
+    }
+
+    /// Puts the first and last pieces of the files at the front of the queue, in place of the
+    /// pieces of the files that were selected before. The deadlines set by others, eg. the reads of
+    /// the streaming server, are kept
+    pub async fn set_edge_deadlines(state: &State, files: &[usize]) {
+        let file_pieces = PieceStorage::file_pieces(state);
+        let now = Instant::now();
+        let mut edge_deadlines = state.edge_deadlines.lock().await;
+        let mut piece_picker = state.piece_picker.lock().await;
+        for (piece_index, deadline) in edge_deadlines.drain(..) {
+            piece_picker.remove_deadline(piece_index, deadline);
+        }
+        for pieces in files.iter().filter_map(|&file_index| file_pieces.get(file_index)) {
+            if let Some(last_piece) = pieces.end.checked_sub(1).filter(|&last_piece| last_piece >= pieces.start) {
+                for (piece_index, deadline) in [(pieces.start, now + FILE_HEAD_DEADLINE), (last_piece, now + FILE_TAIL_DEADLINE)] {
+                    piece_picker.set_deadline(piece_index, deadline);
+                    edge_deadlines.push((piece_index, deadline));
+                }
+            }
+        }
+        debug!(files = files.len(), "file edges put first");
+    }
+
+    /// Gives every file its priority, eg. the ones saved in the resume file, the files missing from
+    /// "priorities" keep theirs
+    pub async fn restore(state: &State, priorities: &[FilePriority]) {
+        for priority in [FilePriority::Skip, FilePriority::Low, FilePriority::Normal, FilePriority::High] {
+            let files = (0..priorities.len())
+                .filter(|&file_index| priorities[file_index] == priority)
+                .collect::<Vec<_>>();
+            if !files.is_empty() {
+                Self::set(state, &files, priority).await;
+            }
+        }
+    }
+
+    /// A piece gets the highest priority of the files it covers, so a piece shared by a skipped
+    /// file and a wanted one is still downloaded. Files without a priority are [FilePriority::Normal]
+    ///
+    /// file_pieces => The pieces each file covers, in the order the torrent lists the files
+    fn piece_priorities(file_pieces: &[Range<usize>], file_priorities: &[FilePriority], piece_count: usize) -> Vec<FilePriority> {
+        let mut piece_priorities = vec![FilePriority::Skip; piece_count];
+        for (file_index, pieces) in file_pieces.iter().enumerate() {
+            let priority = file_priorities.get(file_index).copied().unwrap_or_default();
+            for piece_priority in piece_priorities.iter_mut().take(pieces.end).skip(pieces.start) {
+                *piece_priority = (*piece_priority).max(priority);
+            }
+        }
+        piece_priorities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilePriorities, FilePriority};
+    use crate::core::test_support::TestState;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn pieces_take_the_highest_priority_of_their_files() {
+        // Pieces 1 and 3 are shared by two files each
+        let file_pieces = [0..2, 1..4, 3..5];
+        let priorities = [FilePriority::Skip, FilePriority::Low, FilePriority::High];
+
+        assert_eq!(
+            FilePriorities::piece_priorities(&file_pieces, &priorities, 5),
+            vec![
+                FilePriority::Skip,
+                FilePriority::Low,
+                FilePriority::Low,
+                FilePriority::High,
+                FilePriority::High
+            ]
+        );
+    }
+
+    #[test]
+    fn files_without_a_priority_are_normal() {
+        assert_eq!(
+            FilePriorities::piece_priorities(&[0..1, 1..2], &[FilePriority::Skip], 2),
+            vec![FilePriority::Skip, FilePriority::Normal]
+        );
+    }
+
+    #[tokio::test]
+    async fn selecting_another_file_only_drops_the_edges_of_the_previous_one() {
+        // Two files of three 4 byte pieces each
+        let data = vec![7; 24];
+        let state = TestState::new("edges")
+            .data(&data, 4)
+            .files(vec![(vec!["a".to_string()], vec![7; 12]), (vec!["b".to_string()], vec![7; 12])])
+            .build();
+        let stream_deadline = Instant::now() + Duration::from_secs(10);
+        state.piece_picker.lock().await.set_deadline(1, stream_deadline);
+
+        FilePriorities::set_edge_deadlines(&state, &[0]).await;
+        {
+            let piece_picker = state.piece_picker.lock().await;
+            assert!(piece_picker.deadline(0).is_some());
+            assert!(piece_picker.deadline(2).is_some());
+        }
+
+        FilePriorities::set_edge_deadlines(&state, &[1]).await;
+        let piece_picker = state.piece_picker.lock().await;
+        assert_eq!(piece_picker.deadline(0), None);
+        assert_eq!(piece_picker.deadline(2), None);
+        assert!(piece_picker.deadline(3).is_some());
+        assert!(piece_picker.deadline(5).is_some());
+        // The deadline someone else set is kept
+        assert_eq!(piece_picker.deadline(1), Some(stream_deadline));
+    }
+}
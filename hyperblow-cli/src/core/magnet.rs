@@ -63,9 +63,26 @@ impl MagnetTorrent {
         })
     }
 
+    /// Fetches the metadata and then runs the torrent it describes, like [TorrentFile::run] it
+    /// returns once the session is stopped. Running it again only fetches the metadata if it
+    /// wasn't fetched before
     pub async fn run(&self) {
+        let resolved = self.resolved.read().await.clone();
+        if let Some(torrent) = resolved {
+            torrent.run().await;
+            return;
+        }
+
         debug!(tracker_count = self.tracker_addresses().len(), "running magnet metadata session");
-        match self.resolve_metadata().await {
+        let session = self.session.state.session().await;
+        let resolved = tokio::select! {
+            resolved = self.resolve_metadata() => resolved,
+            _ = session.cancelled() => {
+                info!("magnet metadata session stopped");
+                return;
+            }
+        };
+        match resolved {
             Ok(torrent) => {
                 info!(torrent = %self.display_name(), "magnet metadata fetched");
                 *self.resolved.write().await = Some(torrent.clone());
@@ -108,9 +125,7 @@ impl MagnetTorrent {
     }
 
     pub fn status_label(&self) -> String {
-        if self.tracker_addresses().is_empty() {
-            "No magnet trackers".to_string()
-        } else if self
+        if self
             .resolved
             .try_read()
             .ok()
@@ -118,6 +133,9 @@ impl MagnetTorrent {
             .is_some()
         {
             "Metadata fetched".to_string()
+        } else if self.tracker_addresses().is_empty() {
+            // Without trackers the peers that have the metadata come from the DHT only
+            "Fetching metadata from DHT".to_string()
         } else {
             "Fetching metadata".to_string()
         }
@@ -128,16 +146,23 @@ impl MagnetTorrent {
         self.validate_metadata_hash(&metadata)?;
         let info = serde_bencode::de::from_bytes::<Info>(&metadata)?;
         let file_meta = MagnetFileMeta::from_info(self.meta(), info);
-        Ok(Arc::new(
-            TorrentFile::from_metadata_with_info_hash(
-                "magnet".to_string(),
-                file_meta,
-                self.info_hash.to_vec(),
-                true,
-                self.download_directory.clone(),
-            )
-            .await?,
-        ))
+        let torrent = TorrentFile::from_metadata_with_info_hash(
+            "magnet".to_string(),
+            file_meta,
+            self.info_hash.to_vec(),
+            true,
+            self.download_directory.clone(),
+        )
+        .await?;
+        // The listener port was given to the metadata session, the resolved torrent keeps announcing it
+        let tcp_ports = self.session.state.tcp_ports.lock().await.clone();
+        *torrent.state.tcp_ports.lock().await = tcp_ports;
+        torrent.state.set_tier_mode(self.session.state.tier_mode());
+        *torrent.state.tracker_manager.write().await = self.session.state.tracker_manager().await;
+        // So is the session, pausing the magnet keeps stopping the torrent it resolved to
+        let session = self.session.state.session().await;
+        *torrent.state.session.lock().await = session;
+        Ok(Arc::new(torrent))
     }
 
     fn validate_metadata_hash(&self, metadata: &[u8]) -> Result<(), MagnetTorrentError> {
@@ -179,6 +204,8 @@ impl MagnetFileMeta {
             encoding: None,
             created_by: None,
             acceptable_source: None,
+            url_list: meta.ws.clone().map(|web_seed| vec![web_seed]),
+            httpseeds: None,
         }
     }
 
@@ -193,6 +220,8 @@ impl MagnetFileMeta {
             encoding: None,
             created_by: None,
             acceptable_source: None,
+            url_list: meta.ws.clone().map(|web_seed| vec![web_seed]),
+            httpseeds: None,
         }
     }
 }
@@ -1,12 +1,23 @@
+pub mod choker;
+pub mod dht;
+pub mod file_priorities;
 pub mod magnet;
+pub mod partial_pieces;
 pub mod peer;
 pub mod piece_assembler;
+pub mod piece_checker;
 pub mod piece_picker;
 pub mod piece_storage;
 pub mod protocol;
+pub mod resume;
 pub mod state;
+pub mod stream_server;
+#[cfg(test)]
+pub(crate) mod test_support;
 pub mod torrentFile;
 pub mod tracker;
+pub mod transfer_rate;
+pub mod web_seed;
 
 use async_recursion::async_recursion;
 use hyperblow::parser::torrent_parser::FileMeta;
@@ -54,8 +65,9 @@ pub struct File {
     /// Some(i64) where the size is in bytes
     pub size: Option<i64>,
 
-    /// Denotes whether to download the file or not
-    pub should_download: bool,
+    /// Index of a regular file among the files of the torrent, in the order the torrent lists
+    /// them, it's what its priority is set by. Directories have none
+    pub index: Option<usize>,
 
     /// Denotes the progress in percentage
     pub progressPerc: f32,
@@ -81,7 +93,7 @@ impl File {
             file_type: FileType::Directory,
             inner_files: Some(Vec::new()),
             size: None,
-            should_download: true,
+            index: None,
             progressPerc: 0_f32,
             isDownloaded: false,
         };
@@ -92,7 +104,7 @@ impl File {
             // Multiple file mode
             // Go through all the files inside of meta.info.files given by the ".torrent" file
             let mut currentFile = rootFile.clone();
-            for f in files {
+            for (file_index, f) in files.iter().enumerate() {
                 // The eventual path of the file, will also include the directory
                 let path_s = &f.path;
                 for (ind, path) in path_s.iter().enumerate() {
@@ -118,7 +130,8 @@ impl File {
                                 };
 
                                 let size = if file_type == FileType::Regular { Some(f.length) } else { None };
-                                currentFileLock.constructDirectoryOrFile(path, file_type, size);
+                                let index = (file_type == FileType::Regular).then_some(file_index);
+                                currentFileLock.constructDirectoryOrFile(path, file_type, size, index);
                                 let inner_files = currentFileLock.inner_files.as_ref().unwrap();
                                 inner_files[inner_files.len() - 1].clone()
                             };
@@ -138,17 +151,18 @@ impl File {
                 rootFile.name = name.clone()
             }
             rootFile.size = meta.info.length;
+            rootFile.index = Some(0);
             println!("{:?}", rootFile);
             Ok(ArcMutex!(rootFile))
         }
     }
 
-    fn constructDirectoryOrFile(&mut self, fileOrFolderName: &String, file_type: FileType, size: Option<i64>) {
+    fn constructDirectoryOrFile(&mut self, fileOrFolderName: &String, file_type: FileType, size: Option<i64>, index: Option<usize>) {
         if let Some(ref mut inner_files) = self.inner_files {
             inner_files.push(ArcMutex!(File {
                 name: fileOrFolderName.to_owned(),
                 progressPerc: 0_f32,
-                should_download: true,
+                index,
                 size, // TODO : Use actual size
                 isDownloaded: false,
                 inner_files: if file_type == FileType::Regular { None } else { Some(Vec::new()) },
@@ -212,25 +226,23 @@ impl File {
         names
     }
 
-    pub fn try_tabs_traverse_names(&self, depth: usize) -> Vec<String> {
-        let mut names = vec![];
+    /// Names of the files of the tree indented by their depth, like [File::tabs_traverse_names],
+    /// each with the indexes of the regular files under it. Files that are locked are skipped
+    pub fn try_tabs_traverse_files(&self, depth: usize) -> Vec<(String, Vec<usize>)> {
         let spaces = std::iter::repeat_n(" ", depth).collect::<String>();
-        match self.file_type {
-            FileType::Regular => {
-                names.push(format!("{}{}", spaces, self.name));
-            }
-            FileType::Directory => {
-                names.push(format!("{}{}", spaces, self.name));
-                if let Some(ref inner_files) = self.inner_files {
-                    for file in inner_files {
-                        if let Ok(file) = file.try_lock() {
-                            names.append(&mut file.try_tabs_traverse_names(depth + 1));
-                        }
+        let mut rows = vec![(format!("{}{}", spaces, self.name), self.index.into_iter().collect::<Vec<_>>())];
+        if let Some(ref inner_files) = self.inner_files {
+            for file in inner_files {
+                if let Ok(file) = file.try_lock() {
+                    let inner_rows = file.try_tabs_traverse_files(depth + 1);
+                    if let Some((_, files)) = inner_rows.first() {
+                        rows[0].1.extend_from_slice(files);
                     }
+                    rows.extend(inner_rows);
                 }
             }
-        };
-        names
+        }
+        rows
     }
 }
 
@@ -0,0 +1,389 @@
+use super::{
+    piece_assembler::{PieceAssembler, PieceAssemblyError},
+    piece_picker::PiecePicker,
+};
+use std::{collections::BTreeMap, time::Instant};
+
+/// Size of the blocks pieces are requested in, no sane client serves more than 16 KiB at once
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// A block of a piece, as it's requested from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub piece_index: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    /// Nobody asked for the block yet, or the peer that did went away
+    Open,
+
+    /// No of peers the block is requested from, more than one only in endgame
+    Requested(u16),
+    Received,
+}
+
+/// What became of a block a peer sent
+#[derive(Debug)]
+pub enum ReceivedBlock {
+    /// The block isn't wanted, it was already received or its piece isn't being downloaded
+    Ignored,
+
+    /// The block was kept, the piece is still missing others
+    ///
+    /// requested_elsewhere => The block was requested from other peers as well, in endgame, and
+    /// they should be sent a Cancel for it
+    Stored { requested_elsewhere: bool },
+
+    /// It was the last block of the piece, the piece is no longer partial and is ready to be
+    /// assembled and verified
+    Completed {
+        assembler: PieceAssembler,
+        requested_elsewhere: bool,
+    },
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    assembler: PieceAssembler,
+    piece_length: usize,
+    blocks: Vec<BlockState>,
+}
+
+impl PartialPiece {
+    fn new(expected_hash: [u8; 20], piece_length: usize) -> Self {
+        Self {
+            assembler: PieceAssembler::new(expected_hash, piece_length),
+            piece_length,
+            blocks: vec![BlockState::Open; piece_length.div_ceil(BLOCK_SIZE)],
+        }
+    }
+
+    /// Hands out up to `count` of the open blocks of the piece, marking them as requested
+    fn request_open_blocks(&mut self, piece_index: usize, count: usize, requests: &mut Vec<BlockRequest>) {
+        let piece_length = self.piece_length;
+        for (block, state) in self.blocks.iter_mut().enumerate() {
+            if requests.len() >= count {
+                return;
+            }
+            if *state == BlockState::Open {
+                *state = BlockState::Requested(1);
+                requests.push(Self::block_request(piece_index, piece_length, block));
+            }
+        }
+    }
+
+    /// Hands out up to `count` of the blocks of the piece that are requested from other peers but
+    /// not from this one, counting this one as another requester
+    ///
+    /// in_flight => The blocks already requested from the peer
+    fn request_requested_blocks(&mut self, piece_index: usize, count: usize, in_flight: &[BlockRequest], requests: &mut Vec<BlockRequest>) {
+        let piece_length = self.piece_length;
+        for (block, state) in self.blocks.iter_mut().enumerate() {
+            if requests.len() >= count {
+                return;
+            }
+            let BlockState::Requested(requesters) = state else {
+                continue;
+            };
+            let request = Self::block_request(piece_index, piece_length, block);
+            if !in_flight.contains(&request) && !requests.contains(&request) {
+                *requesters += 1;
+                requests.push(request);
+            }
+        }
+    }
+
+    fn block_request(piece_index: usize, piece_length: usize, block: usize) -> BlockRequest {
+        let begin = block * BLOCK_SIZE;
+        BlockRequest {
+            piece_index,
+            begin,
+            length: BLOCK_SIZE.min(piece_length - begin),
+        }
+    }
+
+    fn block_of(&self, begin: usize) -> Option<usize> {
+        let block = begin / BLOCK_SIZE;
+        (begin.is_multiple_of(BLOCK_SIZE) && block < self.blocks.len()).then_some(block)
+    }
+}
+
+/// The pieces being downloaded, block by block. They're shared by the peer sessions of the
+/// torrent, so a piece one peer started can be finished by the others
+///
+/// The [PiecePicker] only picks the pieces to start, a piece stays requested in it for as long
+/// as it's partial here
+#[derive(Debug, Default)]
+pub struct PartialPieces {
+    pieces: BTreeMap<usize, PartialPiece>,
+}
+
+impl PartialPieces {
+    /// Hands out up to `count` blocks the peer that has the pieces `has` tells can be asked for. The open
+    /// blocks of partial pieces come first, so pieces get finished rather than started, then new
+    /// pieces are started in the order of the picker
+    ///
+    /// Once every missing piece is requested (endgame), the blocks still on their way from other
+    /// peers are handed out too, so the last pieces don't wait on the slowest peer. Whichever peer
+    /// sends such a block first wins, the others are to be sent a Cancel
+    ///
+    /// in_flight => The blocks already requested from the peer
+    /// piece_of => The expected hash and the length of a piece
+    pub fn request_blocks(
+        &mut self,
+        picker: &mut PiecePicker,
+        has: impl Fn(usize) -> bool,
+        in_flight: &[BlockRequest],
+        count: usize,
+        piece_of: impl Fn(usize) -> Option<([u8; 20], usize)>,
+    ) -> Vec<BlockRequest> {
+        let mut requests = Vec::new();
+        for (&piece_index, piece) in self.pieces.iter_mut().filter(|(&piece_index, _)| has(piece_index)) {
+            piece.request_open_blocks(piece_index, count, &mut requests);
+        }
+
+        while requests.len() < count {
+            let Some(piece_index) = picker.next_piece(&has) else {
+                break;
+            };
+            let Some((expected_hash, piece_length)) = piece_of(piece_index) else {
+                break;
+            };
+            picker.mark_requested(piece_index);
+            self.pieces
+                .entry(piece_index)
+                .or_insert_with(|| PartialPiece::new(expected_hash, piece_length))
+                .request_open_blocks(piece_index, count, &mut requests);
+        }
+
+        // In endgame every block in flight is worth asking another peer for, otherwise only the
+        // ones of pieces that are past their deadline
+        if requests.len() < count {
+            let endgame = picker.all_requested();
+            let now = Instant::now();
+            for (&piece_index, piece) in self
+                .pieces
+                .iter_mut()
+                .filter(|(&piece_index, _)| has(piece_index) && (endgame || picker.is_overdue(piece_index, now)))
+            {
+                piece.request_requested_blocks(piece_index, count, in_flight, &mut requests);
+            }
+        }
+        requests
+    }
+
+    /// Keeps a block a peer sent, when it's one of a partial piece that hasn't been received yet
+    ///
+    /// The error is the one of a block that doesn't fit where it claims to be, the block stays
+    /// requested until the peer that sent it releases it
+    pub fn insert_block(&mut self, piece_index: usize, begin: usize, block: Vec<u8>) -> Result<ReceivedBlock, PieceAssemblyError> {
+        let Some(piece) = self.pieces.get_mut(&piece_index) else {
+            return Ok(ReceivedBlock::Ignored);
+        };
+        let Some(index) = piece.block_of(begin).filter(|&index| piece.blocks[index] != BlockState::Received) else {
+            return Ok(ReceivedBlock::Ignored);
+        };
+        piece.assembler.insert_block(begin, block)?;
+        let requested_elsewhere = matches!(piece.blocks[index], BlockState::Requested(requesters) if requesters > 1);
+        piece.blocks[index] = BlockState::Received;
+
+        if piece.blocks.iter().all(|&state| state == BlockState::Received) {
+            let piece = self.pieces.remove(&piece_index).expect("piece is partial");
+            return Ok(ReceivedBlock::Completed {
+                assembler: piece.assembler,
+                requested_elsewhere,
+            });
+        }
+        Ok(ReceivedBlock::Stored { requested_elsewhere })
+    }
+
+    /// Gives back a block that was requested but never arrived, eg. because the peer choked us or
+    /// went away, so it can be requested from another peer. A block that's requested from other
+    /// peers as well, in endgame, stays requested from them
+    pub fn release(&mut self, request: &BlockRequest) {
+        let Some(piece) = self.pieces.get_mut(&request.piece_index) else {
+            return;
+        };
+        if let Some(index) = piece.block_of(request.begin) {
+            piece.blocks[index] = match piece.blocks[index] {
+                BlockState::Requested(requesters) if requesters > 1 => BlockState::Requested(requesters - 1),
+                BlockState::Requested(_) => BlockState::Open,
+                state => state,
+            };
+        }
+    }
+
+    /// Forgets every partial piece, eg. when the peer sessions downloading them were stopped
+    pub fn clear(&mut self) {
+        self.pieces.clear();
+    }
+
+    /// No of pieces being downloaded
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockRequest, PartialPieces, ReceivedBlock, BLOCK_SIZE};
+    use crate::core::piece_picker::PiecePicker;
+    use sha1::{Digest, Sha1};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn peers_share_the_blocks_of_a_partial_piece() {
+        let mut picker = PiecePicker::new(2);
+        let mut partial = PartialPieces::default();
+        let piece_of = |_| Some(([0; 20], 3 * BLOCK_SIZE));
+
+        let first = partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 2, piece_of);
+        let second = partial.request_blocks(&mut picker, |piece_index| piece_index < 2, &[], 2, piece_of);
+
+        assert_eq!(
+            first,
+            vec![
+                BlockRequest {
+                    piece_index: 0,
+                    begin: 0,
+                    length: BLOCK_SIZE
+                },
+                BlockRequest {
+                    piece_index: 0,
+                    begin: BLOCK_SIZE,
+                    length: BLOCK_SIZE
+                },
+            ]
+        );
+        // The last block of the started piece is handed out before a new piece is started
+        assert_eq!(
+            second,
+            vec![
+                BlockRequest {
+                    piece_index: 0,
+                    begin: 2 * BLOCK_SIZE,
+                    length: BLOCK_SIZE
+                },
+                BlockRequest {
+                    piece_index: 1,
+                    begin: 0,
+                    length: BLOCK_SIZE
+                },
+            ]
+        );
+        assert_eq!(picker.requested_count(), 2);
+    }
+
+    #[test]
+    fn released_blocks_are_requested_again() {
+        let mut picker = PiecePicker::new(1);
+        let mut partial = PartialPieces::default();
+        let piece_of = |_| Some(([0; 20], BLOCK_SIZE + 10));
+        let requests = partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 4, piece_of);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].length, 10);
+        assert!(partial
+            .request_blocks(&mut picker, |piece_index| piece_index == 0, &requests, 4, piece_of)
+            .is_empty());
+
+        partial.release(&requests[1]);
+
+        assert_eq!(
+            partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &requests[..1], 4, piece_of),
+            vec![requests[1]]
+        );
+    }
+
+    #[test]
+    fn endgame_requests_blocks_from_every_peer_that_has_them() {
+        let mut picker = PiecePicker::new(1);
+        let mut partial = PartialPieces::default();
+        let piece_of = |_| Some(([0; 20], 2 * BLOCK_SIZE));
+        let first = partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 4, piece_of);
+        assert_eq!(first.len(), 2);
+        assert!(picker.all_requested());
+
+        // The blocks the first peer is downloading are handed to the second, but never twice to the same peer
+        let second = partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &first[..1], 4, piece_of);
+        assert_eq!(second, vec![first[1]]);
+        assert_eq!(
+            partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &second, 4, piece_of),
+            vec![first[0]]
+        );
+
+        assert!(matches!(
+            partial.insert_block(0, 0, vec![1; BLOCK_SIZE]),
+            Ok(ReceivedBlock::Stored { requested_elsewhere: true })
+        ));
+        // A peer that gives up on a block leaves it requested from the other
+        partial.release(&first[1]);
+        assert!(partial
+            .request_blocks(&mut picker, |piece_index| piece_index == 0, &[first[1]], 4, piece_of)
+            .is_empty());
+        partial.release(&first[1]);
+        assert_eq!(
+            partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 4, piece_of),
+            vec![first[1]]
+        );
+        assert!(matches!(
+            partial.insert_block(0, BLOCK_SIZE, vec![1; BLOCK_SIZE]),
+            Ok(ReceivedBlock::Completed {
+                requested_elsewhere: false,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn blocks_of_overdue_pieces_are_requested_from_another_peer() {
+        let mut picker = PiecePicker::new(2);
+        let mut partial = PartialPieces::default();
+        let piece_of = |_| Some(([0; 20], BLOCK_SIZE));
+        picker.set_deadline(0, Instant::now() + Duration::from_secs(60));
+        let first = partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 1, piece_of);
+        assert!(!picker.all_requested());
+
+        // The piece isn't late yet, nothing is asked for twice
+        assert!(partial
+            .request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 1, piece_of)
+            .is_empty());
+        picker.set_deadline(0, Instant::now());
+        assert_eq!(
+            partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 1, piece_of),
+            first
+        );
+    }
+
+    #[test]
+    fn last_block_completes_the_piece() {
+        let piece = vec![7_u8; BLOCK_SIZE + 5];
+        let hash: [u8; 20] = Sha1::digest(&piece).into();
+        let mut picker = PiecePicker::new(1);
+        let mut partial = PartialPieces::default();
+        partial.request_blocks(&mut picker, |piece_index| piece_index == 0, &[], 4, |_| Some((hash, piece.len())));
+
+        assert!(matches!(
+            partial.insert_block(0, BLOCK_SIZE, piece[BLOCK_SIZE..].to_vec()),
+            Ok(ReceivedBlock::Stored {
+                requested_elsewhere: false
+            })
+        ));
+        assert!(matches!(
+            partial.insert_block(0, BLOCK_SIZE, piece[BLOCK_SIZE..].to_vec()),
+            Ok(ReceivedBlock::Ignored)
+        ));
+        let Ok(ReceivedBlock::Completed { assembler, .. }) = partial.insert_block(0, 0, piece[..BLOCK_SIZE].to_vec()) else {
+            panic!("last block should complete the piece");
+        };
+
+        assert_eq!(assembler.assemble().expect("piece should verify"), piece);
+        assert!(partial.is_empty());
+    }
+}
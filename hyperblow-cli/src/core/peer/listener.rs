@@ -0,0 +1,138 @@
+use super::{codec::PeerMessageCodec, messages::Handshake, Message, Peer, PeerError, HANDSHAKE_TIMEOUT, MAX_PEERS};
+use crate::core::state::State;
+use futures_util::StreamExt;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+/// Accepts the TCP connections that remote peers open towards us, it's shared by every torrent
+/// of the engine, which is why the first handshake has to be read before we know which torrent the
+/// connection belongs to
+#[derive(Debug)]
+pub struct PeerListener {
+    listener: TcpListener,
+}
+
+impl PeerListener {
+    /// Binds the listener on the first free port in the range of 6881 to 6999, just like the
+    /// trackers UDP socket, and falls back to a port picked by the OS
+    pub async fn bind() -> io::Result<Self> {
+        for port in 6881..=6999 {
+            if let Ok(listener) = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+                return Ok(Self { listener });
+            }
+        }
+        Self::bind_to(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await
+    }
+
+    pub async fn bind_to(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        Ok(Self { listener })
+    }
+
+    /// The port remote peers should connect to, it's the one we announce to the trackers
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|address| address.port()).unwrap_or_default()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for the next remote peer to connect
+    ///
+    /// The handshake is read afterwards by [InboundPeer::read_handshake] on a separately spawned
+    /// task, so a slow peer can't stall the accept loop
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.listener.accept().await
+    }
+}
+
+/// A remote peer that connected to us and sent a valid handshake, waiting to be handed over to
+/// the torrent its handshake asked for
+#[derive(Debug)]
+pub struct InboundPeer {
+    socket_adr: SocketAddr,
+    stream: Framed<TcpStream, PeerMessageCodec>,
+    handshake: Handshake,
+}
+
+impl InboundPeer {
+    /// Reads the first message of the connection, which must be a Handshake
+    pub(crate) async fn read_handshake(tcp_stream: TcpStream, socket_adr: SocketAddr) -> Result<Self, PeerError> {
+        let mut stream = Framed::new(tcp_stream, PeerMessageCodec);
+        let message = timeout(HANDSHAKE_TIMEOUT, stream.next())
+            .await
+            .map_err(|_| PeerError::ConnectionTimeout(socket_adr))?
+            .ok_or(PeerError::HandshakeClosed)??;
+
+        match message {
+            Message::Handshake(handshake) => {
+                debug!(peer = %socket_adr, "received inbound peer handshake");
+                Ok(Self {
+                    socket_adr,
+                    stream,
+                    handshake,
+                })
+            }
+            message => Err(PeerError::UnexpectedHandshakeMessage(message)),
+        }
+    }
+
+    pub fn info_hash(&self) -> &[u8] {
+        self.handshake.info_hash()
+    }
+
+    pub fn socket_adr(&self) -> SocketAddr {
+        self.socket_adr
+    }
+
+    /// Adds the peer to the given torrent and runs its session until the peer disconnects, then
+    /// removes it again. A peer the torrent already knows, or one over the limit of peers, is
+    /// turned away
+    pub async fn run(self, state: Arc<State>) {
+        if self.info_hash() != state.info_hash.as_slice() {
+            warn!(peer = %self.socket_adr, "inbound peer routed to a torrent with another info hash");
+            return;
+        }
+
+        // The session is dropped with the torrent's, when the torrent is paused or removed
+        let session = state.session().await;
+        if session.is_cancelled() {
+            return;
+        }
+        let peer = Peer::new(self.socket_adr, state.clone());
+        {
+            let mut peers = state.peers.lock().await;
+            if peers.iter().any(|known| known.socket_adr == self.socket_adr) {
+                debug!(peer = %self.socket_adr, "turning away inbound peer that's already known");
+                return;
+            }
+            if peers.len() >= MAX_PEERS {
+                debug!(peer = %self.socket_adr, peers = peers.len(), "turning away inbound peer, torrent has enough peers");
+                return;
+            }
+            peers.push(peer.clone());
+        }
+        info!(peer = %self.socket_adr, "accepted inbound peer");
+
+        tokio::select! {
+            result = peer.run_inbound(self.stream, self.handshake.supports_dht()) => match result {
+                Ok(()) => info!(peer = %self.socket_adr, "inbound peer session ended"),
+                Err(error) => warn!(peer = %self.socket_adr, error = %error, "inbound peer session failed"),
+            },
+            _ = session.cancelled() => debug!(peer = %self.socket_adr, "inbound peer session stopped with the torrent"),
+        }
+
+        // Unlike the peers we connect to, nobody reconnects to a peer that connected to us
+        state.peers.lock().await.retain(|known| !Arc::ptr_eq(&known.info, &peer.info));
+    }
+}
@@ -85,6 +85,17 @@ impl Message {
                 buf.put_u32(1);
                 buf.put_u8(3);
             }
+            Message::Have(ref have) => {
+                buf.put_u32(5);
+                buf.put_u8(4);
+                buf.put_u32(have.piece_index);
+            }
+            Message::Bitfield(ref bitfield) => {
+                let bitfield_bytes = bitfield.to_bytes();
+                buf.put_u32(1 + bitfield_bytes.len() as u32);
+                buf.put_u8(5);
+                buf.put_slice(&bitfield_bytes);
+            }
             Message::Request(ref request) => {
                 buf.put_u32(13);
                 buf.put_u8(6);
@@ -92,6 +103,25 @@ impl Message {
                 buf.put_u32(request.begin);
                 buf.put_u32(request.length);
             }
+            Message::Piece(ref block) => {
+                buf.put_u32(9 + block.raw_block.len() as u32);
+                buf.put_u8(7);
+                buf.put_u32(block.piece_index);
+                buf.put_u32(block.byte_index);
+                buf.put_slice(&block.raw_block);
+            }
+            Message::Cancel(ref cancel) => {
+                buf.put_u32(13);
+                buf.put_u8(8);
+                buf.put_u32(cancel.index);
+                buf.put_u32(cancel.begin);
+                buf.put_u32(cancel.length);
+            }
+            Message::Port(ref port) => {
+                buf.put_u32(3);
+                buf.put_u8(9);
+                buf.put_u16(port.listen_port);
+            }
             Message::Extended(ref extended) => {
                 let length = 2_u32.saturating_add(extended.payload.len() as u32);
                 buf.put_u32(length);
@@ -99,10 +129,6 @@ impl Message {
                 buf.put_u8(extended.extension_id);
                 buf.put_slice(&extended.payload);
             }
-            // TODO : Do it for all messages
-
-            //Message::Have(ref have) => {}
-            _ => {}
         }
         buf
     }
@@ -355,6 +381,25 @@ pub struct Bitfield {
 }
 
 impl Bitfield {
+    /// Creates a Bitfield out of the completion flag of every piece, in piece order
+    pub fn from_pieces(pieces: &[bool]) -> Self {
+        let (have, not_have) = (0..pieces.len()).partition(|&piece_index| pieces[piece_index]);
+        Self { have, not_have }
+    }
+
+    /// Packs the pieces into the bitfield payload, most significant bit first, with the spare
+    /// bits of the last byte cleared
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let piece_count = self.have.len() + self.not_have.len();
+        let mut bytes = vec![0_u8; piece_count.div_ceil(8)];
+        for &piece_index in &self.have {
+            if let Some(byte) = bytes.get_mut(piece_index / 8) {
+                *byte |= 1 << (7 - piece_index % 8);
+            }
+        }
+        bytes
+    }
+
     pub fn from_bytes(src: &mut BytesMut) -> Self {
         let mut have = Vec::new();
         let mut not_have = Vec::new();
@@ -532,6 +577,11 @@ impl Handshake {
     pub fn supports_extensions(&self) -> bool {
         self.reserved.get(5).is_some_and(|byte| byte & 0x10 == 0x10)
     }
+
+    /// Whether the peer runs a DHT node, it'll tell us its port with a Port message
+    pub fn supports_dht(&self) -> bool {
+        self.reserved.get(7).is_some_and(|byte| byte & 0x01 == 0x01)
+    }
 }
 
 /// Unchoke message
@@ -717,6 +767,15 @@ pub struct Cancel {
 }
 
 impl Cancel {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self { index, begin, length }
+    }
+
+    /// Whether this Cancel is meant for the given Request
+    pub fn cancels(&self, request: &Request) -> bool {
+        self.index == request.index && self.begin == request.begin && self.length == request.length
+    }
+
     /// Creates a Cancel instance from the bytes of Cancel Message Frame
     /// src - It must be atleast 17 bytes long and must be validated by
     /// Cancel::is_cancel_message() function before actually creating it
@@ -756,6 +815,14 @@ pub struct Port {
 }
 
 impl Port {
+    pub fn new(listen_port: u16) -> Self {
+        Self { listen_port }
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
     /// Creates a Port instance from the bytes of Port Message Frame
     /// src - It must be atleast 7 bytes long and must be validated by
     /// Port::is_port_message() function before actually creating it.
@@ -773,7 +840,7 @@ impl Port {
 
 #[cfg(test)]
 mod tests {
-    use super::{Bitfield, Block, ExtendedMessage, Handshake, Have, Message, Port, Request};
+    use super::{Bitfield, Block, Cancel, ExtendedMessage, Handshake, Have, Message, Port, Request};
     use bytes::{BufMut, BytesMut};
 
     #[test]
@@ -788,6 +855,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn encodes_piece_have_and_cancel() {
+        let block = Block {
+            piece_index: 1,
+            byte_index: 4,
+            raw_block: BytesMut::from(&b"ab"[..]),
+        };
+
+        assert_eq!(
+            Message::Piece(block).to_bytes().as_ref(),
+            &[0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 4, b'a', b'b']
+        );
+        assert_eq!(
+            Message::Have(Have { piece_index: 3 }).to_bytes().as_ref(),
+            &[0, 0, 0, 5, 4, 0, 0, 0, 3]
+        );
+        assert_eq!(
+            Message::Cancel(Cancel::new(2, 16, 32)).to_bytes().as_ref(),
+            &[0, 0, 0, 13, 8, 0, 0, 0, 2, 0, 0, 0, 16, 0, 0, 0, 32]
+        );
+    }
+
+    #[test]
+    fn encodes_bitfield_most_significant_first() {
+        let bitfield = Bitfield::from_pieces(&[true, false, true, false, false, false, false, false, true]);
+
+        assert_eq!(
+            Message::Bitfield(bitfield).to_bytes().as_ref(),
+            &[0, 0, 0, 3, 5, 0b1010_0000, 0b1000_0000]
+        );
+    }
+
     #[test]
     fn parses_and_consumes_handshake() {
         let mut bytes = BytesMut::new();
@@ -808,10 +907,11 @@ mod tests {
     }
 
     #[test]
-    fn outbound_handshake_advertises_extension_protocol() {
+    fn outbound_handshake_advertises_extension_protocol_and_dht() {
         let handshake = Handshake::from_info_hash(&[7; 20]);
 
         assert!(handshake.supports_extensions());
+        assert!(handshake.supports_dht());
         assert_eq!(handshake.info_hash(), &[7; 20]);
     }
 
@@ -886,5 +986,6 @@ mod tests {
 
         assert_eq!(port.listen_port, 6881);
         assert!(bytes.is_empty());
+        assert_eq!(Message::Port(port).to_bytes().as_ref(), &[0, 0, 0, 3, 9, 0x1a, 0xe1]);
     }
 }
@@ -1,28 +1,39 @@
 mod codec;
+mod listener;
 mod messages;
 mod metadata;
 mod piece;
 
 use super::{
-    piece_assembler::{PieceAssembler, PieceAssemblyError},
+    choker::Choker,
+    partial_pieces::{BlockRequest, ReceivedBlock, BLOCK_SIZE},
+    piece_assembler::PieceAssemblyError,
     piece_storage::{PieceStorage, PieceStorageError},
     state::State,
+    transfer_rate::TransferRate,
 };
 use crate::ArcMutex;
+use bytes::BytesMut;
 use codec::{PeerCodecError, PeerMessageCodec};
 use futures_util::{SinkExt, StreamExt};
-use messages::{Block, Handshake, Message, Request};
-use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use messages::{Block, Cancel, Handshake, Have, Message, Port, Request};
+use std::{collections::VecDeque, io, net::SocketAddr, sync::Arc, time::Duration};
 use thiserror::Error;
 use tokio::{
     net::TcpStream,
-    sync::Mutex,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex,
+    },
+    task::JoinError,
     time::{sleep, timeout},
 };
 use tracing::{debug, info, warn};
 
 use tokio_util::codec::Framed;
 
+pub(crate) use listener::{InboundPeer, PeerListener};
+pub(crate) use messages::Bitfield;
 pub(crate) use metadata::{MagnetMetadataError, MagnetMetadataFetcher};
 
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(16);
@@ -30,6 +41,24 @@ const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(2);
 const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
+/// Requests for blocks bigger than this are dropped, no sane client asks for more than 16 KiB
+const MAX_REQUEST_LENGTH: usize = 128 * 1024;
+
+/// Limit of the requests a peer can have waiting to be served
+const MAX_QUEUED_UPLOADS: usize = 256;
+
+/// How many seconds worth of blocks, at the rate the peer sends them, are kept requested from it,
+/// like libtorrent's "request_queue_time". It keeps the link busy however high its latency is
+const REQUEST_QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// Bounds of the no of blocks requested from a peer at once, a new peer without a measured rate
+/// gets the lower one
+const MIN_REQUEST_QUEUE: usize = 4;
+const MAX_REQUEST_QUEUE: usize = 250;
+
+/// Limit of the peers of a torrent, the peers that connect to us once it's reached are turned away
+const MAX_PEERS: usize = 200;
+
 /// PeerState denotes high level overview of the current state of
 /// relationship of this client with the remote Peer
 #[derive(Debug, Clone)]
@@ -61,6 +90,22 @@ pub enum PeerState {
     // DOWNLOADING_PIECE,
 }
 
+/// Commands the rest of the torrent session gives to a running peer session
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerCommand {
+    /// Stop serving the peer, sent by the [Choker]
+    Choke,
+
+    /// Start serving the peer, sent by the [Choker]
+    Unchoke,
+
+    /// Tell the peer about a piece we just verified
+    Have(u32),
+
+    /// Take back the request for a block another peer sent first, in endgame
+    Cancel(BlockRequest),
+}
+
 /// It defines the type of Peer
 #[derive(Debug, Clone)]
 pub enum PeerType {
@@ -81,8 +126,8 @@ pub enum PeerType {
 /// or doesn't have, the type of the peer
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
-    /// Zero based index of the piece the peer has
-    pieces_have: Vec<u32>,
+    /// Whether the peer has each piece of the torrent, by piece index
+    pieces_have: Vec<bool>,
 
     /// Zero based index of the piece the peer does not have
     pieces_not_have: Vec<u32>,
@@ -92,6 +137,15 @@ pub struct PeerInfo {
 
     /// State of the peer
     peer_state: PeerState,
+
+    /// Whether we are choking the peer, every connection starts choked
+    am_choking: bool,
+
+    /// Whether the peer is interested in the pieces we have
+    peer_interested: bool,
+
+    /// Port of the peer's DHT node, as told by its Port message
+    dht_port: Option<u16>,
 }
 
 #[derive(Debug, Clone)]
@@ -111,10 +165,19 @@ pub struct Peer {
     pub socket_adr: SocketAddr,
 
     stream: Arc<Mutex<Option<Framed<TcpStream, PeerMessageCodec>>>>,
+
+    /// Sender and Receiver of the commands for the session of this peer
+    commands: (UnboundedSender<PeerCommand>, Arc<Mutex<UnboundedReceiver<PeerCommand>>>),
+
+    /// Rate at which the peer sends us blocks
+    pub download_rate: Arc<TransferRate>,
+
+    /// Rate at which we send the peer blocks
+    pub upload_rate: Arc<TransferRate>,
 }
 
 #[derive(Debug, Error)]
-enum PeerError {
+pub(crate) enum PeerError {
     #[error("timed out connecting to peer {0}")]
     ConnectionTimeout(SocketAddr),
 
@@ -133,11 +196,17 @@ enum PeerError {
     #[error("peer handshake info hash did not match torrent")]
     InfoHashMismatch,
 
+    #[error("peer sent a block of {actual} bytes for a request of {expected}")]
+    BlockLength { expected: usize, actual: usize },
+
     #[error("piece assembly error")]
     PieceAssembly(#[from] PieceAssemblyError),
 
     #[error("piece storage error")]
     PieceStorage(#[from] PieceStorageError),
+
+    #[error("piece write task failed")]
+    PieceWrite(#[from] JoinError),
 }
 
 impl Peer {
@@ -147,22 +216,51 @@ impl Peer {
     /// state : The State of teh torrent session
     pub fn new(socket_adr: SocketAddr, state: Arc<State>) -> Self {
         let info = ArcMutex!(PeerInfo {
-            pieces_have: Vec::new(),
+            pieces_have: vec![false; state.pieces_hash.len()],
             pieces_not_have: Vec::new(),
             peer_type: PeerType::Unknown,
             peer_state: PeerState::NotConnected,
+            am_choking: true,
+            peer_interested: false,
+            dht_port: None,
         });
 
         let stream = ArcMutex!(None);
+        let (command_sender, command_receiver) = unbounded_channel();
 
         Self {
             info,
             state,
             socket_adr,
             stream,
+            commands: (command_sender, ArcMutex!(command_receiver)),
+            download_rate: Arc::default(),
+            upload_rate: Arc::default(),
         }
     }
 
+    /// Queues a command for the session of this peer, it's dropped if the peer isn't connected
+    /// by the time the session gets to it
+    pub fn send_command(&self, command: PeerCommand) {
+        let _ = self.commands.0.send(command);
+    }
+
+    /// Whether the session is running and the peer wants the pieces we have
+    pub async fn is_interested_in_us(&self) -> bool {
+        let info = self.info.lock().await;
+        matches!(info.peer_state, PeerState::Running) && info.peer_interested
+    }
+
+    pub async fn is_choked_by_us(&self) -> bool {
+        self.info.lock().await.am_choking
+    }
+
+    /// Whether the session is running and we're serving the peer, i.e it holds an unchoke slot
+    pub async fn is_unchoked_by_us(&self) -> bool {
+        let info = self.info.lock().await;
+        matches!(info.peer_state, PeerState::Running) && !info.am_choking
+    }
+
     /// It will run infinitely, non blockingly, until it gets a TCP connection with the given
     /// socket address
     ///
@@ -202,34 +300,234 @@ impl Peer {
 
     async fn run_session(&self) -> Result<(), PeerError> {
         let mut stream = self.connect_once().await?;
-        self.send_and_validate_handshake(&mut stream).await?;
+        let handshake = self.send_and_validate_handshake(&mut stream).await?;
+        self.run_connected(stream, handshake.supports_dht()).await
+    }
+
+    /// Runs the session of a peer that connected to us, after its handshake was read and matched
+    /// with this torrent by the [PeerListener]
+    pub(crate) async fn run_inbound(
+        &self,
+        mut stream: Framed<TcpStream, PeerMessageCodec>,
+        peer_supports_dht: bool,
+    ) -> Result<(), PeerError> {
+        self.set_peer_state(PeerState::Connected).await;
+        stream.send(vec![Message::Handshake(Handshake::new(self.state.clone()))]).await?;
+        self.set_peer_state(PeerState::HandshakeComplete).await;
+        debug!(peer = %self.socket_adr, "inbound peer handshake complete");
+        let result = self.run_connected(stream, peer_supports_dht).await;
+        self.set_peer_state(PeerState::Disconnected).await;
+        result
+    }
+
+    /// The message loop shared by outbound and inbound sessions, it starts right after both the
+    /// handshakes have been exchanged
+    ///
+    /// However the session ends, the blocks requested from the peer are given back, its pieces
+    /// stop counting towards their availability and it gives up its unchoke slot
+    async fn run_connected(&self, stream: Framed<TcpStream, PeerMessageCodec>, peer_supports_dht: bool) -> Result<(), PeerError> {
+        // The blocks requested from the peer that haven't arrived yet
+        let mut requests = Vec::new();
+        let result = self.exchange_messages(stream, peer_supports_dht, &mut requests).await;
+        self.release_requests(&mut requests).await;
+        self.forget_peer_pieces().await;
+        {
+            let mut info = self.info.lock().await;
+            info.am_choking = true;
+            info.peer_interested = false;
+        }
+        result
+    }
+
+    async fn exchange_messages(
+        &self,
+        mut stream: Framed<TcpStream, PeerMessageCodec>,
+        peer_supports_dht: bool,
+        requests: &mut Vec<BlockRequest>,
+    ) -> Result<(), PeerError> {
+        // Every connection starts out choked and not interested on both sides, whatever the
+        // previous connection to this peer ended with
+        {
+            let mut info = self.info.lock().await;
+            info.am_choking = true;
+            info.peer_interested = false;
+        }
+        let mut commands = self.commands.1.lock().await;
+        while commands.try_recv().is_ok() {}
+
+        self.send_bitfield(&mut stream).await?;
+        if peer_supports_dht {
+            self.send_dht_port(&mut stream).await?;
+        }
         stream.send(vec![Message::Interested]).await?;
         self.set_peer_state(PeerState::Running).await;
         let mut peer_choking = true;
-        let mut active_piece = None;
+        let mut uploads = VecDeque::new();
 
-        while let Some(message) = stream.next().await {
-            match message? {
-                Message::Choke => {
-                    peer_choking = true;
-                    self.release_active_piece(active_piece.take()).await;
+        loop {
+            // Incoming messages are always handled first, so a Cancel that already arrived removes
+            // its Request from the queue before we get to serve it
+            tokio::select! {
+                biased;
+                message = stream.next() => {
+                    let Some(message) = message else {
+                        break;
+                    };
+                    match message? {
+                        Message::Choke => {
+                            // A peer that chokes us drops the requests we made
+                            peer_choking = true;
+                            self.release_requests(requests).await;
+                        }
+                        Message::Unchoke => {
+                            peer_choking = false;
+                            self.maybe_request_blocks(&mut stream, requests, peer_choking).await?;
+                        }
+                        Message::Piece(block) => {
+                            self.handle_piece_block(block, requests).await?;
+                            self.maybe_request_blocks(&mut stream, requests, peer_choking).await?;
+                        }
+                        Message::Interested => self.handle_interested(&mut stream, &mut uploads).await?,
+                        Message::NotInterested => self.info.lock().await.peer_interested = false,
+                        Message::Request(request) => self.queue_upload(request, &mut uploads).await,
+                        Message::Cancel(cancel) => uploads.retain(|request| !cancel.cancels(request)),
+                        message => {
+                            self.handle_message(message).await;
+                            self.maybe_request_blocks(&mut stream, requests, peer_choking).await?;
+                        }
+                    }
                 }
-                Message::Unchoke => {
-                    peer_choking = false;
-                    self.maybe_request_piece(&mut stream, &mut active_piece, peer_choking).await?;
+                Some(command) = commands.recv() => match command {
+                    PeerCommand::Cancel(block) => {
+                        self.cancel_request(&mut stream, block, requests).await?;
+                        self.maybe_request_blocks(&mut stream, requests, peer_choking).await?;
+                    }
+                    command => self.handle_command(&mut stream, command, &mut uploads).await?,
+                },
+                _ = std::future::ready(()), if !uploads.is_empty() => {
+                    if let Some(request) = uploads.pop_front() {
+                        self.serve_request(&mut stream, request).await?;
+                    }
                 }
-                Message::Piece(block) => {
-                    self.handle_piece_block(block, &mut active_piece).await?;
-                    self.maybe_request_piece(&mut stream, &mut active_piece, peer_choking).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records that the peer wants our pieces, it's unchoked right away when the [Choker] has a
+    /// free slot, otherwise it waits for the next rechoke
+    async fn handle_interested(
+        &self,
+        stream: &mut Framed<TcpStream, PeerMessageCodec>,
+        uploads: &mut VecDeque<Request>,
+    ) -> Result<(), PeerError> {
+        self.info.lock().await.peer_interested = true;
+        if Choker::has_free_slot(&self.state).await {
+            self.handle_command(stream, PeerCommand::Unchoke, uploads).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_command(
+        &self,
+        stream: &mut Framed<TcpStream, PeerMessageCodec>,
+        command: PeerCommand,
+        uploads: &mut VecDeque<Request>,
+    ) -> Result<(), PeerError> {
+        match command {
+            PeerCommand::Choke => {
+                if std::mem::replace(&mut self.info.lock().await.am_choking, true) {
+                    return Ok(());
                 }
-                message => {
-                    self.handle_message(message).await;
-                    self.maybe_request_piece(&mut stream, &mut active_piece, peer_choking).await?;
+                // Choking a peer discards all of its pending requests
+                uploads.clear();
+                debug!(peer = %self.socket_adr, "choking peer");
+                stream.send(vec![Message::Choke]).await?;
+            }
+            PeerCommand::Unchoke => {
+                if !std::mem::replace(&mut self.info.lock().await.am_choking, false) {
+                    return Ok(());
                 }
+                debug!(peer = %self.socket_adr, "unchoking peer");
+                stream.send(vec![Message::Unchoke]).await?;
+            }
+            PeerCommand::Have(piece_index) => {
+                stream.send(vec![Message::Have(Have { piece_index })]).await?;
             }
+            // Only the run loop knows the blocks requested from the peer
+            PeerCommand::Cancel(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Takes back the request for a block, when it's still on its way from the peer
+    async fn cancel_request(
+        &self,
+        stream: &mut Framed<TcpStream, PeerMessageCodec>,
+        block: BlockRequest,
+        requests: &mut Vec<BlockRequest>,
+    ) -> Result<(), PeerError> {
+        let Some(position) = requests.iter().position(|request| *request == block) else {
+            return Ok(());
+        };
+        requests.remove(position);
+        debug!(peer = %self.socket_adr, piece_index = block.piece_index, begin = block.begin, "cancelling block request");
+        stream
+            .send(vec![Message::Cancel(Cancel::new(
+                block.piece_index as u32,
+                block.begin as u32,
+                block.length as u32,
+            ))])
+            .await?;
+        Ok(())
+    }
+
+    /// Queues a block the peer asked for, as long as the peer is unchoked and the block belongs to
+    /// a piece we have verified
+    async fn queue_upload(&self, request: Request, uploads: &mut VecDeque<Request>) {
+        if self.info.lock().await.am_choking {
+            debug!(peer = %self.socket_adr, piece_index = request.index(), "ignoring request from choked peer");
+            return;
+        }
+        if request.length() as usize > MAX_REQUEST_LENGTH || uploads.len() >= MAX_QUEUED_UPLOADS {
+            debug!(peer = %self.socket_adr, length = request.length(), "ignoring oversized or excess request");
+            return;
+        }
+        if !self.state.piece_picker.lock().await.is_completed(request.index() as usize)
+            || !PieceStorage::is_readable(&self.state, request.index() as usize).await
+        {
+            debug!(peer = %self.socket_adr, piece_index = request.index(), "ignoring request for missing piece");
+            return;
         }
-        self.release_active_piece(active_piece).await;
+        uploads.push_back(request);
+    }
+
+    async fn serve_request(&self, stream: &mut Framed<TcpStream, PeerMessageCodec>, request: Request) -> Result<(), PeerError> {
+        let piece_index = request.index() as usize;
+        let raw_block = match PieceStorage::read_block(&self.state, piece_index, request.begin() as usize, request.length() as usize).await
+        {
+            Ok(raw_block) => raw_block,
+            Err(error) => {
+                warn!(peer = %self.socket_adr, piece_index, error = %error, "failed to read requested block");
+                return Ok(());
+            }
+        };
 
+        let length = raw_block.len();
+        stream
+            .send(vec![Message::Piece(Block {
+                piece_index: request.index(),
+                byte_index: request.begin(),
+                raw_block: BytesMut::from(raw_block.as_slice()),
+            })])
+            .await?;
+        self.state.set_uploaded(self.state.uploaded().saturating_add(length));
+        self.state
+            .set_session_uploaded(self.state.session_uploaded().saturating_add(length));
+        self.state.upload_rate.record(length);
+        self.upload_rate.record(length);
+        debug!(peer = %self.socket_adr, piece_index, begin = request.begin(), length, "uploaded block");
         Ok(())
     }
 
@@ -256,7 +554,7 @@ impl Peer {
         Ok(Framed::new(tcp_stream, PeerMessageCodec))
     }
 
-    async fn send_and_validate_handshake(&self, stream: &mut Framed<TcpStream, PeerMessageCodec>) -> Result<(), PeerError> {
+    async fn send_and_validate_handshake(&self, stream: &mut Framed<TcpStream, PeerMessageCodec>) -> Result<Handshake, PeerError> {
         stream.send(vec![Message::Handshake(Handshake::new(self.state.clone()))]).await?;
         self.set_peer_state(PeerState::SentHandshake).await;
         debug!(peer = %self.socket_adr, "sent peer handshake");
@@ -270,134 +568,298 @@ impl Peer {
             Message::Handshake(handshake) if handshake.info_hash() == self.state.info_hash.as_slice() => {
                 self.set_peer_state(PeerState::HandshakeComplete).await;
                 debug!(peer = %self.socket_adr, "peer handshake complete");
-                Ok(())
+                Ok(handshake)
             }
             Message::Handshake(_) => Err(PeerError::InfoHashMismatch),
             message => Err(PeerError::UnexpectedHandshakeMessage(message)),
         }
     }
 
+    /// Tells the peer which pieces we already have, it's skipped when we have none. The pieces
+    /// that were only partly written, as they're shared with a skipped file, are left out
+    async fn send_bitfield(&self, stream: &mut Framed<TcpStream, PeerMessageCodec>) -> Result<(), PeerError> {
+        let dropped = PieceStorage::dropped_pieces(&self.state).await;
+        let bitfield = {
+            let picker = self.state.piece_picker.lock().await;
+            let pieces = picker
+                .completed_pieces()
+                .iter()
+                .enumerate()
+                .map(|(piece_index, &completed)| completed && !dropped.iter().any(|pieces| pieces.contains(&piece_index)))
+                .collect::<Vec<_>>();
+            pieces.contains(&true).then(|| Bitfield::from_pieces(&pieces))
+        };
+        if let Some(bitfield) = bitfield {
+            debug!(peer = %self.socket_adr, pieces_have = bitfield.have.len(), "sending bitfield");
+            stream.send(vec![Message::Bitfield(bitfield)]).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells a peer that runs a DHT node the port of ours, so it can add us to its routing table
+    async fn send_dht_port(&self, stream: &mut Framed<TcpStream, PeerMessageCodec>) -> Result<(), PeerError> {
+        let Some(port) = self
+            .state
+            .dht()
+            .await
+            .and_then(|dht| dht.local_addr().ok())
+            .map(|address| address.port())
+        else {
+            return Ok(());
+        };
+        debug!(peer = %self.socket_adr, port, "sending DHT port");
+        stream.send(vec![Message::Port(Port::new(port))]).await?;
+        Ok(())
+    }
+
+    /// Pings the DHT node of the peer, our DHT node adds it to the routing table once it answers
+    async fn ping_dht_node(&self, port: u16) {
+        self.info.lock().await.dht_port = Some(port);
+        let Some(dht) = self.state.dht().await else {
+            return;
+        };
+        let address = SocketAddr::new(self.socket_adr.ip(), port);
+        debug!(peer = %self.socket_adr, port, "peer told its DHT port");
+        // The ping waits for the response, so it's kept out of the message loop
+        tokio::spawn(async move {
+            if let Err(error) = dht.ping(address).await {
+                debug!(node = %address, error = %error, "DHT node of peer didn't answer ping");
+            }
+        });
+    }
+
     async fn handle_message(&self, message: Message) {
         match message {
             Message::Have(have) => {
-                let mut info = self.info.lock().await;
-                if !info.pieces_have.contains(&have.piece_index) {
-                    info.pieces_have.push(have.piece_index);
-                    debug!(peer = %self.socket_adr, piece_index = have.piece_index, "peer announced piece");
+                let piece_index = have.piece_index as usize;
+                {
+                    let mut info = self.info.lock().await;
+                    match info.pieces_have.get_mut(piece_index) {
+                        Some(has) if !*has => *has = true,
+                        _ => return,
+                    }
                 }
+                self.state.piece_picker.lock().await.add_peer_pieces(&[piece_index]);
+                debug!(peer = %self.socket_adr, piece_index = have.piece_index, "peer announced piece");
             }
             Message::Bitfield(bitfield) => {
-                let pieces_have = bitfield.have.len();
-                let mut info = self.info.lock().await;
-                info.pieces_have = bitfield.have.into_iter().map(|piece| piece as u32).collect();
-                info.pieces_not_have = bitfield.not_have.into_iter().map(|piece| piece as u32).collect();
-                info.peer_type = if info.pieces_not_have.is_empty() {
-                    PeerType::Seeder
-                } else {
-                    PeerType::Leecher
+                let mut pieces = bitfield.have;
+                let previous_pieces = {
+                    let mut info = self.info.lock().await;
+                    // The spare bits at the end of the bitfield are of no piece
+                    let mut pieces_have = vec![false; info.pieces_have.len()];
+                    pieces.retain(|&piece| piece < pieces_have.len());
+                    for &piece in &pieces {
+                        pieces_have[piece] = true;
+                    }
+                    let previous_pieces = std::mem::replace(&mut info.pieces_have, pieces_have);
+                    info.pieces_not_have = bitfield.not_have.into_iter().map(|piece| piece as u32).collect();
+                    info.peer_type = if info.pieces_not_have.is_empty() {
+                        PeerType::Seeder
+                    } else {
+                        PeerType::Leecher
+                    };
+                    previous_pieces
                 };
-                debug!(peer = %self.socket_adr, pieces_have, "peer sent bitfield");
+                {
+                    let mut picker = self.state.piece_picker.lock().await;
+                    picker.remove_peer_pieces(&Self::piece_indexes(&previous_pieces));
+                    picker.add_peer_pieces(&pieces);
+                }
+                debug!(peer = %self.socket_adr, pieces_have = pieces.len(), "peer sent bitfield");
             }
+            Message::Port(port) if port.listen_port() != 0 => self.ping_dht_node(port.listen_port()).await,
             _ => {}
         }
     }
 
-    async fn maybe_request_piece(
+    /// Tops up the blocks requested from the peer to its request queue depth, the blocks can be of
+    /// several pieces, and of pieces other peers are downloading as well
+    async fn maybe_request_blocks(
         &self,
         stream: &mut Framed<TcpStream, PeerMessageCodec>,
-        active_piece: &mut Option<ActivePiece>,
+        requests: &mut Vec<BlockRequest>,
         peer_choking: bool,
     ) -> Result<(), PeerError> {
-        if peer_choking || active_piece.is_some() {
+        let queue_depth = self.request_queue_depth();
+        if peer_choking || requests.len() >= queue_depth {
             return Ok(());
         }
 
-        let peer_pieces = {
+        // The peer info is locked before the partial pieces and the piece picker, and nothing locks
+        // it while holding either of them
+        let new_requests = {
             let info = self.info.lock().await;
-            info.pieces_have.iter().map(|piece| *piece as usize).collect::<Vec<_>>()
-        };
-        if peer_pieces.is_empty() {
-            return Ok(());
-        }
-
-        let piece_index = {
-            let mut picker = self.state.piece_picker.lock().await;
-            let piece_index = picker.next_rarest_piece(&[peer_pieces]);
-            if let Some(piece_index) = piece_index {
-                picker.mark_requested(piece_index);
+            if !info.pieces_have.contains(&true) {
+                return Ok(());
             }
-            piece_index
+            let mut partial_pieces = self.state.partial_pieces.lock().await;
+            let mut picker = self.state.piece_picker.lock().await;
+            partial_pieces.request_blocks(
+                &mut picker,
+                |piece_index| info.pieces_have.get(piece_index).copied().unwrap_or(false),
+                requests,
+                queue_depth - requests.len(),
+                |piece_index| Some((self.state.piece_hash(piece_index)?, self.state.piece_length_at(piece_index)?)),
+            )
         };
-
-        let Some(piece_index) = piece_index else {
+        if new_requests.is_empty() {
             return Ok(());
-        };
+        }
 
-        let Some(piece) = ActivePiece::new(self.state.clone(), piece_index) else {
-            self.state.piece_picker.lock().await.mark_request_failed(piece_index);
-            warn!(peer = %self.socket_adr, piece_index, "selected piece has no metadata");
-            return Ok(());
-        };
-        let requests = piece.requests();
         debug!(
             peer = %self.socket_adr,
-            piece_index,
-            request_count = requests.len(),
-            piece_length = piece.piece_length,
-            "requesting piece from peer"
+            request_count = new_requests.len(),
+            queued = requests.len(),
+            queue_depth,
+            "requesting blocks from peer"
         );
-        if let Err(error) = stream.send(requests.into_iter().map(Message::Request).collect()).await {
-            self.state.piece_picker.lock().await.mark_request_failed(piece_index);
+        let messages = new_requests
+            .iter()
+            .map(|block| Message::Request(Request::new(block.piece_index as u32, block.begin as u32, block.length as u32)))
+            .collect();
+        requests.extend(new_requests);
+        if let Err(error) = stream.send(messages).await {
+            self.release_requests(requests).await;
             return Err(error.into());
         }
-        *active_piece = Some(piece);
         Ok(())
     }
 
-    async fn handle_piece_block(&self, block: Block, active_piece: &mut Option<ActivePiece>) -> Result<(), PeerError> {
-        let Some(piece) = active_piece.as_mut() else {
+    /// No of blocks to keep requested from the peer, enough for [REQUEST_QUEUE_TIME] at the rate
+    /// the peer has been sending them
+    fn request_queue_depth(&self) -> usize {
+        let queued_bytes = self
+            .download_rate
+            .bytes_per_second()
+            .saturating_mul(REQUEST_QUEUE_TIME.as_secs() as usize);
+        (queued_bytes / BLOCK_SIZE).clamp(MIN_REQUEST_QUEUE, MAX_REQUEST_QUEUE)
+    }
+
+    async fn handle_piece_block(&self, block: Block, requests: &mut Vec<BlockRequest>) -> Result<(), PeerError> {
+        let piece_index = block.piece_index as usize;
+        let begin = block.byte_index as usize;
+        let Some(position) = requests
+            .iter()
+            .position(|request| request.piece_index == piece_index && request.begin == begin)
+        else {
+            debug!(peer = %self.socket_adr, piece_index, begin, "ignoring block that wasn't requested");
             return Ok(());
         };
-        if block.piece_index as usize != piece.index() {
-            return Ok(());
+        // A block that's rejected stays in the requests, so it's given back when the session ends
+        let request = requests[position];
+        if block.raw_block.len() != request.length {
+            return Err(PeerError::BlockLength {
+                expected: request.length,
+                actual: block.raw_block.len(),
+            });
         }
 
-        if let Err(error) = piece.insert_block(block.byte_index as usize, block.raw_block.to_vec()) {
-            self.release_active_piece(active_piece.take()).await;
-            return Err(error.into());
+        self.download_rate.record(block.raw_block.len());
+        self.state.download_rate.record(block.raw_block.len());
+        let received = self
+            .state
+            .partial_pieces
+            .lock()
+            .await
+            .insert_block(piece_index, begin, block.raw_block.to_vec())?;
+        requests.remove(position);
+        let (assembler, requested_elsewhere) = match received {
+            ReceivedBlock::Ignored => return Ok(()),
+            ReceivedBlock::Stored { requested_elsewhere } => (None, requested_elsewhere),
+            ReceivedBlock::Completed {
+                assembler,
+                requested_elsewhere,
+            } => (Some(assembler), requested_elsewhere),
+        };
+        if requested_elsewhere {
+            self.broadcast_cancel(request).await;
         }
-        if piece.is_complete() {
-            let piece_index = piece.index();
-            let assembled = match active_piece.take().expect("piece exists").assemble() {
-                Ok(piece) => piece,
-                Err(error) => {
-                    self.state.piece_picker.lock().await.mark_request_failed(piece_index);
-                    return Err(error.into());
-                }
-            };
-            if let Err(error) = PieceStorage::write_piece(&self.state, piece_index, &assembled).await {
+        let Some(assembler) = assembler else {
+            return Ok(());
+        };
+
+        let assembled = match assembler.assemble() {
+            Ok(piece) => piece,
+            Err(error) => {
                 self.state.piece_picker.lock().await.mark_request_failed(piece_index);
                 return Err(error.into());
             }
-            self.state
-                .set_bytes_complete(self.state.bytes_complete().saturating_add(assembled.len()));
-            self.state.set_pieces_downloaded(self.state.pieces_downloaded().saturating_add(1));
-            self.state.piece_picker.lock().await.mark_completed(piece_index);
-            info!(
-                peer = %self.socket_adr,
-                piece_index,
-                bytes = assembled.len(),
-                bytes_complete = self.state.bytes_complete(),
-                "piece downloaded"
-            );
+        };
+        let piece_length = assembled.len();
+        self.store_piece(piece_index, assembled).await?;
+        if PieceStorage::is_readable(&self.state, piece_index).await {
+            self.broadcast_have(piece_index).await;
         }
+        info!(
+            peer = %self.socket_adr,
+            piece_index,
+            bytes = piece_length,
+            bytes_complete = self.state.bytes_complete(),
+            "piece downloaded"
+        );
         Ok(())
     }
 
-    async fn release_active_piece(&self, active_piece: Option<ActivePiece>) {
-        if let Some(piece) = active_piece {
-            debug!(peer = %self.socket_adr, piece_index = piece.index(), "releasing active piece");
-            self.state.piece_picker.lock().await.mark_request_failed(piece.index());
+    /// Writes a downloaded piece onto the disk and counts it as complete
+    ///
+    /// It's done in a task tracked by the state rather than in the session, so a piece that's
+    /// halfway onto the disk when the session stops still gets there and is counted
+    async fn store_piece(&self, piece_index: usize, piece: Vec<u8>) -> Result<(), PeerError> {
+        let state = self.state.clone();
+        let write = self
+            .state
+            .piece_writes
+            .spawn(async move { PieceStorage::complete_piece(&state, piece_index, &piece).await });
+        write.await??;
+        Ok(())
+    }
+
+    /// Tells every other peer of the torrent that we now have the piece
+    async fn broadcast_have(&self, piece_index: usize) {
+        let peers = self.state.peers.lock().await;
+        for peer in peers.iter().filter(|peer| peer.socket_adr != self.socket_adr) {
+            peer.send_command(PeerCommand::Have(piece_index as u32));
+        }
+    }
+
+    /// Tells every other peer of the torrent to take back their request for a block we just got,
+    /// the ones that never requested it ignore it
+    async fn broadcast_cancel(&self, block: BlockRequest) {
+        let peers = self.state.peers.lock().await;
+        for peer in peers.iter().filter(|peer| peer.socket_adr != self.socket_adr) {
+            peer.send_command(PeerCommand::Cancel(block));
+        }
+    }
+
+    /// Stops counting the pieces of the peer towards their availability, the peer sends its
+    /// bitfield again when it reconnects
+    async fn forget_peer_pieces(&self) {
+        let pieces = {
+            let mut info = self.info.lock().await;
+            let piece_count = info.pieces_have.len();
+            Self::piece_indexes(&std::mem::replace(&mut info.pieces_have, vec![false; piece_count]))
+        };
+        if pieces.is_empty() {
+            return;
+        }
+        self.state.piece_picker.lock().await.remove_peer_pieces(&pieces);
+    }
+
+    /// Indexes of the pieces set in the bitfield
+    fn piece_indexes(pieces_have: &[bool]) -> Vec<usize> {
+        (0..pieces_have.len()).filter(|&piece_index| pieces_have[piece_index]).collect()
+    }
+
+    /// Gives the blocks requested from the peer back, so the other peers can request them
+    async fn release_requests(&self, requests: &mut Vec<BlockRequest>) {
+        if requests.is_empty() {
+            return;
+        }
+        debug!(peer = %self.socket_adr, blocks = requests.len(), "releasing requested blocks");
+        let mut partial_pieces = self.state.partial_pieces.lock().await;
+        for request in requests.drain(..) {
+            partial_pieces.release(&request);
         }
     }
 
@@ -466,67 +928,24 @@ impl Peer {
     }
 }
 
-struct ActivePiece {
-    piece_index: usize,
-    assembler: PieceAssembler,
-    piece_length: usize,
-}
-
-impl ActivePiece {
-    fn new(state: Arc<State>, piece_index: usize) -> Option<Self> {
-        let expected_hash = state.piece_hash(piece_index)?;
-        let piece_length = state.piece_length_at(piece_index)?;
-        Some(Self {
-            piece_index,
-            assembler: PieceAssembler::new(expected_hash, piece_length),
-            piece_length,
-        })
-    }
-
-    fn index(&self) -> usize {
-        self.piece_index
-    }
-
-    fn requests(&self) -> Vec<Request> {
-        const BLOCK_SIZE: usize = 16 * 1024;
-        let mut requests = Vec::new();
-        let mut begin = 0_usize;
-        while begin < self.piece_length {
-            let length = BLOCK_SIZE.min(self.piece_length - begin);
-            requests.push(Request::new(self.piece_index as u32, begin as u32, length as u32));
-            begin += length;
-        }
-        requests
-    }
-
-    fn insert_block(&mut self, begin: usize, block: Vec<u8>) -> Result<(), PieceAssemblyError> {
-        self.assembler.insert_block(begin, block)
-    }
-
-    fn is_complete(&self) -> bool {
-        self.assembler.is_complete()
-    }
-
-    fn assemble(self) -> Result<Vec<u8>, PieceAssemblyError> {
-        self.assembler.assemble()
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use super::{Peer, PeerError};
+    use super::messages::{Bitfield, Have, Message};
+    use super::{BlockRequest, Choker, InboundPeer, Peer, PeerCommand, PeerError, PeerListener, BLOCK_SIZE};
     use crate::core::{
+        dht::DhtNode,
         piece_picker::PiecePicker,
-        state::{DownState, State},
+        piece_storage::PieceStorage,
+        state::State,
+        test_support::{temp_dir, TestState},
     };
-    use crossbeam::atomic::AtomicCell;
-    use hyperblow::parser::torrent_parser::{FileMeta, Info};
     use sha1::{Digest, Sha1};
-    use std::{fs, path::PathBuf, sync::Arc};
+    use std::{fs, path::PathBuf, sync::Arc, time::Duration};
     use tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
-        net::TcpListener,
-        sync::{Mutex, RwLock},
+        net::{TcpListener, TcpStream},
+        sync::{oneshot, Mutex},
+        time::{sleep, timeout},
     };
 
     #[tokio::test]
@@ -561,7 +980,7 @@ mod tests {
 
     #[tokio::test]
     async fn peer_session_requests_and_writes_piece() {
-        let output_dir = PeerDownloadFixture::temp_dir();
+        let output_dir = temp_dir("peer-download");
         let piece = b"hello peer".to_vec();
         let info_hash = vec![7; 20];
         let state = PeerDownloadFixture::state(output_dir.clone(), info_hash.clone(), piece.clone());
@@ -616,6 +1035,343 @@ mod tests {
         fs::remove_dir_all(output_dir).expect("output dir should remove");
     }
 
+    #[tokio::test]
+    async fn peer_session_pipelines_block_requests_across_pieces() {
+        let output_dir = temp_dir("peer-pipeline");
+        let piece_length = BLOCK_SIZE + 100;
+        let data = (0..2 * piece_length).map(|byte| byte as u8).collect::<Vec<_>>();
+        let info_hash = vec![7; 20];
+        let mut state = PeerDownloadFixture::state(output_dir.clone(), info_hash.clone(), data.clone());
+        let inner = Arc::get_mut(&mut state).expect("state should be uniquely owned");
+        inner.pieces_hash = data.chunks(piece_length).map(|piece| Sha1::digest(piece).into()).collect();
+        inner.meta_info.info.piece_length = Some(piece_length as i64);
+        inner.meta_info.info.pieces = inner.pieces_hash.concat();
+        inner.piece_picker = Arc::new(Mutex::new(PiecePicker::new(2)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+        let server_data = data.clone();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("peer should connect");
+            let mut handshake = [0_u8; 68];
+            socket.read_exact(&mut handshake).await.expect("peer should send handshake");
+            socket.write_all(&handshake).await.expect("server should send handshake response");
+            let mut interested = [0_u8; 5];
+            socket.read_exact(&mut interested).await.expect("peer should send interested");
+
+            socket.write_all(&[0, 0, 0, 1, 1]).await.expect("unchoke should send");
+            socket.write_all(&[0, 0, 0, 2, 5, 0b1100_0000]).await.expect("bitfield should send");
+
+            // Every block of both pieces is requested before any of them arrives
+            let mut requested = Vec::new();
+            for _ in 0..4 {
+                let mut request = [0_u8; 17];
+                socket.read_exact(&mut request).await.expect("block request should arrive");
+                assert_eq!(request[4], 6);
+                let field = |at: usize| u32::from_be_bytes(request[at..at + 4].try_into().expect("request field")) as usize;
+                requested.push((field(5), field(9), field(13)));
+            }
+            requested.sort();
+            assert_eq!(
+                requested,
+                vec![(0, 0, BLOCK_SIZE), (0, BLOCK_SIZE, 100), (1, 0, BLOCK_SIZE), (1, BLOCK_SIZE, 100)]
+            );
+
+            for (index, begin, length) in requested.into_iter().rev() {
+                let offset = index * piece_length + begin;
+                let mut response = Vec::new();
+                response.extend_from_slice(&(9 + length as u32).to_be_bytes());
+                response.push(7);
+                response.extend_from_slice(&(index as u32).to_be_bytes());
+                response.extend_from_slice(&(begin as u32).to_be_bytes());
+                response.extend_from_slice(&server_data[offset..offset + length]);
+                socket.write_all(&response).await.expect("block should send");
+            }
+        });
+
+        let peer = Peer::new(address, state.clone());
+        peer.run_session().await.expect("peer session should download both pieces");
+        server.await.expect("server task should complete");
+
+        assert_eq!(state.pieces_downloaded(), 2);
+        assert!(state.partial_pieces.lock().await.is_empty());
+        // The pieces of the peer that went away no longer count towards their availability
+        assert_eq!(state.piece_picker.lock().await.availability(0), 0);
+        assert_eq!(
+            fs::read(output_dir.join("peer-test.bin")).expect("downloaded file should exist"),
+            data
+        );
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn peer_session_cancels_blocks_another_peer_sent_first() {
+        let output_dir = temp_dir("peer-endgame");
+        let piece_length = 2 * BLOCK_SIZE;
+        let data = (0..piece_length).map(|byte| byte as u8).collect::<Vec<_>>();
+        let info_hash = vec![7; 20];
+        let mut state = PeerDownloadFixture::state(output_dir.clone(), info_hash.clone(), data.clone());
+        let inner = Arc::get_mut(&mut state).expect("state should be uniquely owned");
+        inner.pieces_hash = vec![Sha1::digest(&data).into()];
+        inner.meta_info.info.piece_length = Some(piece_length as i64);
+        inner.meta_info.info.pieces = inner.pieces_hash.concat();
+        inner.piece_picker = Arc::new(Mutex::new(PiecePicker::new(1)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+        let peer = Peer::new(address, state.clone());
+        let server_peer = peer.clone();
+        let server_state = state.clone();
+        let server_data = data.clone();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("peer should connect");
+            let mut handshake = [0_u8; 68];
+            socket.read_exact(&mut handshake).await.expect("peer should send handshake");
+            socket.write_all(&handshake).await.expect("server should send handshake response");
+            let mut interested = [0_u8; 5];
+            socket.read_exact(&mut interested).await.expect("peer should send interested");
+            socket.write_all(&[0, 0, 0, 1, 1]).await.expect("unchoke should send");
+            socket.write_all(&[0, 0, 0, 2, 5, 0b1000_0000]).await.expect("bitfield should send");
+            let mut requests = [0_u8; 34];
+            socket.read_exact(&mut requests).await.expect("block requests should arrive");
+
+            // Another peer sends the second block first
+            let second_block = BlockRequest {
+                piece_index: 0,
+                begin: BLOCK_SIZE,
+                length: BLOCK_SIZE,
+            };
+            server_state
+                .partial_pieces
+                .lock()
+                .await
+                .insert_block(0, BLOCK_SIZE, server_data[BLOCK_SIZE..].to_vec())
+                .expect("block should fit the piece");
+            server_peer.send_command(PeerCommand::Cancel(second_block));
+
+            let mut cancel = [0_u8; 17];
+            socket.read_exact(&mut cancel).await.expect("cancel should arrive");
+            let mut expected = vec![0, 0, 0, 13, 8, 0, 0, 0, 0];
+            expected.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+            expected.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+            assert_eq!(cancel.to_vec(), expected);
+
+            let mut response = Vec::new();
+            response.extend_from_slice(&(9 + BLOCK_SIZE as u32).to_be_bytes());
+            response.extend_from_slice(&[7, 0, 0, 0, 0, 0, 0, 0, 0]);
+            response.extend_from_slice(&server_data[..BLOCK_SIZE]);
+            socket.write_all(&response).await.expect("block should send");
+        });
+
+        peer.run_session().await.expect("peer session should download the piece");
+        server.await.expect("server task should complete");
+
+        assert_eq!(state.pieces_downloaded(), 1);
+        assert_eq!(
+            fs::read(output_dir.join("peer-test.bin")).expect("downloaded file should exist"),
+            data
+        );
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn peer_session_rejects_short_block_and_releases_it() {
+        let output_dir = temp_dir("peer-short-block");
+        let piece = b"hello peer".to_vec();
+        let info_hash = vec![7; 20];
+        let state = PeerDownloadFixture::state(output_dir.clone(), info_hash.clone(), piece.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("peer should connect");
+            let mut handshake = [0_u8; 68];
+            socket.read_exact(&mut handshake).await.expect("peer should send handshake");
+            socket.write_all(&handshake).await.expect("server should send handshake response");
+            let mut interested = [0_u8; 5];
+            socket.read_exact(&mut interested).await.expect("peer should send interested");
+            socket.write_all(&[0, 0, 0, 1, 1]).await.expect("unchoke should send");
+            socket.write_all(&[0, 0, 0, 2, 5, 0b1000_0000]).await.expect("bitfield should send");
+            let mut request = [0_u8; 17];
+            socket.read_exact(&mut request).await.expect("piece request should arrive");
+
+            // Half of the block that was asked for
+            let mut response = vec![0, 0, 0, 14, 7, 0, 0, 0, 0, 0, 0, 0, 0];
+            response.extend_from_slice(b"hello");
+            socket.write_all(&response).await.expect("block should send");
+            let mut rest = Vec::new();
+            let _ = socket.read_to_end(&mut rest).await;
+        });
+
+        let peer = Peer::new(address, state.clone());
+        let error = peer.run_session().await.expect_err("short block should end the session");
+        assert!(matches!(error, PeerError::BlockLength { expected: 10, actual: 5 }));
+        server.await.expect("server task should complete");
+
+        // The block is open again, for the next peer to be asked for
+        let mut partial_pieces = state.partial_pieces.lock().await;
+        let requests = partial_pieces.request_blocks(
+            &mut *state.piece_picker.lock().await,
+            |piece_index| piece_index == 0,
+            &[],
+            1,
+            |_| None,
+        );
+        assert_eq!(
+            requests,
+            vec![BlockRequest {
+                piece_index: 0,
+                begin: 0,
+                length: 10
+            }]
+        );
+        assert_eq!(state.pieces_downloaded(), 0);
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn peer_session_serves_requests_and_honours_cancel() {
+        let output_dir = temp_dir("peer-upload");
+        let piece = b"seeded data".to_vec();
+        let info_hash = vec![7; 20];
+        let state = PeerDownloadFixture::state(output_dir.clone(), info_hash.clone(), piece.clone());
+        PieceStorage::write_piece(&state, 0, &piece).await.expect("piece should write");
+        state.piece_picker.lock().await.mark_completed(0);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+        let (served_sender, served_receiver) = oneshot::channel();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("peer should connect");
+            let mut handshake = [0_u8; 68];
+            socket.read_exact(&mut handshake).await.expect("peer should send handshake");
+            socket.write_all(&handshake).await.expect("server should send handshake response");
+
+            let mut bitfield_and_interested = [0_u8; 11];
+            socket
+                .read_exact(&mut bitfield_and_interested)
+                .await
+                .expect("peer should send bitfield and interested");
+            assert_eq!(bitfield_and_interested, [0, 0, 0, 2, 5, 0b1000_0000, 0, 0, 0, 1, 2]);
+
+            socket.write_all(&[0, 0, 0, 1, 2]).await.expect("interested should send");
+            let mut unchoke = [0_u8; 5];
+            socket.read_exact(&mut unchoke).await.expect("peer should unchoke us");
+            assert_eq!(unchoke, [0, 0, 0, 1, 1]);
+
+            let mut messages = Vec::new();
+            messages.extend_from_slice(&[0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+            messages.extend_from_slice(&[0, 0, 0, 13, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+            messages.extend_from_slice(&[0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 3]);
+            socket.write_all(&messages).await.expect("requests should send");
+
+            let mut block = [0_u8; 16];
+            socket.read_exact(&mut block).await.expect("peer should send the block");
+            assert_eq!(&block[..13], &[0, 0, 0, 12, 7, 0, 0, 0, 0, 0, 0, 0, 2]);
+            assert_eq!(&block[13..], b"ede");
+
+            served_sender.send(()).expect("test should wait for the block");
+            let mut choke = [0_u8; 5];
+            socket.read_exact(&mut choke).await.expect("choker command should choke us");
+            assert_eq!(choke, [0, 0, 0, 1, 0]);
+        });
+
+        let peer = Peer::new(address, state.clone());
+        let session = {
+            let peer = peer.clone();
+            tokio::spawn(async move { peer.run_session().await })
+        };
+        served_receiver.await.expect("server should receive the block");
+        peer.send_command(PeerCommand::Choke);
+        server.await.expect("server task should complete");
+        session
+            .await
+            .expect("session task should complete")
+            .expect("peer session should serve the block");
+
+        assert_eq!(state.uploaded(), 3);
+        assert!(peer.is_choked_by_us().await);
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn peer_gives_up_its_unchoke_slot_when_the_session_ends() {
+        let state = test_state(vec![7; 20]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+        let peer = Peer::new(address, state.clone());
+        state.peers.lock().await.push(peer.clone());
+        let server_peer = peer.clone();
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("peer should connect");
+            let mut handshake = [0_u8; 68];
+            socket.read_exact(&mut handshake).await.expect("peer should send handshake");
+            socket.write_all(&handshake).await.expect("server should send handshake response");
+            let mut interested = [0_u8; 5];
+            socket.read_exact(&mut interested).await.expect("peer should send interested");
+            socket.write_all(&[0, 0, 0, 1, 2]).await.expect("interested should send");
+            let mut unchoke = [0_u8; 5];
+            socket.read_exact(&mut unchoke).await.expect("peer should unchoke us");
+            assert_eq!(unchoke, [0, 0, 0, 1, 1]);
+            assert!(server_peer.is_unchoked_by_us().await);
+        });
+
+        peer.run_session().await.expect("peer session should end with the connection");
+        server.await.expect("server task should complete");
+
+        assert!(peer.is_choked_by_us().await);
+        assert!(!peer.is_unchoked_by_us().await);
+        assert!(Choker::has_free_slot(&state).await);
+    }
+
+    #[tokio::test]
+    async fn peer_session_exchanges_dht_ports() {
+        let state = test_state(vec![7; 20]);
+        let our_node = DhtNode::spawn_local([1; 20]).await;
+        let peer_node = DhtNode::spawn_local([2; 20]).await;
+        let our_dht_port = our_node.local_addr().expect("DHT socket should have an address").port();
+        let peer_dht_port = peer_node.local_addr().expect("DHT socket should have an address").port();
+        *state.dht.write().await = Some(our_node.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("peer should connect");
+            let mut handshake = [0_u8; 68];
+            socket.read_exact(&mut handshake).await.expect("peer should send handshake");
+            assert_eq!(handshake[27] & 0x01, 0x01);
+            socket.write_all(&handshake).await.expect("server should send handshake response");
+
+            let mut port_and_interested = [0_u8; 12];
+            socket
+                .read_exact(&mut port_and_interested)
+                .await
+                .expect("peer should send port and interested");
+            assert_eq!(&port_and_interested[..5], &[0, 0, 0, 3, 9]);
+            assert_eq!(u16::from_be_bytes([port_and_interested[5], port_and_interested[6]]), our_dht_port);
+            assert_eq!(&port_and_interested[7..], &[0, 0, 0, 1, 2]);
+
+            let mut port = vec![0, 0, 0, 3, 9];
+            port.extend_from_slice(&peer_dht_port.to_be_bytes());
+            socket.write_all(&port).await.expect("server should send its DHT port");
+        });
+
+        let peer = Peer::new(address, state);
+        peer.run_session().await.expect("peer session should complete after server closes");
+        server.await.expect("server task should complete");
+
+        // The ping of the peer's DHT node adds it to our routing table
+        timeout(Duration::from_secs(5), async {
+            while our_node.node_count().await == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peer's DHT node should be pinged");
+        assert_eq!(peer.info.lock().await.dht_port, Some(peer_dht_port));
+    }
+
     #[tokio::test]
     async fn peer_session_rejects_wrong_info_hash() {
         let state = test_state(vec![7; 20]);
@@ -637,83 +1393,121 @@ mod tests {
         server.await.expect("server task should complete");
     }
 
+    #[tokio::test]
+    async fn inbound_peer_gets_handshake_and_bitfield() {
+        let output_dir = temp_dir("peer-inbound");
+        let info_hash = vec![7; 20];
+        let state = PeerDownloadFixture::state(output_dir, info_hash.clone(), b"seeded".to_vec());
+        state.piece_picker.lock().await.mark_completed(0);
+        let listener = PeerListener::bind_to("127.0.0.1:0".parse().expect("address should parse"))
+            .await
+            .expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+
+        let client = tokio::spawn(async move {
+            let mut socket = TcpStream::connect(address).await.expect("client should connect");
+            let mut handshake = [0_u8; 68];
+            handshake[0] = 19;
+            handshake[1..20].copy_from_slice(b"BitTorrent protocol");
+            handshake[28..48].copy_from_slice(&info_hash);
+            socket.write_all(&handshake).await.expect("client should send handshake");
+
+            let mut response = [0_u8; 68];
+            socket.read_exact(&mut response).await.expect("listener should answer handshake");
+            assert_eq!(&response[28..48], info_hash.as_slice());
+
+            let mut bitfield = [0_u8; 6];
+            socket.read_exact(&mut bitfield).await.expect("listener should send bitfield");
+            assert_eq!(bitfield, [0, 0, 0, 2, 5, 0b1000_0000]);
+        });
+
+        let (tcp_stream, socket_adr) = listener.accept().await.expect("listener should accept");
+        let inbound = InboundPeer::read_handshake(tcp_stream, socket_adr)
+            .await
+            .expect("inbound handshake should be read");
+        assert_eq!(inbound.info_hash(), &[7; 20]);
+        inbound.run(state.clone()).await;
+        client.await.expect("client task should complete");
+
+        // The peer is forgotten once it disconnects
+        assert!(state.peers.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn peer_pieces_count_once_towards_availability_until_the_peer_goes() {
+        let state = TestState::new("pieces.bin").data(&[1; 12], 4).build();
+        let peer = Peer::new("127.0.0.1:6881".parse().expect("address"), state.clone());
+        let availability = |state: Arc<State>| async move {
+            let picker = state.piece_picker.lock().await;
+            (0..3).map(|piece_index| picker.availability(piece_index)).collect::<Vec<_>>()
+        };
+
+        // A piece announced twice and one the torrent doesn't have are ignored
+        for piece_index in [1, 1, 9] {
+            peer.handle_message(Message::Have(Have { piece_index })).await;
+        }
+        assert_eq!(availability(state.clone()).await, vec![0, 1, 0]);
+
+        // A bitfield replaces what the peer announced before, its spare bits are of no piece
+        peer.handle_message(Message::Bitfield(Bitfield {
+            have: vec![0, 2, 5],
+            not_have: vec![1],
+        }))
+        .await;
+        assert_eq!(availability(state.clone()).await, vec![1, 0, 1]);
+
+        peer.forget_peer_pieces().await;
+        assert_eq!(availability(state.clone()).await, vec![0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn inbound_peer_already_known_is_turned_away() {
+        let state = test_state(vec![7; 20]);
+        let listener = PeerListener::bind_to("127.0.0.1:0".parse().expect("address should parse"))
+            .await
+            .expect("listener should bind");
+        let address = listener.local_addr().expect("listener should have local address");
+
+        let client = tokio::spawn(async move {
+            let mut socket = TcpStream::connect(address).await.expect("client should connect");
+            let mut handshake = [0_u8; 68];
+            handshake[0] = 19;
+            handshake[1..20].copy_from_slice(b"BitTorrent protocol");
+            handshake[28..48].copy_from_slice(&[7; 20]);
+            socket.write_all(&handshake).await.expect("client should send handshake");
+            let mut response = Vec::new();
+            socket
+                .read_to_end(&mut response)
+                .await
+                .expect("listener should close the connection");
+            assert!(response.is_empty());
+        });
+
+        let (tcp_stream, socket_adr) = listener.accept().await.expect("listener should accept");
+        let known = Peer::new(socket_adr, state.clone());
+        state.peers.lock().await.push(known);
+        let inbound = InboundPeer::read_handshake(tcp_stream, socket_adr)
+            .await
+            .expect("inbound handshake should be read");
+        inbound.run(state.clone()).await;
+        client.await.expect("client task should complete");
+
+        assert_eq!(state.peers.lock().await.len(), 1);
+    }
+
     fn test_state(info_hash: Vec<u8>) -> Arc<State> {
-        Arc::new(State {
-            meta_info: FileMeta {
-                announce: "udp://tracker.example.test:6969".to_string(),
-                announce_list: None,
-                info: Info {
-                    name: Some("peer-test".to_string()),
-                    length: Some(0),
-                    files: None,
-                    piece_length: Some(16 * 1024),
-                    pieces: Vec::new(),
-                },
-                creation_data: None,
-                comment: None,
-                encoding: None,
-                created_by: None,
-                acceptable_source: None,
-            },
-            download_directory: std::env::temp_dir(),
-            d_state: DownState::Unknown,
-            file_tree: None,
-            trackers: Arc::new(RwLock::new(Vec::new())),
-            udp_ports: Arc::new(Mutex::new(vec![6881])),
-            tcp_ports: Arc::new(Mutex::new(Vec::new())),
-            info_hash,
-            pieces_hash: Vec::new(),
-            piece_picker: Arc::new(Mutex::new(PiecePicker::new(0))),
-            peers: Arc::new(Mutex::new(Vec::new())),
-            uptime: AtomicCell::new(0),
-            bytes_complete: AtomicCell::new(0),
-            pieces_downloaded: AtomicCell::new(0),
-        })
+        TestState::new("peer-test").info_hash(info_hash).build()
     }
 
     struct PeerDownloadFixture;
 
     impl PeerDownloadFixture {
-        fn temp_dir() -> PathBuf {
-            let path = std::env::temp_dir().join(format!("hyperblow-peer-download-{}", std::process::id()));
-            let _ = fs::remove_dir_all(&path);
-            fs::create_dir_all(&path).expect("temp dir should create");
-            path
-        }
-
         fn state(download_directory: PathBuf, info_hash: Vec<u8>, piece: Vec<u8>) -> Arc<State> {
-            let piece_hash: [u8; 20] = Sha1::digest(&piece).into();
-            Arc::new(State {
-                meta_info: FileMeta {
-                    announce: "udp://tracker.example.test:6969".to_string(),
-                    announce_list: None,
-                    info: Info {
-                        name: Some("peer-test.bin".to_string()),
-                        length: Some(piece.len() as i64),
-                        files: None,
-                        piece_length: Some(piece.len() as i64),
-                        pieces: piece_hash.to_vec(),
-                    },
-                    creation_data: None,
-                    comment: None,
-                    encoding: None,
-                    created_by: None,
-                    acceptable_source: None,
-                },
-                download_directory,
-                d_state: DownState::Unknown,
-                file_tree: None,
-                trackers: Arc::new(RwLock::new(Vec::new())),
-                udp_ports: Arc::new(Mutex::new(Vec::new())),
-                tcp_ports: Arc::new(Mutex::new(Vec::new())),
-                info_hash,
-                pieces_hash: vec![piece_hash],
-                piece_picker: Arc::new(Mutex::new(PiecePicker::new(1))),
-                peers: Arc::new(Mutex::new(Vec::new())),
-                uptime: AtomicCell::new(0),
-                bytes_complete: AtomicCell::new(0),
-                pieces_downloaded: AtomicCell::new(0),
-            })
+            TestState::new("peer-test.bin")
+                .data(&piece, piece.len())
+                .info_hash(info_hash)
+                .download_directory(download_directory)
+                .build()
         }
     }
 }
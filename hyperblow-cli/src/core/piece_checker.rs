@@ -0,0 +1,125 @@
+use super::{
+    piece_storage::PieceStorage,
+    state::{DownState, State},
+};
+use sha1::{Digest, Sha1};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// Hashes the data already on the disk against the piece hashes of the torrent, so the pieces
+/// that were downloaded before, by us or by another client, aren't downloaded again
+pub struct PieceChecker;
+
+impl PieceChecker {
+    /// Whether the torrent has any data on the disk worth checking
+    pub fn has_existing_data(state: &State) -> bool {
+        PieceStorage::has_existing_files(state)
+    }
+
+    /// Reads every piece through the file mapping and SHA-1s it, the matching pieces are marked
+    /// completed and every other one is marked missing. It gives the no of pieces that matched
+    ///
+    /// The torrent reports [DownState::Checking] meanwhile, with the progress in "pieces_checked"
+    pub async fn recheck(state: &Arc<State>) -> usize {
+        let previous_d_state = state.d_state();
+        let piece_count = state.pieces_hash.len();
+        state.set_pieces_checked(0);
+        state.set_d_state(DownState::Checking);
+        info!(piece_count, "rechecking torrent data");
+
+        let mut verified_pieces = 0;
+        for piece_index in 0..piece_count {
+            let verified = Self::check_piece(state, piece_index).await;
+            // The progress follows every piece, so a recheck stopped partway leaves it matching
+            // the pieces marked so far
+            let was_completed = {
+                let mut piece_picker = state.piece_picker.lock().await;
+                let was_completed = piece_picker.is_completed(piece_index);
+                if verified {
+                    piece_picker.mark_completed(piece_index);
+                } else {
+                    piece_picker.mark_missing(piece_index);
+                }
+                was_completed
+            };
+            let piece_length = state.piece_length_at(piece_index).unwrap_or_default();
+            if verified && !was_completed {
+                state.set_pieces_downloaded(state.pieces_downloaded().saturating_add(1));
+                state.set_bytes_complete(state.bytes_complete().saturating_add(piece_length));
+            } else if !verified && was_completed {
+                state.set_pieces_downloaded(state.pieces_downloaded().saturating_sub(1));
+                state.set_bytes_complete(state.bytes_complete().saturating_sub(piece_length));
+            }
+            if verified {
+                state.piece_completed.notify_waiters();
+                verified_pieces += 1;
+            }
+            state.set_pieces_checked(piece_index + 1);
+        }
+
+        state.set_d_state(match previous_d_state {
+            DownState::Checking => DownState::Unknown,
+            d_state => d_state,
+        });
+        info!(verified_pieces, piece_count, "recheck finished");
+        verified_pieces
+    }
+
+    async fn check_piece(state: &Arc<State>, piece_index: usize) -> bool {
+        let Some(expected_hash) = state.piece_hash(piece_index) else {
+            return false;
+        };
+        match PieceStorage::read_piece(state, piece_index).await {
+            // Hashing is CPU bound, so it's kept off the async workers
+            Ok(piece) => tokio::task::spawn_blocking(move || <[u8; 20]>::from(Sha1::digest(&piece)))
+                .await
+                .is_ok_and(|actual_hash| actual_hash == expected_hash),
+            Err(error) => {
+                debug!(piece_index, error = %error, "piece data isn't on the disk");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PieceChecker;
+    use crate::core::{
+        state::DownState,
+        test_support::{temp_dir, TestState},
+    };
+    use std::fs;
+
+    #[tokio::test]
+    async fn marks_pieces_matching_data_on_disk() {
+        let download_directory = temp_dir("checker");
+        let state = TestState::new("checked")
+            .data(b"abcdefghijkl", 4)
+            .files(vec![
+                (vec!["first.bin".to_string()], b"abcdef".to_vec()),
+                (vec!["second.bin".to_string()], b"ghijkl".to_vec()),
+            ])
+            .download_directory(download_directory.clone())
+            .build();
+        assert!(!PieceChecker::has_existing_data(&state));
+
+        // The second piece "efgh" spans both files and has a corrupt byte
+        fs::create_dir_all(download_directory.join("checked")).expect("torrent directory should be created");
+        fs::write(download_directory.join("checked").join("first.bin"), b"abcdXf").expect("first file should be written");
+        fs::write(download_directory.join("checked").join("second.bin"), b"ghijkl").expect("second file should be written");
+        state.piece_picker.lock().await.mark_completed(1);
+        state.set_pieces_downloaded(1);
+        state.set_bytes_complete(4);
+
+        assert!(PieceChecker::has_existing_data(&state));
+        assert_eq!(PieceChecker::recheck(&state).await, 2);
+        assert_eq!(state.piece_picker.lock().await.completed_pieces(), &[true, false, true]);
+        assert_eq!(state.pieces_downloaded(), 2);
+        assert_eq!(state.bytes_complete(), 8);
+        assert_eq!(state.pieces_checked(), 3);
+        assert_eq!(state.d_state(), DownState::Unknown);
+
+        fs::remove_dir_all(download_directory).expect("temp dir should be removed");
+    }
+}
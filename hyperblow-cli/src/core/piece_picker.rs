@@ -1,10 +1,46 @@
-use std::collections::HashSet;
+use super::file_priorities::FilePriority;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Instant,
+};
 
+/// The priorities pieces are picked in, from first to last. Skipped pieces are never picked
+const PICKED_PRIORITIES: [FilePriority; 3] = [FilePriority::High, FilePriority::Normal, FilePriority::Low];
+
+/// No of pieces, from the first one we're missing, that sequential mode downloads in order. The
+/// pieces after them are still picked rarest first
+pub const SEQUENTIAL_WINDOW: usize = 16;
+
+/// Which pieces to download next, by priority and rarest first across the swarm
+///
+/// The no of connected peers that have each piece (its availability) is kept up to date as peers
+/// send their bitfields and haves and as they go away. The pieces we still want are kept in
+/// buckets by priority and availability, so the rarest one is found without counting the pieces
+/// of every peer
+///
+/// Pieces with a deadline, eg. the ones a media player is about to read, are picked before all the
+/// others, and in sequential mode so are the pieces of the window right after what's downloaded
 #[derive(Debug, Clone)]
 pub struct PiecePicker {
     piece_count: usize,
     completed: Vec<bool>,
     requested: Vec<bool>,
+
+    /// No of connected peers that have each piece
+    availability: Vec<usize>,
+
+    /// Priority of each piece, the highest of the files it covers
+    priorities: Vec<FilePriority>,
+
+    /// The pieces that are neither completed, requested nor skipped, bucketed by their priority in
+    /// the order of [PICKED_PRIORITIES] and then by their availability
+    wanted: Vec<Vec<BTreeSet<usize>>>,
+
+    /// Pieces to be picked before any other, with when they're wanted by
+    deadlines: BTreeMap<usize, Instant>,
+
+    /// Whether the pieces of the [SEQUENTIAL_WINDOW] are picked in order before the rarest ones
+    sequential: bool,
 }
 
 impl PiecePicker {
@@ -13,45 +49,239 @@ impl PiecePicker {
             piece_count,
             completed: vec![false; piece_count],
             requested: vec![false; piece_count],
+            availability: vec![0; piece_count],
+            priorities: vec![FilePriority::Normal; piece_count],
+            wanted: PICKED_PRIORITIES
+                .iter()
+                .map(|&priority| match priority {
+                    FilePriority::Normal => vec![(0..piece_count).collect()],
+                    _ => Vec::new(),
+                })
+                .collect(),
+            deadlines: BTreeMap::new(),
+            sequential: false,
         }
     }
 
     pub fn mark_completed(&mut self, piece_index: usize) {
         if piece_index < self.piece_count {
-            self.completed[piece_index] = true;
-            self.requested[piece_index] = false;
+            self.update(piece_index, |picker| {
+                picker.completed[piece_index] = true;
+                picker.requested[piece_index] = false;
+            });
+            self.deadlines.remove(&piece_index);
+        }
+    }
+
+    /// Forgets that a piece was completed, eg. when a recheck finds its data missing or corrupt
+    pub fn mark_missing(&mut self, piece_index: usize) {
+        if piece_index < self.piece_count {
+            self.update(piece_index, |picker| picker.completed[piece_index] = false);
         }
     }
 
     pub fn mark_requested(&mut self, piece_index: usize) {
         if piece_index < self.piece_count && !self.completed[piece_index] {
-            self.requested[piece_index] = true;
+            self.update(piece_index, |picker| picker.requested[piece_index] = true);
         }
     }
 
     pub fn mark_request_failed(&mut self, piece_index: usize) {
         if piece_index < self.piece_count && !self.completed[piece_index] {
-            self.requested[piece_index] = false;
+            self.update(piece_index, |picker| picker.requested[piece_index] = false);
+        }
+    }
+
+    /// Forgets every outstanding request, eg. when the peer sessions that made them were stopped
+    pub fn clear_requests(&mut self) {
+        for piece_index in 0..self.piece_count {
+            if self.requested[piece_index] {
+                self.update(piece_index, |picker| picker.requested[piece_index] = false);
+            }
+        }
+    }
+
+    /// Counts the pieces of a peer that sent its bitfield, or announced a piece with a have
+    ///
+    /// pieces => Every piece is to be given once per peer
+    pub fn add_peer_pieces(&mut self, pieces: &[usize]) {
+        let piece_count = self.piece_count;
+        for &piece_index in pieces.iter().filter(|&&piece_index| piece_index < piece_count) {
+            self.update(piece_index, |picker| picker.availability[piece_index] += 1);
+        }
+    }
+
+    /// Stops counting the pieces of a peer that went away, or that sent another bitfield
+    pub fn remove_peer_pieces(&mut self, pieces: &[usize]) {
+        let piece_count = self.piece_count;
+        for &piece_index in pieces.iter().filter(|&&piece_index| piece_index < piece_count) {
+            self.update(piece_index, |picker| {
+                picker.availability[piece_index] = picker.availability[piece_index].saturating_sub(1)
+            });
         }
     }
 
-    pub fn next_rarest_piece(&self, peer_piece_sets: &[Vec<usize>]) -> Option<usize> {
-        let mut availability = vec![0_usize; self.piece_count];
-        for piece_set in peer_piece_sets {
-            let mut counted_for_peer = HashSet::new();
-            for &piece_index in piece_set {
-                if piece_index < self.piece_count && counted_for_peer.insert(piece_index) {
-                    availability[piece_index] += 1;
-                }
+    /// Forgets the pieces of every peer, eg. when the peer sessions were stopped
+    pub fn clear_availability(&mut self) {
+        self.availability.iter_mut().for_each(|count| *count = 0);
+        self.wanted.iter_mut().for_each(Vec::clear);
+        for piece_index in 0..self.piece_count {
+            self.update(piece_index, |_| {});
+        }
+    }
+
+    /// Gives every piece its priority, see [FilePriorities](super::file_priorities::FilePriorities)
+    pub fn set_priorities(&mut self, priorities: &[FilePriority]) {
+        for (piece_index, &priority) in priorities.iter().enumerate().take(self.piece_count) {
+            if self.priorities[piece_index] != priority {
+                self.update(piece_index, |picker| picker.priorities[piece_index] = priority);
             }
         }
+    }
+
+    pub fn priority(&self, piece_index: usize) -> FilePriority {
+        self.priorities.get(piece_index).copied().unwrap_or_default()
+    }
+
+    /// Has the piece picked before every piece without a deadline, the ones with the earliest
+    /// deadline come first. The deadline is dropped once the piece is completed
+    pub fn set_deadline(&mut self, piece_index: usize, deadline: Instant) {
+        if piece_index < self.piece_count && !self.completed[piece_index] {
+            self.deadlines.insert(piece_index, deadline);
+        }
+    }
+
+    /// Drops the deadline of the piece, as long as it's still the given one, so a deadline that
+    /// was set in its place by someone else is kept
+    pub fn remove_deadline(&mut self, piece_index: usize, deadline: Instant) {
+        if self.deadlines.get(&piece_index) == Some(&deadline) {
+            self.deadlines.remove(&piece_index);
+        }
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    pub fn deadline(&self, piece_index: usize) -> Option<Instant> {
+        self.deadlines.get(&piece_index).copied()
+    }
+
+    /// Whether the piece is past its deadline, so its blocks in flight are worth requesting from
+    /// more than one peer
+    pub fn is_overdue(&self, piece_index: usize, now: Instant) -> bool {
+        self.deadlines.get(&piece_index).is_some_and(|&deadline| deadline <= now)
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    /// No of connected peers that have the piece
+    pub fn availability(&self, piece_index: usize) -> usize {
+        self.availability.get(piece_index).copied().unwrap_or(0)
+    }
+
+    /// The wanted piece to download next, among the ones `has` says are available from where it's
+    /// downloaded from. It's the one with the earliest deadline, then in sequential mode the first
+    /// one of the sequential window, and otherwise the one of the highest priority that the fewest
+    /// peers of the swarm have. The lowest index breaks ties
+    pub fn next_piece(&self, has: impl Fn(usize) -> bool) -> Option<usize> {
+        self.next_deadline_piece(&has)
+            .or_else(|| self.next_sequential_piece(&has))
+            .or_else(|| self.next_rarest_piece(&has))
+    }
+
+    fn next_deadline_piece(&self, has: &impl Fn(usize) -> bool) -> Option<usize> {
+        self.deadlines
+            .iter()
+            .filter(|&(&piece_index, _)| self.is_wanted(piece_index) && has(piece_index))
+            .min_by_key(|&(&piece_index, &deadline)| (deadline, piece_index))
+            .map(|(&piece_index, _)| piece_index)
+    }
+
+    /// The window starts at the first piece we're missing, skipped pieces don't take part in it
+    fn next_sequential_piece(&self, has: &impl Fn(usize) -> bool) -> Option<usize> {
+        if !self.sequential {
+            return None;
+        }
+        (0..self.piece_count)
+            .filter(|&piece_index| !self.completed[piece_index] && self.priorities[piece_index] != FilePriority::Skip)
+            .take(SEQUENTIAL_WINDOW)
+            .find(|&piece_index| !self.requested[piece_index] && has(piece_index))
+    }
+
+    fn next_rarest_piece(&self, has: &impl Fn(usize) -> bool) -> Option<usize> {
+        self.wanted
+            .iter()
+            .flatten()
+            .flatten()
+            .copied()
+            .find(|&piece_index| has(piece_index))
+    }
+
+    fn is_wanted(&self, piece_index: usize) -> bool {
+        !self.completed[piece_index] && !self.requested[piece_index] && self.priorities[piece_index] != FilePriority::Skip
+    }
 
-        availability
+    /// The bucket of a wanted piece, see [PiecePicker::wanted]
+    fn bucket_of(&self, piece_index: usize) -> Option<(usize, usize)> {
+        let priority_index = PICKED_PRIORITIES
             .iter()
-            .enumerate()
-            .filter(|(piece_index, count)| **count > 0 && !self.completed[*piece_index] && !self.requested[*piece_index])
-            .min_by_key(|(piece_index, count)| (**count, *piece_index))
-            .map(|(piece_index, _)| piece_index)
+            .position(|&priority| priority == self.priorities[piece_index])?;
+        Some((priority_index, self.availability[piece_index]))
+    }
+
+    /// Changes the state of a piece, moving it to the bucket it belongs to afterwards
+    fn update(&mut self, piece_index: usize, change: impl FnOnce(&mut Self)) {
+        if let Some((priority_index, availability)) = self.bucket_of(piece_index) {
+            if let Some(bucket) = self.wanted[priority_index].get_mut(availability) {
+                bucket.remove(&piece_index);
+            }
+        }
+        change(self);
+        if !self.is_wanted(piece_index) {
+            return;
+        }
+        if let Some((priority_index, availability)) = self.bucket_of(piece_index) {
+            let buckets = &mut self.wanted[priority_index];
+            if buckets.len() <= availability {
+                buckets.resize_with(availability + 1, BTreeSet::new);
+            }
+            buckets[availability].insert(piece_index);
+        }
+    }
+
+    /// Whether every piece we're missing and not skipping has been requested already, i.e the
+    /// download is in its endgame and the last blocks are worth requesting from more than one peer
+    pub fn all_requested(&self) -> bool {
+        (0..self.piece_count).all(|piece_index| {
+            self.completed[piece_index] || self.requested[piece_index] || self.priorities[piece_index] == FilePriority::Skip
+        })
+    }
+
+    pub fn is_completed(&self, piece_index: usize) -> bool {
+        self.completed.get(piece_index).copied().unwrap_or(false)
+    }
+
+    /// Completion flag of every piece, in piece order
+    pub fn completed_pieces(&self) -> &[bool] {
+        &self.completed
+    }
+
+    /// Whether every piece has been verified, i.e we're seeding
+    pub fn all_completed(&self) -> bool {
+        self.completed.iter().all(|&completed| completed)
+    }
+
+    /// Whether every piece we're not skipping has been verified, i.e there's nothing left to
+    /// download and we're seeding what we have
+    pub fn all_wanted_completed(&self) -> bool {
+        (0..self.piece_count).all(|piece_index| self.completed[piece_index] || self.priorities[piece_index] == FilePriority::Skip)
     }
 
     pub fn completed_count(&self) -> usize {
@@ -65,42 +295,159 @@ impl PiecePicker {
 
 #[cfg(test)]
 mod tests {
-    use super::PiecePicker;
+    use super::{PiecePicker, SEQUENTIAL_WINDOW};
+    use crate::core::file_priorities::FilePriority;
+    use std::time::{Duration, Instant};
+
+    fn with_peers(piece_count: usize, peers: &[Vec<usize>]) -> PiecePicker {
+        let mut picker = PiecePicker::new(piece_count);
+        for pieces in peers {
+            picker.add_peer_pieces(pieces);
+        }
+        picker
+    }
 
     #[test]
-    fn chooses_rarest_available_piece() {
-        let picker = PiecePicker::new(5);
-        let peers = vec![vec![0, 1, 2], vec![0, 2], vec![0, 3]];
+    fn chooses_rarest_piece_across_the_swarm() {
+        let picker = with_peers(5, &[vec![0, 1, 2], vec![0, 2], vec![0, 3]]);
 
-        assert_eq!(picker.next_rarest_piece(&peers), Some(1));
+        assert_eq!(picker.next_piece(|piece| [0, 1, 2].contains(&piece)), Some(1));
+        // Piece 2 is rarer than piece 0 in the swarm, although the peer has both
+        assert_eq!(picker.next_piece(|piece| [0, 2].contains(&piece)), Some(2));
+        // Nobody has piece 4, it's the rarest of all for a source that has every piece
+        assert_eq!(picker.next_piece(|_| true), Some(4));
     }
 
     #[test]
     fn ignores_completed_and_requested_pieces() {
-        let mut picker = PiecePicker::new(5);
+        let mut picker = with_peers(5, &[vec![1, 2, 3], vec![3, 4]]);
         picker.mark_completed(1);
         picker.mark_requested(3);
-        let peers = vec![vec![1, 2, 3], vec![3, 4]];
 
-        assert_eq!(picker.next_rarest_piece(&peers), Some(2));
+        assert_eq!(picker.next_piece(|piece| piece > 0), Some(2));
     }
 
     #[test]
     fn failed_request_makes_piece_eligible_again() {
-        let mut picker = PiecePicker::new(3);
+        let mut picker = with_peers(3, &[vec![2]]);
         picker.mark_requested(2);
-        assert_eq!(picker.next_rarest_piece(&[vec![2]]), None);
+        assert_eq!(picker.next_piece(|piece| piece == 2), None);
 
         picker.mark_request_failed(2);
 
-        assert_eq!(picker.next_rarest_piece(&[vec![2]]), Some(2));
+        assert_eq!(picker.next_piece(|piece| piece == 2), Some(2));
+    }
+
+    #[test]
+    fn availability_follows_peers_coming_and_going() {
+        let mut picker = with_peers(2, &[vec![0, 1, 99], vec![1]]);
+        assert_eq!(picker.next_piece(|_| true), Some(0));
+
+        picker.add_peer_pieces(&[0]);
+        picker.add_peer_pieces(&[0]);
+        assert_eq!(picker.availability(0), 3);
+        assert_eq!(picker.next_piece(|_| true), Some(1));
+
+        picker.remove_peer_pieces(&[0]);
+        picker.remove_peer_pieces(&[0]);
+        assert_eq!(picker.next_piece(|_| true), Some(0));
+
+        picker.clear_availability();
+        assert_eq!(picker.availability(1), 0);
+        assert_eq!(picker.next_piece(|_| true), Some(0));
+    }
+
+    #[test]
+    fn higher_priority_pieces_come_first_and_skipped_ones_never() {
+        let mut picker = with_peers(4, &[vec![0, 1, 2, 3], vec![0, 1, 2]]);
+        picker.set_priorities(&[FilePriority::Skip, FilePriority::Low, FilePriority::Normal, FilePriority::Low]);
+
+        // Piece 2 is less rare than piece 3, but of a higher priority
+        assert_eq!(picker.next_piece(|_| true), Some(2));
+        picker.mark_requested(2);
+        assert_eq!(picker.next_piece(|_| true), Some(3));
+        picker.mark_requested(3);
+        assert_eq!(picker.next_piece(|_| true), Some(1));
+        picker.mark_requested(1);
+        assert_eq!(picker.next_piece(|_| true), None);
+        assert!(picker.all_requested());
+        for piece_index in 1..4 {
+            picker.mark_completed(piece_index);
+        }
+        assert!(picker.all_wanted_completed());
+        assert!(!picker.all_completed());
+
+        picker.set_priorities(&[FilePriority::High; 4]);
+        assert_eq!(picker.priority(0), FilePriority::High);
+        assert_eq!(picker.next_piece(|_| true), Some(0));
+    }
+
+    #[test]
+    fn all_requested_once_every_missing_piece_is_requested() {
+        let mut picker = PiecePicker::new(3);
+        picker.mark_completed(0);
+        picker.mark_requested(1);
+        assert!(!picker.all_requested());
+
+        picker.mark_requested(2);
+        assert!(picker.all_requested());
+
+        picker.mark_request_failed(1);
+        assert!(!picker.all_requested());
+    }
+
+    #[test]
+    fn pieces_with_a_deadline_come_first_earliest_first() {
+        let mut picker = with_peers(6, &[vec![0, 1, 2, 3, 4, 5], vec![1, 2, 3, 4, 5]]);
+        let now = Instant::now();
+        picker.set_deadline(5, now + Duration::from_secs(2));
+        picker.set_deadline(3, now + Duration::from_secs(1));
+
+        assert_eq!(picker.next_piece(|_| true), Some(3));
+        picker.mark_requested(3);
+        assert_eq!(picker.next_piece(|_| true), Some(5));
+        // A peer without the pieces that have a deadline still gets the rarest piece it has
+        assert_eq!(picker.next_piece(|piece| piece < 3), Some(0));
+
+        assert!(!picker.is_overdue(3, now));
+        assert!(picker.is_overdue(3, now + Duration::from_secs(1)));
+        picker.mark_completed(3);
+        assert_eq!(picker.deadline(3), None);
+
+        // Only the deadline that was set is removed, not one that replaced it
+        picker.remove_deadline(5, now);
+        assert_eq!(picker.deadline(5), Some(now + Duration::from_secs(2)));
+        picker.remove_deadline(5, now + Duration::from_secs(2));
+        assert_eq!(picker.deadline(5), None);
+        picker.set_deadline(5, now);
+
+        picker.clear_deadlines();
+        assert_eq!(picker.next_piece(|_| true), Some(0));
     }
 
     #[test]
-    fn ignores_duplicate_and_out_of_range_peer_entries() {
-        let picker = PiecePicker::new(3);
-        let peers = vec![vec![0, 0, 99], vec![1, 2], vec![1]];
+    fn sequential_mode_downloads_the_window_in_order_and_the_rest_rarest_first() {
+        let piece_count = SEQUENTIAL_WINDOW + 2;
+        let rarest = piece_count - 1;
+        let mut picker = with_peers(piece_count, &[(0..piece_count).collect(), (0..rarest).collect()]);
+        picker.set_sequential(true);
+        picker.mark_completed(0);
+        picker.set_priorities(
+            &(0..piece_count)
+                .map(|piece| if piece == 1 { FilePriority::Skip } else { FilePriority::Normal })
+                .collect::<Vec<_>>(),
+        );
 
-        assert_eq!(picker.next_rarest_piece(&peers), Some(0));
+        // The window starts at piece 2, the first one that's missing and not skipped
+        assert_eq!(picker.next_piece(|_| true), Some(2));
+        for piece in 2..rarest {
+            picker.mark_requested(piece);
+        }
+        // Once the window is requested, the pieces after it are picked rarest first
+        assert_eq!(picker.next_piece(|_| true), Some(rarest));
+
+        picker.set_sequential(false);
+        picker.mark_request_failed(2);
+        assert_eq!(picker.next_piece(|_| true), Some(rarest));
     }
 }
@@ -1,13 +1,16 @@
-use super::state::State;
+use super::{file_priorities::FilePriority, state::State};
 use std::{
+    io::ErrorKind,
+    ops::Range,
     path::{Component, Path, PathBuf},
     sync::Arc,
 };
 use thiserror::Error;
 use tokio::{
-    fs::{create_dir_all, OpenOptions},
-    io::{AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    fs::{create_dir_all, remove_dir, remove_file, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
 };
+use tracing::{debug, info};
 
 #[derive(Debug, Error)]
 pub enum PieceStorageError {
@@ -20,49 +23,246 @@ pub enum PieceStorageError {
     #[error("piece data was longer than mapped files")]
     PieceDataTooLong,
 
+    #[error("block of {length} bytes at {begin} is outside piece {piece_index}")]
+    BlockOutOfRange { piece_index: usize, begin: usize, length: usize },
+
     #[error("file storage error")]
     Io(#[from] std::io::Error),
 }
 
+/// The part of one of the torrent's files that a byte range of a piece falls into, named the way
+/// the torrent names it rather than by where it's written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TorrentFileRange {
+    /// Path of the file inside the torrent, the first component is the name of the torrent
+    pub path: Vec<String>,
+
+    /// Where the range starts in the file
+    pub offset: usize,
+
+    pub length: usize,
+}
+
+/// One of the torrent's files, where it lies among the bytes of the whole torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TorrentFileSpan {
+    /// Index of the file among the files of the torrent
+    pub index: usize,
+
+    /// Where the file starts in the torrent
+    pub offset: usize,
+
+    pub length: usize,
+}
+
 pub struct PieceStorage;
 
 impl PieceStorage {
+    /// Writes a piece into the files it's mapped onto
+    ///
+    /// The parts of a piece that fall into skipped files are dropped rather than written, unless
+    /// the file is on the disk already, so a skipped file never gets allocated
     pub async fn write_piece(state: &Arc<State>, piece_index: usize, piece: &[u8]) -> Result<(), PieceStorageError> {
-        let piece_length = state.piece_length().ok_or(PieceStorageError::MissingPieceLength)?;
-        let piece_offset = piece_index.saturating_mul(piece_length);
+        let piece_offset = Self::piece_offset(state, piece_index)?;
         let files = TorrentOutputFiles::from_state(state);
-        let mut remaining = piece;
-        let mut cursor = piece_offset;
+        let file_priorities = state.file_priorities.lock().await.clone();
+        let slices = TorrentOutputFiles::slices(&files, piece_offset, piece.len());
+        let mapped = slices.iter().map(|slice| slice.length).sum::<usize>();
+        if mapped == 0 && !piece.is_empty() {
+            return Err(PieceStorageError::PieceOutOfRange { piece_index });
+        }
+        if mapped < piece.len() {
+            return Err(PieceStorageError::PieceDataTooLong);
+        }
 
-        for file in files {
-            if remaining.is_empty() {
-                break;
-            }
-            if cursor >= file.end_offset() {
-                continue;
-            }
-            if cursor < file.start_offset {
-                break;
+        let mut remaining = piece;
+        for slice in slices {
+            let skipped = file_priorities.get(slice.file.index) == Some(&FilePriority::Skip);
+            if skipped && !slice.file.path.is_file() {
+                debug!(piece_index, path = %slice.file.path.display(), "dropping the part of the piece in a skipped file");
+            } else {
+                FileSliceWriter::write(&slice.file.path, slice.offset as u64, &remaining[..slice.length]).await?;
             }
+            remaining = &remaining[slice.length..];
+        }
+        Ok(())
+    }
 
-            let offset_in_file = cursor - file.start_offset;
-            let writable = remaining.len().min(file.length.saturating_sub(offset_in_file));
-            if writable == 0 {
-                continue;
-            }
+    /// Writes a verified piece onto the disk and counts it as downloaded, the trackers are told
+    /// once it was the last piece missing. It's the same for pieces of peers and of web seeds
+    pub(crate) async fn complete_piece(state: &Arc<State>, piece_index: usize, piece: &[u8]) -> Result<(), PieceStorageError> {
+        if let Err(error) = Self::write_piece(state, piece_index, piece).await {
+            state.piece_picker.lock().await.mark_request_failed(piece_index);
+            return Err(error);
+        }
+        state.set_bytes_complete(state.bytes_complete().saturating_add(piece.len()));
+        state.set_session_downloaded(state.session_downloaded().saturating_add(piece.len()));
+        state.set_pieces_downloaded(state.pieces_downloaded().saturating_add(1));
+        let download_completed = {
+            let mut piece_picker = state.piece_picker.lock().await;
+            let was_completed = piece_picker.all_completed();
+            piece_picker.mark_completed(piece_index);
+            !was_completed && piece_picker.all_completed()
+        };
+        state.piece_completed.notify_waiters();
+        if download_completed {
+            info!("all pieces downloaded");
+            state.announce_completed().await;
+        }
+        Ok(())
+    }
 
-            FileSliceWriter::write(&file.path, offset_in_file as u64, &remaining[..writable]).await?;
-            remaining = &remaining[writable..];
-            cursor = cursor.saturating_add(writable);
+    /// Maps a piece onto the ranges of the torrent's files it covers, in order, it's used to
+    /// download the piece from web seeds that serve the files rather than pieces
+    pub(crate) fn file_ranges(state: &State, piece_index: usize) -> Result<Vec<TorrentFileRange>, PieceStorageError> {
+        let piece_length = state
+            .piece_length_at(piece_index)
+            .ok_or(PieceStorageError::PieceOutOfRange { piece_index })?;
+        let piece_offset = Self::piece_offset(state, piece_index)?;
+        let files = TorrentOutputFiles::from_state(state);
+        let ranges = TorrentOutputFiles::slices(&files, piece_offset, piece_length)
+            .into_iter()
+            .map(|slice| TorrentFileRange {
+                path: slice.file.torrent_path.clone(),
+                offset: slice.offset,
+                length: slice.length,
+            })
+            .collect::<Vec<_>>();
+        if ranges.iter().map(|range| range.length).sum::<usize>() < piece_length {
+            return Err(PieceStorageError::PieceOutOfRange { piece_index });
         }
+        Ok(ranges)
+    }
 
-        if remaining.is_empty() {
-            Ok(())
-        } else if cursor == piece_offset {
-            Err(PieceStorageError::PieceOutOfRange { piece_index })
-        } else {
-            Err(PieceStorageError::PieceDataTooLong)
+    /// Reads a block of an already written piece back from the files it's mapped onto, it's used
+    /// to answer the Request messages of the peers
+    pub async fn read_block(state: &Arc<State>, piece_index: usize, begin: usize, length: usize) -> Result<Vec<u8>, PieceStorageError> {
+        let piece_length = state
+            .piece_length_at(piece_index)
+            .ok_or(PieceStorageError::PieceOutOfRange { piece_index })?;
+        if begin.saturating_add(length) > piece_length {
+            return Err(PieceStorageError::BlockOutOfRange {
+                piece_index,
+                begin,
+                length,
+            });
+        }
+
+        let block_offset = Self::piece_offset(state, piece_index)?.saturating_add(begin);
+        let files = TorrentOutputFiles::from_state(state);
+        let slices = TorrentOutputFiles::slices(&files, block_offset, length);
+        if slices.iter().map(|slice| slice.length).sum::<usize>() < length {
+            return Err(PieceStorageError::BlockOutOfRange {
+                piece_index,
+                begin,
+                length,
+            });
+        }
+
+        let mut block = Vec::with_capacity(length);
+        for slice in slices {
+            FileSliceReader::read(&slice.file.path, slice.offset as u64, slice.length, &mut block).await?;
+        }
+        Ok(block)
+    }
+
+    /// Reads a whole piece back from the files, it's used to recheck the data already on the disk
+    pub async fn read_piece(state: &Arc<State>, piece_index: usize) -> Result<Vec<u8>, PieceStorageError> {
+        let piece_length = state
+            .piece_length_at(piece_index)
+            .ok_or(PieceStorageError::PieceOutOfRange { piece_index })?;
+        Self::read_block(state, piece_index, 0, piece_length).await
+    }
+
+    /// The pieces each file of the torrent covers, in the order the torrent lists the files. Empty
+    /// files cover none
+    pub(crate) fn file_pieces(state: &State) -> Vec<Range<usize>> {
+        let piece_length = state.piece_length().unwrap_or_default().max(1);
+        TorrentOutputFiles::from_state(state)
+            .iter()
+            .map(|file| match file.length {
+                0 => 0..0,
+                _ => file.start_offset / piece_length..(file.end_offset() - 1) / piece_length + 1,
+            })
+            .collect()
+    }
+
+    /// Finds a file by its path inside the torrent, without the name of the torrent in front of the
+    /// path of a multi file torrent. A single file torrent's only file is found by its name
+    pub(crate) fn file_span(state: &State, path: &[String]) -> Option<TorrentFileSpan> {
+        let has_root = state.meta_info.info.files.is_some();
+        TorrentOutputFiles::from_state(state)
+            .iter()
+            .find(|file| &file.torrent_path[usize::from(has_root)..] == path)
+            .map(|file| TorrentFileSpan {
+                index: file.index,
+                offset: file.start_offset,
+                length: file.length,
+            })
+    }
+
+    /// The pieces of the skipped files that were never allocated. The parts of such pieces in those
+    /// files were dropped, so the ones that are completed can't be read back from the disk and
+    /// aren't announced or served to peers, until the file is no longer skipped and they're
+    /// downloaded again
+    pub(crate) async fn dropped_pieces(state: &State) -> Vec<Range<usize>> {
+        let file_priorities = state.file_priorities.lock().await.clone();
+        let file_pieces = Self::file_pieces(state);
+        file_priorities
+            .iter()
+            .enumerate()
+            .filter(|&(file_index, &priority)| priority == FilePriority::Skip && !Self::file_exists(state, file_index))
+            .filter_map(|(file_index, _)| file_pieces.get(file_index).cloned())
+            .collect()
+    }
+
+    /// Whether a completed piece can be read back from the disk, see [PieceStorage::dropped_pieces]
+    pub(crate) async fn is_readable(state: &State, piece_index: usize) -> bool {
+        !Self::dropped_pieces(state).await.iter().any(|pieces| pieces.contains(&piece_index))
+    }
+
+    /// Whether the file at the index, among the files of the torrent, is on the disk
+    pub(crate) fn file_exists(state: &State, file_index: usize) -> bool {
+        TorrentOutputFiles::from_state(state)
+            .get(file_index)
+            .is_some_and(|file| file.path.is_file())
+    }
+
+    /// Whether any of the files the torrent gets written into already exists
+    pub fn has_existing_files(state: &State) -> bool {
+        TorrentOutputFiles::from_state(state).iter().any(|file| file.path.is_file())
+    }
+
+    /// Deletes the files the torrent was written into, and the directories of a multi file torrent
+    /// that are left empty by that. Files that were never written are skipped
+    pub async fn remove_files(state: &State) -> Result<(), PieceStorageError> {
+        let files = TorrentOutputFiles::from_state(state);
+        for file in &files {
+            match remove_file(&file.path).await {
+                Ok(()) => {}
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        // Only the directories inside the torrent's root directory are removed, never the download
+        // directory itself, and only while they're empty
+        for file in &files {
+            let mut directory = file.path.parent();
+            while let Some(path) = directory.filter(|path| path.starts_with(&state.download_directory) && *path != state.download_directory)
+            {
+                if remove_dir(path).await.is_err() {
+                    break;
+                }
+                directory = path.parent();
+            }
         }
+        Ok(())
+    }
+
+    fn piece_offset(state: &State, piece_index: usize) -> Result<usize, PieceStorageError> {
+        let piece_length = state.piece_length().ok_or(PieceStorageError::MissingPieceLength)?;
+        Ok(piece_index.saturating_mul(piece_length))
     }
 }
 
@@ -82,7 +282,9 @@ impl TorrentOutputFiles {
                     .fold(root_path.clone(), |path, component| SafePath::join(path, component));
                 let length = file.length.max(0) as usize;
                 files.push(OutputFile {
+                    index: files.len(),
                     path,
+                    torrent_path: std::iter::once(root_name.to_string()).chain(file.path.iter().cloned()).collect(),
                     start_offset,
                     length,
                 });
@@ -90,17 +292,67 @@ impl TorrentOutputFiles {
             }
         } else {
             files.push(OutputFile {
+                index: 0,
                 path: SafePath::join(state.download_directory.clone(), root_name),
+                torrent_path: vec![root_name.to_string()],
                 start_offset: 0,
                 length: state.meta_info.total_length().max(0) as usize,
             });
         }
         files
     }
+
+    /// Maps a byte range of the whole torrent onto the parts of the files it covers, in order
+    ///
+    /// The range gets cut short where the files end, so the caller can find out if the entire
+    /// range was mapped by summing up the slice lengths
+    fn slices(files: &[OutputFile], offset: usize, length: usize) -> Vec<FileSlice<'_>> {
+        let mut slices = Vec::new();
+        let end = offset.saturating_add(length);
+        let mut cursor = offset;
+        for file in files {
+            if cursor >= end {
+                break;
+            }
+            if cursor >= file.end_offset() {
+                continue;
+            }
+            if cursor < file.start_offset {
+                break;
+            }
+
+            let offset_in_file = cursor - file.start_offset;
+            let slice_length = (end - cursor).min(file.length - offset_in_file);
+            if slice_length == 0 {
+                continue;
+            }
+            slices.push(FileSlice {
+                file,
+                offset: offset_in_file,
+                length: slice_length,
+            });
+            cursor = cursor.saturating_add(slice_length);
+        }
+        slices
+    }
+}
+
+/// A part of a single output file that a byte range of the torrent falls into
+struct FileSlice<'a> {
+    file: &'a OutputFile,
+    offset: usize,
+    length: usize,
 }
 
 struct OutputFile {
+    /// Index of the file among the files of the torrent
+    index: usize,
+
     path: PathBuf,
+
+    /// The path the torrent gives the file, see [TorrentFileRange::path]
+    torrent_path: Vec<String>,
+
     start_offset: usize,
     length: usize,
 }
@@ -126,6 +378,19 @@ impl FileSliceWriter {
     }
 }
 
+struct FileSliceReader;
+
+impl FileSliceReader {
+    async fn read(path: &Path, offset: u64, length: usize, block: &mut Vec<u8>) -> Result<(), PieceStorageError> {
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let start = block.len();
+        block.resize(start + length, 0);
+        file.read_exact(&mut block[start..]).await?;
+        Ok(())
+    }
+}
+
 struct SafePath;
 
 impl SafePath {
@@ -141,20 +406,19 @@ impl SafePath {
 
 #[cfg(test)]
 mod tests {
-    use super::PieceStorage;
+    use super::{PieceStorage, PieceStorageError};
     use crate::core::{
-        piece_picker::PiecePicker,
-        state::{DownState, State},
+        file_priorities::{FilePriorities, FilePriority},
+        state::State,
+        test_support::{temp_dir, TestState},
     };
-    use crossbeam::atomic::AtomicCell;
-    use hyperblow::parser::torrent_parser::{File, FileMeta, Info};
-    use sha1::{Digest, Sha1};
+    use hyperblow::parser::torrent_parser::File;
     use std::{fs, path::PathBuf, sync::Arc};
-    use tokio::sync::{Mutex, RwLock};
+    use tokio::sync::Mutex;
 
     #[tokio::test]
     async fn writes_single_file_piece_to_download_directory() {
-        let output_dir = TestOutput::temp_dir();
+        let output_dir = temp_dir("piece-storage");
         let piece = b"hello".to_vec();
         let state = TestOutput::state(output_dir.clone(), piece.clone());
 
@@ -166,7 +430,7 @@ mod tests {
 
     #[tokio::test]
     async fn writes_multi_file_piece_without_trusting_metadata_paths() {
-        let output_dir = TestOutput::temp_dir();
+        let output_dir = temp_dir("piece-storage");
         let piece = b"safe".to_vec();
         let unique = TestOutput::unique_name();
         let escaped_root = format!("../hyperblow-piece-storage-escaped-root-{unique}");
@@ -198,53 +462,128 @@ mod tests {
         fs::remove_dir_all(output_dir).expect("output dir should remove");
     }
 
+    #[tokio::test]
+    async fn reads_block_back_across_file_boundary() {
+        let output_dir = temp_dir("piece-storage");
+        let piece = b"hello world".to_vec();
+        let mut state = TestOutput::state(output_dir.clone(), piece.clone());
+        let inner = Arc::get_mut(&mut state).expect("state should be uniquely owned");
+        inner.meta_info.info.name = Some("multi".to_string());
+        inner.meta_info.info.length = None;
+        inner.meta_info.info.files = Some(vec![
+            File {
+                length: 4,
+                path: vec!["first.bin".to_string()],
+                md5sum: None,
+            },
+            File {
+                length: 7,
+                path: vec!["second.bin".to_string()],
+                md5sum: None,
+            },
+        ]);
+
+        PieceStorage::write_piece(&state, 0, &piece).await.expect("piece should write");
+
+        assert_eq!(
+            PieceStorage::read_block(&state, 0, 2, 5).await.expect("block should read"),
+            b"llo w"
+        );
+        assert!(matches!(
+            PieceStorage::read_block(&state, 0, 8, 4).await,
+            Err(PieceStorageError::BlockOutOfRange { .. })
+        ));
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn never_allocates_skipped_files() {
+        let output_dir = temp_dir("piece-storage");
+        let piece = b"hello world".to_vec();
+        let mut state = TestOutput::state(output_dir.clone(), piece.clone());
+        let inner = Arc::get_mut(&mut state).expect("state should be uniquely owned");
+        inner.meta_info.info.name = Some("multi".to_string());
+        inner.meta_info.info.length = None;
+        inner.meta_info.info.files = Some(vec![
+            File {
+                length: 4,
+                path: vec!["first.bin".to_string()],
+                md5sum: None,
+            },
+            File {
+                length: 7,
+                path: vec!["second.bin".to_string()],
+                md5sum: None,
+            },
+        ]);
+        inner.file_priorities = Arc::new(Mutex::new(vec![FilePriority::Normal; 2]));
+        assert_eq!(PieceStorage::file_pieces(&state), vec![0..1, 0..1]);
+        FilePriorities::set(&state, &[0], FilePriority::Skip).await;
+
+        PieceStorage::complete_piece(&state, 0, &piece)
+            .await
+            .expect("piece should complete");
+
+        assert!(!output_dir.join("multi").join("first.bin").exists());
+        assert_eq!(
+            fs::read(output_dir.join("multi").join("second.bin")).expect("output should exist"),
+            b"o world"
+        );
+        // The piece is complete for the file that wants it, but can't be given to peers
+        assert!(state.piece_picker.lock().await.is_completed(0));
+        assert!(!PieceStorage::is_readable(&state, 0).await);
+
+        // The piece is missing the part of the file that's no longer skipped
+        FilePriorities::set(&state, &[0], FilePriority::High).await;
+        assert!(!state.piece_picker.lock().await.is_completed(0));
+        assert_eq!(state.piece_picker.lock().await.priority(0), FilePriority::High);
+        assert_eq!(state.bytes_complete(), 0);
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn removes_written_files_and_their_empty_directories() {
+        let output_dir = temp_dir("piece-storage");
+        let piece = b"hello world".to_vec();
+        let mut state = TestOutput::state(output_dir.clone(), piece.clone());
+        let inner = Arc::get_mut(&mut state).expect("state should be uniquely owned");
+        inner.meta_info.info.name = Some("multi".to_string());
+        inner.meta_info.info.length = None;
+        inner.meta_info.info.files = Some(vec![
+            File {
+                length: 4,
+                path: vec!["nested".to_string(), "first.bin".to_string()],
+                md5sum: None,
+            },
+            File {
+                length: 7,
+                path: vec!["second.bin".to_string()],
+                md5sum: None,
+            },
+        ]);
+        PieceStorage::write_piece(&state, 0, &piece).await.expect("piece should write");
+        fs::write(output_dir.join("unrelated.bin"), b"keep").expect("unrelated file should write");
+
+        PieceStorage::remove_files(&state).await.expect("files should remove");
+
+        assert!(!output_dir.join("multi").exists());
+        assert!(output_dir.join("unrelated.bin").is_file());
+        PieceStorage::remove_files(&state).await.expect("removing again should be a no-op");
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
     struct TestOutput;
 
     impl TestOutput {
-        fn temp_dir() -> PathBuf {
-            let path = std::env::temp_dir().join(format!("hyperblow-piece-storage-{}", Self::unique_name()));
-            let _ = fs::remove_dir_all(&path);
-            fs::create_dir_all(&path).expect("temp dir should create");
-            path
-        }
-
         fn unique_name() -> String {
             format!("{}-{:?}", std::process::id(), std::thread::current().id())
         }
 
         fn state(download_directory: PathBuf, piece: Vec<u8>) -> Arc<State> {
-            let hash: [u8; 20] = Sha1::digest(&piece).into();
-            Arc::new(State {
-                meta_info: FileMeta {
-                    announce: "udp://tracker.example.test:6969".to_string(),
-                    announce_list: None,
-                    info: Info {
-                        name: Some("piece-test.bin".to_string()),
-                        length: Some(piece.len() as i64),
-                        files: None,
-                        piece_length: Some(piece.len() as i64),
-                        pieces: hash.to_vec(),
-                    },
-                    creation_data: None,
-                    comment: None,
-                    encoding: None,
-                    created_by: None,
-                    acceptable_source: None,
-                },
-                download_directory,
-                d_state: DownState::Unknown,
-                file_tree: None,
-                trackers: Arc::new(RwLock::new(Vec::new())),
-                udp_ports: Arc::new(Mutex::new(Vec::new())),
-                tcp_ports: Arc::new(Mutex::new(Vec::new())),
-                info_hash: vec![1; 20],
-                pieces_hash: vec![hash],
-                piece_picker: Arc::new(Mutex::new(PiecePicker::new(1))),
-                peers: Arc::new(Mutex::new(Vec::new())),
-                uptime: AtomicCell::new(0),
-                bytes_complete: AtomicCell::new(0),
-                pieces_downloaded: AtomicCell::new(0),
-            })
+            TestState::new("piece-test.bin")
+                .data(&piece, piece.len())
+                .download_directory(download_directory)
+                .build()
         }
     }
 }
@@ -1,4 +1,5 @@
 pub const PROTOCOL_IDENTIFIER: &[u8; 19] = b"BitTorrent protocol";
 pub const PROTOCOL_IDENTIFIER_LEN: u8 = 19;
-pub const RESERVED_BYTES: [u8; 8] = [0; 8];
+/// Reserved bytes of our handshake, the last bit tells peers we run a DHT node (BEP 5)
+pub const RESERVED_BYTES: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0x01];
 pub const PEER_ID: [u8; 20] = *b"-HBYxxx-QMAXYDGHQAHF";
@@ -0,0 +1,415 @@
+use super::{
+    file_priorities::{FilePriorities, FilePriority},
+    peer::Bitfield,
+    state::State,
+    File,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio::{fs, sync::Mutex};
+use tracing::{debug, warn};
+
+/// Version of the resume file format, it's bumped whenever a field changes its meaning so an older
+/// resume file is ignored rather than misread
+pub const RESUME_VERSION: i64 = 2;
+
+/// Directory inside of the download directory that keeps the resume files and the DHT node table
+pub(crate) const STATE_DIRECTORY: &str = ".hyperblow";
+
+const RESUME_EXTENSION: &str = "resume";
+
+#[derive(Debug, Error)]
+pub enum ResumeError {
+    #[error("resume file could not be read or written")]
+    Io(#[from] io::Error),
+
+    #[error("resume file bencode could not be encoded or decoded")]
+    Bencode(#[from] serde_bencode::Error),
+
+    #[error("unsupported resume file version {0}")]
+    UnsupportedVersion(i64),
+}
+
+/// Everything needed to continue a torrent after a restart without rechecking its data, much
+/// like libtorrent's fastresume
+///
+/// It's stored bencoded as "<download directory>/.hyperblow/<hex info hash>.resume", one file per
+/// torrent. Integers are i64 and flags are 0 or 1, as bencode has no other number or boolean type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub version: i64,
+
+    #[serde(rename = "info-hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+
+    /// How the torrent was added, either "file" or "magnet"
+    #[serde(rename = "source-kind")]
+    pub source_kind: String,
+
+    /// The ".torrent" file path or the magnet URI the torrent was added with
+    pub source: String,
+
+    #[serde(rename = "piece-count")]
+    pub piece_count: i64,
+
+    /// Verified pieces, packed most significant bit first like the payload of a Bitfield message
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+
+    #[serde(rename = "bytes-complete")]
+    pub bytes_complete: i64,
+
+    pub uploaded: i64,
+
+    /// Priority of each file of the torrent
+    pub files: Vec<ResumeFile>,
+
+    /// URLs of the trackers by tier, in the order they were last tried
+    pub trackers: Vec<Vec<String>>,
+
+    /// 1 when the pieces are downloaded in order, see [PiecePicker::set_sequential]
+    ///
+    /// [PiecePicker::set_sequential]: super::piece_picker::PiecePicker::set_sequential
+    #[serde(default)]
+    pub sequential: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeFile {
+    /// Names from the root of the file tree down to the file
+    pub path: Vec<String>,
+
+    /// 0 to skip the file, 1, 2 or 3 to download it with a low, normal or high priority
+    pub priority: i64,
+}
+
+impl ResumeData {
+    /// Takes a snapshot of the progress of the torrent
+    pub async fn capture(state: &State, source_kind: &str, source: &str) -> Self {
+        let (completed, sequential) = {
+            let piece_picker = state.piece_picker.lock().await;
+            (piece_picker.completed_pieces().to_vec(), piece_picker.is_sequential())
+        };
+        let files = match state.file_tree {
+            Some(ref file_tree) => FileSelections::collect(file_tree, &state.file_priorities.lock().await.clone()).await,
+            None => Vec::new(),
+        };
+        let trackers = state
+            .trackers
+            .read()
+            .await
+            .iter()
+            .map(|tier| tier.iter().map(|tracker| tracker.address.to_string()).collect())
+            .collect();
+
+        Self {
+            version: RESUME_VERSION,
+            info_hash: state.info_hash.clone(),
+            source_kind: source_kind.to_string(),
+            source: source.to_string(),
+            piece_count: completed.len() as i64,
+            pieces: Bitfield::from_pieces(&completed).to_bytes(),
+            bytes_complete: state.bytes_complete() as i64,
+            uploaded: state.uploaded() as i64,
+            files,
+            trackers,
+            sequential: i64::from(sequential),
+        }
+    }
+
+    /// Restores the progress into the state of a torrent that hasn't started yet, it gives false
+    /// when the resume data belongs to another torrent or was taken before the metadata was known
+    pub async fn apply(&self, state: &State) -> bool {
+        if self.info_hash != state.info_hash || self.piece_count <= 0 || self.piece_count as usize != state.pieces_hash.len() {
+            return false;
+        }
+
+        let completed = self.completed_pieces();
+        {
+            let mut piece_picker = state.piece_picker.lock().await;
+            for piece_index in &completed {
+                piece_picker.mark_completed(*piece_index);
+            }
+            piece_picker.set_sequential(self.sequential != 0);
+        }
+        state.set_pieces_downloaded(completed.len());
+        state.set_bytes_complete(self.bytes_complete.max(0) as usize);
+        state.set_uploaded(self.uploaded.max(0) as usize);
+        if let Some(ref file_tree) = state.file_tree {
+            let priorities = FileSelections::restore(file_tree, &self.files, state.file_priorities.lock().await.clone()).await;
+            FilePriorities::restore(state, &priorities).await;
+        }
+        true
+    }
+
+    /// Indexes of the verified pieces
+    pub fn completed_pieces(&self) -> Vec<usize> {
+        (0..self.piece_count.max(0) as usize)
+            .filter(|piece_index| {
+                self.pieces
+                    .get(piece_index / 8)
+                    .is_some_and(|byte| byte & (1 << (7 - piece_index % 8)) != 0)
+            })
+            .collect()
+    }
+
+    /// Puts the trackers of each tier in the order they were saved in, the tiers themselves and
+    /// trackers that weren't saved stay as the torrent lists them
+    pub fn order_trackers(&self, tiers: Vec<Vec<String>>) -> Vec<Vec<String>> {
+        let saved = self.trackers.iter().flatten().collect::<Vec<_>>();
+        tiers
+            .into_iter()
+            .map(|mut tier| {
+                tier.sort_by_key(|url| saved.iter().position(|saved| *saved == url).unwrap_or(usize::MAX));
+                tier
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ResumeError> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ResumeError> {
+        let resume = serde_bencode::from_bytes::<Self>(bytes)?;
+        if resume.version != RESUME_VERSION {
+            return Err(ResumeError::UnsupportedVersion(resume.version));
+        }
+        Ok(resume)
+    }
+
+    /// Path of the resume file of the torrent with the given info hash
+    pub fn path(download_directory: &Path, info_hash: &[u8]) -> PathBuf {
+        let file_name = info_hash.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+        download_directory
+            .join(STATE_DIRECTORY)
+            .join(file_name)
+            .with_extension(RESUME_EXTENSION)
+    }
+
+    /// Writes the resume file, through a temporary file so a crash halfway never leaves a broken one
+    pub async fn save(&self, download_directory: &Path) -> Result<(), ResumeError> {
+        let path = Self::path(download_directory, &self.info_hash);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let temporary_path = path.with_extension("resume.tmp");
+        fs::write(&temporary_path, self.to_bytes()?).await?;
+        fs::rename(&temporary_path, &path).await?;
+        debug!(path = %path.display(), "saved resume data");
+        Ok(())
+    }
+
+    /// Reads the resume file of a torrent, it's None when the torrent was never saved
+    pub async fn load(download_directory: &Path, info_hash: &[u8]) -> Result<Option<Self>, ResumeError> {
+        match fs::read(Self::path(download_directory, info_hash)).await {
+            Ok(bytes) => Ok(Some(Self::from_bytes(&bytes)?)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Deletes the resume file of a torrent, so it isn't started again with the next session
+    pub async fn remove(download_directory: &Path, info_hash: &[u8]) -> Result<(), ResumeError> {
+        match fs::remove_file(Self::path(download_directory, info_hash)).await {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Reads every resume file of the download directory, skipping the ones that can't be read
+    pub async fn load_all(download_directory: &Path) -> Vec<Self> {
+        let Ok(mut entries) = fs::read_dir(download_directory.join(STATE_DIRECTORY)).await else {
+            return Vec::new();
+        };
+
+        let mut resumes = Vec::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != RESUME_EXTENSION) {
+                continue;
+            }
+            match fs::read(&path)
+                .await
+                .map_err(ResumeError::from)
+                .and_then(|bytes| Self::from_bytes(&bytes))
+            {
+                Ok(resume) => resumes.push(resume),
+                Err(error) => warn!(path = %path.display(), error = %error, "skipping unreadable resume file"),
+            }
+        }
+        resumes
+    }
+}
+
+/// The priorities in the order they're saved in, a priority is saved as its index
+const SAVED_PRIORITIES: [FilePriority; 4] = [FilePriority::Skip, FilePriority::Low, FilePriority::Normal, FilePriority::High];
+
+/// Walks the file tree to save and restore the priorities of the files by their path, so they
+/// don't depend on the order of the files
+struct FileSelections;
+
+impl FileSelections {
+    async fn collect(root: &Arc<Mutex<File>>, priorities: &[FilePriority]) -> Vec<ResumeFile> {
+        let mut files = Vec::new();
+        let mut pending = vec![(root.clone(), Vec::new())];
+        while let Some((file, mut path)) = pending.pop() {
+            let file = file.lock().await;
+            path.push(file.name.clone());
+            match file.inner_files {
+                Some(ref inner_files) => pending.extend(inner_files.iter().rev().map(|inner| (inner.clone(), path.clone()))),
+                None => {
+                    let priority = file.index.and_then(|index| priorities.get(index)).copied().unwrap_or_default();
+                    files.push(ResumeFile {
+                        path,
+                        priority: SAVED_PRIORITIES.iter().position(|&saved| saved == priority).unwrap_or_default() as i64,
+                    });
+                }
+            }
+        }
+        files
+    }
+
+    /// Gives back the priorities with the ones of the saved files in place
+    async fn restore(root: &Arc<Mutex<File>>, files: &[ResumeFile], mut priorities: Vec<FilePriority>) -> Vec<FilePriority> {
+        let saved = files
+            .iter()
+            .filter_map(|file| Some((file.path.clone(), *SAVED_PRIORITIES.get(usize::try_from(file.priority).ok()?)?)))
+            .collect::<HashMap<_, _>>();
+        let mut pending = vec![(root.clone(), Vec::new())];
+        while let Some((file, mut path)) = pending.pop() {
+            let file = file.lock().await;
+            path.push(file.name.clone());
+            match file.inner_files {
+                Some(ref inner_files) => pending.extend(inner_files.iter().map(|inner| (inner.clone(), path.clone()))),
+                None => {
+                    let priority = file.index.and_then(|index| priorities.get_mut(index));
+                    if let (Some(priority), Some(&saved)) = (priority, saved.get(&path)) {
+                        *priority = saved;
+                    }
+                }
+            }
+        }
+        priorities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResumeData, ResumeError, RESUME_VERSION};
+    use crate::core::{
+        file_priorities::{FilePriorities, FilePriority},
+        state::State,
+        test_support::{temp_dir, TestState},
+    };
+    use std::{path::PathBuf, sync::Arc};
+
+    #[tokio::test]
+    async fn restores_progress_saved_in_resume_file() {
+        let download_directory = temp_dir("resume-restore");
+        let saved = state(download_directory.clone()).await;
+        {
+            let mut piece_picker = saved.piece_picker.lock().await;
+            piece_picker.mark_completed(1);
+            piece_picker.set_sequential(true);
+        }
+        saved.set_bytes_complete(4);
+        saved.set_uploaded(12);
+        deselect_second_file(&saved).await;
+        let resume = ResumeData::capture(&saved, "file", "/torrents/resume.torrent").await;
+        resume.save(&download_directory).await.expect("resume data should save");
+
+        let restored = state(download_directory.clone()).await;
+        let loaded = ResumeData::load(&download_directory, &restored.info_hash)
+            .await
+            .expect("resume file should read")
+            .expect("resume file should exist");
+
+        assert_eq!(loaded, resume);
+        assert!(loaded.apply(&restored).await);
+        assert_eq!(restored.piece_picker.lock().await.completed_pieces(), &[false, true, false]);
+        assert_eq!(restored.pieces_downloaded(), 1);
+        assert_eq!(restored.bytes_complete(), 4);
+        assert_eq!(restored.uploaded(), 12);
+        assert_eq!(
+            *restored.file_priorities.lock().await,
+            vec![FilePriority::Normal, FilePriority::Skip]
+        );
+        // The piece the files share is still downloaded, the one only the second file covers isn't
+        let piece_picker = restored.piece_picker.lock().await;
+        assert_eq!(piece_picker.priority(1), FilePriority::Normal);
+        assert_eq!(piece_picker.priority(2), FilePriority::Skip);
+        assert!(piece_picker.is_sequential());
+        assert_eq!(ResumeData::load_all(&download_directory).await, vec![resume]);
+    }
+
+    #[tokio::test]
+    async fn rejects_resume_data_of_another_version_or_torrent() {
+        let torrent = state(temp_dir("resume-reject")).await;
+        let mut resume = ResumeData::capture(&torrent, "file", "resume.torrent").await;
+        resume.version = RESUME_VERSION + 1;
+        let bytes = resume.to_bytes().expect("resume data should encode");
+
+        assert!(matches!(
+            ResumeData::from_bytes(&bytes),
+            Err(ResumeError::UnsupportedVersion(version)) if version == RESUME_VERSION + 1
+        ));
+
+        resume.version = RESUME_VERSION;
+        resume.info_hash = vec![0; 20];
+        assert!(!resume.apply(&torrent).await);
+    }
+
+    #[test]
+    fn orders_trackers_of_each_tier_as_saved() {
+        let resume = ResumeData {
+            version: RESUME_VERSION,
+            info_hash: vec![1; 20],
+            source_kind: "magnet".to_string(),
+            source: "magnet:?xt=urn:btih:0101010101010101010101010101010101010101".to_string(),
+            piece_count: 0,
+            pieces: Vec::new(),
+            bytes_complete: 0,
+            uploaded: 0,
+            files: Vec::new(),
+            trackers: vec![vec!["udp://b".to_string(), "udp://a".to_string()], vec!["udp://c".to_string()]],
+            sequential: 0,
+        };
+        let tiers = vec![
+            vec!["udp://a".to_string(), "udp://new".to_string(), "udp://b".to_string()],
+            vec!["udp://c".to_string()],
+        ];
+
+        assert_eq!(
+            resume.order_trackers(tiers),
+            vec![
+                vec!["udp://b".to_string(), "udp://a".to_string(), "udp://new".to_string()],
+                vec!["udp://c".to_string()],
+            ]
+        );
+    }
+
+    async fn state(download_directory: PathBuf) -> Arc<State> {
+        TestState::new("resume")
+            .data(b"abcdefghijkl", 4)
+            .files(vec![
+                (vec!["first.bin".to_string()], b"abcdef".to_vec()),
+                (vec!["second.bin".to_string()], b"ghijkl".to_vec()),
+            ])
+            .info_hash(vec![7; 20])
+            .download_directory(download_directory)
+            .build_with_file_tree()
+            .await
+    }
+
+    async fn deselect_second_file(state: &State) {
+        FilePriorities::set(state, &[1], FilePriority::Skip).await;
+    }
+}
@@ -1,10 +1,20 @@
-use crate::core::{peer::Peer, piece_picker::PiecePicker, tracker::Tracker, File};
+use crate::core::{
+    dht::DhtNode,
+    file_priorities::FilePriority,
+    partial_pieces::PartialPieces,
+    peer::Peer,
+    piece_picker::PiecePicker,
+    tracker::{TierMode, Tracker, TrackerManager},
+    transfer_rate::TransferRate,
+    File,
+};
 use crossbeam::atomic::AtomicCell;
 use hyperblow::parser::torrent_parser::FileMeta;
 use paste::paste;
 
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 /// Used to generate getter and setter for Cell<T> types
 /// Eg.
@@ -35,12 +45,14 @@ macro_rules! cell_get_set {
     };
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DownState {
     /// It means the torrent is currently downloading
     Downloading,
     /// It means the download of the torrent is currenlty stopped
     Stopped,
+    /// It means the data already on the disk is being hashed, to find out which pieces we have
+    Checking,
     /// It means the state is unknown, it might be requesting data from some tracker or doing
     /// something else, but not downloading the data of the torrent and not in a paused state
     Unknown,
@@ -55,7 +67,7 @@ pub struct State {
 
     pub download_directory: PathBuf,
 
-    pub d_state: DownState,
+    pub d_state: AtomicCell<DownState>,
 
     /// The entire file tree of the torrent files to be downloaded
     pub file_tree: Option<Arc<Mutex<File>>>,
@@ -78,6 +90,18 @@ pub struct State {
 
     pub piece_picker: Arc<Mutex<PiecePicker>>,
 
+    /// Priority of each file, in the order the torrent lists them. When both are locked it's locked
+    /// before the piece_picker
+    pub file_priorities: Arc<Mutex<Vec<FilePriority>>>,
+
+    /// The deadlines given to the first and last pieces of the file last selected in the Files tab,
+    /// selecting another file drops only these. When both are locked it's locked before the piece_picker
+    pub edge_deadlines: Arc<Mutex<Vec<(usize, Instant)>>>,
+
+    /// The pieces being downloaded from peers, block by block. When both are locked it's locked
+    /// before the piece_picker
+    pub partial_pieces: Arc<Mutex<PartialPieces>>,
+
     /// All the peers of the current session
     pub peers: Arc<Mutex<Vec<Peer>>>,
 
@@ -89,17 +113,128 @@ pub struct State {
 
     // Total downloaded pieces
     pub pieces_downloaded: AtomicCell<usize>,
+
+    /// Rate at which blocks are being downloaded from peers
+    pub download_rate: TransferRate,
+
+    /// Total bytes uploaded to peers
+    pub uploaded: AtomicCell<usize>,
+
+    /// Rate at which blocks are being uploaded to peers
+    pub upload_rate: TransferRate,
+
+    /// No of pieces hashed so far by the running recheck
+    pub pieces_checked: AtomicCell<usize>,
+
+    /// The DHT node the torrent finds peers with, it's set once the UDP socket is bound and peer
+    /// sessions give it the DHT nodes of the peers
+    pub dht: Arc<RwLock<Option<Arc<DhtNode>>>>,
+
+    /// The engine's socket for UDP trackers and the DHT, shared by all its torrents. A torrent run
+    /// without an engine binds one of its own for every session
+    pub tracker_manager: Arc<RwLock<Option<Arc<TrackerManager>>>>,
+
+    /// Cancelled when the torrent is paused or removed, the tracker, DHT and peer tasks of the
+    /// session end with it. Starting the torrent again puts a fresh token in its place
+    pub session: Arc<Mutex<CancellationToken>>,
+
+    /// Writes of downloaded pieces onto the disk, they're tasks of their own so a write that has
+    /// started finishes even when the session stops, and shutdown waits for them
+    pub piece_writes: TaskTracker,
+
+    /// Bytes of verified pieces downloaded from peers since the torrent was last started, the
+    /// trackers are told this rather than what was already on the disk
+    pub session_downloaded: AtomicCell<usize>,
+
+    /// Bytes uploaded to peers since the torrent was last started
+    pub session_uploaded: AtomicCell<usize>,
+
+    /// Whether the announce tiers are tried one after the other or all at once
+    pub tier_mode: AtomicCell<TierMode>,
+
+    /// Wakes everything waiting for a piece whenever one is completed, eg. the reads of the
+    /// streaming server
+    pub piece_completed: Arc<Notify>,
+
+    /// The peer the choker unchoked optimistically, on top of the regular unchoke slots
+    pub optimistic_unchoke: AtomicCell<Option<SocketAddr>>,
 }
 
 impl State {
     /// Stop the download of the torrent
-    fn stop() {
-        // Code to pause the download
+    ///
+    /// Cancels the running session and forgets its peers and sockets, so that the next
+    /// [State::start] connects from scratch. The progress made so far is kept
+    pub async fn stop(&self) {
+        self.set_d_state(DownState::Stopped);
+        self.end_session().await;
+    }
+
+    /// Does what [State::stop] does but leaves the [DownState] alone, eg. for a recheck that
+    /// already switched it to [DownState::Checking]
+    pub async fn end_session(&self) {
+        self.session.lock().await.cancel();
+        self.peers.lock().await.clear();
+        self.set_optimistic_unchoke(None);
+        self.partial_pieces.lock().await.clear();
+        {
+            let mut piece_picker = self.piece_picker.lock().await;
+            piece_picker.clear_requests();
+            piece_picker.clear_availability();
+        }
+        *self.dht.write().await = None;
+        self.udp_ports.lock().await.clear();
+    }
+
+    /// Start the download of the torrent, after it was stopped with [State::stop]
+    pub async fn start(&self) {
+        let mut session = self.session.lock().await;
+        if session.is_cancelled() {
+            *session = CancellationToken::new();
+        }
+        // The trackers hear "started" again, the counters of what they're told start over with it
+        self.set_session_downloaded(0);
+        self.set_session_uploaded(0);
+        self.set_d_state(DownState::Unknown);
     }
 
-    /// Start the download of the torrent
-    fn start() {
-        // Code to resume the download
+    /// Switches the [DownState] to "to" in one step unless "allowed" refuses the current one, so two
+    /// callers can't both switch away from the same state. Gives the state it switched from, or
+    /// the one that was refused
+    pub fn switch_d_state(&self, to: DownState, allowed: impl Fn(DownState) -> bool) -> Result<DownState, DownState> {
+        let mut current = self.d_state();
+        loop {
+            if !allowed(current) {
+                return Err(current);
+            }
+            match self.d_state.compare_exchange(current, to) {
+                Ok(previous) => return Ok(previous),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Token of the current session, it's cancelled once the torrent is stopped
+    pub async fn session(&self) -> CancellationToken {
+        self.session.lock().await.clone()
+    }
+
+    /// Tells the trackers the download completed, it's called once the last piece is verified
+    pub async fn announce_completed(&self) {
+        for tracker in self.trackers.read().await.iter().flatten() {
+            tracker.announce_completed();
+        }
+    }
+
+    /// Waits for the pieces that are being written to reach the disk
+    pub async fn flush_piece_writes(&self) {
+        self.piece_writes.close();
+        self.piece_writes.wait().await;
+        self.piece_writes.reopen();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.d_state() == DownState::Stopped
     }
 
     cell_get_set!(uptime: usize);
@@ -108,6 +243,28 @@ impl State {
 
     cell_get_set!(pieces_downloaded: usize);
 
+    cell_get_set!(uploaded: usize);
+
+    cell_get_set!(pieces_checked: usize);
+
+    cell_get_set!(d_state: DownState);
+
+    cell_get_set!(session_downloaded: usize);
+
+    cell_get_set!(session_uploaded: usize);
+
+    cell_get_set!(tier_mode: TierMode);
+
+    cell_get_set!(optimistic_unchoke: Option<SocketAddr>);
+
+    pub async fn dht(&self) -> Option<Arc<DhtNode>> {
+        self.dht.read().await.clone()
+    }
+
+    pub async fn tracker_manager(&self) -> Option<Arc<TrackerManager>> {
+        self.tracker_manager.read().await.clone()
+    }
+
     pub fn piece_length(&self) -> Option<usize> {
         self.meta_info.info.piece_length.map(|length| length.max(0) as usize)
     }
@@ -0,0 +1,598 @@
+use super::{
+    file_priorities::{FilePriorities, FilePriority},
+    piece_storage::{PieceStorage, TorrentFileSpan},
+    state::State,
+};
+use percent_encoding::percent_decode_str;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// Longest request head, the request line and the headers, that's read from a player
+const MAX_REQUEST_HEAD: u64 = 16 * 1024;
+
+/// No of pieces after the one being read that get a deadline as well, so a player reading on
+/// finds them downloaded
+const READ_AHEAD_PIECES: usize = 4;
+
+/// How far apart the deadlines of the pieces read ahead are
+const READ_AHEAD_DEADLINE_STEP: Duration = Duration::from_millis(500);
+
+/// How long a read waits for its piece before it checks the deadlines again, eg. after one of them
+/// was taken by another read
+const PIECE_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum StreamRequestError {
+    #[error("stream request could not be read")]
+    Io(#[from] io::Error),
+
+    #[error("malformed stream request")]
+    Malformed,
+
+    #[error("stream request head is longer than {MAX_REQUEST_HEAD} bytes")]
+    TooLong,
+}
+
+/// Accepts the HTTP connections of media players, on the loopback interface only, so the files
+/// of the torrents aren't served to anyone else
+#[derive(Debug)]
+pub struct StreamListener {
+    listener: TcpListener,
+}
+
+impl StreamListener {
+    /// Binds the listener on 127.0.0.1, a port of 0 has the OS pick a free one
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        Ok(Self { listener })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|address| address.port()).unwrap_or_default()
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.listener.accept().await
+    }
+}
+
+/// A byte range of a file, as the Range header of a request asks for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// "bytes=<first>-<last>", the last byte is included
+    Bounded { first: usize, last: usize },
+
+    /// "bytes=<first>-", up to the end of the file
+    From { first: usize },
+
+    /// "bytes=-<length>", the last bytes of the file
+    Suffix { length: usize },
+}
+
+impl ByteRange {
+    /// Parses the value of a Range header, a header asking for more than one range or in another
+    /// unit gives none and the whole file is served, like a server without range support would
+    fn parse(value: &str) -> Option<Self> {
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (first, last) = spec.trim().split_once('-')?;
+        match (first.trim(), last.trim()) {
+            ("", length) => length.parse().ok().map(|length| Self::Suffix { length }),
+            (first, "") => first.parse().ok().map(|first| Self::From { first }),
+            (first, last) => {
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                (first <= last).then_some(Self::Bounded { first, last })
+            }
+        }
+    }
+
+    /// The bytes of a file of the length the range covers, from the first up to the one after the
+    /// last. There are none when the range starts past the end of the file
+    fn resolve(self, file_length: usize) -> Option<(usize, usize)> {
+        let (first, end) = match self {
+            Self::Bounded { first, last } => (first, last.saturating_add(1).min(file_length)),
+            Self::From { first } => (first, file_length),
+            Self::Suffix { length } => (file_length.saturating_sub(length), file_length),
+        };
+        (first < end).then_some((first, end))
+    }
+}
+
+/// A request of a player for a file of a torrent, its path is "/<hex info hash>/<path of the file>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamRequest {
+    pub method: String,
+    pub path: String,
+    pub range: Option<ByteRange>,
+
+    /// Whether the connection stays open for another request afterwards
+    pub keep_alive: bool,
+}
+
+impl StreamRequest {
+    /// Reads the head of the next request on a connection, there's none once the player closed it
+    ///
+    /// The body of a request is never read, as the requests of a player don't have one
+    pub async fn read<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Self>, StreamRequestError> {
+        let mut head = String::new();
+        let mut limited = reader.take(MAX_REQUEST_HEAD);
+        loop {
+            let line_start = head.len();
+            if limited.read_line(&mut head).await? == 0 {
+                if head.trim().is_empty() {
+                    return Ok(None);
+                }
+                return Err(match limited.limit() {
+                    0 => StreamRequestError::TooLong,
+                    _ => StreamRequestError::Malformed,
+                });
+            }
+            if head[line_start..].trim().is_empty() {
+                // Blank lines before the request line are skipped, the one after the headers ends them
+                if head.trim().is_empty() {
+                    head.clear();
+                    continue;
+                }
+                break;
+            }
+        }
+        Self::parse(&head).map(Some)
+    }
+
+    fn parse(head: &str) -> Result<Self, StreamRequestError> {
+        let mut lines = head.lines().map(str::trim_end).filter(|line| !line.is_empty());
+        let mut request_line = lines.next().ok_or(StreamRequestError::Malformed)?.split_whitespace();
+        let (Some(method), Some(path), Some(version), None) =
+            (request_line.next(), request_line.next(), request_line.next(), request_line.next())
+        else {
+            return Err(StreamRequestError::Malformed);
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(StreamRequestError::Malformed);
+        }
+
+        let mut range = None;
+        // Connections of HTTP/1.1 are kept open unless told otherwise, the ones of HTTP/1.0 aren't
+        let mut keep_alive = version != "HTTP/1.0";
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(StreamRequestError::Malformed)?;
+            match name.trim().to_ascii_lowercase().as_str() {
+                "range" => range = ByteRange::parse(value),
+                "connection" => match value.trim().to_ascii_lowercase().as_str() {
+                    "close" => keep_alive = false,
+                    "keep-alive" => keep_alive = true,
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            range,
+            keep_alive,
+        })
+    }
+
+    /// The info hash of the torrent and the path of the file inside of it that the request is for
+    pub fn target(&self) -> Option<(Vec<u8>, Vec<String>)> {
+        let path = self.path.split(['?', '#']).next().unwrap_or_default();
+        let mut segments = path.strip_prefix('/')?.split('/');
+        let info_hash = Self::decode_info_hash(segments.next()?)?;
+        let file_path = segments
+            .map(|segment| percent_decode_str(segment).decode_utf8().ok().map(|segment| segment.into_owned()))
+            .collect::<Option<Vec<_>>>()?;
+        if file_path.is_empty() || file_path.iter().any(String::is_empty) {
+            return None;
+        }
+        Some((info_hash, file_path))
+    }
+
+    fn decode_info_hash(hex: &str) -> Option<Vec<u8>> {
+        if hex.len() != 40 || !hex.is_ascii() {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamStatus {
+    Ok,
+    PartialContent,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    RangeNotSatisfiable,
+    ServiceUnavailable,
+}
+
+impl StreamStatus {
+    fn line(self) -> &'static str {
+        match self {
+            Self::Ok => "200 OK",
+            Self::PartialContent => "206 Partial Content",
+            Self::BadRequest => "400 Bad Request",
+            Self::NotFound => "404 Not Found",
+            Self::MethodNotAllowed => "405 Method Not Allowed",
+            Self::RangeNotSatisfiable => "416 Range Not Satisfiable",
+            Self::ServiceUnavailable => "503 Service Unavailable",
+        }
+    }
+}
+
+/// Serves the files of a torrent to media players while the torrent downloads
+///
+/// The bytes of a file are read from the disk through the same file mapping the pieces are
+/// written with, each read waits until the pieces it covers are verified. The pieces a read waits
+/// for get a deadline in the piece picker, and so do the few after them
+pub struct FileStream;
+
+impl FileStream {
+    /// Answers a request, "state" is the torrent whose info hash the path of the request has, if
+    /// there's such a torrent
+    ///
+    /// closed => Cancelled once the player closed the connection, a read that waits for its pieces
+    /// gives up then
+    pub async fn serve<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        request: &StreamRequest,
+        state: Option<&Arc<State>>,
+        closed: &CancellationToken,
+    ) -> io::Result<()> {
+        let head_only = match request.method.as_str() {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                return Self::write_head(
+                    writer,
+                    StreamStatus::MethodNotAllowed,
+                    &[("Allow", "GET, HEAD".to_string())],
+                    0,
+                    request,
+                )
+                .await
+            }
+        };
+        let (Some(state), Some((_, file_path))) = (state, request.target()) else {
+            return Self::write_head(writer, StreamStatus::NotFound, &[], 0, request).await;
+        };
+        // A magnet knows nothing about its files until its metadata is fetched
+        if state.pieces_hash.is_empty() || state.piece_length().unwrap_or_default() == 0 {
+            return Self::write_head(writer, StreamStatus::ServiceUnavailable, &[], 0, request).await;
+        }
+        let Some(file) = PieceStorage::file_span(state, &file_path) else {
+            return Self::write_head(writer, StreamStatus::NotFound, &[], 0, request).await;
+        };
+
+        let (status, start, end) = match request.range.map(|range| range.resolve(file.length)) {
+            None => (StreamStatus::Ok, 0, file.length),
+            Some(Some((start, end))) => (StreamStatus::PartialContent, start, end),
+            Some(None) => {
+                let content_range = format!("bytes */{}", file.length);
+                return Self::write_head(
+                    writer,
+                    StreamStatus::RangeNotSatisfiable,
+                    &[("Content-Range", content_range)],
+                    0,
+                    request,
+                )
+                .await;
+            }
+        };
+        let mut headers = vec![("Accept-Ranges", "bytes".to_string())];
+        if status == StreamStatus::PartialContent {
+            headers.push(("Content-Range", format!("bytes {start}-{}/{}", end - 1, file.length)));
+        }
+        Self::write_head(writer, status, &headers, end - start, request).await?;
+        if head_only || start == end {
+            return Ok(());
+        }
+
+        // The player wants the file, so it's downloaded even if it was skipped
+        if state.file_priorities.lock().await.get(file.index) == Some(&FilePriority::Skip) {
+            debug!(file_index = file.index, "streaming a skipped file, it's downloaded now");
+            FilePriorities::set(state, &[file.index], FilePriority::Normal).await;
+        }
+        Self::write_body(writer, state, file, start, end, closed).await
+    }
+
+    /// Tells the player its request couldn't be read, the connection is closed afterwards
+    pub async fn reject<W: AsyncWrite + Unpin>(writer: &mut W) -> io::Result<()> {
+        writer
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        writer.flush().await
+    }
+
+    async fn write_head<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        status: StreamStatus,
+        headers: &[(&str, String)],
+        content_length: usize,
+        request: &StreamRequest,
+    ) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\nContent-Length: {content_length}\r\n", status.line());
+        if matches!(status, StreamStatus::Ok | StreamStatus::PartialContent) {
+            head.push_str("Content-Type: application/octet-stream\r\n");
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !request.keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes()).await?;
+        writer.flush().await
+    }
+
+    /// Writes the bytes of the file from "start" up to "end", piece by piece as they're verified
+    async fn write_body<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        state: &Arc<State>,
+        file: TorrentFileSpan,
+        start: usize,
+        end: usize,
+        closed: &CancellationToken,
+    ) -> io::Result<()> {
+        let piece_length = state.piece_length().unwrap_or_default();
+        let last_piece = (file.offset + end - 1) / piece_length;
+        let mut position = start;
+        while position < end {
+            let torrent_offset = file.offset + position;
+            let piece_index = torrent_offset / piece_length;
+            let begin = torrent_offset % piece_length;
+            let length = (piece_length - begin).min(end - position);
+
+            Self::wait_for_piece(state, piece_index, last_piece, closed).await?;
+            let block = PieceStorage::read_block(state, piece_index, begin, length)
+                .await
+                .map_err(io::Error::other)?;
+            writer.write_all(&block).await?;
+            position += length;
+        }
+        writer.flush().await
+    }
+
+    /// Waits until the piece is verified, meanwhile the piece and the ones after it, up to the
+    /// last one the read covers, have a deadline
+    ///
+    /// It gives up once the player closes the connection or the torrent is stopped. However it
+    /// ends, the deadlines it set are dropped again, so they don't hold on to a position the player
+    /// has moved away from
+    async fn wait_for_piece(state: &State, piece_index: usize, last_piece: usize, closed: &CancellationToken) -> io::Result<()> {
+        let session = state.session().await;
+        let mut deadlines = Vec::new();
+        let result = loop {
+            let completed = state.piece_completed.notified();
+            tokio::pin!(completed);
+            // Registered before the piece is checked, so a piece completed in between still wakes it
+            completed.as_mut().enable();
+            {
+                let mut piece_picker = state.piece_picker.lock().await;
+                if piece_picker.is_completed(piece_index) {
+                    break Ok(());
+                }
+                let now = Instant::now();
+                for (distance, piece_index) in (piece_index..=last_piece.min(piece_index + READ_AHEAD_PIECES)).enumerate() {
+                    // A deadline that's already set is kept, so it can pass and the piece is
+                    // requested from more peers
+                    if piece_picker.deadline(piece_index).is_none() {
+                        let deadline = now + READ_AHEAD_DEADLINE_STEP * distance as u32;
+                        piece_picker.set_deadline(piece_index, deadline);
+                        deadlines.push((piece_index, deadline));
+                    }
+                }
+            }
+            tokio::select! {
+                _ = timeout(PIECE_WAIT_INTERVAL, completed) => {}
+                _ = closed.cancelled() => break Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                _ = session.cancelled() => break Err(io::Error::other("torrent was stopped")),
+            }
+        };
+
+        if !deadlines.is_empty() {
+            let mut piece_picker = state.piece_picker.lock().await;
+            for (piece_index, deadline) in deadlines {
+                piece_picker.remove_deadline(piece_index, deadline);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteRange, FileStream, StreamRequest};
+    use crate::core::{
+        file_priorities::{FilePriorities, FilePriority},
+        piece_storage::PieceStorage,
+        state::State,
+        test_support::{temp_dir, TestState},
+    };
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+    use tokio::io::{AsyncReadExt, BufReader};
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn reads_requests_one_after_the_other() {
+        let requests =
+            "GET /0707070707070707070707070707070707070707/dir/my%20file.mkv HTTP/1.1\r\nHost: localhost\r\nRange: bytes=10-\r\n\r\n\
+                        HEAD /x HTTP/1.0\r\n\r\n";
+        let mut reader = BufReader::new(requests.as_bytes());
+
+        let first = StreamRequest::read(&mut reader)
+            .await
+            .expect("request should parse")
+            .expect("request should be there");
+        assert_eq!(first.method, "GET");
+        assert_eq!(first.range, Some(ByteRange::From { first: 10 }));
+        assert!(first.keep_alive);
+        assert_eq!(
+            first.target(),
+            Some((vec![7; 20], vec!["dir".to_string(), "my file.mkv".to_string()]))
+        );
+
+        let second = StreamRequest::read(&mut reader)
+            .await
+            .expect("request should parse")
+            .expect("request should be there");
+        assert_eq!(second.method, "HEAD");
+        assert!(!second.keep_alive);
+        assert_eq!(second.target(), None);
+        assert!(StreamRequest::read(&mut reader).await.expect("end should read").is_none());
+    }
+
+    #[test]
+    fn resolves_ranges_against_the_file_length() {
+        assert_eq!(ByteRange::parse("bytes=0-499"), Some(ByteRange::Bounded { first: 0, last: 499 }));
+        assert_eq!(ByteRange::parse("bytes=-5"), Some(ByteRange::Suffix { length: 5 }));
+        assert_eq!(ByteRange::parse("bytes=0-1,4-5"), None);
+        assert_eq!(ByteRange::parse("bytes=5-1"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+
+        assert_eq!(ByteRange::Bounded { first: 2, last: 99 }.resolve(10), Some((2, 10)));
+        assert_eq!(ByteRange::From { first: 3 }.resolve(10), Some((3, 10)));
+        assert_eq!(ByteRange::Suffix { length: 20 }.resolve(10), Some((0, 10)));
+        assert_eq!(ByteRange::From { first: 10 }.resolve(10), None);
+    }
+
+    #[tokio::test]
+    async fn serves_a_range_once_its_pieces_are_verified() {
+        let download_directory = temp_dir("stream-range");
+        let state = state(download_directory.clone()).await;
+        // The part of piece 1 in the skipped file is dropped, so the piece is downloaded again
+        // once the file is streamed
+        FilePriorities::set(&state, &[1], FilePriority::Skip).await;
+        PieceStorage::complete_piece(&state, 1, b"efgh")
+            .await
+            .expect("piece should complete");
+
+        // "second.bin" is "ghijkl", its bytes 1 to 4 lie in pieces 1 and 2
+        let request = StreamRequest::parse(&format!(
+            "GET /{}/second.bin HTTP/1.1\r\nRange: bytes=1-4\r\nConnection: close\r\n\r\n",
+            "07".repeat(20)
+        ))
+        .expect("request should parse");
+        let (mut player, mut server) = tokio::io::duplex(1024);
+        let serving_state = state.clone();
+        let serving =
+            tokio::spawn(async move { FileStream::serve(&mut server, &request, Some(&serving_state), &CancellationToken::new()).await });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.piece_picker.lock().await.deadline(1).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the piece the read waits for should get a deadline");
+        assert!(state.piece_picker.lock().await.deadline(2).is_some());
+        assert!(!serving.is_finished());
+        assert_eq!(*state.file_priorities.lock().await, vec![FilePriority::Normal; 2]);
+        PieceStorage::complete_piece(&state, 1, b"efgh")
+            .await
+            .expect("piece should complete");
+        PieceStorage::complete_piece(&state, 2, b"ijkl")
+            .await
+            .expect("piece should complete");
+        serving.await.expect("serving should finish").expect("response should write");
+
+        let mut response = String::new();
+        player.read_to_string(&mut response).await.expect("response should read");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 1-4/6\r\n"));
+        assert!(response.contains("Content-Length: 4\r\n"));
+        assert!(response.ends_with("\r\n\r\nhijk"));
+        std::fs::remove_dir_all(download_directory).expect("download directory should remove");
+    }
+
+    #[tokio::test]
+    async fn read_gives_up_and_drops_its_deadlines_once_the_player_is_gone() {
+        let download_directory = temp_dir("stream-closed");
+        let state = state(download_directory.clone()).await;
+        let request = StreamRequest::parse(&format!("GET /{}/first.bin HTTP/1.1\r\n\r\n", "07".repeat(20))).expect("request should parse");
+        let (_player, mut server) = tokio::io::duplex(1024);
+        let closed = CancellationToken::new();
+        let serving_state = state.clone();
+        let serving_closed = closed.clone();
+        let serving = tokio::spawn(async move { FileStream::serve(&mut server, &request, Some(&serving_state), &serving_closed).await });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.piece_picker.lock().await.deadline(1).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the pieces of the read should get a deadline");
+        closed.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .expect("read should give up")
+            .expect("serving should finish");
+
+        assert!(result.is_err());
+        let piece_picker = state.piece_picker.lock().await;
+        assert_eq!(piece_picker.deadline(0), None);
+        assert_eq!(piece_picker.deadline(1), None);
+        drop(piece_picker);
+        let _ = std::fs::remove_dir_all(download_directory);
+    }
+
+    #[tokio::test]
+    async fn answers_requests_it_cannot_serve() {
+        let download_directory = temp_dir("stream-errors");
+        let state = state(download_directory.clone()).await;
+        let info_hash = "07".repeat(20);
+
+        for (request, status) in [
+            (format!("GET /{info_hash}/missing.bin HTTP/1.1\r\n\r\n"), "404 Not Found"),
+            (
+                format!("GET /{info_hash}/first.bin HTTP/1.1\r\nRange: bytes=6-\r\n\r\n"),
+                "416 Range Not Satisfiable",
+            ),
+            (format!("POST /{info_hash}/first.bin HTTP/1.1\r\n\r\n"), "405 Method Not Allowed"),
+            (format!("HEAD /{info_hash}/first.bin HTTP/1.1\r\n\r\n"), "200 OK"),
+        ] {
+            let request = StreamRequest::parse(&request).expect("request should parse");
+            let mut response = Vec::new();
+            FileStream::serve(&mut response, &request, Some(&state), &CancellationToken::new())
+                .await
+                .expect("response should write");
+            let response = String::from_utf8(response).expect("response should be text");
+            assert!(response.starts_with(&format!("HTTP/1.1 {status}\r\n")), "{response}");
+        }
+        let _ = std::fs::remove_dir_all(download_directory);
+    }
+
+    async fn state(download_directory: PathBuf) -> Arc<State> {
+        TestState::new("stream")
+            .data(b"abcdefghijkl", 4)
+            .files(vec![
+                (vec!["first.bin".to_string()], b"abcdef".to_vec()),
+                (vec!["second.bin".to_string()], b"ghijkl".to_vec()),
+            ])
+            .info_hash(vec![7; 20])
+            .download_directory(download_directory)
+            .build_with_file_tree()
+            .await
+    }
+}
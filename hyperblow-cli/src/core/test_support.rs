@@ -0,0 +1,172 @@
+use super::{
+    file_priorities::FilePriority,
+    piece_picker::PiecePicker,
+    state::{DownState, State},
+    transfer_rate::TransferRate,
+    TorrentFile,
+};
+use crossbeam::atomic::AtomicCell;
+use hyperblow::parser::torrent_parser::{File, FileMeta, Info};
+use sha1::{Digest, Sha1};
+use std::{fs, path::PathBuf, sync::Arc};
+use tokio::{io::AsyncReadExt, net::TcpStream, sync::Mutex};
+use tokio_util::task::TaskTracker;
+
+/// An empty directory for the files of a test, it's named after the test process and thread so
+/// tests running at the same time don't share one
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("hyperblow-{name}-{}-{:?}", std::process::id(), std::thread::current().id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).expect("temp dir should be created");
+    path
+}
+
+/// Builds the [State] of a torrent for tests, without a .torrent file or an engine to create it
+pub(crate) struct TestState {
+    name: String,
+    length: usize,
+    piece_length: usize,
+    pieces_hash: Vec<[u8; 20]>,
+    files: Option<Vec<File>>,
+    info_hash: Vec<u8>,
+    download_directory: PathBuf,
+}
+
+impl TestState {
+    /// A torrent of a single, empty file of the given name
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            length: 0,
+            piece_length: 16 * 1024,
+            pieces_hash: Vec::new(),
+            files: None,
+            info_hash: vec![1; 20],
+            download_directory: std::env::temp_dir(),
+        }
+    }
+
+    /// The torrent is of the given data, split into pieces of the given length
+    pub fn data(mut self, data: &[u8], piece_length: usize) -> Self {
+        self.length = data.len();
+        self.piece_length = piece_length;
+        self.pieces_hash = data.chunks(piece_length).map(|piece| Sha1::digest(piece).into()).collect();
+        self
+    }
+
+    /// The data is split into the given files, of a path and the bytes of each
+    pub fn files(mut self, files: Vec<(Vec<String>, Vec<u8>)>) -> Self {
+        self.files = Some(
+            files
+                .into_iter()
+                .map(|(path, bytes)| File {
+                    length: bytes.len() as i64,
+                    path,
+                    md5sum: None,
+                })
+                .collect(),
+        );
+        self
+    }
+
+    /// Length of the torrent, for the ones without any pieces
+    pub fn length(mut self, length: usize) -> Self {
+        self.length = length;
+        self
+    }
+
+    pub fn info_hash(mut self, info_hash: Vec<u8>) -> Self {
+        self.info_hash = info_hash;
+        self
+    }
+
+    pub fn download_directory(mut self, download_directory: PathBuf) -> Self {
+        self.download_directory = download_directory;
+        self
+    }
+
+    pub fn build(self) -> Arc<State> {
+        Arc::new(self.state())
+    }
+
+    /// Builds it with the file tree, for the tests of what works on the files by their paths
+    pub async fn build_with_file_tree(self) -> Arc<State> {
+        let mut state = self.state();
+        state.file_tree = Some(TorrentFile::generateFileTree(&state.meta_info).await);
+        Arc::new(state)
+    }
+
+    fn state(self) -> State {
+        let file_count = self.files.as_ref().map_or(1, Vec::len);
+        let single_file = self.files.is_none();
+        State {
+            meta_info: FileMeta {
+                announce: "http://tracker.example.test/announce".to_string(),
+                announce_list: None,
+                info: Info {
+                    name: Some(self.name),
+                    length: single_file.then_some(self.length as i64),
+                    files: self.files,
+                    piece_length: Some(self.piece_length as i64),
+                    pieces: self.pieces_hash.concat(),
+                },
+                creation_data: None,
+                comment: None,
+                encoding: None,
+                created_by: None,
+                acceptable_source: None,
+                url_list: None,
+                httpseeds: None,
+            },
+            download_directory: self.download_directory,
+            d_state: AtomicCell::new(DownState::Unknown),
+            file_tree: None,
+            trackers: Arc::default(),
+            udp_ports: Arc::new(Mutex::new(vec![6881])),
+            tcp_ports: Arc::default(),
+            info_hash: self.info_hash,
+            piece_picker: Arc::new(Mutex::new(PiecePicker::new(self.pieces_hash.len()))),
+            pieces_hash: self.pieces_hash,
+            file_priorities: Arc::new(Mutex::new(vec![FilePriority::default(); file_count])),
+            edge_deadlines: Arc::default(),
+            partial_pieces: Arc::default(),
+            peers: Arc::default(),
+            uptime: AtomicCell::new(0),
+            bytes_complete: AtomicCell::new(0),
+            pieces_downloaded: AtomicCell::new(0),
+            download_rate: TransferRate::default(),
+            uploaded: AtomicCell::new(0),
+            upload_rate: TransferRate::default(),
+            pieces_checked: AtomicCell::new(0),
+            dht: Arc::default(),
+            tracker_manager: Arc::default(),
+            session: Arc::default(),
+            piece_writes: TaskTracker::new(),
+            session_downloaded: AtomicCell::new(0),
+            session_uploaded: AtomicCell::new(0),
+            tier_mode: AtomicCell::default(),
+            piece_completed: Arc::default(),
+            optimistic_unchoke: AtomicCell::new(None),
+        }
+    }
+}
+
+/// The server side of the HTTP exchanges in tests, eg. of trackers and web seeds
+pub(crate) struct TestHttp;
+
+impl TestHttp {
+    /// Reads the head of a request, the request line and the headers, it's cut short when the
+    /// client closes the connection before the blank line ending it
+    pub async fn read_head(socket: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0_u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = socket.read(&mut buf).await.expect("request should be readable");
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+        String::from_utf8_lossy(&request).into_owned()
+    }
+}
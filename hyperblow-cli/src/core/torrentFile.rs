@@ -7,34 +7,45 @@
 // their iformations and all other data related to it
 
 // TODO : Find the folder to save the data
-// TODO : Create the DataStructure in such a way that it could resume the download later on as well
 // TODO : Return error on error generated rather than this Option<T> on TorrentFile::new()
 use super::peer::{MagnetMetadataError, MagnetMetadataFetcher, Peer};
 use crate::{
     core::{
+        choker::Choker,
+        dht::DhtNode,
+        file_priorities::FilePriority,
+        partial_pieces::PartialPieces,
+        piece_checker::PieceChecker,
         piece_picker::PiecePicker,
+        resume::ResumeData,
         state::{DownState, State},
-        tracker::Tracker,
+        tracker::{TierMode, Tracker, TrackerManager, TrackerTiers},
+        transfer_rate::TransferRate,
+        web_seed::WebSeeds,
         File,
     },
     ACell, ArcMutex, ArcRwLock,
 };
 use crossbeam::atomic::AtomicCell;
 use hyperblow::parser::torrent_parser::{FileMeta, FileMetaError};
-use std::{io, path::PathBuf, sync::Arc};
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
 use thiserror::Error;
 use tokio::{
     join,
-    net::UdpSocket,
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Mutex, RwLock,
+        Mutex, Notify, RwLock,
     },
+    task::JoinSet,
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{debug, info, warn};
 
 const TRANS_ID: i32 = 10;
 
+/// How long to wait between two DHT lookups of the torrent's peers
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Debug, Error)]
 pub enum TError {
     #[error("no tracker resolved")]
@@ -74,13 +85,16 @@ pub struct TorrentFile {
     /// peers we can invoke run() method of the peer and
     /// store in the peers field of [Peers]
     peers_channel: (Arc<UnboundedSender<Peer>>, Arc<Mutex<UnboundedReceiver<Peer>>>),
+
+    /// Whether the progress of the previous session was restored, it's done before the first
+    /// session only, not every time the torrent is started again
+    prepared: AtomicCell<bool>,
 }
 
 struct Peers {
     peers: Vec<u32>,
 }
 
-/// TODO: Implement DHT(Distributed Hash Table) as well
 impl TorrentFile {
     /// It will try to parse the given the path of the torrent file and create a new data structure
     /// from the Torrent file
@@ -108,7 +122,7 @@ impl TorrentFile {
     ) -> Result<Self, TError> {
         let pieces_hash = meta_info.getPiecesHash()?;
         let pieces_count = pieces_hash.len();
-        let d_state = DownState::Unknown;
+        let d_state = ACell!(DownState::Unknown);
         let file_tree = if build_file_tree {
             Some(Self::generateFileTree(&meta_info).await)
         } else {
@@ -122,6 +136,22 @@ impl TorrentFile {
         let bytes_complete = ACell!(0);
         let pieces_downloaded = ACell!(0);
         let uptime = ACell!(0);
+        let uploaded = ACell!(0);
+        let upload_rate = TransferRate::default();
+        let download_rate = TransferRate::default();
+        let pieces_checked = ACell!(0);
+        let dht = ArcRwLock!(None);
+        let session = ArcMutex!(CancellationToken::new());
+        let piece_writes = TaskTracker::new();
+        let session_downloaded = ACell!(0);
+        let session_uploaded = ACell!(0);
+        let tier_mode = ACell!(TierMode::Failover);
+        let tracker_manager = ArcRwLock!(None);
+        let partial_pieces = ArcMutex!(PartialPieces::default());
+        let file_priorities = ArcMutex!(vec![FilePriority::default(); meta_info.info.files.as_ref().map_or(1, Vec::len)]);
+        let edge_deadlines = ArcMutex!(Vec::new());
+        let piece_completed = Arc::new(Notify::new());
+        let optimistic_unchoke = ACell!(None);
 
         let peers_channel = unbounded_channel::<Peer>();
         let peers_channel = (Arc::new(peers_channel.0), ArcMutex!(peers_channel.1));
@@ -141,6 +171,22 @@ impl TorrentFile {
             piece_picker,
             peers,
             uptime,
+            uploaded,
+            upload_rate,
+            download_rate,
+            pieces_checked,
+            dht,
+            session,
+            piece_writes,
+            session_downloaded,
+            session_uploaded,
+            tier_mode,
+            tracker_manager,
+            partial_pieces,
+            file_priorities,
+            edge_deadlines,
+            piece_completed,
+            optimistic_unchoke,
         });
 
         Ok(Self {
@@ -148,6 +194,7 @@ impl TorrentFile {
             pieces_count,
             state,
             peers_channel,
+            prepared: ACell!(false),
         })
     }
 
@@ -208,154 +255,145 @@ impl TorrentFile {
         File::new(meta, &".".to_owned()).await.unwrap()
     }
 
-    async fn getUDPSocket(&self) -> Result<Arc<UdpSocket>, io::Error> {
-        for port in 6881..=6999 {
-            let adr = format!("0.0.0.0:{port}");
-            match UdpSocket::bind(adr).await {
-                Ok(socket) => {
-                    let mut udp_ports = self.state.udp_ports.lock().await;
-                    udp_ports.push(port);
-                    return Ok(Arc::new(socket));
-                }
-                Err(_e) => {
-                    continue;
-                }
-            }
+    /// The tracker manager of the engine, or one bound for the session when the torrent runs without
+    /// an engine, it's told by the second value. The port of its socket is recorded either way
+    async fn tracker_manager(&self) -> Result<(Arc<TrackerManager>, bool), io::Error> {
+        let (manager, owned) = match self.state.tracker_manager().await {
+            Some(manager) => (manager, false),
+            None => (TrackerManager::bind(&self.state.download_directory).await?, true),
+        };
+        let mut udp_ports = self.state.udp_ports.lock().await;
+        udp_ports.clear();
+        udp_ports.push(manager.port()?);
+        Ok((manager, owned))
+    }
+
+    /// URLs of the trackers by tier, according to BEP12 the "announce" field is ignored when
+    /// "announce_list" is present, as the URL in the "announce" field is already in the list
+    fn announce_tiers(&self) -> Vec<Vec<String>> {
+        match self.state.meta_info.announce_list {
+            Some(ref announce_list) => announce_list.clone(),
+            None => vec![vec![self.state.meta_info.announce.clone()]],
         }
+    }
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        if let Ok(address) = socket.local_addr() {
-            let mut udp_ports = self.state.udp_ports.lock().await;
-            udp_ports.push(address.port());
+    /// URLs of the trackers by tier in the order they were left in by the previous session, the
+    /// working trackers were moved to the front of their tiers
+    async fn current_announce_tiers(&self) -> Vec<Vec<String>> {
+        let trackers = self.state.trackers.read().await;
+        if trackers.is_empty() {
+            return TrackerTiers::shuffle(self.announce_tiers());
         }
-        Ok(Arc::new(socket))
+        trackers
+            .iter()
+            .map(|tier| tier.iter().map(|tracker| tracker.address.to_string()).collect())
+            .collect()
     }
 
-    // Running of trackers is divided into two sub tasks
-    // 1. Sending trackers requests
-    // 2. Receiving trackers response
-    //
-    // One is, sending Trackers requests and other is
-    // If there are 'n' no of trackers, then
-    // In the first async task of 'req', it spawns 'n' no of tasks within itself, and these each task make a
-    // request and for every response that comes in the socket, its handled by second async
-    // task of 'res
-    async fn runTrackers(&self, socket: Arc<UdpSocket>) {
-        // Step 1 : Generate "Tracker" instance from all the tracker's URL in "announce" or
-        // "announce_list" field of FileMeta and spawm a tokio task internally to call each tracker's run method
+    /// Creates [Tracker] instances from the given tiers of tracker URLs and announces to them tier by
+    /// tier, see [TrackerTiers]. A tracker manager the session bound itself is run along with them,
+    /// the engine runs its own
+    async fn runTrackers(&self, manager: Arc<TrackerManager>, owns_manager: bool, announce_tiers: Vec<Vec<String>>) {
         let trackers: Vec<Vec<Arc<Tracker>>> = {
             let mut tracker_s = Vec::default();
-            if let Some(ref announce_list_s) = self.state.meta_info.announce_list {
-                for announce_list in announce_list_s {
-                    let mut _trackers = Vec::new();
-                    for announce_url in announce_list {
-                        match Tracker::new(announce_url, self.state.clone(), self.peers_channel.0.clone()) {
-                            Ok(tracker) => {
-                                let tracker = Arc::new(tracker);
-                                let tracker_cloned = tracker.clone();
-                                let socket = socket.clone();
-                                tokio::spawn(async move {
-                                    tracker_cloned.resolveTracker().await;
-                                    if tracker_cloned.is_udp() {
-                                        tracker_cloned.run_me(socket).await;
-                                    } else if tracker_cloned.is_http() {
-                                        tracker_cloned.run_http().await;
-                                    }
-                                });
-                                _trackers.push(tracker);
-                            }
-                            Err(error) => {
-                                warn!(tracker = %announce_url, error = %error, "skipping tracker");
+            for announce_list in announce_tiers {
+                let mut _trackers = Vec::new();
+                for announce_url in announce_list {
+                    match Tracker::new(&announce_url, self.state.clone(), self.peers_channel.0.clone()) {
+                        Ok(tracker) => {
+                            let tracker = Arc::new(tracker);
+                            if tracker.is_udp() {
+                                manager.register_udp_scrapes(&tracker);
                             }
+                            _trackers.push(tracker);
+                        }
+                        Err(error) => {
+                            warn!(tracker = %announce_url, error = %error, "skipping tracker");
                         }
                     }
-                    tracker_s.push(_trackers);
                 }
-            } else {
-                let announce_url = &self.state.meta_info.announce;
-                match Tracker::new(announce_url, self.state.clone(), self.peers_channel.0.clone()) {
-                    Ok(tracker) => {
-                        let tracker = Arc::new(tracker);
-                        let tracker_cloned = tracker.clone();
-                        let socket = socket.clone();
-                        tokio::spawn(async move {
-                            tracker_cloned.resolveTracker().await;
-                            if tracker_cloned.is_udp() {
-                                tracker_cloned.run_me(socket).await;
-                            } else if tracker_cloned.is_http() {
-                                tracker_cloned.run_http().await;
-                            }
-                        });
-                        tracker_s.push(vec![tracker])
-                    }
-                    Err(error) => {
-                        warn!(tracker = %announce_url, error = %error, "skipping tracker");
-                    }
+                if !_trackers.is_empty() {
+                    tracker_s.push(_trackers);
                 }
             }
             tracker_s
         };
         let tracker_count = trackers.iter().map(Vec::len).sum::<usize>();
-        info!(tracker_count, "tracker sessions started");
+        info!(tracker_count, tier_mode = ?self.state.tier_mode(), "tracker sessions started");
         *self.state.trackers.write().await = trackers;
 
-        // Step 2 : Recv by listening on the UDP socket and then find out for whom the message came for and give
-        // back to that specific tracker the response messsage
+        let receive = async {
+            if owns_manager {
+                manager.run().await;
+            }
+        };
+        join!(TrackerTiers::run(self.state.clone(), manager.clone()), receive);
+    }
+
+    /// Hands the DHT node on the tracker socket to the state, so peer sessions can feed it the DHT
+    /// nodes of the peers
+    async fn start_dht(&self, manager: &TrackerManager) -> Arc<DhtNode> {
+        let dht = manager.dht();
+        *self.state.dht.write().await = Some(dht.clone());
+        dht
+    }
+
+    /// Finds peers of the torrent on the DHT every now and then, and announces our listener port
+    /// to the nodes closest to the info hash. The peers go into the same channel as the tracker ones
+    async fn runDht(&self, dht: Arc<DhtNode>) {
+        let Ok(info_hash) = <[u8; 20]>::try_from(self.state.info_hash.as_slice()) else {
+            return;
+        };
         loop {
-            // A buffer of 4KiB capacity
-            let mut buf = [0; 4096];
-            match socket.recv_from(&mut buf).await {
-                Ok((len, ref s_addrs)) => {
-                    // NOTE : I could've stored all trackers in the top scope of this
-                    // receive_trackers_response() function, so that i don't have to await. But, the
-                    // problem is of BEP12, where i have to constantly arrange the Trackers, this
-                    // changes the index in the self.state.trackers field
-                    let trackers = self.state.trackers.read().await;
-                    for trackers in trackers.iter() {
-                        for tracker in trackers {
-                            if tracker.isEqualTo(s_addrs).await {
-                                if let Some((ref sd, _)) = tracker.udp_channel {
-                                    if !sd.is_closed() {
-                                        let mut buf = buf.to_vec();
-                                        buf.truncate(len);
-                                        let _ = sd.send(buf);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    // Error on receiving from the UDP Socket
-                }
+            if dht.node_count().await == 0 {
+                dht.bootstrap().await;
             }
+            let announce_port = self.state.tcp_ports.lock().await.first().copied();
+            let peers = dht.find_peers(info_hash, announce_port).await;
+            info!(peer_count = peers.len(), "DHT lookup returned peers");
+            for peer_socket_adr in peers {
+                let _ = self.peers_channel.0.send(Peer::new(peer_socket_adr, self.state.clone()));
+            }
+            dht.save_node_table().await;
+            tokio::time::sleep(DHT_LOOKUP_INTERVAL).await;
         }
     }
 
     pub async fn runDownload(&self) {
         let peers_rcv = &self.peers_channel.1;
         let mut peers_rcv = peers_rcv.lock().await;
-        while let Some(peer) = peers_rcv.recv().await {
-            let mut peers = self.state.peers.lock().await;
-            if !peers.iter().any(|stored| stored.socket_adr == peer.socket_adr) {
-                let peer_runner = peer.clone();
-                info!(peer = %peer.socket_adr, "discovered peer");
-                peers.push(peer);
-                tokio::spawn(async move {
-                    peer_runner.run().await;
-                });
-            } else {
-                debug!(peer = %peer.socket_adr, "skipping duplicate peer");
+        // Like the tracker tasks, the peer sessions end with the future of the download
+        let mut peer_sessions = JoinSet::new();
+        loop {
+            tokio::select! {
+                peer = peers_rcv.recv() => {
+                    let Some(peer) = peer else {
+                        break;
+                    };
+                    let mut peers = self.state.peers.lock().await;
+                    if !peers.iter().any(|stored| stored.socket_adr == peer.socket_adr) {
+                        let peer_runner = peer.clone();
+                        info!(peer = %peer.socket_adr, "discovered peer");
+                        peers.push(peer);
+                        peer_sessions.spawn(async move {
+                            peer_runner.run().await;
+                        });
+                    } else {
+                        debug!(peer = %peer.socket_adr, "skipping duplicate peer");
+                    }
+                }
+                Some(_) = peer_sessions.join_next(), if !peer_sessions.is_empty() => {}
             }
         }
-        // TODO: Run in a loop, but never return anything
     }
 
     pub(crate) async fn fetch_magnet_metadata(&self) -> Result<Vec<u8>, TError> {
-        let trackers_udp_socket = self.getUDPSocket().await?;
+        let (manager, owns_manager) = self.tracker_manager().await?;
+        let dht = self.start_dht(&manager).await;
 
         tokio::select! {
-            _ = self.runTrackers(trackers_udp_socket) => Err(TError::NoTrackerResolved),
+            _ = self.runTrackers(manager, owns_manager, TrackerTiers::shuffle(self.announce_tiers())) => Err(TError::NoTrackerResolved),
+            _ = self.runDht(dht) => Err(TError::NoTrackerResolved),
             metadata = self.run_magnet_metadata_fetch() => metadata,
         }
     }
@@ -392,22 +430,89 @@ impl TorrentFile {
         }
     }
 
+    /// Picks up the progress saved by a previous session from the resume file, if there's one for
+    /// this torrent, so the download continues without rechecking the data
+    async fn restore_resume_data(&self) -> Option<ResumeData> {
+        match ResumeData::load(&self.state.download_directory, &self.state.info_hash).await {
+            Ok(Some(resume)) => {
+                if resume.apply(&self.state).await {
+                    info!(pieces = self.state.pieces_downloaded(), "restored resume data");
+                    Some(resume)
+                } else {
+                    debug!("resume data doesn't match the torrent, starting over");
+                    None
+                }
+            }
+            Ok(None) => None,
+            Err(error) => {
+                warn!(error = %error, "failed to read resume data");
+                None
+            }
+        }
+    }
+
     /// TODO : Add examples for the rust docs
     /// Starts to download the torrent, it will keep on mutating the "state" field as it
     /// makes progress, and if the torrent needs to be pause or started, one can use the method on
     /// that State instance
     ///
+    /// It returns once the session is stopped through [State::stop], every tracker, DHT and peer
+    /// task of the session is dropped by then. Running it again after [State::start] starts a new
+    /// session, keeping the progress of the previous one
+    ///
     /// NOTE : While using this method, one must clone and keep a Arc pointer of "state" field,
     /// so that they can use it later on to display the UI or the data changed
     pub async fn run(&self) {
-        // A UDP socket for all the Trackers to send requests and receive responses
-        let Ok(trackers_udp_socket) = self.getUDPSocket().await else {
-            return;
+        let session = self.state.session().await;
+        tokio::select! {
+            _ = session.cancelled() => info!("torrent session stopped"),
+            _ = self.run_session() => {}
+        }
+    }
+
+    async fn run_session(&self) {
+        // It's only marked prepared once prepare() is done, a session stopped during the first
+        // recheck prepares again next time
+        let announce_tiers = if self.prepared.load() {
+            self.current_announce_tiers().await
+        } else {
+            let announce_tiers = self.prepare().await;
+            self.prepared.store(true);
+            announce_tiers
+        };
+
+        // A UDP socket for all the Trackers and the DHT node to send requests and receive responses
+        let (manager, owns_manager) = match self.tracker_manager().await {
+            Ok(manager) => manager,
+            Err(error) => {
+                warn!(error = %error, "failed to bind tracker socket");
+                return;
+            }
         };
+        let dht = self.start_dht(&manager).await;
 
-        let run_trackers = self.runTrackers(trackers_udp_socket.clone());
+        let run_trackers = self.runTrackers(manager, owns_manager, announce_tiers);
+        let run_dht = self.runDht(dht);
         let run_download = self.runDownload();
+        let run_choker = Choker::new(self.state.clone()).run();
+        let run_web_seeds = WebSeeds::run(self.state.clone());
+
+        join!(run_trackers, run_dht, run_download, run_choker, run_web_seeds);
+    }
 
-        join!(run_trackers, run_download);
+    /// Restores the progress of the previous session before the first session starts, it gives
+    /// the announce tiers in the order the resume data saved them in
+    async fn prepare(&self) -> Vec<Vec<String>> {
+        match self.restore_resume_data().await {
+            Some(resume) => resume.order_trackers(self.announce_tiers()),
+            None => {
+                // Without resume data, files already in the download directory are verified
+                // before any network activity, so their pieces aren't downloaded again
+                if PieceChecker::has_existing_data(&self.state) {
+                    PieceChecker::recheck(&self.state).await;
+                }
+                TrackerTiers::shuffle(self.announce_tiers())
+            }
+        }
     }
 }
@@ -1,6 +1,6 @@
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{BufMut, BytesMut};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use crate::core::protocol::PEER_ID;
 
@@ -53,7 +53,7 @@ impl AnnounceRequest {
             downloaded: None,
             left: None,
             uploaded: None,
-            event: Some(1),
+            event: Some(0),
             ip_address: 0,
             key: None,
             num_want: -1,
@@ -112,6 +112,10 @@ impl AnnounceRequest {
     pub fn set_key(&mut self, v: i32) {
         self.key = Some(v);
     }
+
+    pub fn set_event(&mut self, v: i32) {
+        self.event = Some(v);
+    }
 }
 
 /// IPv4 announce response:
@@ -126,6 +130,14 @@ impl AnnounceRequest {
 /// 24 + 6 * n  16-bit integer  TCP port
 /// 20 + 6 * Ns
 ///
+/// IPv6 announce response, what a tracker replies to an announce that reached it over IPv6, the
+/// same except for 18 byte peers:
+///
+/// Offet       Size            Name            Value
+/// 20 + 18 * n 128-bit integer IP address
+/// 36 + 18 * n 16-bit integer  TCP port
+/// 20 + 18 * N
+///
 /// Struct to handle the response received by sending "Announce" request
 #[derive(Debug, Clone)]
 pub struct AnnounceResponse {
@@ -143,16 +155,27 @@ impl AnnounceResponse {
     /// The error produced here are the IO errors from parsing the given buffer bytes into
     /// respective types
     pub fn from(v: &[u8]) -> Result<Self, std::io::Error> {
+        Self::parse(v, 4)
+    }
+
+    /// Creates a AnnounceResponse from the response to an announce sent over IPv6, whose peers
+    /// have 16 byte IP addresses
+    pub fn from_ipv6(v: &[u8]) -> Result<Self, std::io::Error> {
+        Self::parse(v, 16)
+    }
+
+    fn parse(v: &[u8], ip_len: usize) -> Result<Self, std::io::Error> {
+        let peer_len = ip_len + 2;
         if v.len() < 20 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("announce response must be at least 20 bytes, got {}", v.len()),
             ));
         }
-        if !(v.len() - 20).is_multiple_of(6) {
+        if !(v.len() - 20).is_multiple_of(peer_len) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("compact peer payload length must be a multiple of 6, got {}", v.len() - 20),
+                format!("compact peer payload length must be a multiple of {peer_len}, got {}", v.len() - 20),
             ));
         }
 
@@ -172,12 +195,15 @@ impl AnnounceResponse {
         let x = 20..v.len();
 
         let mut peersAddresses = vec![];
-        for i in x.step_by(6) {
-            let port_bytes = [v[i + 4], v[i + 5]];
+        for i in x.step_by(peer_len) {
+            let port_bytes = [v[i + ip_len], v[i + ip_len + 1]];
             let mut port_bytes = &port_bytes[..];
             let port = ReadBytesExt::read_u16::<BigEndian>(&mut port_bytes)?;
-            let socket_adr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(v[i], v[i + 1], v[i + 2], v[i + 3])), port);
-            peersAddresses.push(socket_adr);
+            let ip = match <[u8; 16]>::try_from(&v[i..i + ip_len]) {
+                Ok(ip) => IpAddr::V6(Ipv6Addr::from(ip)),
+                Err(_) => IpAddr::V4(Ipv4Addr::new(v[i], v[i + 1], v[i + 2], v[i + 3])),
+            };
+            peersAddresses.push(SocketAddr::new(ip, port));
         }
 
         Ok(AnnounceResponse {
@@ -195,7 +221,7 @@ impl AnnounceResponse {
 mod tests {
     use super::AnnounceResponse;
     use bytes::{BufMut, BytesMut};
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
     #[test]
     fn parses_udp_announce_response_peers_without_port_overflow() {
@@ -229,4 +255,24 @@ mod tests {
 
         assert!(AnnounceResponse::from(&bytes).is_err());
     }
+
+    #[test]
+    fn parses_udp_announce_response_ipv6_peers() {
+        let mut bytes = BytesMut::new();
+        bytes.put_i32(1);
+        bytes.put_i32(42);
+        bytes.put_i32(1800);
+        bytes.put_i32(0);
+        bytes.put_i32(1);
+        bytes.put_slice(&Ipv6Addr::LOCALHOST.octets());
+        bytes.put_u16(51413);
+
+        let response = AnnounceResponse::from_ipv6(&bytes).expect("valid IPv6 announce response");
+
+        assert_eq!(
+            response.peersAddresses,
+            vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 51413)]
+        );
+        assert!(AnnounceResponse::from_ipv6(&bytes[..30]).is_err());
+    }
 }
@@ -1,9 +1,9 @@
 use byteorder::{BigEndian, ReadBytesExt};
-use std::error;
 
-/// Struct to handle response message from "Connect" request to the UDP Tracker
-/// Used to create an instance of AnnounceRequest
-/// Connect Response Bytes Structure from the UDP Tracker Protocol :
+/// Struct to handle the error message a UDP Tracker replies with, in place of the response to any
+/// of the requests
+///
+/// Error Response Bytes Structure from the UDP Tracker Protocol :
 ///
 /// Offset  Size            Name            Value
 /// 0       32-bit integer  action          3 // error
@@ -17,14 +17,20 @@ pub struct ErrorResponse {
 }
 
 impl ErrorResponse {
-    pub fn from(v: &[u8]) -> Result<ErrorResponse, Box<dyn error::Error>> {
-        let mut action_bytes = &v[0..=3];
-        let mut transaction_id_bytes = &v[4..=7];
-        let message_bytes = v[8..(v.len())].to_vec();
+    /// Creates an ErrorResponse from the given buffer, a message that isn't valid UTF-8 is decoded
+    /// lossily rather than rejected, it's only ever shown to the user
+    pub fn from(v: &[u8]) -> Result<ErrorResponse, std::io::Error> {
+        if v.len() < 8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("error response must be at least 8 bytes, got {}", v.len()),
+            ));
+        }
 
-        let action = ReadBytesExt::read_i32::<BigEndian>(&mut action_bytes).unwrap();
-        let transaction_id = ReadBytesExt::read_i32::<BigEndian>(&mut transaction_id_bytes).unwrap();
-        let message = String::from_utf8(message_bytes)?;
+        let mut bytes = v;
+        let action = ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?;
+        let transaction_id = ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?;
+        let message = String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string();
 
         Ok(Self {
             action,
@@ -33,3 +39,28 @@ impl ErrorResponse {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorResponse;
+    use bytes::{BufMut, BytesMut};
+
+    #[test]
+    fn parses_udp_error_response_message() {
+        let mut bytes = BytesMut::new();
+        bytes.put_i32(3);
+        bytes.put_i32(42);
+        bytes.put_slice(b"torrent not registered\0");
+
+        let response = ErrorResponse::from(&bytes).expect("valid error response");
+
+        assert_eq!(response.action, 3);
+        assert_eq!(response.transaction_id, 42);
+        assert_eq!(response.message, "torrent not registered");
+    }
+
+    #[test]
+    fn rejects_truncated_udp_error_response() {
+        assert!(ErrorResponse::from(&[0, 0, 0, 3, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use super::{scrape_batch::UdpScrapeBatches, Tracker, CONNECTION_ID_LIFETIME};
+use crate::core::dht::DhtNode;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{sleep, timeout},
+};
+use tracing::{debug, info, warn};
+
+/// How long receiving waits after the socket failed, it doubles with every failure in a row
+const RECEIVE_ERROR_DELAY: Duration = Duration::from_millis(50);
+
+/// Longest receiving waits after the socket failed
+const MAX_RECEIVE_ERROR_DELAY: Duration = Duration::from_secs(5);
+
+/// The UDP socket every torrent of the engine talks to its UDP trackers over, along with the DHT
+/// node sharing it. The trackers resolved to IPv6 addresses are talked to over a second socket,
+/// bound to `[::]`, when the system has IPv6
+///
+/// Every request is registered by its transaction id until it's answered or times out, a response
+/// is handed to the request with its transaction id only, when it comes from the address the request
+/// was sent to, and the packets no request waits for are dropped. The connection id a tracker gives is kept for the other torrents announcing to it, so
+/// each tracker is connected to once a minute at most rather than once per torrent
+#[derive(Debug)]
+pub struct TrackerManager {
+    socket: Arc<UdpSocket>,
+
+    socket_v6: Option<UdpSocket>,
+
+    dht: Arc<DhtNode>,
+
+    /// The requests waiting for their response, by their transaction id
+    transactions: Mutex<HashMap<i32, PendingRequest>>,
+
+    /// The connection id each tracker address gave, with when it was received
+    connections: Mutex<HashMap<SocketAddr, (i64, Instant)>>,
+
+    /// The UDP trackers of every running torrent, by URL
+    scrape_batches: UdpScrapeBatches,
+}
+
+impl TrackerManager {
+    /// Binds the socket to the first free port of 6881-6999, or to any port when they're all
+    /// taken, and creates the DHT node on it from the node table of the download directory
+    pub async fn bind(download_directory: &Path) -> Result<Arc<Self>, io::Error> {
+        let socket = Arc::new(Self::bind_socket().await?);
+        let socket_v6 = match UdpSocket::bind("[::]:0").await {
+            Ok(socket_v6) => Some(socket_v6),
+            Err(error) => {
+                debug!(error = %error, "no IPv6 tracker socket, IPv6 trackers won't be reached");
+                None
+            }
+        };
+        let dht = Arc::new(DhtNode::new(socket.clone(), download_directory).await);
+        let manager = Self::with_dht(socket, socket_v6, dht);
+        info!(port = manager.port()?, ipv6 = manager.socket_v6.is_some(), "bound tracker socket");
+        Ok(manager)
+    }
+
+    async fn bind_socket() -> Result<UdpSocket, io::Error> {
+        for port in 6881..=6999 {
+            if let Ok(socket) = UdpSocket::bind(format!("0.0.0.0:{port}")).await {
+                return Ok(socket);
+            }
+        }
+        UdpSocket::bind("0.0.0.0:0").await
+    }
+
+    pub fn with_dht(socket: Arc<UdpSocket>, socket_v6: Option<UdpSocket>, dht: Arc<DhtNode>) -> Arc<Self> {
+        Arc::new(Self {
+            socket,
+            socket_v6,
+            dht,
+            transactions: Mutex::default(),
+            connections: Mutex::default(),
+            scrape_batches: UdpScrapeBatches::default(),
+        })
+    }
+
+    /// A manager on its own localhost sockets
+    #[cfg(test)]
+    pub(crate) async fn bind_local() -> Arc<Self> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("socket should bind"));
+        let socket_v6 = UdpSocket::bind("[::1]:0").await.ok();
+        let dht = Arc::new(DhtNode::with_id(socket.clone(), rand::random()));
+        Self::with_dht(socket, socket_v6, dht)
+    }
+
+    pub fn port(&self) -> Result<u16, io::Error> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    pub fn dht(&self) -> Arc<DhtNode> {
+        self.dht.clone()
+    }
+
+    /// Whether there's a socket to send requests to the given address from
+    pub fn reaches(&self, remote: &SocketAddr) -> bool {
+        remote.is_ipv4() || self.socket_v6.is_some()
+    }
+
+    fn socket_for(&self, remote: &SocketAddr) -> Result<&UdpSocket, io::Error> {
+        match (remote, self.socket_v6.as_ref()) {
+            (SocketAddr::V4(_), _) => Ok(&self.socket),
+            (SocketAddr::V6(_), Some(socket_v6)) => Ok(socket_v6),
+            (SocketAddr::V6(_), None) => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "there's no IPv6 socket to reach the tracker from",
+            )),
+        }
+    }
+
+    /// Sends a request to the tracker at the given address and waits for the response with the
+    /// same transaction id, for as long as the given timeout
+    ///
+    /// Error :
+    /// TimedOut when there's no response in time, AlreadyExists when another request with the same
+    /// transaction id is still waiting, or the IO error of sending the request
+    pub async fn request(&self, request: &[u8], transaction_id: i32, remote: SocketAddr, wait: Duration) -> Result<Vec<u8>, io::Error> {
+        // The request is forgotten however this ends, a response coming in later is stray
+        let _forget = ForgetOnDrop {
+            transactions: &self.transactions,
+            transaction_id,
+        };
+        let (response_sender, response) = oneshot::channel();
+        {
+            let mut transactions = self.transactions.lock().unwrap_or_else(PoisonError::into_inner);
+            if transactions.contains_key(&transaction_id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("a request with transaction id {transaction_id} is already pending"),
+                ));
+            }
+            transactions.insert(
+                transaction_id,
+                PendingRequest {
+                    remote,
+                    deadline: Instant::now() + wait,
+                    response: response_sender,
+                },
+            );
+        }
+        self.socket_for(&remote)?.send_to(request, remote).await?;
+        match timeout(wait, response).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "tracker request was dropped")),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("tracker didn't answer in {} seconds", wait.as_secs()),
+            )),
+        }
+    }
+
+    /// The connection id the tracker at the given address gave, if it can still be used, along
+    /// with when it was received
+    pub fn connection_id(&self, remote: SocketAddr) -> Option<(i64, Instant)> {
+        let connections = self.connections.lock().unwrap_or_else(PoisonError::into_inner);
+        connections
+            .get(&remote)
+            .copied()
+            .filter(|(_, connected_at)| connected_at.elapsed() < CONNECTION_ID_LIFETIME)
+    }
+
+    /// Keeps the connection id the tracker at the given address just gave
+    pub fn connected(&self, remote: SocketAddr, connection_id: i64) -> Instant {
+        let connected_at = Instant::now();
+        let mut connections = self.connections.lock().unwrap_or_else(PoisonError::into_inner);
+        connections.retain(|_, (_, connected_at)| connected_at.elapsed() < CONNECTION_ID_LIFETIME);
+        connections.insert(remote, (connection_id, connected_at));
+        connected_at
+    }
+
+    /// Makes the UDP tracker part of the scrapes of the other torrents that use it
+    pub fn register_udp_scrapes(&self, tracker: &Arc<Tracker>) {
+        self.scrape_batches.register(tracker);
+    }
+
+    /// Trackers of other torrents with the same URL as the given one that are due a scrape, see
+    /// [UdpScrapeBatches::batch_for]
+    pub fn scrape_batch(&self, tracker: &Tracker) -> Vec<Arc<Tracker>> {
+        self.scrape_batches.batch_for(tracker)
+    }
+
+    /// Receives on the sockets for as long as they're open, the KRPC packets go to the DHT node and
+    /// the tracker responses to whoever waits for their transaction id
+    pub async fn run(&self) {
+        match self.socket_v6 {
+            Some(ref socket_v6) => {
+                tokio::join!(self.receive(&self.socket), self.receive(socket_v6));
+            }
+            None => self.receive(&self.socket).await,
+        }
+    }
+
+    async fn receive(&self, socket: &UdpSocket) {
+        let mut buf = [0; 4096];
+        let mut error_delay = RECEIVE_ERROR_DELAY;
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => {
+                    error_delay = RECEIVE_ERROR_DELAY;
+                    received
+                }
+                Err(error) => {
+                    warn!(error = %error, retry_after_ms = error_delay.as_millis() as u64, "failed to receive on tracker socket");
+                    sleep(error_delay).await;
+                    error_delay = (error_delay * 2).min(MAX_RECEIVE_ERROR_DELAY);
+                    continue;
+                }
+            };
+            let packet = &buf[..len];
+            if DhtNode::is_krpc(packet) {
+                self.dht.handle_packet(packet, from).await;
+                continue;
+            }
+            if len < 8 {
+                debug!(remote = %from, len, "dropping stray UDP packet");
+                continue;
+            }
+
+            let transaction_id = i32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+            let pending = {
+                let mut transactions = self.transactions.lock().unwrap_or_else(PoisonError::into_inner);
+                // Only the tracker the request went to answers it, the request keeps waiting for it
+                match transactions.get(&transaction_id) {
+                    Some(pending) if pending.remote != from => {
+                        debug!(remote = %from, expected = %pending.remote, transaction_id, "dropping UDP packet from another address");
+                        continue;
+                    }
+                    _ => transactions.remove(&transaction_id),
+                }
+            };
+            match pending {
+                Some(pending) if pending.deadline < Instant::now() => {
+                    debug!(remote = %from, transaction_id, "dropping late UDP tracker response");
+                }
+                Some(pending) => {
+                    let _ = pending.response.send(packet.to_vec());
+                }
+                None => debug!(remote = %from, transaction_id, len, "dropping stray UDP packet"),
+            }
+        }
+    }
+}
+
+/// A request sent through the [TrackerManager], waiting for its response
+#[derive(Debug)]
+struct PendingRequest {
+    /// Address the request was sent to, the response comes from there
+    remote: SocketAddr,
+
+    /// When the request times out, a response after it isn't taken
+    deadline: Instant,
+
+    response: oneshot::Sender<Vec<u8>>,
+}
+
+/// Removes a request from the registry once it's done with, answered or not
+struct ForgetOnDrop<'a> {
+    transactions: &'a Mutex<HashMap<i32, PendingRequest>>,
+    transaction_id: i32,
+}
+
+impl Drop for ForgetOnDrop<'_> {
+    fn drop(&mut self) {
+        let mut transactions = self.transactions.lock().unwrap_or_else(PoisonError::into_inner);
+        // Once answered, the transaction id may be taken by a newer request already
+        if transactions
+            .get(&self.transaction_id)
+            .is_some_and(|pending| pending.response.is_closed())
+        {
+            transactions.remove(&self.transaction_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrackerManager;
+    use std::{io, sync::Arc, time::Duration};
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn hands_each_response_to_the_request_with_its_transaction_id() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 64];
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (len, client) = tracker_socket.recv_from(&mut buf).await.expect("request should arrive");
+                requests.push((buf[..len].to_vec(), client));
+            }
+            let client = requests[0].1;
+            // A packet nobody asked for comes first, then the responses in the reverse order
+            tracker_socket
+                .send_to(&[0, 0, 0, 1, 0, 0, 0, 99, 0xff], client)
+                .await
+                .expect("stray packet should send");
+            for (request, client) in requests.iter().rev() {
+                let mut response = request[..8].to_vec();
+                response.push(request[3]);
+                tracker_socket.send_to(&response, *client).await.expect("response should send");
+            }
+        });
+
+        let manager = spawn_manager().await;
+        let wait = Duration::from_secs(5);
+        let first = manager.request(&[0, 0, 0, 1, 0, 0, 0, 1], 1, tracker_address, wait);
+        let second = manager.request(&[0, 0, 0, 2, 0, 0, 0, 2], 2, tracker_address, wait);
+        let (first, second) = tokio::join!(first, second);
+
+        assert_eq!(first.expect("first request should be answered"), vec![0, 0, 0, 1, 0, 0, 0, 1, 1]);
+        assert_eq!(second.expect("second request should be answered"), vec![0, 0, 0, 2, 0, 0, 0, 2, 2]);
+        assert!(manager.transactions.lock().expect("registry should lock").is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgets_requests_that_time_out() {
+        let silent_tracker = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = silent_tracker.local_addr().expect("tracker should have address");
+
+        let manager = spawn_manager().await;
+        let request = [0, 0, 0, 0, 0, 0, 0, 7];
+        let pending = manager.request(&request, 7, tracker_address, Duration::from_secs(5));
+        let duplicate_request = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            manager.request(&request, 7, tracker_address, Duration::from_secs(5)).await
+        };
+        // The pending request is dropped once the duplicate is refused, it's forgotten with it
+        let duplicate = tokio::select! {
+            _ = pending => panic!("the silent tracker shouldn't answer"),
+            duplicate = duplicate_request => duplicate,
+        };
+        assert_eq!(duplicate.expect_err("transaction id is taken").kind(), io::ErrorKind::AlreadyExists);
+        assert!(manager.transactions.lock().expect("registry should lock").is_empty());
+
+        let error = manager
+            .request(&request, 7, tracker_address, Duration::from_millis(20))
+            .await
+            .expect_err("the silent tracker shouldn't answer");
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(manager.transactions.lock().expect("registry should lock").is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_responses_from_addresses_the_request_didnt_go_to() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.expect("spoofer should bind");
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 64];
+            let (len, client) = tracker_socket.recv_from(&mut buf).await.expect("request should arrive");
+            // Someone else answers first with the transaction id of the request
+            spoofer
+                .send_to(&[0, 0, 0, 1, 0, 0, 0, 3, 0xee], client)
+                .await
+                .expect("spoofed packet should send");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut response = buf[..len].to_vec();
+            response.push(1);
+            tracker_socket.send_to(&response, client).await.expect("response should send");
+        });
+
+        let manager = spawn_manager().await;
+        let response = manager
+            .request(&[0, 0, 0, 1, 0, 0, 0, 3], 3, tracker_address, Duration::from_secs(5))
+            .await
+            .expect("the tracker should answer");
+
+        assert_eq!(response, vec![0, 0, 0, 1, 0, 0, 0, 3, 1]);
+    }
+
+    async fn spawn_manager() -> Arc<TrackerManager> {
+        let manager = TrackerManager::bind_local().await;
+        let receiver = manager.clone();
+        tokio::spawn(async move { receiver.run().await });
+        manager
+    }
+}
@@ -1,12 +1,21 @@
-// TODO: Implement DHT
-
 mod announce_req_res;
 mod connect_req_res;
 mod error_res;
+mod manager;
+mod scrape_batch;
+mod scrape_req_res;
+mod tiers;
 
 use self::{
     announce_req_res::{AnnounceRequest, AnnounceResponse},
     connect_req_res::{ConnectRequest, ConnectResponse},
+    error_res::ErrorResponse,
+    scrape_req_res::{ScrapeRequest, ScrapeResponse},
+};
+pub use self::{
+    manager::TrackerManager,
+    scrape_req_res::ScrapeStats,
+    tiers::{TierMode, TrackerTiers},
 };
 use crate::{
     core::{peer::Peer, protocol::PEER_ID, state::State},
@@ -16,28 +25,46 @@ use byteorder::{BigEndian, ReadBytesExt};
 use crossbeam::atomic::AtomicCell;
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
 use std::{
+    collections::HashMap,
     fmt::Display,
     fmt::Write,
     io,
     net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, PoisonError},
     time::{Duration, Instant},
 };
 use thiserror::Error;
 use tokio::{
     net::UdpSocket,
-    sync::{
-        mpsc,
-        mpsc::{UnboundedReceiver, UnboundedSender},
-        Mutex,
-    },
+    select,
+    sync::{mpsc::UnboundedSender, Mutex, Notify},
+    task::JoinSet,
     time::{sleep, timeout},
 };
 use tracing::{debug, info, warn};
 use url::Url;
 
-type UdpTrackerChannel = (UnboundedSender<Vec<u8>>, Arc<Mutex<UnboundedReceiver<Vec<u8>>>>);
+/// How often a tracker is scraped for the swarm counts of its torrent
+const SCRAPE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How long a tracker gets to answer a scrape
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// UDP requests that time out in a row before the tracker is given up on for the next one of its
+/// tier, they take 15 + 30 + 60 seconds
+const UDP_FAILOVER_ATTEMPTS: u32 = 3;
+
+/// How long the connection id of a UDP tracker can be used for, according to BEP-15
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How long a HTTP tracker gets to answer an announce or a scrape
+const HTTP_TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a UDP tracker gets to answer the connect request of a stopped announce
+const STOPPED_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 type TrackerResult<T> = Result<T, TrackerError>;
 
 #[derive(Debug, Error)]
@@ -57,9 +84,15 @@ pub enum TrackerError {
     #[error("tracker returned failure: {0}")]
     TrackerFailure(String),
 
+    #[error("tracker scrape response has no entry for the torrent")]
+    ScrapeMissingTorrent,
+
     #[error("invalid peer IP address in tracker response: {ip}")]
     InvalidPeerIp { ip: String, source: AddrParseError },
 
+    #[error("tracker socket error")]
+    Io(#[from] io::Error),
+
     #[error("compact {family} peer list length must be a multiple of {chunk_size}, got {len}")]
     InvalidCompactPeerList {
         family: &'static str,
@@ -68,6 +101,72 @@ pub enum TrackerError {
     },
 }
 
+/// HTTP trackers are scraped at the URL of their announce, with its last path segment starting with
+/// "scrape" rather than "announce", trackers whose URL doesn't follow that can't be scraped
+struct HttpScrapeCodec;
+
+impl HttpScrapeCodec {
+    fn scrape_url(address: &Url) -> Option<Url> {
+        let last_segment = address.path_segments()?.next_back()?;
+        let rest = last_segment.strip_prefix("announce")?;
+        let scrape_segment = format!("scrape{rest}");
+
+        let mut scrape_url = address.clone();
+        scrape_url.path_segments_mut().ok()?.pop().push(&scrape_segment);
+        Some(scrape_url)
+    }
+
+    fn build_url(scrape_url: &Url, info_hash: &[u8]) -> String {
+        let mut base = scrape_url.clone();
+        let mut query = base.query().map(str::to_owned).unwrap_or_default();
+        base.set_query(None);
+        base.set_fragment(None);
+
+        HttpAnnounceCodec::append_bytes_query_pair(&mut query, "info_hash", info_hash);
+        let mut url = base.to_string();
+        url.push('?');
+        url.push_str(&query);
+        url
+    }
+
+    fn parse_response(bytes: &[u8], info_hash: &[u8]) -> TrackerResult<ScrapeStats> {
+        let response: HttpScrapeResponse = serde_bencode::de::from_bytes(bytes)?;
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerError::TrackerFailure(reason));
+        }
+
+        let file = response
+            .files
+            .into_iter()
+            .find(|(file_info_hash, _)| file_info_hash.as_slice() == info_hash)
+            .map(|(_, file)| file)
+            .ok_or(TrackerError::ScrapeMissingTorrent)?;
+        Ok(ScrapeStats {
+            seeders: file.complete.clamp(0, i32::MAX as i64) as i32,
+            completed: file.downloaded.clamp(0, i32::MAX as i64) as i32,
+            leechers: file.incomplete.clamp(0, i32::MAX as i64) as i32,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HttpScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, HttpScrapeFile>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HttpScrapeFile {
+    #[serde(default)]
+    complete: i64,
+    #[serde(default)]
+    downloaded: i64,
+    #[serde(default)]
+    incomplete: i64,
+}
+
 #[derive(Debug, Deserialize)]
 struct HttpAnnounceResponse {
     interval: Option<i64>,
@@ -93,16 +192,77 @@ struct HttpPeer {
     port: u16,
 }
 
+/// Event of an announce, it tells the tracker what happened to the torrent since the last one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the "event" field of a UDP announce request
+    pub fn udp_code(self) -> i32 {
+        match self {
+            Self::None => 0,
+            Self::Completed => 1,
+            Self::Started => 2,
+            Self::Stopped => 3,
+        }
+    }
+
+    /// Value of the "event" query parameter of a HTTP announce, it's left out for [AnnounceEvent::None]
+    pub fn http_name(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Completed => Some("completed"),
+            Self::Started => Some("started"),
+            Self::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// Transfer totals an announce reports, the uploaded and downloaded bytes are counted from when the
+/// torrent was last started, the left bytes are what's still to be verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AnnounceProgress {
+    uploaded: i64,
+    downloaded: i64,
+    left: i64,
+}
+
+impl AnnounceProgress {
+    fn of(state: &State) -> Self {
+        Self {
+            uploaded: state.session_uploaded() as i64,
+            downloaded: state.session_downloaded() as i64,
+            left: state.meta_info.total_length().saturating_sub(state.bytes_complete() as i64),
+        }
+    }
+}
+
 struct HttpAnnounceCodec;
 
 impl HttpAnnounceCodec {
-    fn build_url(address: &Url, state: &State, port: u16) -> String {
-        let downloaded = state.bytes_complete() as i64;
-        let left = state.meta_info.total_length().saturating_sub(downloaded);
-        Self::build_url_with_values(address, &state.info_hash, downloaded, left, port)
+    fn build_url(address: &Url, state: &State, port: u16, event: AnnounceEvent) -> String {
+        let AnnounceProgress {
+            uploaded,
+            downloaded,
+            left,
+        } = AnnounceProgress::of(state);
+        Self::build_url_with_values(address, &state.info_hash, uploaded, downloaded, left, port, event)
     }
 
-    fn build_url_with_values(address: &Url, info_hash: &[u8], downloaded: i64, left: i64, port: u16) -> String {
+    fn build_url_with_values(
+        address: &Url,
+        info_hash: &[u8],
+        uploaded: i64,
+        downloaded: i64,
+        left: i64,
+        port: u16,
+        event: AnnounceEvent,
+    ) -> String {
         let mut base = address.clone();
         let original_query = base.query().map(str::to_owned);
         base.set_query(None);
@@ -112,12 +272,14 @@ impl HttpAnnounceCodec {
         Self::append_bytes_query_pair(&mut query, "info_hash", info_hash);
         Self::append_bytes_query_pair(&mut query, "peer_id", &PEER_ID);
         Self::append_query_pair(&mut query, "port", port);
-        Self::append_query_pair(&mut query, "uploaded", 0);
+        Self::append_query_pair(&mut query, "uploaded", uploaded.max(0));
         Self::append_query_pair(&mut query, "downloaded", downloaded.max(0));
         Self::append_query_pair(&mut query, "left", left.max(0));
         Self::append_query_pair(&mut query, "compact", 1);
         Self::append_query_pair(&mut query, "numwant", 80);
-        Self::append_query_pair(&mut query, "event", "started");
+        if let Some(event) = event.http_name() {
+            Self::append_query_pair(&mut query, "event", event);
+        }
 
         let mut announce_url = base.to_string();
         announce_url.push('?');
@@ -225,7 +387,7 @@ pub enum TrackerProtocol {
 /// **TCP and UDP** - Tracker state for both UDP and TCP based tracker
 /// **TCP** - Tracker state for only TCP based tracker
 /// **UDP** - Tracker state for only UDP based tracker
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum TrackerState {
     /// Default state of the Tracker, where **NO** action is performed on the tracker
     /// For : **TCP and UDP** Tracker
@@ -260,6 +422,10 @@ pub enum TrackerState {
     /// a response
     /// For : **UDP** Tracker
     WaitingForScrapeResponse,
+
+    /// The tracker replied with an error rather than a response, eg. for a torrent it doesn't track
+    /// For : **TCP and UDP** Tracker
+    Error { message: String },
 }
 
 //impl Display {}
@@ -273,6 +439,7 @@ impl Display for TrackerState {
             Self::WaitingForConnectResponse => write!(f, "Waiting for Connect Response"),
             Self::WaitingForAnnounceResponse => write!(f, "Waiting for Announce Response"),
             Self::WaitingForScrapeResponse => write!(f, "Waiting for Scrape Response"),
+            Self::Error { ref message } => write!(f, "Error: {message}"),
             Self::DNSUnresolved { ref retry_time } => write!(
                 f,
                 "DNSUnresolved ({:?}/30 sec)",
@@ -285,7 +452,7 @@ impl Display for TrackerState {
 /// A tracker in BitTorrent is simply, a "URL", that uses certian request and response technique in
 /// order to get information about peers
 ///
-///One way to avoid using Trackers is using DHT(Distributed Hash Table).
+///The other way of finding peers is the DHT(Distributed Hash Table), see [crate::core::dht].
 #[derive(Debug)]
 pub struct Tracker {
     /// The state of the torrent file
@@ -309,8 +476,6 @@ pub struct Tracker {
     /// from other trackers.
     pub peer_sender: Arc<UnboundedSender<Peer>>,
 
-    pub udp_channel: Option<UdpTrackerChannel>,
-
     /// Data to make connect request
     pub connect_request: Arc<Mutex<TrackerRequest>>,
 
@@ -330,7 +495,24 @@ pub struct Tracker {
     pub scrape_response: Arc<Mutex<TrackerResponse>>,
 
     // TODO : Store UDP Socket here in the struct
-    pub tracker_state: AtomicCell<TrackerState>,
+    tracker_state: std::sync::Mutex<TrackerState>,
+
+    /// Event the next announce sends, it stays "started" until the tracker answers one
+    pub next_event: AtomicCell<AnnounceEvent>,
+
+    /// Set when the download completed while "started" was still pending, "completed" is sent once
+    /// the tracker answered it
+    completed_after_started: AtomicCell<bool>,
+
+    /// Wakes the tracker up to announce before its interval runs out
+    pub reannounce: Notify,
+
+    /// Swarm counts of the torrent from the last scrape of the tracker
+    pub scrape_stats: AtomicCell<Option<ScrapeStats>>,
+
+    /// When the tracker is to be scraped next, the scrape of another torrent on the same tracker
+    /// can do it earlier
+    next_scrape: AtomicCell<Instant>,
 }
 
 impl Tracker {
@@ -350,21 +532,13 @@ impl Tracker {
             "http" | "https" => TrackerProtocol::Http,
             scheme => return Err(TrackerError::UnsupportedProtocol(scheme.to_string())),
         };
-        let (sd, rv) = mpsc::unbounded_channel::<Vec<u8>>();
-
-        let udp_channel = match protocol {
-            TrackerProtocol::Udp => Some((sd, ArcMutex!(rv))),
-            TrackerProtocol::Http => None,
-        };
-
-        let tracker_state = ACell!(TrackerState::Idle);
+        let tracker_state = std::sync::Mutex::new(TrackerState::Idle);
 
         Ok(Tracker {
             torrent_state,
             address,
             socketAddrs: Arc::default(),
             protocol,
-            udp_channel,
             connect_request,
             connect_response,
             announce_request,
@@ -373,9 +547,28 @@ impl Tracker {
             scrape_response,
             peer_sender,
             tracker_state,
+            next_event: ACell!(AnnounceEvent::Started),
+            completed_after_started: ACell!(false),
+            reannounce: Notify::new(),
+            scrape_stats: ACell!(None),
+            next_scrape: ACell!(Instant::now()),
         })
     }
 
+    pub fn tracker_state(&self) -> TrackerState {
+        self.tracker_state.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn set_tracker_state(&self, tracker_state: TrackerState) {
+        *self.tracker_state.lock().unwrap_or_else(PoisonError::into_inner) = tracker_state;
+    }
+
+    /// Keeps the error the tracker replied with, so it's shown instead of the state it was in
+    fn tracker_failed(&self, message: String) {
+        warn!(tracker = %self.address, error = %message, "tracker replied with an error");
+        self.set_tracker_state(TrackerState::Error { message });
+    }
+
     /// Initially we are only given the URL of the tracker, in order to check if the tracker is even
     /// alive or not, we must check if it's IP is availaible or not by simply resolving the
     /// tracker's DNS, that's what this method does, it resolves the DNS of the tracker
@@ -389,23 +582,20 @@ impl Tracker {
             }
         };
 
-        self.tracker_state.store(TrackerState::DNSResolving);
+        self.set_tracker_state(TrackerState::DNSResolving);
         debug!(tracker = %self.address, "resolving tracker DNS");
         if resolveDNS().await {
             let resolved_addresses = self.socketAddrs.lock().await.len();
-            self.tracker_state.store(TrackerState::DNSResolved);
+            self.set_tracker_state(TrackerState::DNSResolved);
             info!(tracker = %self.address, resolved_addresses, "tracker DNS resolved");
         } else {
-            self.tracker_state.store(TrackerState::DNSUnresolved {
+            self.set_tracker_state(TrackerState::DNSUnresolved {
                 retry_time: Instant::now(),
             });
             warn!(tracker = %self.address, "tracker DNS resolution failed");
         }
     }
 
-    // Compares given socket address to the trackers list of socket addresses,
-    // if it matches any one of it, then we can say that the given socket address belongs
-    // to the tracker i.e the socket address is equal to the Tracker
     pub fn is_udp(&self) -> bool {
         self.protocol == TrackerProtocol::Udp
     }
@@ -414,56 +604,66 @@ impl Tracker {
         self.protocol == TrackerProtocol::Http
     }
 
-    pub async fn isEqualTo(&self, sAdr1: &SocketAddr) -> bool {
-        let socket_addresses = self.socketAddrs.lock().await;
-        socket_addresses.iter().any(|sAdr2| sAdr1 == sAdr2)
-    }
-
-    pub async fn run(&self, _socket: Arc<UdpSocket>) {
-        //self.resolveTracker()
+    /// Resolves the tracker and announces to it until it fails, it gives whether the tracker answered
+    /// any announce before that. UDP trackers talk to the tracker through the given manager
+    pub async fn run(&self, manager: &TrackerManager) -> bool {
+        self.resolveTracker().await;
+        let answered = if self.is_udp() {
+            self.run_me(manager).await
+        } else {
+            self.run_http().await
+        };
+        info!(tracker = %self.address, answered, "tracker stopped answering");
+        answered
     }
 
-    pub async fn run_http(&self) {
-        let client = reqwest::Client::new();
-        let mut retry_delay = Duration::from_secs(15);
+    /// Announces to the HTTP tracker at the interval it asks for, until an announce fails
+    async fn run_http(&self) -> bool {
+        let client = reqwest::Client::builder().timeout(HTTP_TRACKER_TIMEOUT).build().unwrap_or_default();
+        let mut answered = false;
 
         loop {
             match self.send_http_announce_request(&client).await {
                 Ok(response) => {
-                    retry_delay = Duration::from_secs(15);
+                    answered = true;
                     let interval = response.interval.max(30) as u64;
                     {
                         let mut announce_response = self.announce_response.lock().await;
                         *announce_response = TrackerResponse::AnnounceResponse(response);
                     }
-                    sleep(Duration::from_secs(interval)).await;
+                    self.promote().await;
+                    if self.is_scrape_due() {
+                        self.scrape_http(&client).await;
+                    }
+                    self.wait_for_reannounce(Duration::from_secs(interval)).await;
+                }
+                Err(TrackerError::TrackerFailure(message)) => {
+                    self.tracker_failed(message);
+                    return answered;
                 }
                 Err(error) => {
-                    self.tracker_state.store(TrackerState::DNSUnresolved {
+                    self.set_tracker_state(TrackerState::DNSUnresolved {
                         retry_time: Instant::now(),
                     });
-                    warn!(
-                        tracker = %self.address,
-                        error = %error,
-                        retry_delay_secs = retry_delay.as_secs(),
-                        "HTTP tracker announce failed"
-                    );
-                    sleep(retry_delay).await;
-                    retry_delay = (retry_delay * 2).min(Duration::from_secs(300));
+                    warn!(tracker = %self.address, error = %error, "HTTP tracker announce failed");
+                    return answered;
                 }
             }
         }
     }
 
     async fn send_http_announce_request(&self, client: &reqwest::Client) -> TrackerResult<AnnounceResponse> {
-        self.tracker_state.store(TrackerState::WaitingForAnnounceResponse);
-        let announce_url = HttpAnnounceCodec::build_url(&self.address, &self.torrent_state, self.announce_port().await);
+        self.set_tracker_state(TrackerState::WaitingForAnnounceResponse);
+        let event = self.next_event.load();
+        let announce_url = HttpAnnounceCodec::build_url(&self.address, &self.torrent_state, self.announce_port().await, event);
         let response_bytes = client.get(announce_url).send().await?.error_for_status()?.bytes().await?;
         let announce_response = HttpAnnounceCodec::parse_response(&response_bytes)?;
+        self.announce_answered(event);
         info!(
             tracker = %self.address,
             peer_count = announce_response.peersAddresses.len(),
             interval = announce_response.interval,
+            ?event,
             "HTTP tracker announce returned peers"
         );
 
@@ -472,230 +672,468 @@ impl Tracker {
             let _ = self.peer_sender.send(peer);
         }
 
-        self.tracker_state.store(TrackerState::DNSResolved);
+        self.set_tracker_state(TrackerState::DNSResolved);
         Ok(announce_response)
     }
 
+    /// Scrapes the HTTP tracker, if its URL has a scrape URL. The next scrape is only after
+    /// [SCRAPE_INTERVAL] either way
+    async fn scrape_http(&self, client: &reqwest::Client) {
+        self.next_scrape.store(Instant::now() + SCRAPE_INTERVAL);
+        match timeout(SCRAPE_TIMEOUT, self.send_http_scrape_request(client)).await {
+            Ok(Ok(Some(stats))) => {
+                info!(tracker = %self.address, ?stats, "HTTP tracker scrape returned swarm counts");
+                self.scrape_stats.store(Some(stats));
+            }
+            Ok(Ok(None)) => debug!(tracker = %self.address, "HTTP tracker has no scrape URL"),
+            Ok(Err(error)) => warn!(tracker = %self.address, error = %error, "HTTP tracker scrape failed"),
+            Err(_) => warn!(tracker = %self.address, "HTTP tracker scrape timed out"),
+        }
+    }
+
+    async fn send_http_scrape_request(&self, client: &reqwest::Client) -> TrackerResult<Option<ScrapeStats>> {
+        let Some(scrape_url) = HttpScrapeCodec::scrape_url(&self.address) else {
+            return Ok(None);
+        };
+        let info_hash = &self.torrent_state.info_hash;
+        let response_bytes = client
+            .get(HttpScrapeCodec::build_url(&scrape_url, info_hash))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        HttpScrapeCodec::parse_response(&response_bytes, info_hash).map(Some)
+    }
+
+    /// Moves on to regular announces once the tracker answered the one that sent `event`, unless
+    /// another event came up in the meantime. A "completed" held back by "started" goes out next
+    fn announce_answered(&self, event: AnnounceEvent) {
+        if event != AnnounceEvent::None
+            && self.next_event.compare_exchange(event, AnnounceEvent::None).is_ok()
+            && event == AnnounceEvent::Started
+            && self.completed_after_started.swap(false)
+        {
+            self.send_completed();
+        }
+    }
+
+    /// Has the tracker send "completed" right away rather than at the next interval
+    ///
+    /// While the "started" announce is still pending, "completed" is held back until the tracker
+    /// answers it, trackers only count a completed download of a peer they know started
+    pub fn announce_completed(&self) {
+        if self.next_event.load() == AnnounceEvent::Started {
+            self.completed_after_started.store(true);
+            if self.next_event.load() == AnnounceEvent::Started {
+                debug!(tracker = %self.address, "completed download waits for the started announce");
+                return;
+            }
+            // The started announce was answered meanwhile, whoever takes the flag back announces
+            if !self.completed_after_started.swap(false) {
+                return;
+            }
+        }
+        self.send_completed();
+    }
+
+    fn send_completed(&self) {
+        if self
+            .next_event
+            .compare_exchange(AnnounceEvent::None, AnnounceEvent::Completed)
+            .is_ok()
+        {
+            debug!(tracker = %self.address, "announcing completed download");
+            self.reannounce.notify_one();
+        }
+    }
+
+    /// Sleeps for the announce interval, or until there's an event to announce
+    async fn wait_for_reannounce(&self, interval: Duration) {
+        select! {
+            _ = sleep(interval) => {}
+            _ = self.reannounce.notified() => {}
+        }
+    }
+
+    /// The port peers should connect to, it's the port of the inbound peer listener when the
+    /// torrent has one
     async fn announce_port(&self) -> u16 {
+        if let Some(port) = self.torrent_state.tcp_ports.lock().await.first() {
+            return *port;
+        }
         let ports = self.torrent_state.udp_ports.lock().await;
         ports.first().copied().unwrap_or(6881)
     }
 
-    /// Starts running the tracker
-    /// socket => Socket through which the tracker will send UDP request and receive UDP response
-    pub async fn run_me(&self, socket: Arc<UdpSocket>) {
-        // A timeout duration for all types of responses
-        let timeout_duration = |n: u64| Duration::from_secs(15_u64.saturating_mul(1_u64 << n.min(8)));
-
-        let mut no_of_times_connect_request_timeout = 0;
+    /// Runs the UDP tracker, it connects and announces at the interval the tracker asks for, until
+    /// the tracker fails i.e it replies with an error or it times out [UDP_FAILOVER_ATTEMPTS] times
+    /// in a row. It gives whether the tracker answered any announce
+    ///
+    /// manager => Sends the UDP requests of the tracker and hands it their responses, the
+    /// connection id another torrent got from the same tracker is used rather than connecting again
+    async fn run_me(&self, manager: &TrackerManager) -> bool {
+        // A timeout duration for all types of responses, according to BEP-15 it's 15 * 2 ^ n
+        // seconds, n being the number of timeouts in a row
+        let timeout_duration = |n: u32| Duration::from_secs(15_u64.saturating_mul(1_u64 << n.min(8)));
+
+        let mut answered = false;
+        let mut no_of_times_request_timeout = 0;
+
+        while no_of_times_request_timeout < UDP_FAILOVER_ATTEMPTS {
+            let remote = match self.remote_address(manager, "connect").await {
+                Ok(remote) => remote,
+                Err(error) => {
+                    warn!(tracker = %self.address, error = %error, "UDP tracker connect failed");
+                    return answered;
+                }
+            };
 
-        loop {
-            let sendConReq = self.sendConnectRequest(socket.clone());
-            match timeout(timeout_duration(no_of_times_connect_request_timeout), sendConReq).await {
-                Ok(v) => match v {
-                    // TODO : Replace with get_connect_response();
-                    Ok(_) => {
-                        if let Some(res) = self.getResponse().await {
-                            // Save the received ConnectResponse in self.connect_response
-                            {
-                                let mut connect_response = self.connect_response.lock().await;
-                                *connect_response = res;
-                            }
+            // According to BEP-15, client can use a Connection ID until one minute after it has received it.
+            //
+            // This means we can't use the connection_id stored inside of ConnectResponse after 1 minute
+            let (connection_id, connected_at) = match manager.connection_id(remote) {
+                Some(connection) => {
+                    debug!(tracker = %self.address, "reusing UDP tracker connection id");
+                    connection
+                }
+                None => {
+                    self.set_tracker_state(TrackerState::WaitingForConnectResponse);
+                    match self
+                        .sendConnectRequest(manager, timeout_duration(no_of_times_request_timeout))
+                        .await
+                    {
+                        Ok(TrackerResponse::ConnectResponse(res)) => {
                             debug!(tracker = %self.address, "UDP tracker connect response received");
-
-                            // According to BEP-15, client can use a Connection ID until one minute after it has received it.
-                            //
-                            // This means we can't use the connection_id stored inside of ConnectResponse after 1 minute
-                            let connection_id_timeout_duration = Duration::from_secs(60);
-                            let duration_since_connect_response = Instant::now();
-                            let mut no_of_times_announce_request_timeout = 0;
-
-                            'announce: loop {
-                                let now = Instant::now();
-                                let now = now.duration_since(duration_since_connect_response);
-
-                                if now <= connection_id_timeout_duration {
-                                    match timeout(
-                                        timeout_duration(no_of_times_announce_request_timeout),
-                                        self.send_announce_request(socket.clone()),
-                                    )
-                                    .await
-                                    {
-                                        Ok(vv) => {
-                                            match vv {
-                                                // TODO : Replace with get_announce_response();
-                                                Ok(_) => match self.getResponse().await {
-                                                    Some(res) => {
-                                                        if let TrackerResponse::AnnounceResponse(ref ar) = res {
-                                                            //println!("The interval is {}", ar.interval);
-                                                            let sleep_duration = Duration::from_secs(ar.interval as u64);
-                                                            info!(
-                                                                tracker = %self.address,
-                                                                peer_count = ar.peersAddresses.len(),
-                                                                interval = ar.interval,
-                                                                "UDP tracker announce returned peers"
-                                                            );
-                                                            {
-                                                                for peer_socket_adr in ar.peersAddresses.clone() {
-                                                                    let peer = Peer::new(peer_socket_adr, self.torrent_state.clone());
-                                                                    let _ = self.peer_sender.send(peer);
-                                                                }
-                                                                let mut announce_response = self.announce_response.lock().await;
-                                                                *announce_response = res;
-                                                            }
-                                                            sleep(sleep_duration).await;
-                                                            break 'announce;
-                                                        }
-
-                                                        // Save the received AnnounceResponse in self.announannounce_response
-                                                    }
-                                                    None => {
-                                                        //println!("GOT ERROR");
-                                                    }
-                                                },
-                                                Err(_e) => {
-                                                    // Error while sending AnnounceRequest, probably some kind of socket issue
-                                                    //println!("CONNECT REQUEST SOCKET ISSUE {:?}", e.to_string());
-                                                    sleep(Duration::from_secs(1000)).await;
-                                                    // TODO : Replace this with some actual solution rather than sleeping
-                                                }
-                                            }
-                                        }
-                                        Err(_) => {
-                                            if no_of_times_announce_request_timeout <= 8 {
-                                                no_of_times_announce_request_timeout += 1;
-                                            }
-                                            warn!(
-                                                tracker = %self.address,
-                                                attempt = no_of_times_announce_request_timeout,
-                                                "UDP tracker announce timed out"
-                                            );
-                                            // Announce Request timeout error
-                                        }
-                                    }
-                                }
-                            }
+                            let connection_id = res.connection_id;
+                            *self.connect_response.lock().await = TrackerResponse::ConnectResponse(res);
+                            (connection_id, manager.connected(remote, connection_id))
+                        }
+                        Ok(TrackerResponse::Error(error)) => {
+                            self.tracker_failed(error.message);
+                            return answered;
+                        }
+                        // A reply the request can't be answered with counts as no reply at all
+                        Ok(_) => {
+                            no_of_times_request_timeout += 1;
+                            warn!(tracker = %self.address, "UDP tracker sent an invalid connect response");
+                            continue;
+                        }
+                        Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                            no_of_times_request_timeout += 1;
+                            warn!(
+                                tracker = %self.address,
+                                attempt = no_of_times_request_timeout,
+                                "UDP tracker connect timed out"
+                            );
+                            continue;
+                        }
+                        Err(error) => {
+                            // Error while sending ConnectRequest, probably some kind of socket issue
+                            warn!(tracker = %self.address, error = %error, "UDP tracker connect failed");
+                            return answered;
                         }
                     }
-                    Err(_e) => {
-                        // Error while sending ConnectRequest, probably some kind of socket issue
-                        //println!("CONNECT REQUEST SOCKET ISSUE {:?}", e.to_string());
-                        sleep(Duration::from_secs(1000)).await;
+                }
+            };
 
-                        // TODO : Replace this with some actual solution rather than sleeping
+            while connected_at.elapsed() <= CONNECTION_ID_LIFETIME && no_of_times_request_timeout < UDP_FAILOVER_ATTEMPTS {
+                self.set_tracker_state(TrackerState::WaitingForAnnounceResponse);
+                let announce = self.send_announce_request(manager, connection_id, timeout_duration(no_of_times_request_timeout));
+                match announce.await {
+                    Ok((event, TrackerResponse::AnnounceResponse(ar))) => {
+                        answered = true;
+                        no_of_times_request_timeout = 0;
+                        self.announce_answered(event);
+                        self.set_tracker_state(TrackerState::DNSResolved);
+                        info!(
+                            tracker = %self.address,
+                            peer_count = ar.peersAddresses.len(),
+                            interval = ar.interval,
+                            ?event,
+                            "UDP tracker announce returned peers"
+                        );
+                        for peer_socket_adr in ar.peersAddresses.clone() {
+                            let peer = Peer::new(peer_socket_adr, self.torrent_state.clone());
+                            let _ = self.peer_sender.send(peer);
+                        }
+                        let interval = Duration::from_secs(ar.interval.max(0) as u64);
+                        *self.announce_response.lock().await = TrackerResponse::AnnounceResponse(ar);
+                        self.promote().await;
+
+                        if self.is_scrape_due() {
+                            self.scrape(manager, connection_id).await;
+                        }
+                        self.wait_for_reannounce(interval).await;
+                        // The connection id has most likely expired by now
+                        break;
+                    }
+                    Ok((_, TrackerResponse::Error(error))) => {
+                        self.tracker_failed(error.message);
+                        return answered;
+                    }
+                    Ok(_) => {
+                        no_of_times_request_timeout += 1;
+                        warn!(tracker = %self.address, "UDP tracker sent an invalid announce response");
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                        no_of_times_request_timeout += 1;
+                        warn!(
+                            tracker = %self.address,
+                            attempt = no_of_times_request_timeout,
+                            "UDP tracker announce timed out"
+                        );
                     }
-                },
-                Err(_) => {
-                    //println!("GOT TIMEOUT ERROR FOR CONNECT RESPONSE");
-                    // Connect Request timeout error
-                    if no_of_times_connect_request_timeout <= 8 {
-                        no_of_times_connect_request_timeout += 1;
+                    Err(error) => {
+                        // Error while sending AnnounceRequest, probably some kind of socket issue
+                        warn!(tracker = %self.address, error = %error, "UDP tracker announce failed");
+                        return answered;
                     }
-                    warn!(
-                        tracker = %self.address,
-                        attempt = no_of_times_connect_request_timeout,
-                        "UDP tracker connect timed out"
-                    );
                 }
             }
-            //println!("ENDED PRETTY QUICK");
         }
+        answered
     }
 
-    /// Creates a ConnectRequest instance and tries to send it through the given UDP Socket to the
-    /// given UDP Socket Address
-    ///
-    /// If ConnectRequest is sent, then instance of ConnectRequest is stored in [Tracker] "connect_req" field
+    /// Moves the tracker to the front of its tier, as BEP12 asks for a tracker that answered, so
+    /// it's the first one tried from then on
+    async fn promote(&self) {
+        let mut tiers = self.torrent_state.trackers.write().await;
+        for tier in tiers.iter_mut() {
+            if let Some(index) = tier.iter().position(|tracker| std::ptr::eq(tracker.as_ref(), self)) {
+                if index > 0 {
+                    let tracker = tier.remove(index);
+                    tier.insert(0, tracker);
+                    debug!(tracker = %self.address, "moved tracker to the front of its tier");
+                }
+                return;
+            }
+        }
+    }
+
+    /// The address the UDP requests of the tracker are sent to, the first resolved address the
+    /// manager has a socket for, so an IPv6 address is skipped when there's no IPv6 socket
     ///
-    /// Error :
-    /// The only error is IO error passed by tokio::net::UDPSocket
+    /// TODO : Let's say it contains 10 socket addresses in self.socketAddrs, how to to decide
+    /// which one to use. One can use the exact concept that a browser makes use of in order to
+    /// decide the socket address to be used right now and in the future, as a round robin or ?.
+    /// Currenlty we are deciding to use the first reachable one
+    async fn remote_address(&self, manager: &TrackerManager, request: &str) -> Result<SocketAddr, io::Error> {
+        self.socketAddrs
+            .lock()
+            .await
+            .iter()
+            .find(|remote| manager.reaches(remote))
+            .copied()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("tracker has no reachable socket addresses for {request} request"),
+                )
+            })
+    }
+
+    /// Creates a ConnectRequest instance and sends it through the given manager to the tracker, it
+    /// gives the response the tracker answered it with
     ///
-    /// Panic :
-    /// Panic might occur if there is no any Vector inside of self.socketAddrs  
-    /// Even if there is a vector, if there is no item at index 0, then a panic will occur
+    /// The ConnectRequest is stored in [Tracker] "connect_request" field
     ///
-    pub async fn sendConnectRequest(&self, socket: Arc<UdpSocket>) -> Result<(), io::Error> {
+    /// Error :
+    /// The IO error passed by tokio::net::UDPSocket, a TimedOut one when the tracker doesn't answer
+    /// within `wait`, or an AddrNotAvailable one when the DNS of the tracker wasn't resolved
+    pub async fn sendConnectRequest(&self, manager: &TrackerManager, wait: Duration) -> Result<TrackerResponse, io::Error> {
         let connect_req = ConnectRequest::new();
         let connect_req_bytes = connect_req.serializeToBytes();
-        // Assumption : Only those Trackers come upto this point whose DNS is resolved from the given URL and contains atleast one resolved socket address
-        // that's why unwrap() is called without any worries;
-        //
-        // TODO : Let's say it contains 10 socket addresses in self.socketAddrs, how to to decide
-        // which one to use
-        //
-        // NOTE : One can use the exact concept that a browser makes use of in order to decide the
-        // socket address to be used right now and in the future, as a round robin or ?. Currenlty
-        // we are deciding to use the socket at the index 0
-        let socketAddrs = {
-            let socket_addresses = self.socketAddrs.lock().await;
-            // TODO : Make choosable among other indices too
-            socket_addresses.first().copied().ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::AddrNotAvailable,
-                    "tracker has no resolved socket addresses for connect request",
-                )
-            })?
+        let remote = self.remote_address(manager, "connect").await?;
+
+        let transaction_id = connect_req.transaction_id;
+        *self.connect_request.lock().await = TrackerRequest::ConnectRequest(connect_req);
+        debug!(tracker = %self.address, remote = %remote, "sending UDP tracker connect request");
+        let response = manager.request(&connect_req_bytes, transaction_id, remote, wait).await?;
+        Ok(self.response_of(&response, remote).await)
+    }
+
+    /// Sends the AnnounceRequest for the next event through the given manager, the event is
+    /// returned along with the response, so it can be marked as announced once the tracker answers
+    pub async fn send_announce_request(
+        &self,
+        manager: &TrackerManager,
+        connection_id: i64,
+        wait: Duration,
+    ) -> Result<(AnnounceEvent, TrackerResponse), io::Error> {
+        let event = self.next_event.load();
+        let announce_req = self.announce_request(connection_id, rand::random(), event).await;
+        let (Some(announce_req_bytes), Some(transaction_id)) = (announce_req.serialize_to_bytes(), announce_req.transaction_id) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "announce request is missing fields"));
         };
+        let remote = self.remote_address(manager, "announce").await?;
+
+        *self.announce_request.lock().await = TrackerRequest::AnnounceRequest(announce_req);
+        debug!(tracker = %self.address, remote = %remote, ?event, "sending UDP tracker announce request");
+        let response = manager.request(&announce_req_bytes, transaction_id, remote, wait).await?;
+        Ok((event, self.response_of(&response, remote).await))
+    }
+
+    /// Fills an AnnounceRequest with the event and progress of the torrent, for the given connection
+    async fn announce_request(&self, connection_id: i64, transaction_id: i32, event: AnnounceEvent) -> AnnounceRequest {
+        let mut announce_req = AnnounceRequest::new();
+        announce_req.set_connection_id(connection_id);
+        announce_req.set_transaction_id(transaction_id);
+        announce_req.set_info_hash(&self.torrent_state.info_hash);
+        let progress = AnnounceProgress::of(&self.torrent_state);
+        announce_req.set_downloaded(progress.downloaded);
+        announce_req.set_uploaded(progress.uploaded);
+        announce_req.set_left(progress.left);
+        announce_req.set_event(event.udp_code());
+        announce_req.set_port(self.announce_port().await as i16);
+        announce_req.set_key(rand::random());
+        announce_req
+    }
+
+    fn is_scrape_due(&self) -> bool {
+        !self.torrent_state.is_stopped() && self.next_scrape.load() <= Instant::now()
+    }
+
+    /// Scrapes the tracker over the given connection, the torrents of other trackers with the
+    /// same URL that are due a scrape are asked about in the same request
+    ///
+    /// Whether it worked or not, the next scrape is only after [SCRAPE_INTERVAL], a tracker that
+    /// doesn't support scraping isn't asked over and over
+    async fn scrape(&self, manager: &TrackerManager, connection_id: i64) {
+        let batch = manager.scrape_batch(self);
+        let next_scrape = Instant::now() + SCRAPE_INTERVAL;
+        self.next_scrape.store(next_scrape);
+        for tracker in &batch {
+            tracker.next_scrape.store(next_scrape);
+        }
+
+        let mut info_hashes = vec![self.torrent_state.info_hash.clone()];
+        info_hashes.extend(batch.iter().map(|tracker| tracker.torrent_state.info_hash.clone()));
+        let scrape_req = ScrapeRequest::new(connection_id, info_hashes);
 
-        return match socket.send_to(connect_req_bytes.as_ref(), socketAddrs).await {
-            Ok(_) => {
-                let mut con_req = self.connect_request.lock().await;
-                *con_req = TrackerRequest::ConnectRequest(connect_req);
-                debug!(tracker = %self.address, remote = %socketAddrs, "sent UDP tracker connect request");
-                Ok(())
+        let scrape_res = match self.send_scrape_request(manager, scrape_req).await {
+            Ok(scrape_res) => scrape_res,
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                warn!(tracker = %self.address, "UDP tracker scrape timed out");
+                return;
+            }
+            Err(error) => {
+                warn!(tracker = %self.address, error = %error, "UDP tracker scrape failed");
+                return;
             }
-            Err(e) => Err(e),
         };
+
+        let mut stats = scrape_res.stats.iter().copied();
+        self.scrape_stats.store(stats.next());
+        for (tracker, stats) in batch.iter().zip(stats) {
+            tracker.scrape_stats.store(Some(stats));
+        }
+        info!(
+            tracker = %self.address,
+            torrents = batch.len() + 1,
+            stats = ?self.scrape_stats.load(),
+            "UDP tracker scrape returned swarm counts"
+        );
+        *self.scrape_response.lock().await = TrackerResponse::ScrapeResponse(scrape_res);
     }
 
-    pub async fn send_announce_request(&self, socket: Arc<UdpSocket>) -> Result<(), io::Error> {
-        let mut announce_req = AnnounceRequest::new();
+    /// Sends the ScrapeRequest through the given manager and waits for the tracker to answer it
+    async fn send_scrape_request(&self, manager: &TrackerManager, scrape_req: ScrapeRequest) -> Result<ScrapeResponse, io::Error> {
+        let remote = self.remote_address(manager, "scrape").await?;
+        let scrape_req_bytes = scrape_req.serialize_to_bytes();
+        let transaction_id = scrape_req.transaction_id;
+        let info_hashes = scrape_req.info_hashes.len();
+        *self.scrape_request.lock().await = TrackerRequest::ScrapeRequest(scrape_req);
+        debug!(tracker = %self.address, remote = %remote, info_hashes, "sending UDP tracker scrape request");
+
+        let response = manager.request(&scrape_req_bytes, transaction_id, remote, SCRAPE_TIMEOUT).await?;
+        match self.response_of(&response, remote).await {
+            TrackerResponse::ScrapeResponse(scrape_res) => Ok(scrape_res),
+            // Not every tracker supports scraping, its announces may still work so it's not marked as failed
+            TrackerResponse::Error(error) => Err(io::Error::other(error.message)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tracker sent an invalid scrape response",
+            )),
+        }
+    }
+
+    /// Tells every tracker the torrent announced to that it stopped, so they drop us from their
+    /// peer lists. The trackers are told in parallel and the ones that haven't answered by the
+    /// deadline are given up on
+    pub async fn announce_stopped(trackers: Vec<Arc<Tracker>>, deadline: Duration) {
+        let client = reqwest::Client::builder().timeout(HTTP_TRACKER_TIMEOUT).build().unwrap_or_default();
+        let mut announces = JoinSet::new();
+        for tracker in trackers {
+            let client = client.clone();
+            announces.spawn(async move {
+                if let Err(error) = tracker.send_stopped_announce(&client).await {
+                    warn!(tracker = %tracker.address, error = %error, "stopped announce failed");
+                }
+            });
+        }
+        if timeout(deadline, async { while announces.join_next().await.is_some() {} })
+            .await
+            .is_err()
         {
-            let connect_response = self.connect_response.lock().await;
-            if let TrackerResponse::ConnectResponse(ref c_res) = *connect_response {
-                announce_req.set_connection_id(c_res.connection_id);
-                announce_req.set_transaction_id(c_res.transaction_id);
-                announce_req.set_info_hash(&self.torrent_state.info_hash);
-                let downloaded = self.torrent_state.bytes_complete() as i64;
-                let total = self.torrent_state.meta_info.total_length();
-                announce_req.set_downloaded(downloaded);
-                announce_req.set_uploaded(0);
-                announce_req.set_left(total.saturating_sub(downloaded));
-                {
-                    let ports = self.torrent_state.udp_ports.lock().await;
-                    if let Some(port) = ports.first() {
-                        announce_req.set_port(*port as i16);
-                    }
+            warn!(pending = announces.len(), "stopped announces timed out");
+        }
+    }
+
+    /// Sends a "stopped" announce, if the tracker was announced to before
+    ///
+    /// The socket shared by the trackers is gone by the time the torrent stops, so a UDP tracker is
+    /// connected to again from a socket of its own. The tracker doesn't answer a stopped announce
+    /// with anything useful, so no response is waited for
+    pub async fn send_stopped_announce(&self, client: &reqwest::Client) -> TrackerResult<()> {
+        if !matches!(*self.announce_response.lock().await, TrackerResponse::AnnounceResponse(_)) {
+            return Ok(());
+        }
+
+        if self.is_http() {
+            let announce_url = HttpAnnounceCodec::build_url(
+                &self.address,
+                &self.torrent_state,
+                self.announce_port().await,
+                AnnounceEvent::Stopped,
+            );
+            client.get(announce_url).send().await?.error_for_status()?;
+            info!(tracker = %self.address, "sent stopped announce");
+            return Ok(());
+        }
+
+        let Some(remote) = self.socketAddrs.lock().await.first().copied() else {
+            return Ok(());
+        };
+        let socket = match remote {
+            SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+            SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+        };
+        let connect_req = ConnectRequest::new();
+        socket.send_to(&connect_req.serializeToBytes(), remote).await?;
+
+        let mut buf = [0; 1024];
+        // Packets of others could keep coming in, the tracker only gets so long to answer
+        let connection_id = timeout(STOPPED_CONNECT_TIMEOUT, async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                if from != remote || len < 16 {
+                    continue;
+                }
+                let connect_res = ConnectResponse::from(&buf[..len])?;
+                if connect_res.action == 0 && connect_res.transaction_id == connect_req.transaction_id {
+                    return Ok::<_, TrackerError>(connect_res.connection_id);
                 }
-                announce_req.set_key(rand::random());
             }
-        }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "UDP tracker didn't answer the connect request"))??;
 
+        let announce_req = self.announce_request(connection_id, rand::random(), AnnounceEvent::Stopped).await;
         if let Some(announce_req_bytes) = announce_req.serialize_to_bytes() {
-            let socketAddrs = {
-                let socket_addresses = self.socketAddrs.lock().await;
-                // TODO : Make choosable among other indices too
-                socket_addresses.first().copied().ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::AddrNotAvailable,
-                        "tracker has no resolved socket addresses for announce request",
-                    )
-                })?
-            };
-            return match socket.send_to(&announce_req_bytes, socketAddrs).await {
-                Ok(_) => {
-                    let mut ann_req = self.announce_request.lock().await;
-                    *ann_req = TrackerRequest::AnnounceRequest(announce_req);
-                    debug!(tracker = %self.address, remote = %socketAddrs, "sent UDP tracker announce request");
-                    Ok(())
-                }
-                Err(e) => Err(e),
-            };
-        } else {
-            //println!("Error in serializing to bytes");
-            // TODO : Write else case
+            socket.send_to(&announce_req_bytes, remote).await?;
+            info!(tracker = %self.address, "sent stopped announce");
         }
-
         Ok(())
     }
 
@@ -720,38 +1158,28 @@ impl Tracker {
         }
     }
 
-    /// It will return "None", when the channel is closed
-    pub async fn getResponse(&self) -> Option<TrackerResponse> {
-        let NONE = Some(TrackerResponse::None);
-        let (_, rx) = self.udp_channel.as_ref().unwrap();
-        let mut rx = rx.lock().await;
-
-        return match rx.recv().await {
-            Some(d) => {
-                // Check for ConnectResponse
-                if self.isConnectResponse(&d).await {
-                    //println!("GOT A CONNECT RESPONSE HERE");
-                    //println!("{:?}", self.torrents);
-
-                    return if let Ok(cr) = ConnectResponse::from(&d) {
-                        Some(TrackerResponse::ConnectResponse(cr))
-                    } else {
-                        NONE
-                    };
-                } else if self.isAnnounceResponse(&d).await {
-                    //println!("GOT ANNOUNCE RESPONSE");
-
-                    return if let Ok(ar) = AnnounceResponse::from(&d) {
-                        Some(TrackerResponse::AnnounceResponse(ar))
-                    } else {
-                        NONE
-                    };
-                } else {
-                    NONE
-                }
+    /// Parses the packet the tracker answered one of its requests with, it's [TrackerResponse::None]
+    /// when the packet isn't a response to the pending request of its kind
+    ///
+    /// remote => The address the request was sent to, the peers of an announce that went over
+    /// IPv6 are IPv6 addresses (BEP-15)
+    pub async fn response_of(&self, d: &[u8], remote: SocketAddr) -> TrackerResponse {
+        let parsed = if self.isConnectResponse(d).await {
+            ConnectResponse::from(d).map(TrackerResponse::ConnectResponse)
+        } else if self.isAnnounceResponse(d).await {
+            if remote.is_ipv6() {
+                AnnounceResponse::from_ipv6(d).map(TrackerResponse::AnnounceResponse)
+            } else {
+                AnnounceResponse::from(d).map(TrackerResponse::AnnounceResponse)
             }
-            None => None,
+        } else if self.isScrapeResponse(d).await {
+            ScrapeResponse::from(d).map(TrackerResponse::ScrapeResponse)
+        } else if self.isErrorResponse(d).await {
+            ErrorResponse::from(d).map(TrackerResponse::Error)
+        } else {
+            Ok(TrackerResponse::None)
         };
+        parsed.unwrap_or(TrackerResponse::None)
     }
 
     /// Checks from the given buffer, if the given response is a ConnectResponse or not
@@ -819,14 +1247,36 @@ impl Tracker {
         IS_ANNOUNCE_RESPONSE
     }
 
-    pub async fn isScrapeResponse(&self, _d: &[u8]) -> bool {
-        false // Dummy
+    /// Checks from the given buffer, if it's a ScrapeResponse to the pending ScrapeRequest or not
+    pub async fn isScrapeResponse(&self, d: &[u8]) -> bool {
+        if d.len() < 8 {
+            return false;
+        }
+        let action = i32::from_be_bytes([d[0], d[1], d[2], d[3]]);
+        let transaction_id = i32::from_be_bytes([d[4], d[5], d[6], d[7]]);
+        match *self.scrape_request.lock().await {
+            TrackerRequest::ScrapeRequest(ref scrape_req) => action == 2 && scrape_req.transaction_id == transaction_id,
+            _ => false,
+        }
     }
 
-    /// Checks from the given buffer, if the given response is an Error or not
-    pub async fn isErrorResponse(&self, _d: &[u8]) -> bool {
-        //let action = ReadBytesExt::read_i32::<BigEndian>(&mut action_bytes);
-        false //dummy
+    /// Checks from the given buffer, if it's an ErrorResponse to any of the pending requests or not
+    pub async fn isErrorResponse(&self, d: &[u8]) -> bool {
+        if d.len() < 8 || i32::from_be_bytes([d[0], d[1], d[2], d[3]]) != 3 {
+            return false;
+        }
+        let transaction_id = i32::from_be_bytes([d[4], d[5], d[6], d[7]]);
+        if let TrackerRequest::ConnectRequest(ref connect_req) = *self.connect_request.lock().await {
+            if connect_req.transaction_id == transaction_id {
+                return true;
+            }
+        }
+        if let TrackerRequest::AnnounceRequest(ref announce_req) = *self.announce_request.lock().await {
+            if announce_req.transaction_id == Some(transaction_id) {
+                return true;
+            }
+        }
+        matches!(*self.scrape_request.lock().await, TrackerRequest::ScrapeRequest(ref scrape_req) if scrape_req.transaction_id == transaction_id)
     }
 }
 
@@ -834,7 +1284,8 @@ impl Tracker {
 pub enum TrackerResponse {
     ConnectResponse(ConnectResponse),
     AnnounceResponse(AnnounceResponse),
-    Error,
+    ScrapeResponse(ScrapeResponse),
+    Error(ErrorResponse),
     None,
 }
 
@@ -842,28 +1293,31 @@ pub enum TrackerResponse {
 pub enum TrackerRequest {
     ConnectRequest(ConnectRequest),
     AnnounceRequest(AnnounceRequest),
+    ScrapeRequest(ScrapeRequest),
     #[default]
     None,
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{HttpAnnounceCodec, Tracker};
+    use super::{
+        AnnounceEvent, AnnounceResponse, HttpAnnounceCodec, HttpScrapeCodec, ScrapeStats, Tracker, TrackerManager, TrackerResponse,
+        TrackerState, TrackerTiers,
+    };
     use crate::core::{
-        piece_picker::PiecePicker,
-        state::{DownState, State},
+        state::State,
+        test_support::{TestHttp, TestState},
     };
     use bytes::{BufMut, BytesMut};
     use crossbeam::atomic::AtomicCell;
-    use hyperblow::parser::torrent_parser::{FileMeta, Info};
     use std::{
         net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
         sync::Arc,
     };
     use tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
-        net::TcpListener,
-        sync::{mpsc, Mutex, RwLock},
+        io::AsyncWriteExt,
+        net::{TcpListener, UdpSocket},
+        sync::mpsc,
     };
     use url::Url;
 
@@ -874,14 +1328,16 @@ mod tests {
             0x00, 0x01, 0x02, 0x03, 0x04, b'a', b'b', b'c', b'd', b'e', b'f', 0x7f, 0x80, 0x81, 0xfe, 0xff, b'1', b'2', b'3', b'4',
         ];
 
-        let announce_url = HttpAnnounceCodec::build_url_with_values(&url, &info_hash, 25, 75, 6881);
+        let announce_url = HttpAnnounceCodec::build_url_with_values(&url, &info_hash, 10, 25, 75, 6881, AnnounceEvent::Started);
 
         assert!(announce_url.starts_with("https://tracker.example.test/announce?existing=1&"));
         assert!(announce_url.contains("info_hash=%00%01%02%03%04abcdef%7F%80%81%FE%FF1234"));
         assert!(announce_url.contains("peer_id=%2DHBYxxx%2DQMAXYDGHQAHF"));
+        assert!(announce_url.contains("uploaded=10"));
         assert!(announce_url.contains("downloaded=25"));
         assert!(announce_url.contains("left=75"));
         assert!(announce_url.contains("compact=1"));
+        assert!(announce_url.contains("event=started"));
     }
 
     #[test]
@@ -956,18 +1412,7 @@ mod tests {
         let tracker_address = format!("http://{}/announce", listener.local_addr().expect("tracker should have address"));
         let server = tokio::spawn(async move {
             let (mut socket, _) = listener.accept().await.expect("client should connect");
-            let mut request = Vec::new();
-            let mut buf = [0_u8; 1024];
-            loop {
-                let read = socket.read(&mut buf).await.expect("request should be readable");
-                if read == 0 {
-                    break;
-                }
-                request.extend_from_slice(&buf[..read]);
-                if request.windows(4).any(|window| window == b"\r\n\r\n") {
-                    break;
-                }
-            }
+            let request = TestHttp::read_head(&mut socket).await;
 
             let mut peers = BytesMut::new();
             peers.put_slice(&[127, 0, 0, 1]);
@@ -983,7 +1428,7 @@ mod tests {
             socket.write_all(response.as_bytes()).await.expect("response header should write");
             socket.write_all(&body).await.expect("response body should write");
 
-            String::from_utf8(request).expect("request should be UTF-8")
+            request
         });
 
         let (peer_sender, mut peer_receiver) = mpsc::unbounded_channel();
@@ -1007,37 +1452,473 @@ mod tests {
         assert!(request.contains("compact=1"));
     }
 
-    fn test_state(info_hash: Vec<u8>) -> Arc<State> {
-        Arc::new(State {
-            meta_info: FileMeta {
-                announce: "http://tracker.example.test/announce".to_string(),
-                announce_list: None,
-                info: Info {
-                    name: Some("tracker-test".to_string()),
-                    length: Some(1024),
-                    files: None,
-                    piece_length: Some(16 * 1024),
-                    pieces: Vec::new(),
-                },
-                creation_data: None,
-                comment: None,
-                encoding: None,
-                created_by: None,
-                acceptable_source: None,
-            },
-            download_directory: std::env::temp_dir(),
-            d_state: DownState::Unknown,
-            file_tree: None,
-            trackers: Arc::new(RwLock::new(Vec::new())),
-            udp_ports: Arc::new(Mutex::new(vec![6881])),
-            tcp_ports: Arc::new(Mutex::new(Vec::new())),
-            info_hash,
-            pieces_hash: Vec::new(),
-            piece_picker: Arc::new(Mutex::new(PiecePicker::new(0))),
-            peers: Arc::new(Mutex::new(Vec::new())),
-            uptime: AtomicCell::new(0),
-            bytes_complete: AtomicCell::new(0),
-            pieces_downloaded: AtomicCell::new(0),
+    #[tokio::test]
+    async fn http_announces_send_started_once_then_regular_then_completed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("HTTP tracker should bind");
+        let tracker_address = format!("http://{}/announce", listener.local_addr().expect("tracker should have address"));
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..3 {
+                let (mut socket, _) = listener.accept().await.expect("client should connect");
+                let request = TestHttp::read_head(&mut socket).await;
+                let body = b"d8:intervali30e5:peers0:e";
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                socket.write_all(response.as_bytes()).await.expect("response header should write");
+                socket.write_all(body).await.expect("response body should write");
+                requests.push(request);
+            }
+            requests
+        });
+
+        let state = test_state(vec![5; 20]);
+        state.set_bytes_complete(1000);
+        state.set_session_downloaded(600);
+        state.set_session_uploaded(250);
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let tracker = Tracker::new(&tracker_address, state, Arc::new(peer_sender)).expect("tracker should construct");
+        let client = reqwest::Client::new();
+
+        tracker
+            .send_http_announce_request(&client)
+            .await
+            .expect("started announce should succeed");
+        assert_eq!(tracker.next_event.load(), AnnounceEvent::None);
+        tracker
+            .send_http_announce_request(&client)
+            .await
+            .expect("regular announce should succeed");
+        tracker.announce_completed();
+        assert_eq!(tracker.next_event.load(), AnnounceEvent::Completed);
+        tracker
+            .send_http_announce_request(&client)
+            .await
+            .expect("completed announce should succeed");
+        assert_eq!(tracker.next_event.load(), AnnounceEvent::None);
+
+        let requests = server.await.expect("server should finish");
+        assert!(requests[0].contains("event=started"));
+        assert!(!requests[1].contains("event="));
+        assert!(requests[2].contains("event=completed"));
+        for request in &requests {
+            assert!(request.contains("uploaded=250"));
+            assert!(request.contains("downloaded=600"));
+            assert!(request.contains("left=24"));
+        }
+    }
+
+    #[tokio::test]
+    async fn http_completed_before_started_is_answered_goes_out_after_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("HTTP tracker should bind");
+        let tracker_address = format!("http://{}/announce", listener.local_addr().expect("tracker should have address"));
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.expect("client should connect");
+                let request = TestHttp::read_head(&mut socket).await;
+                let body = b"d8:intervali30e5:peers0:e";
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                socket.write_all(response.as_bytes()).await.expect("response header should write");
+                socket.write_all(body).await.expect("response body should write");
+                requests.push(request);
+            }
+            requests
+        });
+
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let tracker = Tracker::new(&tracker_address, test_state(vec![5; 20]), Arc::new(peer_sender)).expect("tracker should construct");
+        let client = reqwest::Client::new();
+
+        tracker.announce_completed();
+        assert_eq!(tracker.next_event.load(), AnnounceEvent::Started);
+        tracker
+            .send_http_announce_request(&client)
+            .await
+            .expect("started announce should succeed");
+        assert_eq!(tracker.next_event.load(), AnnounceEvent::Completed);
+        tracker
+            .send_http_announce_request(&client)
+            .await
+            .expect("completed announce should succeed");
+        assert_eq!(tracker.next_event.load(), AnnounceEvent::None);
+
+        let requests = server.await.expect("server should finish");
+        assert!(requests[0].contains("event=started"));
+        assert!(requests[1].contains("event=completed"));
+    }
+
+    #[test]
+    fn derives_http_scrape_url_from_announce_url() {
+        let scrape_url = |announce: &str| HttpScrapeCodec::scrape_url(&Url::parse(announce).expect("valid URL")).map(String::from);
+
+        assert_eq!(
+            scrape_url("http://tracker.test/announce").as_deref(),
+            Some("http://tracker.test/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://tracker.test/x/announce.php?passkey=1").as_deref(),
+            Some("http://tracker.test/x/scrape.php?passkey=1")
+        );
+        assert_eq!(scrape_url("http://tracker.test/a"), None);
+        assert_eq!(scrape_url("http://tracker.test/x/announce/"), None);
+    }
+
+    #[tokio::test]
+    async fn http_scrape_request_reaches_scrape_url_and_stores_swarm_counts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("HTTP tracker should bind");
+        let tracker_address = format!("http://{}/announce", listener.local_addr().expect("tracker should have address"));
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("client should connect");
+            let request = TestHttp::read_head(&mut socket).await;
+
+            let mut body = BytesMut::new();
+            body.put_slice(b"d5:filesd20:");
+            body.put_slice(&[8; 20]);
+            body.put_slice(b"d8:completei5e10:downloadedi50e10:incompletei7eeee");
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            socket.write_all(response.as_bytes()).await.expect("response header should write");
+            socket.write_all(&body).await.expect("response body should write");
+
+            request
+        });
+
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let tracker = Tracker::new(&tracker_address, test_state(vec![8; 20]), Arc::new(peer_sender)).expect("tracker should construct");
+
+        tracker.scrape_http(&reqwest::Client::new()).await;
+
+        assert_eq!(
+            tracker.scrape_stats.load(),
+            Some(ScrapeStats {
+                seeders: 5,
+                completed: 50,
+                leechers: 7
+            })
+        );
+        assert!(!tracker.is_scrape_due());
+        let request = server.await.expect("server should finish");
+        assert!(request.starts_with("GET /scrape?info_hash=%08%08%08%08"));
+    }
+
+    #[tokio::test]
+    async fn udp_stopped_announce_connects_again_and_sends_stopped_event() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        let server = tokio::spawn(async move {
+            let mut buf = [0_u8; 1024];
+            let (len, client) = tracker_socket.recv_from(&mut buf).await.expect("connect request should arrive");
+            assert_eq!(len, 16);
+            let mut connect_response = BytesMut::new();
+            connect_response.put_i32(0);
+            connect_response.put_slice(&buf[12..16]);
+            connect_response.put_i64(42);
+            tracker_socket
+                .send_to(&connect_response, client)
+                .await
+                .expect("connect response should send");
+
+            let (len, _) = tracker_socket.recv_from(&mut buf).await.expect("announce request should arrive");
+            buf[..len].to_vec()
+        });
+
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let tracker = Tracker::new(&format!("udp://{tracker_address}"), test_state(vec![4; 20]), Arc::new(peer_sender))
+            .expect("tracker should construct");
+        *tracker.socketAddrs.lock().await = vec![tracker_address];
+        *tracker.announce_response.lock().await = TrackerResponse::AnnounceResponse(AnnounceResponse {
+            action: 1,
+            transaction_id: 0,
+            interval: 1800,
+            leechers: 0,
+            seeders: 0,
+            peersAddresses: Vec::new(),
+        });
+
+        tracker
+            .send_stopped_announce(&reqwest::Client::new())
+            .await
+            .expect("stopped announce should be sent");
+
+        let announce = server.await.expect("tracker should finish");
+        assert_eq!(announce.len(), 98);
+        assert_eq!(announce[..8], 42_i64.to_be_bytes());
+        assert_eq!(announce[16..36], [4; 20]);
+        assert_eq!(announce[80..84], AnnounceEvent::Stopped.udp_code().to_be_bytes());
+        // Nothing was downloaded or uploaded this session, the whole 1024 bytes are left
+        assert_eq!(announce[56..64], 0_i64.to_be_bytes());
+        assert_eq!(announce[64..72], 1024_i64.to_be_bytes());
+        assert_eq!(announce[72..80], 0_i64.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn udp_scrape_batches_torrents_sharing_the_tracker() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        let server = tokio::spawn(async move {
+            let mut buf = [0_u8; 1024];
+            let (len, client) = tracker_socket.recv_from(&mut buf).await.expect("scrape request should arrive");
+            let mut scrape_response = BytesMut::new();
+            scrape_response.put_i32(2);
+            scrape_response.put_slice(&buf[12..16]);
+            for count in [5, 50, 7, 1, 10, 2] {
+                scrape_response.put_i32(count);
+            }
+            tracker_socket
+                .send_to(&scrape_response, client)
+                .await
+                .expect("scrape response should send");
+            buf[..len].to_vec()
+        });
+
+        let url = format!("udp://{tracker_address}/scrape-batch");
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let peer_sender = Arc::new(peer_sender);
+        let tracker = Arc::new(Tracker::new(&url, test_state(vec![6; 20]), peer_sender.clone()).expect("tracker should construct"));
+        let other = Arc::new(Tracker::new(&url, test_state(vec![7; 20]), peer_sender).expect("tracker should construct"));
+        let manager = spawn_tracker_manager().await;
+        manager.register_udp_scrapes(&tracker);
+        manager.register_udp_scrapes(&other);
+        *tracker.socketAddrs.lock().await = vec![tracker_address];
+
+        tracker.scrape(&manager, 42).await;
+
+        let request = server.await.expect("tracker should finish");
+        assert_eq!(request.len(), 56);
+        assert_eq!(request[..8], 42_i64.to_be_bytes());
+        assert_eq!(request[16..36], [6; 20]);
+        assert_eq!(request[36..56], [7; 20]);
+        assert_eq!(
+            tracker.scrape_stats.load(),
+            Some(ScrapeStats {
+                seeders: 5,
+                completed: 50,
+                leechers: 7
+            })
+        );
+        assert_eq!(
+            other.scrape_stats.load(),
+            Some(ScrapeStats {
+                seeders: 1,
+                completed: 10,
+                leechers: 2
+            })
+        );
+        assert!(!other.is_scrape_due());
+    }
+
+    #[tokio::test]
+    async fn udp_error_response_is_kept_as_tracker_state() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 1024];
+            let (_, client) = tracker_socket.recv_from(&mut buf).await.expect("connect request should arrive");
+            let mut error_response = BytesMut::new();
+            error_response.put_i32(3);
+            error_response.put_slice(&buf[12..16]);
+            error_response.put_slice(b"torrent not registered");
+            tracker_socket
+                .send_to(&error_response, client)
+                .await
+                .expect("error response should send");
+        });
+
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let tracker = Arc::new(
+            Tracker::new(&format!("udp://{tracker_address}"), test_state(vec![9; 20]), Arc::new(peer_sender))
+                .expect("tracker should construct"),
+        );
+        *tracker.socketAddrs.lock().await = vec![tracker_address];
+
+        let manager = spawn_tracker_manager().await;
+        let running = tracker.clone();
+        let run = tokio::spawn(async move { running.run_me(&manager).await });
+
+        let failed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !matches!(tracker.tracker_state(), TrackerState::Error { .. }) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        run.abort();
+
+        assert!(failed.is_ok(), "tracker should keep the error it was sent");
+        assert_eq!(
+            tracker.tracker_state(),
+            TrackerState::Error {
+                message: "torrent not registered".to_string()
+            }
+        );
+        assert_eq!(tracker.tracker_state().to_string(), "Error: torrent not registered");
+    }
+
+    #[tokio::test]
+    async fn tiers_fail_over_to_the_next_tracker_and_promote_the_one_that_answers() {
+        let unreachable = TcpListener::bind("127.0.0.1:0").await.expect("listener should bind");
+        let unreachable_address = format!(
+            "http://{}/announce",
+            unreachable.local_addr().expect("listener should have address")
+        );
+        drop(unreachable);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("HTTP tracker should bind");
+        let tracker_address = format!("http://{}/announce", listener.local_addr().expect("tracker should have address"));
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("client should connect");
+            TestHttp::read_head(&mut socket).await;
+            let body = b"d8:intervali1800e5:peers0:e";
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            socket.write_all(response.as_bytes()).await.expect("response header should write");
+            socket.write_all(body).await.expect("response body should write");
+        });
+
+        let state = test_state(vec![10; 20]);
+        let (peer_sender, _peer_receiver) = mpsc::unbounded_channel();
+        let peer_sender = Arc::new(peer_sender);
+        let failing = Arc::new(Tracker::new(&unreachable_address, state.clone(), peer_sender.clone()).expect("tracker should construct"));
+        let answering = Arc::new(Tracker::new(&tracker_address, state.clone(), peer_sender).expect("tracker should construct"));
+        *state.trackers.write().await = vec![vec![failing.clone(), answering.clone()]];
+
+        let run = tokio::spawn(TrackerTiers::run(state.clone(), spawn_tracker_manager().await));
+        let promoted = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !Arc::ptr_eq(&state.trackers.read().await[0][0], &answering) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
         })
+        .await;
+        run.abort();
+
+        assert!(promoted.is_ok(), "the answering tracker should move to the front of its tier");
+        assert!(matches!(failing.tracker_state(), TrackerState::DNSUnresolved { .. }));
+        assert_eq!(answering.next_event.load(), AnnounceEvent::None);
+    }
+
+    #[tokio::test]
+    async fn udp_trackers_of_torrents_share_the_connection_and_get_their_own_responses() {
+        let tracker_socket = UdpSocket::bind("127.0.0.1:0").await.expect("UDP tracker should bind");
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        let connects = Arc::new(AtomicCell::new(0_usize));
+        let counted_connects = connects.clone();
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 1024];
+            loop {
+                let (len, client) = tracker_socket.recv_from(&mut buf).await.expect("request should arrive");
+                let mut response = BytesMut::new();
+                match i32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]) {
+                    0 => {
+                        counted_connects.fetch_add(1);
+                        response.put_i32(0);
+                        response.put_slice(&buf[12..16]);
+                        response.put_i64(77);
+                    }
+                    1 => {
+                        assert_eq!(buf[..8], 77_i64.to_be_bytes(), "announce should use the shared connection id");
+                        assert_eq!(len, 98);
+                        // The peer of each torrent has the first byte of its info hash as its port
+                        response.put_i32(1);
+                        response.put_slice(&buf[12..16]);
+                        response.put_i32(1800);
+                        response.put_i32(0);
+                        response.put_i32(1);
+                        response.put_slice(&[127, 0, 0, 1]);
+                        response.put_u16(u16::from(buf[16]));
+                    }
+                    _ => {
+                        response.put_i32(2);
+                        response.put_slice(&buf[12..16]);
+                        response.put_slice(&[0; 12]);
+                    }
+                }
+                tracker_socket.send_to(&response, client).await.expect("response should send");
+            }
+        });
+
+        let manager = spawn_tracker_manager().await;
+        let url = format!("udp://{tracker_address}");
+        let mut runs = Vec::new();
+        for info_hash in [11, 12] {
+            let (peer_sender, mut peer_receiver) = mpsc::unbounded_channel();
+            let tracker =
+                Arc::new(Tracker::new(&url, test_state(vec![info_hash; 20]), Arc::new(peer_sender)).expect("tracker should construct"));
+            let running = tracker.clone();
+            let running_manager = manager.clone();
+            runs.push(tokio::spawn(async move { running.run(&running_manager).await }));
+
+            let peer = tokio::time::timeout(std::time::Duration::from_secs(5), peer_receiver.recv())
+                .await
+                .expect("tracker should announce")
+                .expect("peer should be sent to download channel");
+            assert_eq!(
+                peer.socket_adr,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), u16::from(info_hash))
+            );
+        }
+        for run in runs {
+            run.abort();
+        }
+
+        assert_eq!(connects.load(), 1);
+    }
+
+    #[tokio::test]
+    async fn udp_tracker_over_ipv6_sends_ipv6_peers() {
+        // Hosts without IPv6, eg. some CI containers, can't run it
+        let Ok(tracker_socket) = UdpSocket::bind("[::1]:0").await else {
+            eprintln!("skipping udp_tracker_over_ipv6_sends_ipv6_peers, IPv6 isn't available");
+            return;
+        };
+        let tracker_address = tracker_socket.local_addr().expect("tracker should have address");
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 1024];
+            loop {
+                let (_, client) = tracker_socket.recv_from(&mut buf).await.expect("request should arrive");
+                let mut response = BytesMut::new();
+                match i32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]) {
+                    0 => {
+                        response.put_i32(0);
+                        response.put_slice(&buf[12..16]);
+                        response.put_i64(78);
+                    }
+                    1 => {
+                        response.put_i32(1);
+                        response.put_slice(&buf[12..16]);
+                        response.put_i32(1800);
+                        response.put_i32(0);
+                        response.put_i32(1);
+                        response.put_slice(&Ipv6Addr::LOCALHOST.octets());
+                        response.put_u16(51413);
+                    }
+                    _ => continue,
+                }
+                tracker_socket.send_to(&response, client).await.expect("response should send");
+            }
+        });
+
+        let (peer_sender, mut peer_receiver) = mpsc::unbounded_channel();
+        let tracker = Arc::new(
+            Tracker::new(&format!("udp://{tracker_address}"), test_state(vec![13; 20]), Arc::new(peer_sender))
+                .expect("tracker should construct"),
+        );
+        let manager = spawn_tracker_manager().await;
+        let running = tracker.clone();
+        let run = tokio::spawn(async move { running.run(&manager).await });
+
+        let peer = tokio::time::timeout(std::time::Duration::from_secs(5), peer_receiver.recv())
+            .await
+            .expect("tracker should announce over IPv6")
+            .expect("peer should be sent to download channel");
+        run.abort();
+
+        assert_eq!(peer.socket_adr, SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 51413));
+    }
+
+    async fn spawn_tracker_manager() -> Arc<TrackerManager> {
+        let manager = TrackerManager::bind_local().await;
+        let receiver = manager.clone();
+        tokio::spawn(async move { receiver.run().await });
+        manager
+    }
+
+    fn test_state(info_hash: Vec<u8>) -> Arc<State> {
+        TestState::new("tracker-test").length(1024).info_hash(info_hash).build()
     }
 }
@@ -0,0 +1,53 @@
+use super::Tracker;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// BEP 15 fits about 74 info hashes in the UDP packet of a scrape request
+pub const MAX_SCRAPE_INFO_HASHES: usize = 74;
+
+/// UDP trackers grouped by their URL, so the torrents sharing a tracker are scraped together. The
+/// trackers are held weakly so the ones of a torrent that's gone drop out on their own
+#[derive(Debug, Default)]
+pub struct UdpScrapeBatches {
+    trackers: Mutex<HashMap<String, Vec<Weak<Tracker>>>>,
+}
+
+impl UdpScrapeBatches {
+    pub fn register(&self, tracker: &Arc<Tracker>) {
+        let Ok(mut trackers) = self.trackers.lock() else {
+            return;
+        };
+        trackers
+            .entry(tracker.address.to_string())
+            .or_default()
+            .push(Arc::downgrade(tracker));
+    }
+
+    /// Trackers of other torrents, with the same URL as the given one, whose scrape is due, they're
+    /// scraped along with it
+    pub fn batch_for(&self, tracker: &Tracker) -> Vec<Arc<Tracker>> {
+        let Ok(mut trackers) = self.trackers.lock() else {
+            return Vec::new();
+        };
+        let Some(same_url) = trackers.get_mut(tracker.address.as_str()) else {
+            return Vec::new();
+        };
+        same_url.retain(|other| other.strong_count() > 0);
+
+        let mut info_hashes = vec![tracker.torrent_state.info_hash.clone()];
+        let mut batch = Vec::new();
+        for other in same_url.iter().filter_map(Weak::upgrade) {
+            if batch.len() + 1 == MAX_SCRAPE_INFO_HASHES {
+                break;
+            }
+            if info_hashes.contains(&other.torrent_state.info_hash) || !other.is_scrape_due() {
+                continue;
+            }
+            info_hashes.push(other.torrent_state.info_hash.clone());
+            batch.push(other);
+        }
+        batch
+    }
+}
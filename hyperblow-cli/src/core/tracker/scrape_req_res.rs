@@ -1,160 +1,169 @@
-// TODO : Add Scrape Req and Res
-
-// Offset          Size            Name            Value
-// 0               64-bit integer  connection_id
-// 8               32-bit integer  action          2 // scrape
-// 12              32-bit integer  transaction_id
-// 16 + 20 * n     20-byte string  info_hash
-// 16 + 20 * N
-struct ScrapeRequest {
-    connection_id: Option<i64>,
-    action: i32,
-    transaction_id: Option<i32>,
-    info_hash: Option<Vec<u8>>,
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, BytesMut};
+
+/// Struct to handle "Scrape" request message, it asks a tracker about several torrents at once
+/// Reference : http://www.bittorrent.org/beps/bep_0015.html
+///
+/// Scrape Request Bytes Structure:
+///
+/// Offset          Size            Name            Value
+/// 0               64-bit integer  connection_id
+/// 8               32-bit integer  action          2 // scrape
+/// 12              32-bit integer  transaction_id
+/// 16 + 20 * n     20-byte string  info_hash
+/// 16 + 20 * N
+#[derive(Debug, Clone)]
+pub struct ScrapeRequest {
+    pub connection_id: i64,
+    pub action: i32,
+    pub transaction_id: i32,
+    pub info_hashes: Vec<Vec<u8>>,
 }
 
-impl Default for ScrapeRequest {
-    fn default() -> Self {
+impl ScrapeRequest {
+    /// Creates a ScrapeRequest of the given torrents, with a random transaction id
+    pub fn new(connection_id: i64, info_hashes: Vec<Vec<u8>>) -> Self {
         Self {
-            connection_id: None,
+            connection_id,
             action: 2,
-            transaction_id: None,
-            info_hash: None,
+            transaction_id: rand::random(),
+            info_hashes,
         }
     }
-}
 
-impl ScrapeRequest {
-    // Generates a BytesMut by consuming field of ScrapeRequest
-    fn getBytesMut(&self) -> BytesMut {
-        let mut bytes = BytesMut::with_capacity(36);
-        bytes.put_i64(self.connection_id.unwrap());
+    // Gives a buffer view of the ScrapeRequest
+    pub fn serialize_to_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::with_capacity(16 + 20 * self.info_hashes.len());
+        bytes.put_i64(self.connection_id);
         bytes.put_i32(self.action);
-        bytes.put_i32(self.transaction_id.unwrap());
-        bytes.put_slice(self.info_hash.as_ref().unwrap().as_slice());
+        bytes.put_i32(self.transaction_id);
+        for info_hash in &self.info_hashes {
+            bytes.put_slice(info_hash);
+        }
         bytes
     }
+}
 
-    fn set_connection_id(&mut self, v: i64) {
-        self.connection_id = Some(v);
-    }
+/// What a tracker knows about the swarm of a torrent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers that have the whole torrent
+    pub seeders: i32,
 
-    fn set_transaction_id(&mut self, v: i32) {
-        self.transaction_id = Some(v);
-    }
+    /// Times the tracker was told the torrent completed
+    pub completed: i32,
 
-    // NOTE : It doesnt explicity takes a info hash of 20 bytes
-    // TODO : Make sure the input is 20 bytes so that any sort of bug doesn't occur
-    fn set_info_hash(&mut self, v: Vec<u8>) {
-        self.info_hash = Some(v);
-    }
+    /// Peers that are still downloading
+    pub leechers: i32,
 }
 
-// Offset     Size            Name            Value
-// 0           32-bit integer  action          2 // scrape
-// 4           32-bit integer  transaction_id
-// 8 + 12 * n  32-bit integer  seeders
-// 12 + 12 * n 32-bit integer  completed
-// 16 + 12 * n 32-bit integer  leechers
-// 8 + 12 * N
-
-struct ScrapeReponse {
-    action: i32,
-    transaction_id: i32,
-    seeders: i32,
+/// Scrape Response Bytes Structure, the stats are in the order of the info hashes of the request:
+///
+/// Offset      Size            Name            Value
+/// 0           32-bit integer  action          2 // scrape
+/// 4           32-bit integer  transaction_id
+/// 8 + 12 * n  32-bit integer  seeders
+/// 12 + 12 * n 32-bit integer  completed
+/// 16 + 12 * n 32-bit integer  leechers
+/// 8 + 12 * N
+#[derive(Debug, Clone)]
+pub struct ScrapeResponse {
+    pub action: i32,
+    pub transaction_id: i32,
+    pub stats: Vec<ScrapeStats>,
 }
 
-impl Tracker {
-    /// Create a new Tracker from given Url String
-    pub fn new(url: &String) -> Self {
-        let url = Url::parse(url).expect(TRACKER_ERROR);
-        let protocol = {
-            if url.scheme() == "udp" {
-                TrackerProtocol::UDP
-            } else {
-                TrackerProtocol::HTTP
-            }
-        };
-        Tracker {
-            url,
-            protocol,
-            socket_adr: None,
-            didItResolve: false,
-            connect_request: None,
-            connect_response: None,
-            announce_request: None,
-            announce_response: None,
+impl ScrapeResponse {
+    /// Creates a ScrapeResponse from the given buffer
+    ///
+    /// The error produced here are the IO errors from parsing the given buffer bytes into
+    /// respective types
+    pub fn from(v: &[u8]) -> Result<Self, std::io::Error> {
+        if v.len() < 8 || !(v.len() - 8).is_multiple_of(12) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "scrape response must be 8 bytes and a multiple of 12 bytes of stats, got {}",
+                    v.len()
+                ),
+            ));
         }
-    }
-
-    /// Create list of "Tracker" from data in the announce and announce_list field of "FileMeta"
-    pub fn getTrackers(announce: &String, announce_list: &Vec<Vec<String>>) -> Vec<Arc<TokioMutex<Tracker>>> {
-        let mut trackers: Vec<_> = Vec::new();
 
-        // TODO : Find difference between Announce and Announce List coz i found Announce duplicate
-        // in Announce List
-        //trackers.push(Arc::new(sync::Mutex::new(RefCell::new(Tracker::new(announce)))));
-
-        for tracker_url in announce_list {
-            trackers.push(Arc::new(TokioMutex::new(Tracker::new(&tracker_url[0]))));
+        let mut bytes = v;
+        let action = ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?;
+        let transaction_id = ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?;
+
+        let mut stats = Vec::with_capacity(bytes.len() / 12);
+        while !bytes.is_empty() {
+            stats.push(ScrapeStats {
+                seeders: ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?,
+                completed: ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?,
+                leechers: ReadBytesExt::read_i32::<BigEndian>(&mut bytes)?,
+            });
         }
-        trackers
+
+        Ok(Self {
+            action,
+            transaction_id,
+            stats,
+        })
     }
 }
 
-pub struct UdpScrapeClient;
-
-impl UdpScrapeClient {
-    // To be called at the first step of communicating with the UDP Tracker Server.
-    pub async fn connect_request(transaction_id: i32, socket: &UdpSocket, to: &SocketAddr, tracker: Arc<TokioMutex<Tracker>>) -> Result<()> {
-        let mut guard_tracker = tracker.lock().await;
-        let mut connect_request = ConnectRequest::empty();
-        connect_request.set_transaction_id(transaction_id);
-        guard_tracker.connect_request = Some(connect_request.clone());
-        let buf = connect_request.getBytesMut();
-        socket.send_to(&buf, to).await?;
-        guard_tracker.connect_request = Some(connect_request);
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::{ScrapeRequest, ScrapeResponse, ScrapeStats};
+    use bytes::{BufMut, BytesMut};
+
+    #[test]
+    fn serializes_scrape_request_with_every_info_hash() {
+        let request = ScrapeRequest::new(42, vec![vec![1; 20], vec![2; 20]]);
+        let bytes = request.serialize_to_bytes();
+
+        assert_eq!(bytes.len(), 56);
+        assert_eq!(bytes[..8], 42_i64.to_be_bytes());
+        assert_eq!(bytes[8..12], 2_i32.to_be_bytes());
+        assert_eq!(bytes[12..16], request.transaction_id.to_be_bytes());
+        assert_eq!(bytes[16..36], [1; 20]);
+        assert_eq!(bytes[36..56], [2; 20]);
     }
 
-    pub async fn annnounce_request(
-        connect_response: ConnectResponse,
-        socket: &UdpSocket,
-        to: &SocketAddr,
-        details: Arc<TokioMutex<Details>>,
-        tracker: Arc<TokioMutex<Tracker>>,
-    ) -> Result<()> {
-        let lock_details = details.lock().await;
-        let mut announce_request = AnnounceRequest::empty();
-        announce_request.set_connection_id(connect_response.connection_id);
-        announce_request.set_transaction_id(connect_response.transaction_id);
-        announce_request.set_info_hash(lock_details.info_hash.as_ref().unwrap().clone().to_vec());
-        announce_request.set_downloaded(0);
-        announce_request.set_uploaded(0);
-        announce_request.set_uploaded(0);
-        announce_request.set_left(lock_details.total_bytes);
-        announce_request.set_port(8001);
-        announce_request.set_key(20);
-        socket.send_to(&announce_request.getBytesMut(), to).await?;
-
-        let mut lock_tracker = tracker.lock().await;
-        lock_tracker.announce_request = Some(announce_request);
-        Ok(())
+    #[test]
+    fn parses_scrape_response_stats_in_request_order() {
+        let mut bytes = BytesMut::new();
+        bytes.put_i32(2);
+        bytes.put_i32(7);
+        for value in [10, 20, 30, 1, 2, 3] {
+            bytes.put_i32(value);
+        }
+
+        let response = ScrapeResponse::from(&bytes).expect("valid scrape response");
+
+        assert_eq!(response.transaction_id, 7);
+        assert_eq!(
+            response.stats,
+            vec![
+                ScrapeStats {
+                    seeders: 10,
+                    completed: 20,
+                    leechers: 30
+                },
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 2,
+                    leechers: 3
+                },
+            ]
+        );
     }
 
-    pub async fn scrape_request(
-        connection_response: ConnectResponse,
-        socket: &UdpSocket,
-        to: &SocketAddr,
-        info_hash: Vec<u8>,
-        tracker: Arc<Mutex<RefCell<Tracker>>>,
-    ) -> Result<()> {
-        let mut scrape_request = ScrapeRequest::default();
-        scrape_request.set_connection_id(connection_response.connection_id);
-        scrape_request.set_transaction_id(connection_response.transaction_id);
-        scrape_request.set_info_hash(info_hash.clone());
-
-        socket.send_to(&scrape_request.getBytesMut(), to).await?;
-        Ok(())
+    #[test]
+    fn rejects_truncated_scrape_response() {
+        let mut bytes = BytesMut::new();
+        bytes.put_i32(2);
+        bytes.put_i32(7);
+        bytes.put_i32(10);
+
+        assert!(ScrapeResponse::from(&bytes).is_err());
     }
 }
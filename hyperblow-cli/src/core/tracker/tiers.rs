@@ -0,0 +1,108 @@
+use super::TrackerManager;
+use crate::core::state::State;
+use rand::seq::SliceRandom;
+use std::{ops::Range, sync::Arc, time::Duration};
+use tokio::{task::JoinSet, time::sleep};
+use tracing::{info, warn};
+
+/// How long to wait before trying the trackers again, once every one of them failed
+const TIERS_RETRY_DELAY: Duration = Duration::from_secs(15);
+
+/// The longest wait before trying the trackers again, the wait doubles after every failed round
+const MAX_TIERS_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How the announce tiers of a torrent are used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TierMode {
+    /// As BEP12 says, one tracker is announced to at a time, the next tier is only tried once
+    /// every tracker of the tier before it failed
+    #[default]
+    Failover,
+
+    /// Every tier announces at once, each one failing over among its own trackers, it finds more
+    /// of the swarm at the cost of more announces
+    Parallel,
+}
+
+/// Runs the trackers of a torrent by tier, according to BEP12
+pub struct TrackerTiers;
+
+impl TrackerTiers {
+    /// Shuffles the trackers of every tier, as BEP12 asks for when a torrent is first started,
+    /// from then on the order the trackers end up in is kept
+    pub fn shuffle(mut tiers: Vec<Vec<String>>) -> Vec<Vec<String>> {
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rand::rng());
+        }
+        tiers
+    }
+
+    /// Announces to the trackers in the tiers of the given state, according to its [TierMode]. It
+    /// runs until it's dropped
+    pub async fn run(state: Arc<State>, manager: Arc<TrackerManager>) {
+        let tier_count = state.trackers.read().await.len();
+        match state.tier_mode() {
+            TierMode::Failover => Self::run_tiers(&state, 0..tier_count, &manager).await,
+            TierMode::Parallel => {
+                let mut tiers = JoinSet::new();
+                for tier_index in 0..tier_count {
+                    let state = state.clone();
+                    let manager = manager.clone();
+                    tiers.spawn(async move { Self::run_tiers(&state, tier_index..tier_index + 1, &manager).await });
+                }
+                while tiers.join_next().await.is_some() {}
+            }
+        }
+    }
+
+    /// Tries the trackers of the given tiers in order, a tracker is announced to until it fails and
+    /// then the next one is tried. A tracker that answers moves to the front of its tier, see
+    /// [super::Tracker::run], and once the trackers of the last tier failed too, it starts over from the
+    /// first tier
+    async fn run_tiers(state: &State, tiers: Range<usize>, manager: &TrackerManager) {
+        let mut retry_delay = TIERS_RETRY_DELAY;
+        loop {
+            let mut answered = false;
+            for tier_index in tiers.clone() {
+                // The order is taken for the whole round, so promoted trackers don't make others to
+                // be tried twice or skipped
+                let tier = state.trackers.read().await.get(tier_index).cloned().unwrap_or_default();
+                for tracker in tier {
+                    info!(tracker = %tracker.address, tier = tier_index, "announcing to tracker");
+                    answered |= tracker.run(manager).await;
+                }
+            }
+
+            if answered {
+                retry_delay = TIERS_RETRY_DELAY;
+            } else {
+                warn!(
+                    tiers = ?tiers,
+                    retry_delay_secs = retry_delay.as_secs(),
+                    "every tracker of the tiers failed"
+                );
+                sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_TIERS_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrackerTiers;
+
+    #[test]
+    fn shuffles_trackers_within_their_tiers() {
+        let tiers = vec![
+            (0..8).map(|index| format!("udp://a{index}")).collect::<Vec<_>>(),
+            vec!["udp://b".to_string()],
+        ];
+
+        let mut shuffled = TrackerTiers::shuffle(tiers.clone());
+
+        assert_eq!(shuffled[1], tiers[1]);
+        shuffled[0].sort();
+        assert_eq!(shuffled[0], tiers[0]);
+    }
+}
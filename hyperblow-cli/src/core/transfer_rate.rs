@@ -0,0 +1,79 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The window over which a transfer rate is averaged
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Measures the rate of a transfer, in "bytes/second", averaged over the last few seconds
+///
+/// It's written to from the peer tasks and read from the ui_thread, so it only holds a std Mutex
+/// for the tiny moment it takes to push or sum the samples
+#[derive(Debug, Default)]
+pub struct TransferRate {
+    samples: Mutex<VecDeque<(Instant, usize)>>,
+}
+
+impl TransferRate {
+    /// Records that the given amount of bytes were transferred just now
+    pub fn record(&self, bytes: usize) {
+        self.record_at(Instant::now(), bytes);
+    }
+
+    /// Gives the average rate over the window in "bytes/second"
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_second_at(Instant::now())
+    }
+
+    fn record_at(&self, now: Instant, bytes: usize) {
+        if let Ok(mut samples) = self.samples.lock() {
+            Self::prune(&mut samples, now);
+            samples.push_back((now, bytes));
+        }
+    }
+
+    fn bytes_per_second_at(&self, now: Instant) -> usize {
+        let Ok(mut samples) = self.samples.lock() else {
+            return 0;
+        };
+        Self::prune(&mut samples, now);
+        let bytes = samples.iter().map(|(_, bytes)| *bytes).sum::<usize>();
+        bytes / RATE_WINDOW.as_secs() as usize
+    }
+
+    fn prune(samples: &mut VecDeque<(Instant, usize)>, now: Instant) {
+        while samples
+            .front()
+            .is_some_and(|(recorded_at, _)| now.saturating_duration_since(*recorded_at) > RATE_WINDOW)
+        {
+            samples.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TransferRate, RATE_WINDOW};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn averages_bytes_over_window() {
+        let rate = TransferRate::default();
+        let start = Instant::now();
+        rate.record_at(start, 4000);
+        rate.record_at(start + Duration::from_secs(1), 6000);
+
+        assert_eq!(rate.bytes_per_second_at(start + Duration::from_secs(2)), 2000);
+    }
+
+    #[test]
+    fn forgets_samples_older_than_window() {
+        let rate = TransferRate::default();
+        let start = Instant::now();
+        rate.record_at(start, 5000);
+
+        assert_eq!(rate.bytes_per_second_at(start + RATE_WINDOW + Duration::from_secs(1)), 0);
+    }
+}
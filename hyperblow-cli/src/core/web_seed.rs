@@ -0,0 +1,485 @@
+use super::{
+    peer::PeerCommand,
+    piece_assembler::{PieceAssembler, PieceAssemblyError},
+    piece_storage::{PieceStorage, PieceStorageError, TorrentFileRange},
+    state::State,
+    PercentEncoder,
+};
+use reqwest::{header::RANGE, StatusCode};
+use std::{sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::{
+    task::{JoinError, JoinSet},
+    time::sleep,
+};
+use tracing::{debug, info, warn};
+use url::Url;
+
+/// How long a range request may take before the web seed is considered unresponsive
+const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a web seed waits to look for a piece again, when every missing piece is being
+/// downloaded by someone else
+const IDLE_DELAY: Duration = Duration::from_secs(5);
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest a seed is waited for before it's asked again, whether it failed or said it's busy
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Error)]
+pub(crate) enum WebSeedError {
+    #[error("web seed request failed")]
+    Http(#[from] reqwest::Error),
+
+    #[error("web seed answered a range request with {0}")]
+    Status(StatusCode),
+
+    #[error("web seed doesn't support range requests")]
+    RangesNotSupported,
+
+    #[error("web seed sent {got} bytes for a range of {expected} bytes")]
+    RangeLength { expected: usize, got: usize },
+
+    #[error("HTTP seed is busy, retry after {} seconds", .0.as_secs())]
+    Busy(Duration),
+
+    #[error("piece assembly error")]
+    PieceAssembly(#[from] PieceAssemblyError),
+
+    #[error("piece storage error")]
+    PieceStorage(#[from] PieceStorageError),
+
+    #[error("piece write task failed")]
+    PieceWrite(#[from] JoinError),
+}
+
+/// How a web seed serves the data of the torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// A GetRight style seed (BEP 19) of the "url-list", a plain HTTP server of the torrent's
+    /// files, pieces are downloaded with range requests of the file ranges they map onto
+    UrlList,
+
+    /// A Hoffman style seed (BEP 17) of the "httpseeds", a script that hands out whole pieces
+    /// when asked with `?info_hash=..&piece=..`
+    HttpSeed,
+}
+
+/// A web seed, an HTTP server the pieces of the torrent can be downloaded from, see [WebSeedKind]
+///
+/// The seed has every piece, the [PiecePicker](super::piece_picker::PiecePicker) hands it pieces
+/// like it does to a peer that sent a full bitfield
+#[derive(Debug)]
+pub struct WebSeed {
+    /// For a [WebSeedKind::UrlList] seed, the URL of the file of a single file torrent, or of the
+    /// directory holding the torrent's directory for a multi file one. The script's URL for a
+    /// [WebSeedKind::HttpSeed]
+    url: Url,
+
+    kind: WebSeedKind,
+
+    state: Arc<State>,
+
+    client: reqwest::Client,
+}
+
+impl WebSeed {
+    /// Creates the web seed of the given URL, only HTTP(S) seeds are supported
+    pub fn new(url: &str, kind: WebSeedKind, state: Arc<State>) -> Option<Self> {
+        let url = Url::parse(url).ok().filter(|url| matches!(url.scheme(), "http" | "https"))?;
+        let client = reqwest::Client::builder().timeout(WEB_SEED_TIMEOUT).build().unwrap_or_default();
+        Some(Self { url, kind, state, client })
+    }
+
+    /// Downloads the pieces nobody else is downloading from the seed, until every piece of the
+    /// files that aren't skipped is downloaded. A seed that fails is retried with a growing delay,
+    /// a busy HTTP seed after the time it asked for
+    pub async fn run(&self) {
+        let mut retry_delay = INITIAL_RETRY_DELAY;
+        loop {
+            let piece_index = {
+                let mut picker = self.state.piece_picker.lock().await;
+                if picker.all_wanted_completed() {
+                    debug!(web_seed = %self.url, "all wanted pieces downloaded, web seed done");
+                    return;
+                }
+                // A seed has every piece, the ones no peer has come first
+                let piece_index = picker.next_piece(|_| true);
+                if let Some(piece_index) = piece_index {
+                    picker.mark_requested(piece_index);
+                }
+                piece_index
+            };
+            let Some(piece_index) = piece_index else {
+                sleep(IDLE_DELAY).await;
+                continue;
+            };
+
+            match self.download_piece(piece_index).await {
+                Ok(piece_length) => {
+                    retry_delay = INITIAL_RETRY_DELAY;
+                    info!(
+                        web_seed = %self.url,
+                        piece_index,
+                        bytes = piece_length,
+                        bytes_complete = self.state.bytes_complete(),
+                        "piece downloaded from web seed"
+                    );
+                }
+                Err(WebSeedError::Busy(retry_after)) => {
+                    self.state.piece_picker.lock().await.mark_request_failed(piece_index);
+                    // The seed can't have us wait longer than a failed one
+                    let retry_after = retry_after.min(MAX_RETRY_DELAY);
+                    debug!(web_seed = %self.url, retry_after_secs = retry_after.as_secs(), "HTTP seed is busy");
+                    sleep(retry_after).await;
+                }
+                Err(error) => {
+                    self.state.piece_picker.lock().await.mark_request_failed(piece_index);
+                    warn!(
+                        web_seed = %self.url,
+                        piece_index,
+                        error = %error,
+                        retry_delay_secs = retry_delay.as_secs(),
+                        "web seed download failed"
+                    );
+                    sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Downloads, verifies and stores a piece, it gives the length of the piece
+    async fn download_piece(&self, piece_index: usize) -> Result<usize, WebSeedError> {
+        let (Some(expected_hash), Some(piece_length)) = (self.state.piece_hash(piece_index), self.state.piece_length_at(piece_index))
+        else {
+            return Err(PieceStorageError::PieceOutOfRange { piece_index }.into());
+        };
+
+        let mut assembler = PieceAssembler::new(expected_hash, piece_length);
+        match self.kind {
+            WebSeedKind::UrlList => {
+                let ranges = PieceStorage::file_ranges(&self.state, piece_index)?;
+                debug!(web_seed = %self.url, piece_index, ranges = ranges.len(), "requesting piece from web seed");
+                let mut begin = 0;
+                for range in &ranges {
+                    let block = self.fetch_range(range).await?;
+                    self.state.download_rate.record(block.len());
+                    assembler.insert_block(begin, block)?;
+                    begin += range.length;
+                }
+            }
+            WebSeedKind::HttpSeed => {
+                debug!(web_seed = %self.url, piece_index, "requesting piece from HTTP seed");
+                let piece = self.fetch_piece(piece_index, piece_length).await?;
+                self.state.download_rate.record(piece.len());
+                assembler.insert_block(0, piece)?;
+            }
+        }
+        let piece = assembler.assemble()?;
+
+        let state = self.state.clone();
+        let write = self
+            .state
+            .piece_writes
+            .spawn(async move { PieceStorage::complete_piece(&state, piece_index, &piece).await });
+        write.await??;
+
+        if PieceStorage::is_readable(&self.state, piece_index).await {
+            for peer in self.state.peers.lock().await.iter() {
+                peer.send_command(PeerCommand::Have(piece_index as u32));
+            }
+        }
+        Ok(piece_length)
+    }
+
+    /// Requests a range of one of the torrent's files
+    async fn fetch_range(&self, range: &TorrentFileRange) -> Result<Vec<u8>, WebSeedError> {
+        let last_byte = range.offset + range.length - 1;
+        let response = self
+            .client
+            .get(self.file_url(&range.path))
+            .header(RANGE, format!("bytes={}-{last_byte}", range.offset))
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(WebSeedError::Status(status));
+        }
+        // Both are checked before the body is read, a server that ignores the Range header would
+        // have us download the whole file. It sends the whole file, and that's only what was asked
+        // for when the range is the whole file
+        let content_length = response.content_length().map(|length| length as usize);
+        if status != StatusCode::PARTIAL_CONTENT && !(range.offset == 0 && content_length == Some(range.length)) {
+            return Err(WebSeedError::RangesNotSupported);
+        }
+        if let Some(content_length) = content_length.filter(|&content_length| content_length != range.length) {
+            return Err(WebSeedError::RangeLength {
+                expected: range.length,
+                got: content_length,
+            });
+        }
+        let body = response.bytes().await?;
+        if body.len() != range.length {
+            return Err(WebSeedError::RangeLength {
+                expected: range.length,
+                got: body.len(),
+            });
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Requests a whole piece from an HTTP seed, a busy seed answers with "503 Service Unavailable"
+    /// and the number of seconds to wait before asking again as the body (BEP 17)
+    async fn fetch_piece(&self, piece_index: usize, piece_length: usize) -> Result<Vec<u8>, WebSeedError> {
+        let response = self.client.get(self.piece_url(piece_index)).send().await?;
+        let status = response.status();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let body = response.text().await?;
+            return Err(match body.trim().parse::<u64>() {
+                Ok(seconds) => WebSeedError::Busy(Duration::from_secs(seconds)),
+                Err(_) => WebSeedError::Status(status),
+            });
+        }
+        if !status.is_success() {
+            return Err(WebSeedError::Status(status));
+        }
+        let body = response.bytes().await?;
+        if body.len() != piece_length {
+            return Err(WebSeedError::RangeLength {
+                expected: piece_length,
+                got: body.len(),
+            });
+        }
+        Ok(body.to_vec())
+    }
+
+    /// The URL an HTTP seed serves a piece at, the query the seed's URL already has is kept
+    fn piece_url(&self, piece_index: usize) -> Url {
+        let mut url = self.url.clone();
+        let mut query = url.query().map(|query| format!("{query}&")).unwrap_or_default();
+        query.push_str(&format!(
+            "info_hash={}&piece={piece_index}",
+            PercentEncoder::encode(&self.state.info_hash)
+        ));
+        url.set_query(Some(&query));
+        url
+    }
+
+    /// The URL a file of the torrent is served at
+    ///
+    /// The URL of a single file torrent is the file itself, unless it ends with a "/" in which
+    /// case the name of the torrent is appended to it. The files of a multi file torrent are
+    /// under the URL, at the path the torrent gives them, its name included (BEP 19)
+    fn file_url(&self, path: &[String]) -> Url {
+        let single_file = self.state.meta_info.info.files.is_none();
+        let mut url = self.url.clone();
+        if single_file && !url.path().ends_with('/') {
+            return url;
+        }
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().extend(path);
+        }
+        url
+    }
+}
+
+/// The web seeds of a torrent
+pub struct WebSeeds;
+
+impl WebSeeds {
+    /// Runs every web and HTTP seed the torrent lists, it returns once they're all done, right
+    /// away when the torrent doesn't have any
+    pub async fn run(state: Arc<State>) {
+        let url_list = state.meta_info.url_list.iter().flatten().map(|url| (url, WebSeedKind::UrlList));
+        let httpseeds = state.meta_info.httpseeds.iter().flatten().map(|url| (url, WebSeedKind::HttpSeed));
+        let mut seeds = JoinSet::new();
+        for (url, kind) in url_list.chain(httpseeds) {
+            match WebSeed::new(url, kind, state.clone()) {
+                Some(seed) => {
+                    info!(web_seed = %seed.url, kind = ?seed.kind, "starting web seed");
+                    seeds.spawn(async move { seed.run().await });
+                }
+                None => warn!(web_seed = %url, "skipping unsupported web seed URL"),
+            }
+        }
+        while seeds.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WebSeed, WebSeedError, WebSeedKind};
+    use crate::core::{
+        piece_storage::TorrentFileRange,
+        state::State,
+        test_support::{temp_dir, TestHttp, TestState},
+    };
+    use crossbeam::atomic::AtomicCell;
+    use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn downloads_pieces_spanning_files_with_range_requests() {
+        let data = b"hello, web seeded world!".to_vec();
+        let files = vec![
+            (vec!["first.bin".to_string()], data[..5].to_vec()),
+            (vec!["nested dir".to_string(), "second.bin".to_string()], data[5..].to_vec()),
+        ];
+        let served = files
+            .iter()
+            .map(|(path, bytes)| (format!("/seed/multi/{}", path.join("/").replace(' ', "%20")), bytes.clone()))
+            .collect::<HashMap<_, _>>();
+        let address = TestWebSeed::serve(move |request| {
+            let path = request.split(' ').nth(1).expect("request line has a path");
+            let range = request
+                .lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                .expect("web seed requests are range requests");
+            let (start, end) = range.split_once('-').expect("range has a start and an end");
+            let (start, end) = (start.parse::<usize>().expect("start"), end.parse::<usize>().expect("end"));
+            match served.get(path) {
+                Some(file) => TestWebSeed::response("206 Partial Content", &file[start..=end]),
+                None => TestWebSeed::response("404 Not Found", b""),
+            }
+        })
+        .await;
+
+        let output_dir = temp_dir("web-seed");
+        let state = TestWebSeed::state(output_dir.clone(), &data, 8, Some(files));
+        let seed = WebSeed::new(&format!("http://{address}/seed"), WebSeedKind::UrlList, state.clone()).expect("HTTP web seed");
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), seed.run())
+            .await
+            .expect("web seed should download every piece");
+
+        assert!(state.piece_picker.lock().await.all_completed());
+        assert_eq!(state.bytes_complete(), data.len());
+        assert_eq!(fs::read(output_dir.join("multi").join("first.bin")).expect("first file"), b"hello");
+        assert_eq!(
+            fs::read(output_dir.join("multi").join("nested dir").join("second.bin")).expect("second file"),
+            &data[5..]
+        );
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_whole_file_answer_to_a_range_request_without_reading_it() {
+        // The head announces far more than the range and the body never comes
+        let address = TestWebSeed::serve(|_| b"HTTP/1.1 200 OK\r\nContent-Length: 1000000000\r\n\r\n".to_vec()).await;
+        let output_dir = temp_dir("web-seed");
+        let state = TestWebSeed::state(output_dir.clone(), b"range", 5, None);
+        let seed = WebSeed::new(&format!("http://{address}/single.bin"), WebSeedKind::UrlList, state).expect("HTTP web seed");
+        let range = TorrentFileRange {
+            path: vec!["single.bin".to_string()],
+            offset: 2,
+            length: 3,
+        };
+
+        let fetched = tokio::time::timeout(std::time::Duration::from_secs(5), seed.fetch_range(&range))
+            .await
+            .expect("the answer should be rejected from its head");
+        assert!(matches!(fetched, Err(WebSeedError::RangesNotSupported)));
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[tokio::test]
+    async fn downloads_whole_pieces_from_http_seed_after_it_was_busy() {
+        let data = b"pieces of an HTTP seed".to_vec();
+        let output_dir = temp_dir("web-seed");
+        let state = TestWebSeed::state(output_dir.clone(), &data, 8, None);
+        let requests = Arc::new(AtomicCell::new(0_usize));
+        let served = data.clone();
+        let counted = requests.clone();
+        let address = TestWebSeed::serve(move |request| {
+            let query = request
+                .split(' ')
+                .nth(1)
+                .and_then(|path| path.strip_prefix("/seeder?token=1&"))
+                .expect("request should keep the seed's query");
+            let piece = query
+                .strip_prefix(&format!("info_hash={}&piece=", "%01".repeat(20)))
+                .and_then(|piece| piece.parse::<usize>().ok())
+                .expect("request should name the info hash and the piece");
+            if counted.fetch_add(1) == 0 {
+                return TestWebSeed::response("503 Service Unavailable", b"1");
+            }
+            TestWebSeed::response("200 OK", served.chunks(8).nth(piece).expect("piece exists"))
+        })
+        .await;
+        let seed = WebSeed::new(&format!("http://{address}/seeder?token=1"), WebSeedKind::HttpSeed, state.clone()).expect("HTTP seed");
+
+        let started = std::time::Instant::now();
+        tokio::time::timeout(std::time::Duration::from_secs(5), seed.run())
+            .await
+            .expect("HTTP seed should download every piece");
+
+        assert!(
+            started.elapsed() >= std::time::Duration::from_secs(1),
+            "busy seed should be waited for"
+        );
+        assert_eq!(requests.load(), 4);
+        assert_eq!(fs::read(output_dir.join("single.bin")).expect("output should exist"), data);
+        fs::remove_dir_all(output_dir).expect("output dir should remove");
+    }
+
+    #[test]
+    fn single_file_url_is_the_file_unless_it_ends_with_a_slash() {
+        let state = TestWebSeed::state(PathBuf::from("unused"), b"data", 4, None);
+        let file = WebSeed::new("http://seed.example/files/data.bin", WebSeedKind::UrlList, state.clone()).expect("HTTP web seed");
+        let directory = WebSeed::new("http://seed.example/files/", WebSeedKind::UrlList, state.clone()).expect("HTTP web seed");
+        let path = vec!["single.bin".to_string()];
+
+        assert_eq!(file.file_url(&path).as_str(), "http://seed.example/files/data.bin");
+        assert_eq!(directory.file_url(&path).as_str(), "http://seed.example/files/single.bin");
+        assert!(WebSeed::new("ftp://seed.example/files/", WebSeedKind::UrlList, state).is_none());
+    }
+
+    struct TestWebSeed;
+
+    impl TestWebSeed {
+        /// Serves HTTP requests, every request is answered with what `respond` gives for its head
+        async fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + Sync + 'static) -> std::net::SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("web seed should bind");
+            let address = listener.local_addr().expect("web seed should have address");
+            let respond = Arc::new(respond);
+            tokio::spawn(async move {
+                loop {
+                    let (mut socket, _) = listener.accept().await.expect("client should connect");
+                    let respond = respond.clone();
+                    tokio::spawn(async move {
+                        let request = TestHttp::read_head(&mut socket).await;
+                        if request.is_empty() {
+                            return;
+                        }
+                        let response = respond(&request);
+                        socket.write_all(&response).await.expect("response should write");
+                    });
+                }
+            });
+            address
+        }
+
+        fn response(status: &str, body: &[u8]) -> Vec<u8> {
+            let mut response = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+            response.extend_from_slice(body);
+            response
+        }
+
+        /// A torrent of the given data, in "multi" with the given files, or a single file one
+        fn state(download_directory: PathBuf, data: &[u8], piece_length: usize, files: Option<Vec<(Vec<String>, Vec<u8>)>>) -> Arc<State> {
+            let (name, files) = match files {
+                Some(files) => ("multi", Some(files)),
+                None => ("single.bin", None),
+            };
+            let state = TestState::new(name).data(data, piece_length).download_directory(download_directory);
+            match files {
+                Some(files) => state.files(files),
+                None => state,
+            }
+            .build()
+        }
+    }
+}
@@ -17,27 +17,40 @@
 ///    which can control core behaviours of engine such as shut it down
 use crate::{
     core::{
+        file_priorities::{FilePriorities, FilePriority},
         magnet::{MagnetTorrent, MagnetTorrentError},
-        state::State,
-        tracker::TrackerState,
+        peer::{InboundPeer, PeerListener},
+        piece_checker::PieceChecker,
+        piece_storage::{PieceStorage, PieceStorageError},
+        resume::{ResumeData, ResumeError},
+        state::{DownState, State},
+        stream_server::{FileStream, StreamListener, StreamRequest},
+        tracker::{ScrapeStats, TierMode, Tracker, TrackerManager, TrackerState},
         TError, TorrentFile,
     },
     download_directory::{DownloadDirectory, DownloadDirectoryError},
 };
+use crossbeam::atomic::AtomicCell;
 use hyperblow::parser::magnet_uri_parser::MagnetURIMeta;
 use std::{
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
     thread::JoinHandle,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{
-    runtime::{Builder, Runtime},
+    io::{AsyncBufReadExt, BufReader},
+    net::{tcp::OwnedReadHalf, TcpStream},
+    runtime::{Builder, Handle, Runtime},
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Mutex,
+        Mutex, Notify,
     },
+    time::{interval_at, timeout, Instant, MissedTickBehavior},
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{debug, error, info, warn};
 
 #[derive(Debug)]
@@ -46,6 +59,7 @@ enum Torrent {
     FileTorrent(Arc<TorrentFile>),
 }
 
+#[derive(Debug, Clone)]
 pub enum TorrentSource {
     MagnetURI(String),
     FilePath(String),
@@ -58,6 +72,23 @@ impl TorrentSource {
             Self::FilePath(_) => "file",
         }
     }
+
+    /// The magnet URI or the path of the ".torrent" file
+    pub fn value(&self) -> &str {
+        match self {
+            Self::MagnetURI(uri) => uri,
+            Self::FilePath(path) => path,
+        }
+    }
+
+    /// Recreates the source a torrent was added with from its resume data
+    pub fn from_resume(resume: &ResumeData) -> Option<Self> {
+        match resume.source_kind.as_str() {
+            "magnet" => Some(Self::MagnetURI(resume.source.clone())),
+            "file" => Some(Self::FilePath(resume.source.clone())),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -79,14 +110,72 @@ pub enum EngineError {
 
     #[error("invalid download directory")]
     DownloadDirectory(#[from] DownloadDirectoryError),
+
+    #[error("no torrent at index {0}")]
+    TorrentNotFound(usize),
+
+    #[error("no torrent with info hash {0}")]
+    UnknownInfoHash(String),
+
+    #[error(":{0} doesn't act on a torrent")]
+    NotATorrentCommand(&'static str),
+
+    #[error("torrent is already being checked")]
+    AlreadyChecking,
+
+    #[error("torrent is already paused")]
+    AlreadyPaused,
+
+    #[error("torrent is not paused")]
+    NotPaused,
+
+    #[error("failed to update resume data")]
+    Resume(#[from] ResumeError),
+
+    #[error("failed to delete torrent data")]
+    Storage(#[from] PieceStorageError),
+
+    #[error("engine is shut down")]
+    ShutDown,
+
+    #[error("failed to start the streaming server")]
+    StreamServer(#[source] std::io::Error),
 }
 
 pub struct TrackerSnapshot {
     pub url: String,
     pub status: String,
     pub is_error: bool,
+
+    /// Swarm counts of the tracker's last scrape, if it was scraped
+    pub scrape: Option<ScrapeStats>,
+}
+
+/// A file or a directory of the file tree of a torrent, as the Files tab shows it
+pub struct FileRow {
+    /// Name of the file, indented by its depth in the tree
+    pub name: String,
+
+    /// Indexes of the files the row stands for, a directory stands for every file inside of it
+    pub files: Vec<usize>,
+
+    /// The priority every file of the row has, there's none when they differ
+    pub priority: Option<FilePriority>,
 }
 
+/// How often the resume file of every torrent gets written while it runs
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the trackers get to hear that a torrent stopped, before they're given up on
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the pieces being written get to reach the disk on shutdown
+const PIECE_WRITES_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the tasks still running once the torrents are shut down get to end, before the
+/// engine thread stops anyway
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 type TorrentHandleResultReceiver = Arc<Mutex<UnboundedReceiver<Result<Arc<TorrentHandle>, EngineError>>>>;
 
 pub struct Engine {
@@ -95,8 +184,25 @@ pub struct Engine {
 
     download_directory: DownloadDirectory,
 
-    /// The thread that spawns the tokio runtime, where all the torrents download is gonna take place
-    engine_thread_handle: JoinHandle<()>,
+    /// The thread that spawns the tokio runtime, where all the torrents download is gonna take place,
+    /// it's taken out to be joined on shutdown
+    engine_thread_handle: std::sync::Mutex<Option<JoinHandle<()>>>,
+
+    /// Cancelled by [Engine::shutdown], the engine thread stops its torrents and ends on it
+    shutdown: CancellationToken,
+
+    /// Handle of the runtime of the engine thread, the tasks of the engine itself are spawned on it
+    runtime: Handle,
+
+    /// Tasks of the engine rather than of a torrent, eg. the streaming server and its connections,
+    /// the engine thread waits for them on shutdown
+    tasks: TaskTracker,
+
+    /// How the torrents spawned from now on use their announce tiers
+    tier_mode: Arc<AtomicCell<TierMode>>,
+
+    /// Port of the streaming server on 127.0.0.1, once it's started
+    stream_port: AtomicCell<Option<u16>>,
 
     /// An internal sender that sends the newly spawned torrent source from the ui_thread into the engine_thread
     trnt_thread_sender: UnboundedSender<TorrentSource>,
@@ -118,7 +224,11 @@ impl Engine {
     }
 
     fn with_download_directory(download_directory: DownloadDirectory) -> Arc<Self> {
-        let torrents = Arc::default();
+        let torrents: Arc<Mutex<Vec<Arc<TorrentHandle>>>> = Arc::default();
+        let engine_torrents = torrents.clone();
+        let inbound_router = InboundPeerRouter {
+            torrents: torrents.clone(),
+        };
         let engine_download_directory = download_directory.clone();
         debug!(download_directory = %download_directory.path().display(), "creating engine");
 
@@ -128,45 +238,211 @@ impl Engine {
         // Receives the torrent handle from engine thread and sents it back to ui_thread
         let (thdl_sd, thdl_rx) = unbounded_channel::<Result<Arc<TorrentHandle>, EngineError>>();
 
-        let engine_thread_handle = std::thread::spawn(move || {
-            let tokio_rt = Self::generate_tokio_runtime();
+        let shutdown = CancellationToken::new();
+        let engine_shutdown = shutdown.clone();
+        let tier_mode = Arc::new(AtomicCell::new(TierMode::default()));
+        let engine_tier_mode = tier_mode.clone();
+        let tasks = TaskTracker::new();
+        let engine_tasks = tasks.clone();
 
+        let tokio_rt = Self::generate_tokio_runtime();
+        let runtime = tokio_rt.handle().clone();
+        let engine_thread_handle = std::thread::spawn(move || {
             tokio_rt.block_on(async move {
-                while let Some(src) = tsrc_rx.recv().await {
-                    let source_kind = src.kind();
-                    debug!(source = source_kind, "engine received torrent source");
-                    // TODO : Check if there was any error in creating the torrent handle in this
-                    // engine_thread and then only run the torrent on the engine thread and send its pointer to the ui_thread
-                    let handle = TorrentHandle::new(src, engine_download_directory.path().to_path_buf()).await;
-                    match &handle {
-                        Ok(handle) => {
-                            info!(source = source_kind, torrent = %handle.name(), "torrent handle created");
-                            let tokio_handle = handle.clone();
-                            tokio::task::spawn(async move { tokio_handle.run().await });
+                let listen_port = match PeerListener::bind().await {
+                    Ok(listener) => {
+                        let listen_port = listener.port();
+                        info!(port = listen_port, "listening for inbound peers");
+                        tokio::task::spawn(inbound_router.run(listener));
+                        Some(listen_port)
+                    }
+                    Err(error) => {
+                        warn!(error = %error, "failed to bind inbound peer listener");
+                        None
+                    }
+                };
+
+                // One UDP socket for the trackers and the DHT node of every torrent
+                let tracker_manager = match TrackerManager::bind(engine_download_directory.path()).await {
+                    Ok(manager) => {
+                        let receiver = manager.clone();
+                        tokio::task::spawn(async move { receiver.run().await });
+                        Some(manager)
+                    }
+                    Err(error) => {
+                        warn!(error = %error, "failed to bind tracker socket");
+                        None
+                    }
+                };
+
+                let receive_sources = async {
+                    while let Some(src) = tsrc_rx.recv().await {
+                        let source_kind = src.kind();
+                        debug!(source = source_kind, "engine received torrent source");
+                        // TODO : Check if there was any error in creating the torrent handle in this
+                        // engine_thread and then only run the torrent on the engine thread and send its pointer to the ui_thread
+                        let mut handle = TorrentHandle::new(src, engine_download_directory.path().to_path_buf()).await;
+                        match handle {
+                            Ok(ref mut handle) => {
+                                let mut torrents = engine_torrents.lock().await;
+                                // The same torrent can be given twice, eg. on the command line and by
+                                // its resume file, only the first one is run
+                                if let Some(running) = torrents.iter().find(|running| running.info_hash() == handle.info_hash()) {
+                                    debug!(torrent = %running.name(), "torrent is already running");
+                                    *handle = running.clone();
+                                } else {
+                                    info!(source = source_kind, torrent = %handle.name(), "torrent handle created");
+                                    if let Some(listen_port) = listen_port {
+                                        handle.set_listen_port(listen_port).await;
+                                    }
+                                    if let Some(ref tracker_manager) = tracker_manager {
+                                        handle.set_tracker_manager(tracker_manager.clone()).await;
+                                    }
+                                    handle.current_state().set_tier_mode(engine_tier_mode.load());
+                                    torrents.push(handle.clone());
+                                    let tokio_handle = handle.clone();
+                                    tokio::task::spawn(async move { tokio_handle.run().await });
+                                }
+                            }
+                            Err(ref error) => {
+                                error!(source = source_kind, error = %error, "failed to create torrent handle");
+                            }
                         }
-                        Err(error) => {
-                            error!(source = source_kind, error = %error, "failed to create torrent handle");
+
+                        // Send the handle back to the main thread
+                        if thdl_sd.send(handle).is_err() {
+                            warn!(source = source_kind, "engine response receiver closed");
+                            break;
                         }
                     }
+                };
 
-                    // Send the handle back to the main thread
-                    if thdl_sd.send(handle).is_err() {
-                        warn!(source = source_kind, "engine response receiver closed");
-                        break;
-                    }
+                // The sources stop coming when the engine is dropped without a shutdown, its
+                // torrents are simply dropped with the runtime then
+                tokio::select! {
+                    _ = receive_sources => return,
+                    _ = engine_shutdown.cancelled() => {}
+                }
+                info!("shutting down engine");
+                drop(tsrc_rx);
+                drop(thdl_sd);
+                Self::shutdown_torrents(&engine_torrents).await;
+                engine_tasks.close();
+                if timeout(RUNTIME_SHUTDOWN_TIMEOUT, engine_tasks.wait()).await.is_err() {
+                    warn!(tasks = engine_tasks.len(), "engine tasks still running on shutdown");
                 }
             });
+            // Whatever is left running, the peer and tracker sockets included, is dropped with the runtime
+            tokio_rt.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+            info!("engine thread stopped");
         });
 
         Arc::new(Self {
             torrents,
             download_directory,
-            engine_thread_handle,
+            engine_thread_handle: std::sync::Mutex::new(Some(engine_thread_handle)),
+            shutdown,
+            runtime,
+            tasks,
+            tier_mode,
+            stream_port: AtomicCell::new(None),
             trnt_thread_sender: tsrc_sd,
             trnt_handle_receiver: Arc::new(Mutex::new(thdl_rx)),
         })
     }
 
+    /// Shuts the engine down, it stops taking torrents and stops every torrent it has. Their
+    /// trackers are told so within a deadline, the pieces being written and the resume files
+    /// reach the disk, the peer connections are closed and finally the engine thread is joined
+    ///
+    /// It blocks until the engine thread is done, so it must not be called from an async context
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+        let engine_thread_handle = self.engine_thread_handle.lock().ok().and_then(|mut handle| handle.take());
+        if let Some(engine_thread_handle) = engine_thread_handle {
+            if engine_thread_handle.join().is_err() {
+                error!("engine thread panicked");
+            }
+        }
+    }
+
+    /// Sets how the torrents spawned from now on use their announce tiers
+    pub fn set_tier_mode(&self, tier_mode: TierMode) {
+        self.tier_mode.store(tier_mode);
+    }
+
+    /// Starts serving the files of the torrents over HTTP on 127.0.0.1, so a media player can play
+    /// them while they download, eg. `mpv http://127.0.0.1:<port>/<hex info hash>/<path of the file>`.
+    /// A port of 0 has the OS pick a free one, the port it listens on is given back
+    ///
+    /// It runs on the engine's runtime until the engine is shut down
+    pub async fn start_stream_server(&self, port: u16) -> Result<u16, EngineError> {
+        if let Some(port) = self.stream_port.load() {
+            return Ok(port);
+        }
+        if self.shutdown.is_cancelled() {
+            return Err(EngineError::ShutDown);
+        }
+        let router = StreamRouter {
+            torrents: self.torrents.clone(),
+            tasks: self.tasks.clone(),
+            shutdown: self.shutdown.clone(),
+        };
+        let (port_sender, port_receiver) = tokio::sync::oneshot::channel();
+        self.tasks.spawn_on(
+            async move {
+                match StreamListener::bind(port).await {
+                    Ok(listener) => {
+                        let _ = port_sender.send(Ok(listener.port()));
+                        router.run(listener).await;
+                        debug!("streaming server stopped");
+                    }
+                    Err(error) => {
+                        let _ = port_sender.send(Err(error));
+                    }
+                }
+            },
+            &self.runtime,
+        );
+
+        let port = port_receiver
+            .await
+            .map_err(|_| EngineError::ShutDown)?
+            .map_err(EngineError::StreamServer)?;
+        self.stream_port.store(Some(port));
+        info!(port, "serving torrent files over HTTP");
+        Ok(port)
+    }
+
+    pub fn stream_port(&self) -> Option<u16> {
+        self.stream_port.load()
+    }
+
+    async fn shutdown_torrents(torrents: &Mutex<Vec<Arc<TorrentHandle>>>) {
+        let torrents = torrents.lock().await.clone();
+        let mut trackers = Vec::new();
+        for handle in &torrents {
+            let state = handle.current_state();
+            if !state.is_stopped() {
+                state.stop().await;
+                trackers.extend(state.trackers.read().await.iter().flatten().cloned());
+            }
+        }
+        // All the trackers share one deadline, so a lot of torrents don't hold the exit up for long
+        Tracker::announce_stopped(trackers, STOPPED_ANNOUNCE_TIMEOUT).await;
+
+        for handle in &torrents {
+            let state = handle.current_state();
+            if timeout(PIECE_WRITES_TIMEOUT, state.flush_piece_writes()).await.is_err() {
+                warn!(torrent = %handle.name(), "pieces still being written on shutdown");
+            }
+            if let Err(error) = handle.save_resume_data().await {
+                warn!(torrent = %handle.name(), error = %error, "failed to save resume data");
+            }
+        }
+        info!(torrents = torrents.len(), "torrents shut down");
+    }
+
     /// Creates a tokio runtime on thread its called
     fn generate_tokio_runtime() -> Runtime {
         Builder::new_multi_thread().enable_all().build().unwrap()
@@ -179,16 +455,36 @@ impl Engine {
     ///  TODO : Return some verbose error i.e Result<T,G> rather than Option None
     pub async fn spawn(&self, src: TorrentSource) -> Result<Arc<TorrentHandle>, EngineError> {
         let source_kind = src.kind();
+        if self.shutdown.is_cancelled() {
+            return Err(EngineError::ShutDown);
+        }
         debug!(source = source_kind, "queueing torrent spawn");
         // Sends the torrent source into the engine_thread that holds the tokio runtime
         self.trnt_thread_sender.send(src).map_err(|_| EngineError::CommandChannelClosed)?;
         let mut torrenthandle_receiver = self.trnt_handle_receiver.lock().await;
         let handle = torrenthandle_receiver.recv().await.ok_or(EngineError::ResponseChannelClosed)??;
         info!(source = source_kind, torrent = %handle.name(), "torrent spawned");
-        self.torrents.lock().await.push(handle.clone());
         Ok(handle)
     }
 
+    /// Spawns again every torrent that has a resume file in the download directory, they pick up
+    /// from where the previous session left off
+    pub async fn resume_torrents(&self) -> Vec<Arc<TorrentHandle>> {
+        let mut handles = Vec::new();
+        for resume in ResumeData::load_all(self.download_directory.path()).await {
+            let Some(src) = TorrentSource::from_resume(&resume) else {
+                warn!(source = %resume.source_kind, "skipping resume data of unknown source");
+                continue;
+            };
+            match self.spawn(src).await {
+                Ok(handle) => handles.push(handle),
+                Err(error) => warn!(error = %error, "failed to resume torrent"),
+            }
+        }
+        info!(torrents = handles.len(), "resumed torrents");
+        handles
+    }
+
     pub fn torrent_snapshot(&self) -> Option<Vec<Arc<TorrentHandle>>> {
         self.torrents.try_lock().ok().map(|handles| handles.clone())
     }
@@ -196,12 +492,44 @@ impl Engine {
     pub fn download_directory(&self) -> &Path {
         self.download_directory.path()
     }
+
+    pub async fn torrent(&self, index: usize) -> Result<Arc<TorrentHandle>, EngineError> {
+        self.torrents
+            .lock()
+            .await
+            .get(index)
+            .cloned()
+            .ok_or(EngineError::TorrentNotFound(index))
+    }
+
+    /// Takes the torrent of the info hash out of the engine and stops it for good, see
+    /// [TorrentHandle::remove]. It's looked up by info hash rather than by its row in the UI, which
+    /// may have moved by the time the command runs
+    pub async fn remove(&self, info_hash: &[u8], delete_data: bool) -> Result<Arc<TorrentHandle>, EngineError> {
+        let handle = {
+            let mut torrents = self.torrents.lock().await;
+            let index = torrents
+                .iter()
+                .position(|handle| handle.info_hash() == info_hash)
+                .ok_or_else(|| EngineError::UnknownInfoHash(info_hash.iter().map(|byte| format!("{byte:02x}")).collect()))?;
+            torrents.remove(index)
+        };
+        handle.remove(delete_data).await?;
+        Ok(handle)
+    }
 }
 
 #[derive(Debug)]
 pub struct TorrentHandle {
     inner: Torrent,
+    source: TorrentSource,
     download_directory: PathBuf,
+
+    /// Wakes up the run loop of a paused torrent, once it's resumed or removed
+    resumed: Notify,
+
+    /// Set once the torrent is removed, it's never run or saved again after that
+    removed: AtomicCell<bool>,
 }
 
 impl TorrentHandle {
@@ -212,9 +540,14 @@ impl TorrentHandle {
             TorrentSource::FilePath(ref path) => {
                 debug!(source = "file", path = %path, "loading torrent file");
                 let torrent = TorrentFile::new(path, download_directory.clone()).await?;
+                // The resume data has to find the file again from whatever directory we're restarted in
+                let path = std::fs::canonicalize(path).map_or_else(|_| path.clone(), |path| path.display().to_string());
                 Ok(Arc::new(Self {
                     inner: Torrent::FileTorrent(Arc::new(torrent)),
+                    source: TorrentSource::FilePath(path),
                     download_directory,
+                    resumed: Notify::new(),
+                    removed: AtomicCell::new(false),
                 }))
             }
             TorrentSource::MagnetURI(ref uri) => {
@@ -223,41 +556,197 @@ impl TorrentHandle {
                 let magnet = MagnetTorrent::new(magnet, download_directory.clone()).await?;
                 Ok(Arc::new(Self {
                     inner: Torrent::MagnetUriTorrent(Arc::new(magnet)),
+                    source: src.clone(),
                     download_directory,
+                    resumed: Notify::new(),
+                    removed: AtomicCell::new(false),
                 }))
             }
         }
     }
 
     pub async fn run(&self) {
-        match self.inner {
-            Torrent::MagnetUriTorrent(ref magnet) => {
-                magnet.run().await;
+        tokio::select! {
+            _ = self.run_sessions() => {}
+            _ = self.run_resume_saver() => {}
+        }
+    }
+
+    /// Runs the torrent, and runs it again every time it's resumed after a pause, until it's
+    /// removed or ends on its own
+    async fn run_sessions(&self) {
+        loop {
+            let session = self.current_state().session().await;
+            match self.inner {
+                Torrent::MagnetUriTorrent(ref magnet) => {
+                    magnet.run().await;
+                }
+                Torrent::FileTorrent(ref file_trnt) => {
+                    file_trnt.run().await;
+                }
             }
-            Torrent::FileTorrent(ref file_trnt) => {
-                file_trnt.run().await;
+            if self.removed.load() || !session.is_cancelled() {
+                break;
+            }
+
+            debug!(torrent = %self.name(), "waiting for the torrent to be resumed");
+            self.resumed.notified().await;
+            if self.removed.load() {
+                break;
             }
         }
     }
 
-    fn current_state(&self) -> Arc<State> {
-        match self.inner {
-            Torrent::FileTorrent(ref file_trnt) => file_trnt.state.clone(),
-            Torrent::MagnetUriTorrent(ref magnet) => magnet.state(),
+    /// Keeps the resume file up to date, the first write waits for a whole interval so the
+    /// torrent has restored the previous resume file by then
+    async fn run_resume_saver(&self) {
+        let mut ticker = interval_at(Instant::now() + RESUME_SAVE_INTERVAL, RESUME_SAVE_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(error) = self.save_resume_data().await {
+                warn!(torrent = %self.name(), error = %error, "failed to save resume data");
+            }
+        }
+    }
+
+    /// Writes the resume file of the torrent into the download directory
+    pub async fn save_resume_data(&self) -> Result<(), ResumeError> {
+        // A removed torrent must not bring its resume file back
+        if self.removed.load() {
+            return Ok(());
         }
+        let state = self.current_state();
+        // A magnet whose metadata isn't fetched again yet knows nothing about the pieces, it must
+        // not overwrite the progress of the previous session
+        if state.pieces_hash.is_empty() && ResumeData::path(&self.download_directory, &state.info_hash).exists() {
+            return Ok(());
+        }
+        ResumeData::capture(&state, self.source.kind(), self.source.value())
+            .await
+            .save(&self.download_directory)
+            .await
+    }
+
+    /// Hashes all the data on the disk again, eg. after the files were changed behind our back,
+    /// and gives the no of pieces that matched. A running torrent is stopped meanwhile, so its peers
+    /// don't write pieces under the check, and started again afterwards
+    pub async fn force_recheck(&self) -> Result<usize, EngineError> {
+        let state = self.current_state();
+        let previous_d_state = state
+            .switch_d_state(DownState::Checking, |d_state| d_state != DownState::Checking)
+            .map_err(|_| EngineError::AlreadyChecking)?;
+        let running = previous_d_state != DownState::Stopped;
+        if running {
+            state.end_session().await;
+            Self::announce_stopped(&state).await;
+            state.flush_piece_writes().await;
+        }
+
+        let verified_pieces = PieceChecker::recheck(&state).await;
+        if running && !self.removed.load() {
+            state.start().await;
+            self.resumed.notify_one();
+        } else {
+            state.set_d_state(DownState::Stopped);
+        }
+        if let Err(error) = self.save_resume_data().await {
+            warn!(torrent = %self.name(), error = %error, "failed to save resume data");
+        }
+        Ok(verified_pieces)
+    }
+
+    /// Pauses the torrent, its peer and tracker tasks are dropped and the trackers are told it
+    /// stopped. The progress is saved, so it's kept even if we're closed while it's paused
+    pub async fn pause(&self) -> Result<(), EngineError> {
+        let state = self.current_state();
+        state
+            .switch_d_state(DownState::Stopped, |d_state| {
+                !matches!(d_state, DownState::Stopped | DownState::Checking)
+            })
+            .map_err(|d_state| match d_state {
+                DownState::Checking => EngineError::AlreadyChecking,
+                _ => EngineError::AlreadyPaused,
+            })?;
+        self.stop(&state).await;
+        self.save_resume_data().await?;
+        info!(torrent = %self.name(), "torrent paused");
+        Ok(())
+    }
+
+    /// Starts a paused torrent again, it reconnects to its trackers and peers from scratch
+    pub async fn resume(&self) -> Result<(), EngineError> {
+        let state = self.current_state();
+        state
+            .switch_d_state(DownState::Unknown, |d_state| d_state == DownState::Stopped)
+            .map_err(|_| EngineError::NotPaused)?;
+        state.start().await;
+        self.resumed.notify_one();
+        info!(torrent = %self.name(), "torrent resumed");
+        Ok(())
+    }
+
+    /// Stops the torrent for good and deletes its resume file, so it isn't started again with
+    /// the next session. With "delete_data" the files it downloaded are deleted as well
+    ///
+    /// It's meant to be called through [Engine::remove], which also takes the handle out of the engine
+    pub async fn remove(&self, delete_data: bool) -> Result<(), EngineError> {
+        self.removed.store(true);
+        let state = self.current_state();
+        if state
+            .switch_d_state(DownState::Stopped, |d_state| d_state != DownState::Stopped)
+            .is_ok()
+        {
+            self.stop(&state).await;
+        }
+        self.resumed.notify_one();
+
+        ResumeData::remove(&self.download_directory, &state.info_hash).await?;
+        if delete_data {
+            // The pieces still being written would bring the files back
+            state.flush_piece_writes().await;
+            PieceStorage::remove_files(&state).await?;
+        }
+        info!(torrent = %self.name(), delete_data, "torrent removed");
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.current_state().is_stopped()
+    }
+
+    async fn stop(&self, state: &State) {
+        state.stop().await;
+        Self::announce_stopped(state).await;
+    }
+
+    async fn announce_stopped(state: &State) {
+        let trackers = state.trackers.read().await.iter().flatten().cloned().collect::<Vec<_>>();
+        Tracker::announce_stopped(trackers, STOPPED_ANNOUNCE_TIMEOUT).await;
+    }
+
+    pub fn info_hash(&self) -> Vec<u8> {
+        self.current_state().info_hash.clone()
+    }
+
+    /// Records the port of the engine's inbound peer listener, so it's announced to the trackers
+    async fn set_listen_port(&self, port: u16) {
+        let state = self.current_state();
+        let mut tcp_ports = state.tcp_ports.lock().await;
+        if !tcp_ports.contains(&port) {
+            tcp_ports.insert(0, port);
+        }
+    }
+
+    /// Has the torrent use the engine's socket for its UDP trackers and DHT lookups
+    async fn set_tracker_manager(&self, manager: Arc<TrackerManager>) {
+        *self.current_state().tracker_manager.write().await = Some(manager);
     }
 
-    /// Gets the name of the torrent blockingly
-    pub fn pause_resume(&self) -> String {
+    fn current_state(&self) -> Arc<State> {
         match self.inner {
-            Torrent::FileTorrent(ref file_trnt) => file_trnt
-                .state
-                .meta_info
-                .info
-                .name
-                .clone()
-                .unwrap_or_else(|| "Unnamed torrent".to_string()),
-            Torrent::MagnetUriTorrent(ref magnet) => MagnetTitle::from_meta(magnet.meta()).display(),
+            Torrent::FileTorrent(ref file_trnt) => file_trnt.state.clone(),
+            Torrent::MagnetUriTorrent(ref magnet) => magnet.state(),
         }
     }
 
@@ -320,15 +809,29 @@ impl TorrentHandle {
 
     /// Gives the total download speed in "bytes/second".
     pub fn download_speed(&self) -> usize {
-        0
+        self.current_state().download_rate.bytes_per_second()
     }
 
     /// Gives the total upload speed in "bytes/second".
     pub fn upload_speed(&self) -> usize {
-        0
+        self.current_state().upload_rate.bytes_per_second()
+    }
+
+    /// Gives the total "bytes" uploaded to peers
+    pub fn bytes_uploaded(&self) -> usize {
+        self.current_state().uploaded()
     }
 
     pub fn status_label(&self) -> String {
+        let state = self.current_state();
+        if state.d_state() == DownState::Checking {
+            let checked_percent = (state.pieces_checked().saturating_mul(100) / state.pieces_hash.len().max(1)).min(100);
+            return format!("Checking {checked_percent}%");
+        }
+        if state.is_stopped() {
+            return "Paused".to_string();
+        }
+
         match self.inner {
             Torrent::FileTorrent(_) => "Preparing".to_string(),
             Torrent::MagnetUriTorrent(ref magnet) => magnet.status_label(),
@@ -357,6 +860,15 @@ impl TorrentHandle {
         }
     }
 
+    /// Whether the pieces are downloaded in order, see [TorrentHandle::toggle_sequential]
+    pub fn is_sequential(&self) -> bool {
+        self.current_state()
+            .piece_picker
+            .try_lock()
+            .map(|piece_picker| piece_picker.is_sequential())
+            .unwrap_or_default()
+    }
+
     pub fn peer_addresses(&self) -> Vec<String> {
         match self.inner {
             Torrent::FileTorrent(ref file_trnt) => file_trnt.state.peers.try_lock().map_or_else(
@@ -370,14 +882,199 @@ impl TorrentHandle {
         }
     }
 
-    pub fn file_tree_names(&self) -> Vec<String> {
-        match self.getFileTree() {
-            Some(file_tree) => file_tree
-                .try_lock()
-                .map_or_else(|_| Vec::new(), |file_tree| file_tree.try_tabs_traverse_names(0)),
-            None => Vec::new(),
+    /// The rows of the file tree with the priorities of their files, it's empty until the tree
+    /// and the priorities can be read without waiting
+    pub fn file_rows(&self) -> Vec<FileRow> {
+        let state = self.current_state();
+        let Some(file_tree) = state.file_tree.clone() else {
+            return Vec::new();
+        };
+        let (Ok(file_tree), Ok(priorities)) = (file_tree.try_lock(), state.file_priorities.try_lock()) else {
+            return Vec::new();
+        };
+        file_tree
+            .try_tabs_traverse_files(0)
+            .into_iter()
+            .map(|(name, files)| {
+                let mut file_priorities = files.iter().map(|&index| priorities.get(index).copied().unwrap_or_default());
+                let first = file_priorities.next();
+                let priority = first.filter(|&first| file_priorities.all(|priority| priority == first));
+                FileRow { name, files, priority }
+            })
+            .collect()
+    }
+
+    /// Puts the first and last pieces of the files at the front of the queue, so a player can open
+    /// them while the rest is downloading
+    pub async fn prioritize_file_edges(&self, files: &[usize]) {
+        FilePriorities::set_edge_deadlines(&self.current_state(), files).await;
+        info!(torrent = %self.name(), files = files.len(), "file edges prioritized");
+    }
+
+    /// Switches between downloading the pieces in order and rarest first, and gives whether
+    /// they're now downloaded in order
+    pub async fn toggle_sequential(&self) -> bool {
+        let state = self.current_state();
+        let sequential = {
+            let mut piece_picker = state.piece_picker.lock().await;
+            let sequential = !piece_picker.is_sequential();
+            piece_picker.set_sequential(sequential);
+            sequential
+        };
+        if let Err(error) = self.save_resume_data().await {
+            warn!(torrent = %self.name(), error = %error, "failed to save resume data");
+        }
+        info!(torrent = %self.name(), sequential, "sequential download toggled");
+        sequential
+    }
+
+    /// Gives the files at the indexes, among the files of the torrent, the priority. It's saved
+    /// right away, so a file that was skipped stays skipped whenever we're closed
+    pub async fn set_file_priority(&self, files: &[usize], priority: FilePriority) {
+        FilePriorities::set(&self.current_state(), files, priority).await;
+        if let Err(error) = self.save_resume_data().await {
+            warn!(torrent = %self.name(), error = %error, "failed to save resume data");
+        }
+        info!(torrent = %self.name(), files = files.len(), priority = priority.label(), "file priority set");
+    }
+}
+
+/// Hands the peers accepted by the engine's [PeerListener] over to the torrent whose info hash
+/// they asked for in their handshake
+struct InboundPeerRouter {
+    torrents: Arc<Mutex<Vec<Arc<TorrentHandle>>>>,
+}
+
+impl InboundPeerRouter {
+    async fn run(self, listener: PeerListener) {
+        let router = Arc::new(self);
+        loop {
+            match listener.accept().await {
+                Ok((tcp_stream, socket_adr)) => {
+                    let router = router.clone();
+                    tokio::task::spawn(async move { router.route(tcp_stream, socket_adr).await });
+                }
+                Err(error) => warn!(error = %error, "failed to accept inbound peer"),
+            }
+        }
+    }
+
+    async fn route(&self, tcp_stream: TcpStream, socket_adr: SocketAddr) {
+        let inbound = match InboundPeer::read_handshake(tcp_stream, socket_adr).await {
+            Ok(inbound) => inbound,
+            Err(error) => {
+                debug!(peer = %socket_adr, error = %error, "dropping inbound peer without handshake");
+                return;
+            }
+        };
+
+        match self.state_for(inbound.info_hash()).await {
+            Some(state) => inbound.run(state).await,
+            None => debug!(peer = %socket_adr, "dropping inbound peer for unknown torrent"),
+        }
+    }
+
+    async fn state_for(&self, info_hash: &[u8]) -> Option<Arc<State>> {
+        let torrents = self.torrents.lock().await;
+        torrents
+            .iter()
+            .map(|handle| handle.current_state())
+            // A paused torrent doesn't take peers, not even the ones connecting to us
+            .find(|state| state.info_hash == info_hash && !state.is_stopped())
+    }
+}
+
+/// Answers the HTTP requests of media players with the files of the torrent whose info hash the
+/// path asks for
+struct StreamRouter {
+    torrents: Arc<Mutex<Vec<Arc<TorrentHandle>>>>,
+
+    /// The connections are tasks of the engine, so shutdown waits for them
+    tasks: TaskTracker,
+    shutdown: CancellationToken,
+}
+
+impl StreamRouter {
+    async fn run(self, listener: StreamListener) {
+        let router = Arc::new(self);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = router.shutdown.cancelled() => return,
+            };
+            match accepted {
+                Ok((tcp_stream, socket_adr)) => {
+                    let router = router.clone();
+                    router.tasks.clone().spawn(async move {
+                        tokio::select! {
+                            _ = router.serve(tcp_stream, socket_adr) => {}
+                            _ = router.shutdown.cancelled() => {}
+                        }
+                    });
+                }
+                Err(error) => warn!(error = %error, "failed to accept stream connection"),
+            }
+        }
+    }
+
+    /// Answers the requests of a connection one after the other, until the player closes it
+    async fn serve(&self, tcp_stream: TcpStream, socket_adr: SocketAddr) {
+        let (reader, mut writer) = tcp_stream.into_split();
+        let mut reader = BufReader::new(reader);
+        loop {
+            let request = match StreamRequest::read(&mut reader).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(error) => {
+                    debug!(player = %socket_adr, error = %error, "dropping stream connection");
+                    let _ = FileStream::reject(&mut writer).await;
+                    return;
+                }
+            };
+            debug!(player = %socket_adr, method = %request.method, path = %request.path, "stream request");
+
+            let state = match request.target() {
+                Some((info_hash, _)) => self.state_for(&info_hash).await,
+                None => None,
+            };
+            // A player closes the connection eg. whenever it seeks, the read of the old position
+            // stops waiting for its pieces then
+            let closed = CancellationToken::new();
+            let serving = FileStream::serve(&mut writer, &request, state.as_ref(), &closed);
+            tokio::pin!(serving);
+            let served = tokio::select! {
+                served = &mut serving => served,
+                _ = Self::closed(&mut reader) => {
+                    closed.cancel();
+                    serving.await
+                }
+            };
+            if let Err(error) = served {
+                debug!(player = %socket_adr, error = %error, "stream connection closed");
+                return;
+            }
+            if !request.keep_alive {
+                return;
+            }
+        }
+    }
+
+    /// Resolves once the player closed its side of the connection, the next request it sends
+    /// meanwhile stays in the buffer for after the one being answered
+    async fn closed(reader: &mut BufReader<OwnedReadHalf>) {
+        match reader.fill_buf().await {
+            Ok([]) | Err(_) => {}
+            Ok(_) => std::future::pending().await,
         }
     }
+
+    async fn state_for(&self, info_hash: &[u8]) -> Option<Arc<State>> {
+        let torrents = self.torrents.lock().await;
+        torrents
+            .iter()
+            .map(|handle| handle.current_state())
+            .find(|state| state.info_hash == info_hash)
+    }
 }
 
 struct TrackerSnapshotList;
@@ -392,11 +1089,13 @@ impl TrackerSnapshotList {
             .iter()
             .flat_map(|tier| tier.iter())
             .map(|tracker| {
-                let status = tracker.tracker_state.load();
+                let status = tracker.tracker_state();
                 TrackerSnapshot {
                     url: tracker.address.to_string(),
                     status: status.to_string(),
-                    is_error: matches!(status, TrackerState::DNSUnresolved { .. } | TrackerState::Idle),
+                    // Idle trackers are the backups of their tier waiting for their turn, not failed ones
+                    is_error: matches!(status, TrackerState::DNSUnresolved { .. } | TrackerState::Error { .. }),
+                    scrape: tracker.scrape_stats.load(),
                 }
             })
             .collect::<Vec<_>>();
@@ -415,6 +1114,7 @@ impl TrackerSnapshotList {
                 url,
                 status: "Queued".to_string(),
                 is_error: false,
+                scrape: None,
             })
             .collect()
     }
@@ -458,18 +1158,35 @@ impl MagnetTitle {
 
 #[cfg(test)]
 mod tests {
-    use super::{Engine, Torrent, TorrentHandle, TorrentSource};
-    use crate::core::{magnet::MagnetTorrent, TorrentFile};
+    use super::{Engine, EngineError, Torrent, TorrentHandle, TorrentSource};
+    use crate::{
+        core::{
+            magnet::MagnetTorrent,
+            resume::{ResumeData, RESUME_VERSION},
+            state::DownState,
+            test_support::temp_dir,
+            TorrentFile,
+        },
+        download_directory::DownloadDirectory,
+    };
+    use crossbeam::atomic::AtomicCell;
     use hyperblow::parser::{
         magnet_uri_parser::MagnetURIMeta,
         torrent_parser::{FileMeta, Info},
     };
     use sha1::{Digest, Sha1};
-    use std::{path::PathBuf, sync::Arc};
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        runtime::Builder,
+        sync::Notify,
+    };
 
     #[tokio::test]
     async fn spawns_magnet_handle_without_network_setup() {
-        let engine = Engine::new();
+        let download_directory = temp_dir("engine-magnet");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory));
         let handle = engine
             .spawn(TorrentSource::MagnetURI(
                 "magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10&dn=Sintel&tr=udp://tracker.example.com:6969".to_string(),
@@ -486,9 +1203,192 @@ mod tests {
         assert_eq!(engine.torrents.lock().await.len(), 1);
     }
 
+    #[tokio::test]
+    async fn routes_inbound_peer_to_torrent_by_info_hash() {
+        let download_directory = temp_dir("engine-inbound");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory));
+        let handle = engine
+            .spawn(TorrentSource::MagnetURI(
+                "magnet:?xt=urn:btih:18ada5a7a6183aae1e09d831df6748d566095a10&dn=Inbound".to_string(),
+            ))
+            .await
+            .expect("magnet should spawn");
+        let state = handle.current_state();
+        let listen_port = *state.tcp_ports.lock().await.first().expect("listener port should be recorded");
+
+        let mut socket = TcpStream::connect(("127.0.0.1", listen_port))
+            .await
+            .expect("engine listener should accept");
+        let mut handshake = [0_u8; 68];
+        handshake[0] = 19;
+        handshake[1..20].copy_from_slice(b"BitTorrent protocol");
+        handshake[28..48].copy_from_slice(&state.info_hash);
+        socket.write_all(&handshake).await.expect("handshake should send");
+
+        let mut response = [0_u8; 68];
+        socket.read_exact(&mut response).await.expect("engine should answer handshake");
+        assert_eq!(&response[28..48], state.info_hash.as_slice());
+    }
+
+    #[tokio::test]
+    async fn routes_stream_requests_by_info_hash() {
+        let download_directory = temp_dir("engine-stream");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory));
+        engine
+            .spawn(TorrentSource::MagnetURI(
+                "magnet:?xt=urn:btih:38ada5a7a6183aae1e09d831df6748d566095a10&dn=Streamed".to_string(),
+            ))
+            .await
+            .expect("magnet should spawn");
+        let port = engine.start_stream_server(0).await.expect("streaming server should start");
+        assert_eq!(engine.stream_port(), Some(port));
+
+        let mut socket = TcpStream::connect(("127.0.0.1", port))
+            .await
+            .expect("streaming server should accept");
+        // Both requests go over the same connection, the second one closes it
+        socket
+            .write_all(
+                b"GET /38ada5a7a6183aae1e09d831df6748d566095a10/Streamed HTTP/1.1\r\n\r\n\
+                  GET /48ada5a7a6183aae1e09d831df6748d566095a10/Streamed HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .expect("requests should send");
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.expect("responses should read");
+
+        // The magnet's metadata isn't fetched, and the other info hash is of no torrent
+        let statuses = response.lines().filter(|line| line.starts_with("HTTP/1.1")).collect::<Vec<_>>();
+        assert_eq!(statuses, vec!["HTTP/1.1 503 Service Unavailable", "HTTP/1.1 404 Not Found"]);
+    }
+
+    #[tokio::test]
+    async fn resumes_torrents_saved_in_download_directory() {
+        let download_directory = temp_dir("engine-resume");
+        let uri = "magnet:?xt=urn:btih:28ada5a7a6183aae1e09d831df6748d566095a10&dn=Resumed";
+        let resume = ResumeData {
+            version: RESUME_VERSION,
+            info_hash: vec![
+                0x28, 0xad, 0xa5, 0xa7, 0xa6, 0x18, 0x3a, 0xae, 0x1e, 0x09, 0xd8, 0x31, 0xdf, 0x67, 0x48, 0xd5, 0x66, 0x09, 0x5a, 0x10,
+            ],
+            source_kind: "magnet".to_string(),
+            source: uri.to_string(),
+            piece_count: 0,
+            pieces: Vec::new(),
+            bytes_complete: 0,
+            uploaded: 0,
+            files: Vec::new(),
+            trackers: Vec::new(),
+            sequential: 0,
+        };
+        resume.save(&download_directory).await.expect("resume data should save");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory));
+
+        let resumed = engine.resume_torrents().await;
+        let spawned_again = engine
+            .spawn(TorrentSource::MagnetURI(uri.to_string()))
+            .await
+            .expect("magnet should spawn");
+
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].name(), "Resumed");
+        assert_eq!(resumed[0].info_hash(), resume.info_hash);
+        assert!(Arc::ptr_eq(&resumed[0], &spawned_again));
+        assert_eq!(engine.torrents.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pauses_resumes_and_removes_torrent() {
+        let download_directory = temp_dir("engine-lifecycle");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory.clone()));
+        let handle = engine
+            .spawn(TorrentSource::MagnetURI(
+                "magnet:?xt=urn:btih:38ada5a7a6183aae1e09d831df6748d566095a10&dn=Lifecycle".to_string(),
+            ))
+            .await
+            .expect("magnet should spawn");
+        let resume_path = ResumeData::path(&download_directory, &handle.info_hash());
+
+        handle.pause().await.expect("torrent should pause");
+
+        assert!(handle.is_paused());
+        assert_eq!(handle.status_label(), "Paused");
+        assert!(handle.current_state().session().await.is_cancelled());
+        assert!(resume_path.is_file());
+        assert!(matches!(handle.pause().await, Err(EngineError::AlreadyPaused)));
+
+        handle.resume().await.expect("torrent should resume");
+
+        assert!(!handle.is_paused());
+        assert!(!handle.current_state().session().await.is_cancelled());
+        assert!(matches!(handle.resume().await, Err(EngineError::NotPaused)));
+
+        let removed = engine.remove(&handle.info_hash(), false).await.expect("torrent should be removed");
+
+        assert!(Arc::ptr_eq(&removed, &handle));
+        assert!(engine.torrents.lock().await.is_empty());
+        assert!(!resume_path.exists());
+        assert!(matches!(
+            engine.remove(&handle.info_hash(), false).await,
+            Err(EngineError::UnknownInfoHash(info_hash)) if info_hash == "38ada5a7a6183aae1e09d831df6748d566095a10"
+        ));
+    }
+
+    #[tokio::test]
+    async fn recheck_restarts_a_running_torrent_and_refuses_a_second_one() {
+        let download_directory = temp_dir("engine-recheck");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory));
+        let handle = engine
+            .spawn(TorrentSource::MagnetURI(
+                "magnet:?xt=urn:btih:58ada5a7a6183aae1e09d831df6748d566095a10&dn=Rechecked".to_string(),
+            ))
+            .await
+            .expect("magnet should spawn");
+        let state = handle.current_state();
+
+        state.set_d_state(DownState::Checking);
+        assert!(matches!(handle.force_recheck().await, Err(EngineError::AlreadyChecking)));
+
+        state.set_d_state(DownState::Unknown);
+        let session = state.session().await;
+        assert_eq!(handle.force_recheck().await.expect("torrent should recheck"), 0);
+
+        // The sessions were stopped for the recheck and run again with a fresh token
+        assert!(session.is_cancelled());
+        assert!(!state.session().await.is_cancelled());
+        assert!(!handle.is_paused());
+
+        handle.pause().await.expect("torrent should pause");
+        handle.force_recheck().await.expect("paused torrent should recheck");
+        assert!(handle.is_paused());
+    }
+
+    #[test]
+    fn shutdown_stops_torrents_saves_them_and_joins_engine_thread() {
+        let download_directory = temp_dir("engine-shutdown");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory.clone()));
+        let runtime = Builder::new_current_thread().enable_all().build().expect("runtime should build");
+        let uri = "magnet:?xt=urn:btih:48ada5a7a6183aae1e09d831df6748d566095a10&dn=Shutdown";
+        let handle = runtime
+            .block_on(engine.spawn(TorrentSource::MagnetURI(uri.to_string())))
+            .expect("magnet should spawn");
+
+        engine.shutdown();
+
+        assert!(handle.current_state().is_stopped());
+        assert!(ResumeData::path(&download_directory, &handle.info_hash()).is_file());
+        assert!(engine.engine_thread_handle.lock().expect("handle lock").is_none());
+        assert!(matches!(
+            runtime.block_on(engine.spawn(TorrentSource::MagnetURI(uri.to_string()))),
+            Err(EngineError::ShutDown)
+        ));
+        engine.shutdown();
+    }
+
     #[tokio::test]
     async fn magnet_title_is_readable_without_display_name() {
-        let engine = Engine::new();
+        let download_directory = temp_dir("engine-title");
+        let engine = Engine::with_download_directory(DownloadDirectory::from_path(download_directory));
         let handle = engine
             .spawn(TorrentSource::MagnetURI(
                 "magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10".to_string(),
@@ -501,7 +1401,7 @@ mod tests {
 
     #[tokio::test]
     async fn magnet_handle_reports_resolved_download_progress() {
-        let download_directory = TestTorrent::download_directory();
+        let download_directory = temp_dir("engine-progress");
         let magnet = MagnetTorrent::new(
             MagnetURIMeta::fromMagnetURI("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=Progress")
                 .expect("magnet should parse"),
@@ -525,7 +1425,10 @@ mod tests {
         magnet.set_resolved_for_test(resolved).await;
         let handle = TorrentHandle {
             inner: Torrent::MagnetUriTorrent(Arc::new(magnet)),
+            source: TorrentSource::MagnetURI("magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=Progress".to_string()),
             download_directory,
+            resumed: Notify::new(),
+            removed: AtomicCell::new(false),
         };
 
         assert_eq!(handle.bytes_complete(), 8);
@@ -537,14 +1440,6 @@ mod tests {
     struct TestTorrent;
 
     impl TestTorrent {
-        fn download_directory() -> PathBuf {
-            std::env::temp_dir().join(format!(
-                "hyperblow-engine-test-{}-{:?}",
-                std::process::id(),
-                std::thread::current().id()
-            ))
-        }
-
         fn metadata(piece: &[u8]) -> FileMeta {
             let piece_hash: [u8; 20] = Sha1::digest(piece).into();
             FileMeta {
@@ -562,6 +1457,8 @@ mod tests {
                 encoding: None,
                 created_by: None,
                 acceptable_source: None,
+                url_list: None,
+                httpseeds: None,
             }
         }
     }
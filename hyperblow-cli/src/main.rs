@@ -34,12 +34,20 @@ fn main() -> Result<()> {
     // Creates engine
     let engine = Engine::try_new()?;
     info!(download_directory = %engine.download_directory().display(), "engine initialized");
+    engine.set_tier_mode(args.tier_mode());
+    if let Some(port) = args.stream_port {
+        StartupTorrentLoader::start_stream_server(engine.clone(), port)?;
+    }
+    StartupTorrentLoader::resume_in_engine(engine.clone())?;
     if let Some(source) = args.source()? {
         StartupTorrentLoader::spawn_in_engine(engine.clone(), source)?;
     }
 
     info!("starting TUI");
-    TuiApplication::run_ui(engine.clone())?;
+    let ui_result = TuiApplication::run_ui(engine.clone());
+    // The torrents are stopped and their resume files written, so the next session continues from here
+    engine.shutdown();
+    ui_result?;
 
     info!("hyperblow CLI exited");
     Ok(())
@@ -59,4 +67,18 @@ impl StartupTorrentLoader {
         runtime.block_on(engine.spawn(source))?;
         Ok(())
     }
+
+    /// Serves the files of the torrents to media players, on the given port of 127.0.0.1
+    fn start_stream_server(engine: std::sync::Arc<Engine>, port: u16) -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        runtime.block_on(engine.start_stream_server(port))?;
+        Ok(())
+    }
+
+    /// Continues the torrents of the previous session from their resume files
+    fn resume_in_engine(engine: std::sync::Arc<Engine>) -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        runtime.block_on(engine.resume_torrents());
+        Ok(())
+    }
 }
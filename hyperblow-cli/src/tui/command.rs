@@ -1,4 +1,7 @@
-use crate::engine::{Engine, TorrentSource};
+use crate::{
+    core::file_priorities::FilePriority,
+    engine::{Engine, EngineError, TorrentSource},
+};
 use hyperblow::parser::magnet_uri_parser::MagnetURIMeta;
 use std::{
     env, fs,
@@ -14,14 +17,44 @@ use tracing::{debug, error, info};
 pub(crate) enum CommandAction {
     File(PathBuf),
     Magnet(String),
+    /// Hashes the data of the selected torrent again
+    Recheck,
+    /// Pauses the selected torrent
+    Pause,
+    /// Resumes the selected torrent after a pause
+    Resume,
+    /// Removes the selected torrent, with or without the data it downloaded
+    Remove {
+        delete_data: bool,
+    },
+    /// Switches the selected torrent between downloading its pieces in order and rarest first
+    Sequential,
+    /// Gives files of the selected torrent a priority, it comes from the Files tab rather than
+    /// from a typed command
+    Prioritize {
+        files: Vec<usize>,
+        priority: FilePriority,
+    },
+    /// Puts the first and last pieces of files of the selected torrent first, it comes from
+    /// selecting a file in the Files tab
+    PrioritizeEdges {
+        files: Vec<usize>,
+    },
     Quit,
 }
 
 impl CommandAction {
-    fn kind(&self) -> &'static str {
+    pub(crate) fn kind(&self) -> &'static str {
         match self {
             Self::File(_) => "file",
             Self::Magnet(_) => "magnet",
+            Self::Recheck => "recheck",
+            Self::Pause => "pause",
+            Self::Resume => "resume",
+            Self::Remove { .. } => "remove",
+            Self::Sequential => "sequential",
+            Self::Prioritize { .. } => "priority",
+            Self::PrioritizeEdges { .. } => "edges",
             Self::Quit => "quit",
         }
     }
@@ -29,7 +62,7 @@ impl CommandAction {
 
 #[derive(Debug, Error, PartialEq, Eq)]
 pub(crate) enum CommandInputError {
-    #[error("type :file <path>, :magnet <uri>, :q, or :quit")]
+    #[error("type :file <path>, :magnet <uri>, :recheck, :pause, :resume, :remove, :sequential, :q, or :quit")]
     Empty,
 
     #[error("unknown command :{0}")]
@@ -49,6 +82,9 @@ pub(crate) enum CommandInputError {
 
     #[error("invalid magnet URI")]
     InvalidMagnetUri,
+
+    #[error("unknown option {0}: use :remove or :remove --delete-data")]
+    UnknownRemoveOption(String),
 }
 
 #[derive(Debug)]
@@ -70,6 +106,11 @@ impl CommandParser {
         match command.to_ascii_lowercase().as_str() {
             "file" => Self::parse_file(argument),
             "magnet" => Self::parse_magnet(argument),
+            "recheck" => Ok(CommandAction::Recheck),
+            "pause" => Ok(CommandAction::Pause),
+            "resume" => Ok(CommandAction::Resume),
+            "remove" => Self::parse_remove(argument),
+            "sequential" => Ok(CommandAction::Sequential),
             "q" | "quit" => Ok(CommandAction::Quit),
             unknown => Err(CommandInputError::UnknownCommand(unknown.to_string())),
         }
@@ -109,19 +150,40 @@ impl CommandParser {
         }
         Ok(CommandAction::Magnet(uri.to_string()))
     }
+
+    fn parse_remove(argument: &str) -> Result<CommandAction, CommandInputError> {
+        match argument.trim() {
+            "" => Ok(CommandAction::Remove { delete_data: false }),
+            "--delete-data" => Ok(CommandAction::Remove { delete_data: true }),
+            option => Err(CommandInputError::UnknownRemoveOption(option.to_string())),
+        }
+    }
 }
 
+/// Every command, as it's suggested to the user
+const COMMANDS: [&str; 9] = [
+    "file ",
+    "magnet ",
+    "recheck",
+    "pause",
+    "resume",
+    "remove ",
+    "sequential",
+    "q",
+    "quit",
+];
+
 pub(crate) struct CommandSuggester;
 
 impl CommandSuggester {
     pub(crate) fn suggestions(input: &str, limit: usize) -> Vec<String> {
         let input = input.trim_start();
         if input.is_empty() {
-            return vec!["file ".to_string(), "magnet ".to_string(), "q".to_string(), "quit".to_string()];
+            return COMMANDS.iter().map(|command| command.to_string()).collect();
         }
 
         if !input.contains(char::is_whitespace) {
-            return ["file ", "magnet ", "q", "quit"]
+            return COMMANDS
                 .into_iter()
                 .filter(|command| command.trim_end().starts_with(input))
                 .map(ToOwned::to_owned)
@@ -132,6 +194,7 @@ impl CommandSuggester {
         match command.to_ascii_lowercase().as_str() {
             "file" => FilePathSuggester::suggestions(argument, limit),
             "magnet" => vec!["magnet magnet:?xt=urn:btih:".to_string()],
+            "remove" => vec!["remove --delete-data".to_string()],
             _ => Vec::new(),
         }
     }
@@ -159,17 +222,43 @@ impl CommandExecutor {
         match action {
             CommandAction::File(path) => format!("Opening {}...", path.display()),
             CommandAction::Magnet(_) => "Opening magnet URI...".to_string(),
+            CommandAction::Recheck => "Rechecking torrent data...".to_string(),
+            CommandAction::Pause => "Pausing torrent...".to_string(),
+            CommandAction::Resume => "Resuming torrent...".to_string(),
+            CommandAction::Remove { delete_data: false } => "Removing torrent...".to_string(),
+            CommandAction::Remove { delete_data: true } => "Removing torrent and its data...".to_string(),
+            CommandAction::Sequential => "Switching sequential download...".to_string(),
+            CommandAction::Prioritize { .. } => "Setting file priority...".to_string(),
+            CommandAction::PrioritizeEdges { .. } => "Moving the file to the front...".to_string(),
             CommandAction::Quit => "Quitting...".to_string(),
         }
     }
 
-    pub(crate) fn spawn(action: CommandAction, input: String, engine: Arc<Engine>, command_result_sender: Sender<CommandExecutionResult>) {
+    /// Runs the command on its own thread, "torrent_index" is the torrent selected in the UI that
+    /// torrent commands such as :recheck or :pause act on
+    pub(crate) fn spawn(
+        action: CommandAction,
+        input: String,
+        torrent_index: usize,
+        engine: Arc<Engine>,
+        command_result_sender: Sender<CommandExecutionResult>,
+    ) {
         thread::spawn(move || {
             let action_kind = action.kind();
             debug!(source = action_kind, "command executor started");
             let source = match action {
                 CommandAction::File(path) => TorrentSource::FilePath(path.to_string_lossy().into_owned()),
                 CommandAction::Magnet(uri) => TorrentSource::MagnetURI(uri),
+                CommandAction::Recheck
+                | CommandAction::Pause
+                | CommandAction::Resume
+                | CommandAction::Remove { .. }
+                | CommandAction::Sequential
+                | CommandAction::Prioritize { .. }
+                | CommandAction::PrioritizeEdges { .. } => {
+                    let _ = command_result_sender.send(Self::run_on_torrent(action, input, torrent_index, engine));
+                    return;
+                }
                 CommandAction::Quit => {
                     let _ = command_result_sender.send(CommandExecutionResult::Loaded {
                         message: "Quit command handled".to_string(),
@@ -200,6 +289,86 @@ impl CommandExecutor {
             let _ = command_result_sender.send(execution_result);
         });
     }
+
+    /// Runs a command that acts on the torrent at the index and gives back what it did
+    fn run_on_torrent(action: CommandAction, input: String, torrent_index: usize, engine: Arc<Engine>) -> CommandExecutionResult {
+        let action_kind = action.kind();
+        let result = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|error| error.to_string())
+            .and_then(|runtime| {
+                runtime
+                    .block_on(Self::torrent_action(action, torrent_index, engine))
+                    .map_err(|error| error.to_string())
+            });
+
+        match result {
+            Ok(message) => {
+                info!(command = action_kind, torrent_index, message = %message, "command ran on torrent");
+                CommandExecutionResult::Loaded { message }
+            }
+            Err(message) => {
+                error!(command = action_kind, torrent_index, error = %message, "command failed on torrent");
+                CommandExecutionResult::Failed { input, message }
+            }
+        }
+    }
+
+    async fn torrent_action(action: CommandAction, torrent_index: usize, engine: Arc<Engine>) -> Result<String, EngineError> {
+        match action {
+            CommandAction::Recheck => {
+                let handle = engine.torrent(torrent_index).await?;
+                let verified_pieces = handle.force_recheck().await?;
+                Ok(format!(
+                    "Rechecked {}: {verified_pieces}/{} pieces",
+                    handle.name(),
+                    handle.pieces_total()
+                ))
+            }
+            CommandAction::Pause => {
+                let handle = engine.torrent(torrent_index).await?;
+                handle.pause().await?;
+                Ok(format!("Paused {}", handle.name()))
+            }
+            CommandAction::Resume => {
+                let handle = engine.torrent(torrent_index).await?;
+                handle.resume().await?;
+                Ok(format!("Resumed {}", handle.name()))
+            }
+            CommandAction::Remove { delete_data } => {
+                let info_hash = engine.torrent(torrent_index).await?.info_hash();
+                let handle = engine.remove(&info_hash, delete_data).await?;
+                if delete_data {
+                    Ok(format!("Removed {} and its data", handle.name()))
+                } else {
+                    Ok(format!("Removed {}", handle.name()))
+                }
+            }
+            CommandAction::Sequential => {
+                let handle = engine.torrent(torrent_index).await?;
+                if handle.toggle_sequential().await {
+                    Ok(format!("Downloading {} in order", handle.name()))
+                } else {
+                    Ok(format!("Downloading {} rarest first", handle.name()))
+                }
+            }
+            CommandAction::PrioritizeEdges { files } => {
+                let handle = engine.torrent(torrent_index).await?;
+                handle.prioritize_file_edges(&files).await;
+                Ok(format!(
+                    "Downloading the first and last pieces of the selected file of {} first",
+                    handle.name()
+                ))
+            }
+            CommandAction::Prioritize { files, priority } => {
+                let handle = engine.torrent(torrent_index).await?;
+                handle.set_file_priority(&files, priority).await;
+                Ok(format!("Set {} files of {} to {}", files.len(), handle.name(), priority.label()))
+            }
+            CommandAction::File(_) | CommandAction::Magnet(_) | CommandAction::Quit => Err(EngineError::NotATorrentCommand(action.kind())),
+        }
+    }
 }
 
 struct FilePathSuggester;
@@ -383,17 +552,14 @@ impl PathExpander {
 #[cfg(test)]
 mod tests {
     use super::{CommandAction, CommandInputError, CommandParser, CommandSuggester, PathExpander};
-    use std::{
-        env, fs,
-        path::PathBuf,
-        time::{SystemTime, UNIX_EPOCH},
-    };
+    use crate::core::test_support::temp_dir;
+    use std::{env, fs, path::PathBuf};
 
     const VALID_MAGNET: &str = "magnet:?xt=urn:btih:08ada5a7a6183aae1e09d831df6748d566095a10&dn=Example";
 
     #[test]
     fn parses_file_command_with_existing_path() {
-        let temp_dir = test_temp_dir();
+        let temp_dir = temp_dir("command");
         let torrent_path = temp_dir.join("sample torrent.torrent");
         fs::write(&torrent_path, b"not a real torrent").expect("test torrent path should be writable");
 
@@ -429,6 +595,38 @@ mod tests {
         assert_eq!(CommandParser::parse("quit"), Ok(CommandAction::Quit));
     }
 
+    #[test]
+    fn parses_recheck_command() {
+        assert_eq!(CommandParser::parse("recheck"), Ok(CommandAction::Recheck));
+        assert_eq!(CommandSuggester::suggestions("rec", 8), vec!["recheck".to_string()]);
+    }
+
+    #[test]
+    fn parses_sequential_command() {
+        assert_eq!(CommandParser::parse("sequential"), Ok(CommandAction::Sequential));
+        assert_eq!(CommandSuggester::suggestions("seq", 8), vec!["sequential".to_string()]);
+    }
+
+    #[test]
+    fn parses_lifecycle_commands() {
+        assert_eq!(CommandParser::parse("pause"), Ok(CommandAction::Pause));
+        assert_eq!(CommandParser::parse("resume"), Ok(CommandAction::Resume));
+        assert_eq!(CommandParser::parse("remove"), Ok(CommandAction::Remove { delete_data: false }));
+        assert_eq!(
+            CommandParser::parse("remove --delete-data"),
+            Ok(CommandAction::Remove { delete_data: true })
+        );
+        assert_eq!(
+            CommandParser::parse("remove --force"),
+            Err(CommandInputError::UnknownRemoveOption("--force".to_string()))
+        );
+        assert_eq!(CommandSuggester::suggestions("re", 8), vec!["recheck", "resume", "remove "]);
+        assert_eq!(
+            CommandSuggester::suggestions("remove ", 8),
+            vec!["remove --delete-data".to_string()]
+        );
+    }
+
     #[test]
     fn suggests_quit_commands() {
         assert!(CommandSuggester::suggestions("", 8).contains(&"quit".to_string()));
@@ -456,7 +654,7 @@ mod tests {
 
     #[test]
     fn suggests_file_paths_and_prioritizes_directories() {
-        let temp_dir = test_temp_dir();
+        let temp_dir = temp_dir("command");
         fs::create_dir(temp_dir.join("alpha_dir")).expect("test directory should be created");
         fs::write(temp_dir.join("alpha.torrent"), b"torrent").expect("test torrent should be created");
         fs::write(temp_dir.join("alpha.txt"), b"text").expect("test text file should be created");
@@ -469,16 +667,4 @@ mod tests {
 
         fs::remove_dir_all(temp_dir).expect("temp dir should be removed");
     }
-
-    fn test_temp_dir() -> PathBuf {
-        let nonce = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("system clock should be after unix epoch")
-            .as_nanos();
-        let path = PathBuf::from("target")
-            .join("ui-command-tests")
-            .join(format!("t{}{}", std::process::id(), nonce % 1_000_000_000));
-        fs::create_dir_all(&path).expect("temp dir should be created");
-        path
-    }
 }
@@ -30,6 +30,14 @@ impl DetailsTab {
             .bytes_total_known()
             .map(ByteSizeFormatter::human_readable)
             .unwrap_or_else(|| "Unknown".to_string());
+        // The files are streamed from "<stream>/<path of the file>"
+        let stream = match state.engine.stream_port() {
+            Some(port) => {
+                let info_hash = handle.info_hash().iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+                format!("http://127.0.0.1:{port}/{info_hash}/")
+            }
+            None => "Off, start with --stream-port".to_string(),
+        };
         let rows = [
             Row::new(["Name".to_string(), handle.name()]),
             Row::new(["Size".to_string(), bytes_total]),
@@ -41,6 +49,11 @@ impl DetailsTab {
             Row::new(["Piece size".to_string(), ByteSizeFormatter::human_readable(handle.piece_size())]),
             Row::new(["Peers".to_string(), handle.connected_peers().to_string()]),
             Row::new(["Trackers".to_string(), handle.tracker_snapshots().len().to_string()]),
+            Row::new([
+                "Order".to_string(),
+                if handle.is_sequential() { "Sequential" } else { "Rarest first" }.to_string(),
+            ]),
+            Row::new(["Stream".to_string(), stream]),
         ];
 
         let table = Table::new(rows, [Constraint::Length(16), Constraint::Min(10)]).block(block);
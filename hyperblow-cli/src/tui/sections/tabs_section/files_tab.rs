@@ -1,13 +1,25 @@
 #![allow(unused_imports)]
 
-use crate::tui::tui_state::TUIState;
+use super::RowViewport;
+use crate::{core::file_priorities::FilePriority, tui::tui_state::TUIState};
 use ratatui::{
     layout::{Constraint, Layout, Rect},
-    widgets::{Block, BorderType, Borders, Paragraph, Row, Table},
+    style::{Color, Modifier, Style},
+    widgets::{Block, BorderType, Borders, Cell, Paragraph, Row, Table},
     Frame,
 };
 use std::rc::Rc;
 
+const PATH: &str = "Path";
+const PATH_PERC: u16 = 80;
+
+const PRIORITY: &str = "Priority";
+/// The Priority column is drawn on the right of the Path column, clicking it switches the
+/// priority of the row
+pub(crate) const PRIORITY_PERC: u16 = 20;
+
+const COLUMN_WIDTHS: [Constraint; 2] = [Constraint::Percentage(PATH_PERC), Constraint::Percentage(PRIORITY_PERC)];
+
 /// Data for the Bandwidth Tab Section of TUI
 pub struct FilesTab;
 
@@ -23,7 +35,7 @@ impl FilesTab {
             .cloned()
             .collect();
 
-        let table = Table::new([Row::new(["Path"])], [Constraint::Percentage(100)]);
+        let table = Table::new([Row::new([PATH, PRIORITY])], COLUMN_WIDTHS);
         frame.render_widget(table, area[0]);
 
         let Some(torrent_handles) = state.engine.torrent_snapshot() else {
@@ -35,14 +47,34 @@ impl FilesTab {
             return;
         };
 
-        let names = handle.file_tree_names();
-        if names.is_empty() {
+        let files = handle.file_rows();
+        if files.is_empty() {
+            state.set_max_content_row_index(0);
             frame.render_widget(Paragraph::new("No file tree available yet"), area[1]);
             return;
         }
 
-        let rows = names.into_iter().map(|name| Row::new([name]));
-        let table = Table::new(rows, [Constraint::Percentage(100)]);
+        let visible_rows = area[1].height as usize;
+        let max_selected_index = files.len() - 1;
+        state.set_max_content_row_index(max_selected_index);
+        let selected_index = state.content_row_index().min(max_selected_index);
+        let viewport_start = RowViewport::start_for_selection(selected_index, files.len(), visible_rows);
+
+        let rows = files
+            .into_iter()
+            .enumerate()
+            .skip(viewport_start)
+            .take(visible_rows)
+            .map(|(index, file)| {
+                let priority = file.priority.map_or("Mixed", FilePriority::label);
+                let row = Row::new([Cell::from(file.name), Cell::from(priority)]);
+                if index == selected_index {
+                    row.style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD))
+                } else {
+                    row
+                }
+            });
+        let table = Table::new(rows, COLUMN_WIDTHS);
         frame.render_widget(table, area[1]);
     }
 
@@ -41,3 +41,18 @@ impl TabsSection {
         // Draw the header and torrents column
     }
 }
+
+/// The rows of a tab that are drawn, when there are more of them than fit
+pub(crate) struct RowViewport;
+
+impl RowViewport {
+    /// Index of the first row to draw, so the selected row is always drawn
+    pub(crate) fn start_for_selection(selected_index: usize, total_rows: usize, visible_rows: usize) -> usize {
+        if total_rows == 0 || visible_rows == 0 {
+            return 0;
+        }
+
+        let max_start = total_rows.saturating_sub(visible_rows);
+        selected_index.saturating_add(1).saturating_sub(visible_rows).min(max_start)
+    }
+}
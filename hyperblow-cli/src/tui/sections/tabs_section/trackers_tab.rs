@@ -1,3 +1,4 @@
+use super::RowViewport;
 use crate::tui::tui_state::TUIState;
 use ratatui::{
     layout::{Constraint, Layout, Rect},
@@ -14,7 +15,25 @@ const URL: &str = "URL";
 const URL_PERC: u16 = 35;
 
 const STATUS: &str = "Status";
-const STATUS_PERC: u16 = 60;
+const STATUS_PERC: u16 = 30;
+
+const SEEDERS: &str = "Seeders";
+const SEEDERS_PERC: u16 = 10;
+
+const COMPLETED: &str = "Completed";
+const COMPLETED_PERC: u16 = 10;
+
+const LEECHERS: &str = "Leechers";
+const LEECHERS_PERC: u16 = 10;
+
+const COLUMN_WIDTHS: [Constraint; 6] = [
+    Constraint::Percentage(SN_PERC),
+    Constraint::Percentage(URL_PERC),
+    Constraint::Percentage(STATUS_PERC),
+    Constraint::Percentage(SEEDERS_PERC),
+    Constraint::Percentage(COMPLETED_PERC),
+    Constraint::Percentage(LEECHERS_PERC),
+];
 
 pub struct TrackersTab {}
 
@@ -50,12 +69,8 @@ impl TrackersTab {
     // Draws header row and leaves one row spacing below
     fn draw_header_row(frame: &mut Frame, area: Rect) {
         let table = Table::new(
-            [Row::new(vec![SN, URL, STATUS]), Row::new([""; 3])],
-            [
-                Constraint::Percentage(SN_PERC),
-                Constraint::Percentage(URL_PERC),
-                Constraint::Percentage(STATUS_PERC),
-            ],
+            [Row::new(vec![SN, URL, STATUS, SEEDERS, COMPLETED, LEECHERS]), Row::new([""; 6])],
+            COLUMN_WIDTHS,
         );
         frame.render_widget(table, area.to_owned());
     }
@@ -66,26 +81,12 @@ impl TrackersTab {
 
         let current_torrent_index = state.torrent_index();
         let Some(torrent_handles) = state.engine.torrent_snapshot() else {
-            let table = Table::new(
-                [Row::new(["", "Torrent state is updating...", ""])],
-                [
-                    Constraint::Percentage(SN_PERC),
-                    Constraint::Percentage(URL_PERC),
-                    Constraint::Percentage(STATUS_PERC),
-                ],
-            );
+            let table = Table::new([Row::new(["", "Torrent state is updating...", ""])], COLUMN_WIDTHS);
             frame.render_widget(table, area);
             return;
         };
         let Some(current_torrent_handle) = torrent_handles.get(current_torrent_index) else {
-            let table = Table::new(
-                [Row::new(["", "No torrent selected", ""])],
-                [
-                    Constraint::Percentage(SN_PERC),
-                    Constraint::Percentage(URL_PERC),
-                    Constraint::Percentage(STATUS_PERC),
-                ],
-            );
+            let table = Table::new([Row::new(["", "No torrent selected", ""])], COLUMN_WIDTHS);
             frame.render_widget(table, area);
             return;
         };
@@ -100,14 +101,26 @@ impl TrackersTab {
         let max_selected_index = trackers.len().saturating_sub(1);
         state.set_max_content_row_index(max_selected_index);
         let selected_index = state.content_row_index().min(max_selected_index);
-        let viewport_start = TrackerViewport::start_for_selection(selected_index, trackers.len(), visible_rows);
+        let viewport_start = RowViewport::start_for_selection(selected_index, trackers.len(), visible_rows);
 
         for (index, tracker) in trackers.into_iter().enumerate().skip(viewport_start).take(visible_rows) {
             let sn_widget = Cell::from((index + 1).to_string());
             let url_widget = Cell::from(tracker.url);
             let tracker_state_color = if tracker.is_error { Color::Red } else { Color::Green };
             let tracker_state_widget = Cell::from(tracker.status).style(Style::default().fg(tracker_state_color));
-            let row = Row::new([sn_widget, url_widget, tracker_state_widget]);
+            // Trackers that weren't scraped yet, or don't support it, have no counts to show
+            let [seeders_widget, completed_widget, leechers_widget] = match tracker.scrape {
+                Some(stats) => [stats.seeders, stats.completed, stats.leechers].map(|count| Cell::from(count.to_string())),
+                None => [(); 3].map(|_| Cell::from("-")),
+            };
+            let row = Row::new([
+                sn_widget,
+                url_widget,
+                tracker_state_widget,
+                seeders_widget,
+                completed_widget,
+                leechers_widget,
+            ]);
             let row = if index == selected_index {
                 row.style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD))
             } else {
@@ -116,41 +129,23 @@ impl TrackersTab {
             row_s.push(row);
         }
 
-        let table = Table::new(
-            row_s,
-            [
-                Constraint::Percentage(SN_PERC),
-                Constraint::Percentage(URL_PERC),
-                Constraint::Percentage(STATUS_PERC),
-            ],
-        );
+        let table = Table::new(row_s, COLUMN_WIDTHS);
 
         frame.render_widget(table, area.to_owned());
     }
 }
 
-struct TrackerViewport;
-
-impl TrackerViewport {
-    fn start_for_selection(selected_index: usize, total_rows: usize, visible_rows: usize) -> usize {
-        if total_rows == 0 || visible_rows == 0 {
-            return 0;
-        }
-
-        let max_start = total_rows.saturating_sub(visible_rows);
-        selected_index.saturating_add(1).saturating_sub(visible_rows).min(max_start)
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use super::{TrackerViewport, TrackersTab};
+    use super::TrackersTab;
+    use crate::tui::sections::tabs_section::RowViewport;
     use crate::{
         engine::{Engine, TorrentSource},
         tui::tui_state::TUIState,
     };
     use ratatui::{backend::TestBackend, layout::Rect, Terminal};
-    use std::rc::Rc;
+    use std::{rc::Rc, time::Duration};
+    use url::Url;
 
     #[test]
     fn tracker_rows_scroll_with_content_offset() {
@@ -159,11 +154,24 @@ mod tests {
             .enable_all()
             .build()
             .expect("test runtime should initialize");
-        runtime.block_on(async {
-            engine
+        // The trackers are shuffled once the session creates them, the last row is only known then
+        let last_tracker = runtime.block_on(async {
+            let handle = engine
                 .spawn(TorrentSource::MagnetURI(MagnetTestFixture::many_trackers_uri(13)))
                 .await
                 .expect("magnet should spawn");
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let trackers = handle.tracker_snapshots();
+                    if trackers.iter().all(|tracker| tracker.status != "Queued") {
+                        let last_tracker = Url::parse(&trackers[12].url).expect("tracker URL should parse");
+                        return last_tracker.host_str().expect("tracker URL should have a host").to_string();
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("trackers should be created")
         });
 
         let state = Rc::new(TUIState::new(engine));
@@ -189,12 +197,12 @@ mod tests {
             .map(|cell| cell.symbol())
             .collect::<String>();
 
-        assert!(rendered.contains("tracker12.example.com"));
+        assert!(rendered.contains(&last_tracker));
     }
 
     #[test]
     fn tracker_viewport_keeps_last_item_reachable() {
-        assert_eq!(TrackerViewport::start_for_selection(12, 13, 6), 7);
+        assert_eq!(RowViewport::start_for_selection(12, 13, 6), 7);
     }
 
     struct MagnetTestFixture;
@@ -1,8 +1,8 @@
-use super::mouse::Mouse;
+use super::{command::CommandAction, mouse::Mouse};
 //use super::sections::tabs_section::bandwidth_tab::TabSectionBandwidth;
 //use super::sections::tabs_section::details_tab::TabSectionDetails;
 //use super::sections::tabs_section::files_tab::TabSectionFiles;
-use crate::engine::Engine;
+use crate::{core::file_priorities::FilePriority, engine::Engine};
 use std::{
     cell::{Cell, RefCell},
     rc::Rc,
@@ -337,8 +337,37 @@ impl TUIState {
     //
 
     /// Toggles either pause or resume of the torrent, which means that when this method is called
-    /// with an index of torrent, it shall be paused or resumed
-    pub fn toggle_torrent(&self, _index: usize) {}
+    /// with an index of torrent, it gives the command that pauses or resumes it
+    pub(crate) fn toggle_torrent(&self, index: usize) -> Option<CommandAction> {
+        let torrents = self.engine.torrent_snapshot()?;
+        let handle = torrents.get(index)?;
+        Some(if handle.is_paused() {
+            CommandAction::Resume
+        } else {
+            CommandAction::Pause
+        })
+    }
+
+    /// Gives the command that switches the files of the row selected in the Files tab to their
+    /// next priority, the files of a row with mixed priorities all become Normal
+    pub(crate) fn cycle_file_priority(&self) -> Option<CommandAction> {
+        let torrents = self.engine.torrent_snapshot()?;
+        let handle = torrents.get(self.torrent_index())?;
+        let row = handle.file_rows().into_iter().nth(self.content_row_index())?;
+        Some(CommandAction::Prioritize {
+            files: row.files,
+            priority: row.priority.map_or(FilePriority::Normal, FilePriority::next),
+        })
+    }
+
+    /// Gives the command that puts the first and last pieces of the file selected in the Files tab
+    /// first, there's none for a directory
+    pub(crate) fn prioritize_selected_file(&self) -> Option<CommandAction> {
+        let torrents = self.engine.torrent_snapshot()?;
+        let handle = torrents.get(self.torrent_index())?;
+        let row = handle.file_rows().into_iter().nth(self.content_row_index())?;
+        (row.files.len() == 1).then_some(CommandAction::PrioritizeEdges { files: row.files })
+    }
     // Gets the data to be displayed on the TorrentsSection
     // It has following structure of HashMap represented in JSON Structure:
     // {
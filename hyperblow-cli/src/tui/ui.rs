@@ -6,7 +6,11 @@ use super::{
     mouse::MouseEv,
     sections::{
         tabs_section::{
-            bandwidth_tab::BandwidthTab, details_tab::DetailsTab, files_tab::FilesTab, peers_tab::PeersTab, pieces_tab::PiecesTab,
+            bandwidth_tab::BandwidthTab,
+            details_tab::DetailsTab,
+            files_tab::{FilesTab, PRIORITY_PERC},
+            peers_tab::PeersTab,
+            pieces_tab::PiecesTab,
             trackers_tab::TrackersTab,
         },
         torrents_section::TorrentsSection,
@@ -160,12 +164,46 @@ impl TuiApplication {
                             event::KeyCode::Up => {
                                 state.decrement_torrent_index();
                             }
+                            event::KeyCode::Char('p') => {
+                                if let Some(action) = state.toggle_torrent(state.torrent_index()) {
+                                    CommandController::run_torrent_action(state.as_ref(), action, command_result_sender.clone());
+                                }
+                            }
+                            event::KeyCode::Char(' ') if matches!(*state.tab.borrow(), Tab::Files) => {
+                                if let Some(action) = state.cycle_file_priority() {
+                                    CommandController::run_torrent_action(state.as_ref(), action, command_result_sender.clone());
+                                }
+                            }
+                            event::KeyCode::Char('j') => {
+                                state.increment_content_row_index();
+                            }
+                            event::KeyCode::Char('k') => {
+                                state.decrement_content_row_index();
+                            }
+                            event::KeyCode::Enter if matches!(*state.tab.borrow(), Tab::Files) => {
+                                if let Some(action) = state.prioritize_selected_file() {
+                                    CommandController::run_torrent_action(state.as_ref(), action, command_result_sender.clone());
+                                }
+                            }
+                            event::KeyCode::Char('s') => {
+                                CommandController::run_torrent_action(
+                                    state.as_ref(),
+                                    CommandAction::Sequential,
+                                    command_result_sender.clone(),
+                                );
+                            }
+                            event::KeyCode::Char('x') | event::KeyCode::Delete => {
+                                let action = CommandAction::Remove { delete_data: false };
+                                CommandController::run_torrent_action(state.as_ref(), action, command_result_sender.clone());
+                            }
                             _ => {}
                         }
                     }
 
                     event::Event::Mouse(mouse_event) => {
-                        MouseController::handle_event(mouse_event, terminal_area, state.as_ref());
+                        if let Some(action) = MouseController::handle_event(mouse_event, terminal_area, state.as_ref()) {
+                            CommandController::run_torrent_action(state.as_ref(), action, command_result_sender.clone());
+                        }
                     }
                     _ => {}
                 };
@@ -243,7 +281,7 @@ impl CommandController {
 
     fn submit(state: &TUIState, command_result_sender: Sender<CommandExecutionResult>) -> bool {
         if state.has_pending_commands() {
-            state.set_command_feedback("A torrent open command is already running".to_string(), true);
+            state.set_command_feedback("A command is already running".to_string(), true);
             return false;
         }
 
@@ -257,10 +295,7 @@ impl CommandController {
                 true
             }
             Ok(action) => {
-                state.increment_pending_commands();
-                state.exit_command_mode();
-                state.set_command_feedback(CommandExecutor::pending_message(&action), false);
-                CommandExecutor::spawn(action, input, state.engine.clone(), command_result_sender);
+                Self::execute(state, action, input, command_result_sender);
                 false
             }
             Err(error) => {
@@ -271,6 +306,25 @@ impl CommandController {
         }
     }
 
+    /// Runs a pause, resume or remove of the selected torrent that was asked for with a key, the
+    /// same way as if it was typed in as a command
+    fn run_torrent_action(state: &TUIState, action: CommandAction, command_result_sender: Sender<CommandExecutionResult>) {
+        if state.has_pending_commands() {
+            state.set_command_feedback("A command is already running".to_string(), true);
+            return;
+        }
+        let input = action.kind().to_string();
+        debug!(command = %input, "torrent key pressed");
+        Self::execute(state, action, input, command_result_sender);
+    }
+
+    fn execute(state: &TUIState, action: CommandAction, input: String, command_result_sender: Sender<CommandExecutionResult>) {
+        state.increment_pending_commands();
+        state.exit_command_mode();
+        state.set_command_feedback(CommandExecutor::pending_message(&action), false);
+        CommandExecutor::spawn(action, input, state.torrent_index(), state.engine.clone(), command_result_sender);
+    }
+
     fn refresh_suggestions(state: &TUIState) {
         state.set_command_suggestions(CommandSuggester::suggestions(&state.command_input(), 8));
     }
@@ -444,7 +498,9 @@ impl AppRenderer {
 struct MouseController;
 
 impl MouseController {
-    fn handle_event(mouse_event: event::MouseEvent, terminal_area: Rect, state: &TUIState) {
+    /// Updates the UI for the mouse event, a click can also ask for a command on the selected
+    /// torrent, eg. a click on the Priority column of the Files tab
+    fn handle_event(mouse_event: event::MouseEvent, terminal_area: Rect, state: &TUIState) -> Option<CommandAction> {
         state.mouse.set_x(mouse_event.column);
         state.mouse.set_y(mouse_event.row);
 
@@ -458,10 +514,17 @@ impl MouseController {
             event::MouseEventKind::Down(event::MouseButton::Left) => {
                 state.mouse.set_event(MouseEv::Clicked);
                 if Self::select_tab_at(layout.tab_bar, position, state) {
-                    return;
+                    return None;
                 }
                 if Self::select_content_row_at(layout.tab_content_rows, position, state) {
-                    return;
+                    if HitTest::is_file_priority_at(layout.tab_content_rows, position, state) {
+                        debug!("mouse clicked file priority");
+                        return state.cycle_file_priority();
+                    }
+                    if matches!(*state.tab.borrow(), Tab::Files) {
+                        return state.prioritize_selected_file();
+                    }
+                    return None;
                 }
                 Self::select_torrent_at(layout.torrent_rows, position, state);
             }
@@ -510,6 +573,7 @@ impl MouseController {
             }
             event::MouseEventKind::Down(_) | event::MouseEventKind::Drag(_) | event::MouseEventKind::Moved => {}
         }
+        None
     }
 
     fn select_tab_at(tab_bar: Rect, position: Position, state: &TUIState) -> bool {
@@ -568,6 +632,16 @@ impl HitTest {
         }
     }
 
+    /// Whether the position is on the Priority column of the Files tab
+    fn is_file_priority_at(tab_content_rows: Rect, position: Position, state: &TUIState) -> bool {
+        if !matches!(*state.tab.borrow(), Tab::Files) {
+            return false;
+        }
+        let rows_area = Self::content_rows_area_for_current_tab(tab_content_rows, state);
+        let path_width = (u32::from(rows_area.width) * u32::from(100 - PRIORITY_PERC) / 100) as u16;
+        rows_area.contains(position) && position.x >= rows_area.x.saturating_add(path_width)
+    }
+
     fn content_row_index_at(content_rows: Rect, position: Position) -> Option<usize> {
         if content_rows.contains(position) {
             Some(position.y.saturating_sub(content_rows.y) as usize)
@@ -622,6 +696,7 @@ mod tests {
         AppLayout, AppRenderer, CommandController, HitTest, MouseController, RectMath, TAB_DIVIDER_WIDTH, TAB_PADDING_WIDTH, TAB_TITLES,
     };
     use crate::{
+        core::test_support::temp_dir,
         engine::{Engine, TorrentSource},
         tui::tui_state::TUIState,
     };
@@ -631,13 +706,7 @@ mod tests {
         layout::{Position, Rect},
         Terminal,
     };
-    use std::{
-        fs,
-        path::PathBuf,
-        rc::Rc,
-        sync::mpsc,
-        time::{SystemTime, UNIX_EPOCH},
-    };
+    use std::{fs, rc::Rc, sync::mpsc};
 
     #[test]
     fn renders_empty_app_without_panicking() {
@@ -706,7 +775,7 @@ mod tests {
 
     #[test]
     fn command_overlay_renders_path_hints() {
-        let temp_dir = UiTestHarness::temp_dir();
+        let temp_dir = temp_dir("ui");
         fs::write(temp_dir.join("alpha.torrent"), b"torrent").expect("test torrent should be created");
 
         let backend = TestBackend::new(100, 32);
@@ -887,6 +956,30 @@ mod tests {
         assert_eq!(state.content_row_index(), 2);
     }
 
+    #[test]
+    fn only_the_priority_column_of_the_files_tab_is_a_priority_click() {
+        let terminal_area = Rect::new(0, 0, 100, 32);
+        let layout = AppLayout::new(terminal_area);
+        let state = TUIState::new(Engine::new());
+        state.set_max_tab_index(TAB_TITLES.len() - 1);
+        state.set_tab_index(2);
+
+        let rows_area = HitTest::content_rows_area_for_current_tab(layout.tab_content_rows, &state);
+        let priority_column = Position {
+            x: rows_area.right() - 1,
+            y: rows_area.y,
+        };
+        let path_column = Position {
+            x: rows_area.x + 1,
+            y: rows_area.y,
+        };
+        assert!(HitTest::is_file_priority_at(layout.tab_content_rows, priority_column, &state));
+        assert!(!HitTest::is_file_priority_at(layout.tab_content_rows, path_column, &state));
+
+        state.set_tab_index(3);
+        assert!(!HitTest::is_file_priority_at(layout.tab_content_rows, priority_column, &state));
+    }
+
     struct UiTestHarness;
 
     impl UiTestHarness {
@@ -916,17 +1009,5 @@ mod tests {
                 modifiers: KeyModifiers::empty(),
             }
         }
-
-        fn temp_dir() -> PathBuf {
-            let nonce = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("system clock should be after unix epoch")
-                .as_nanos();
-            let path = PathBuf::from("target")
-                .join("ui-tests")
-                .join(format!("t{}{}", std::process::id(), nonce % 1_000_000_000));
-            fs::create_dir_all(&path).expect("temp dir should be created");
-            path
-        }
     }
 }
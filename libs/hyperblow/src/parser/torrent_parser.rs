@@ -49,6 +49,33 @@ pub struct FileMeta {
     /// **(Optional)** As "as" is a reserved keyword in rust, acceptable_source as in whole word is
     /// written, which Refers to a direct download from a web server. It's URL encoded
     pub acceptable_source: Option<String>,
+
+    /// **(Optional)** URLs of web seeds (BEP 19) the data of the torrent can be downloaded from
+    /// over HTTP, the torrent may give a single URL rather than a list
+    #[serde(rename = "url-list", default, deserialize_with = "UrlList::deserialize")]
+    pub url_list: Option<Vec<String>>,
+
+    /// **(Optional)** URLs of HTTP seeds (BEP 17), servers that hand out whole pieces of the
+    /// torrent by info hash and piece index
+    pub httpseeds: Option<Vec<String>>,
+}
+
+/// The "url-list" field is either a single URL or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl UrlList {
+    fn deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+        let urls = match <UrlList as serde::Deserialize>::deserialize(deserializer)? {
+            UrlList::One(url) => vec![url],
+            UrlList::Many(urls) => urls,
+        };
+        Ok(Some(urls.into_iter().filter(|url| !url.is_empty()).collect()))
+    }
 }
 
 /// The fields within the Info DataStructure are used to build "info hash", so it must the required
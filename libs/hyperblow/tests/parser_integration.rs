@@ -29,6 +29,31 @@ fn rejects_piece_hashes_that_are_not_twenty_byte_chunks() {
     assert!(meta.getPiecesHash().is_err());
 }
 
+#[test]
+fn parses_web_seed_url_list_given_as_a_string_or_a_list() {
+    let single = b"d8:announce30:udp://tracker.example.com:69694:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces20:abcdefghijklmnopqrste8:url-list23:http://seed.example/x/ye".to_vec();
+    let list = b"d8:announce30:udp://tracker.example.com:69694:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces20:abcdefghijklmnopqrste8:url-listl19:http://a.example/x/0:19:http://b.example/x/ee".to_vec();
+
+    let single = FileMeta::fromRawTorrentFile(single).expect("single url-list should parse");
+    let list = FileMeta::fromRawTorrentFile(list).expect("url-list should parse");
+
+    assert_eq!(single.url_list.unwrap(), vec!["http://seed.example/x/y"]);
+    assert_eq!(list.url_list.unwrap(), vec!["http://a.example/x/", "http://b.example/x/"]);
+    assert!(FileMeta::fromRawTorrentFile(ParserFixture::sample_single_file_torrent())
+        .expect("sample torrent should parse")
+        .url_list
+        .is_none());
+}
+
+#[test]
+fn parses_http_seeds() {
+    let torrent = b"d8:announce30:udp://tracker.example.com:69694:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces20:abcdefghijklmnopqrste9:httpseedsl26:http://seed.example/seederee".to_vec();
+
+    let meta = FileMeta::fromRawTorrentFile(torrent).expect("httpseeds should parse");
+
+    assert_eq!(meta.httpseeds.unwrap(), vec!["http://seed.example/seeder"]);
+}
+
 #[test]
 fn parses_magnet_metadata_with_trackers() {
     let magnet = MagnetURIMeta::fromMagnetURI(